@@ -0,0 +1,477 @@
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+use crate::engine::{Engine, PipelineStats};
+
+/// 运行时可替换的日志过滤器句柄（由 init_tracing 创建）。
+pub type LogReloadHandle = reload::Handle<EnvFilter, Registry>;
+
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// 鉴权请求头（settings.admin_token）
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+/// 管理接口共享状态
+#[derive(Clone, Default)]
+pub struct AdminState {
+    pub log_filter: Option<LogReloadHandle>,
+    /// 供 /cache、/stats 与 /metrics 接口读取缓存与计数
+    pub engine: Option<Engine>,
+    /// 共享密钥；配置后所有接口都要求匹配的 X-Admin-Token 头
+    pub token: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AdminResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl AdminResponse {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    fn error(status: u16, msg: impl Into<String>) -> Self {
+        Self::json(status, serde_json::json!({ "error": msg.into() }))
+    }
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    level: String,
+}
+
+pub async fn spawn(bind: SocketAddr, state: AdminState) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!(target = "admin", bind = %bind, "admin api started");
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!(target = "admin", error = %err, "admin accept failed");
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_conn(stream, &state).await {
+                    warn!(target = "admin", peer = %peer, error = %err, "admin request failed");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// 极简 HTTP/1.1：每个连接处理一个请求后关闭。
+async fn handle_conn(mut stream: TcpStream, state: &AdminState) -> anyhow::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let head_end = loop {
+        if let Some(pos) = find_head_end(&buf) {
+            break pos;
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            anyhow::bail!("request head too large");
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before request head");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0usize;
+    let mut token = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            } else if name.eq_ignore_ascii_case(ADMIN_TOKEN_HEADER) {
+                token = Some(value.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        anyhow::bail!("request body too large");
+    }
+
+    let mut body = buf[head_end + 4..].to_vec();
+    while body.len() < content_length {
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    let resp = handle_request(state, &method, &target, token.as_deref(), &body);
+    let out = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        resp.status,
+        reason_phrase(resp.status),
+        resp.content_type,
+        resp.body.len(),
+        resp.body
+    );
+    stream.write_all(out.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+/// 路由分发，独立于 IO 便于测试。token 为请求携带的 X-Admin-Token 头。
+pub fn handle_request(
+    state: &AdminState,
+    method: &str,
+    target: &str,
+    token: Option<&str>,
+    body: &[u8],
+) -> AdminResponse {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if let Some(expected) = state.token.as_deref()
+        && !token.is_some_and(|t| constant_time_eq(t.as_bytes(), expected.as_bytes()))
+    {
+        return AdminResponse::error(401, "missing or invalid admin token");
+    }
+    match (method, path) {
+        ("POST", "/loglevel") => set_log_level(state, body),
+        (_, "/loglevel") => AdminResponse::error(405, "method not allowed"),
+        (_, "/cache/flush" | "/cache/stats") if state.token.is_none() => {
+            // 清缓存可被用来放大上游流量，未配置密钥时不开放
+            AdminResponse::error(403, "admin_token not configured")
+        }
+        ("POST", "/cache/flush") => flush_cache(state, query),
+        ("GET", "/cache/stats") => cache_stats(state),
+        (_, "/cache/flush" | "/cache/stats") => AdminResponse::error(405, "method not allowed"),
+        ("GET", "/stats/pipelines") => pipeline_stats(state),
+        ("GET", "/metrics") => prometheus_metrics(state),
+        (_, "/stats/pipelines" | "/metrics") => AdminResponse::error(405, "method not allowed"),
+        _ => AdminResponse::error(404, "not found"),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn flush_cache(state: &AdminState, query: &str) -> AdminResponse {
+    let Some(engine) = state.engine.as_ref() else {
+        return AdminResponse::error(503, "cache unavailable");
+    };
+    match query_param(query, "qname") {
+        Some("") => AdminResponse::error(400, "empty qname"),
+        Some(qname) => {
+            let removed = engine.flush_cache_qname(qname);
+            info!(target = "admin", qname = %qname, removed, "cache entries flushed");
+            AdminResponse::json(200, serde_json::json!({ "qname": qname, "removed": removed }))
+        }
+        None => {
+            let removed = engine.flush_cache();
+            info!(target = "admin", removed, "cache flushed");
+            AdminResponse::json(200, serde_json::json!({ "removed": removed }))
+        }
+    }
+}
+
+fn cache_stats(state: &AdminState) -> AdminResponse {
+    let Some(engine) = state.engine.as_ref() else {
+        return AdminResponse::error(503, "cache unavailable");
+    };
+    AdminResponse::json(200, serde_json::json!(engine.cache_stats()))
+}
+
+fn pipeline_stats(state: &AdminState) -> AdminResponse {
+    let Some(engine) = state.engine.as_ref() else {
+        return AdminResponse::error(503, "stats unavailable");
+    };
+    let cache = engine.cache_stats();
+    let lookups = cache.hits + cache.misses;
+    let hit_ratio = if lookups == 0 { 0.0 } else { cache.hits as f64 / lookups as f64 };
+    AdminResponse::json(
+        200,
+        serde_json::json!({
+            "cache": { "hits": cache.hits, "misses": cache.misses, "hit_ratio": hit_ratio },
+            "pipelines": engine.pipeline_stats(),
+        }),
+    )
+}
+
+/// Prometheus 文本格式；pipeline 级计数带 pipeline 标签
+fn prometheus_metrics(state: &AdminState) -> AdminResponse {
+    use std::fmt::Write;
+    let Some(engine) = state.engine.as_ref() else {
+        return AdminResponse::error(503, "stats unavailable");
+    };
+    let cache = engine.cache_stats();
+    let mut out = String::new();
+    for (name, help, value) in [
+        ("kixdns_cache_hits_total", "Queries answered from the response cache.", cache.hits),
+        ("kixdns_cache_misses_total", "Queries that missed the response cache.", cache.misses),
+        ("kixdns_cache_evictions_total", "Response cache entries evicted for capacity.", cache.evictions),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+    }
+    let _ = writeln!(out, "# HELP kixdns_cache_entries Entries in the response cache.\n# TYPE kixdns_cache_entries gauge\nkixdns_cache_entries {}", cache.entries);
+
+    let stats = engine.pipeline_stats();
+    type Field = fn(&PipelineStats) -> u64;
+    let series: [(&str, &str, Field); 4] = [
+        ("kixdns_pipeline_requests_total", "Queries handled, by selected pipeline.", |s| s.requests),
+        ("kixdns_pipeline_cache_hits_total", "Cache hits, by selected pipeline.", |s| s.cache_hits),
+        ("kixdns_pipeline_upstream_forwards_total", "Upstream forwards, by forwarding pipeline.", |s| s.upstream_forwards),
+        ("kixdns_pipeline_servfails_total", "SERVFAIL answers, by selected pipeline.", |s| s.servfails),
+    ];
+    for (name, help, value) in series {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for s in &stats {
+            let _ = writeln!(out, "{name}{{pipeline=\"{}\"}} {}", escape_label(&s.pipeline), value(s));
+        }
+    }
+
+    let name = "kixdns_upstream_latency_ewma_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Exponentially weighted moving average of upstream response latency (failures count as the full timeout).\n# TYPE {name} gauge"
+    );
+    for (upstream, latency) in engine.upstream_latencies() {
+        let _ = writeln!(out, "{name}{{upstream=\"{}\"}} {}", escape_label(&upstream), latency.as_secs_f64());
+    }
+
+    let reloads = engine.reload_stats();
+    let name = "kixdns_config_reload_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Config hot reloads, by result.\n# TYPE {name} counter\n{name}{{result=\"success\"}} {}\n{name}{{result=\"failure\"}} {}",
+        reloads.successes(),
+        reloads.failures()
+    );
+    let name = "kixdns_config_last_reload_timestamp";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Unix time of the last successful config load.\n# TYPE {name} gauge\n{name} {}",
+        reloads.last_success_unix()
+    );
+    AdminResponse::text(out)
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn set_log_level(state: &AdminState, body: &[u8]) -> AdminResponse {
+    let Some(handle) = state.log_filter.as_ref() else {
+        return AdminResponse::error(503, "log level reload unavailable");
+    };
+    let req: LogLevelRequest = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(err) => return AdminResponse::error(400, format!("invalid body: {err}")),
+    };
+    // 支持完整 EnvFilter 语法，例如 "debug" 或 "kixdns=debug,warn"
+    let filter = match EnvFilter::try_new(&req.level) {
+        Ok(f) => f,
+        Err(err) => return AdminResponse::error(400, format!("invalid level: {err}")),
+    };
+    if let Err(err) = handle.reload(filter) {
+        return AdminResponse::error(503, format!("reload failed: {err}"));
+    }
+    info!(target = "admin", level = %req.level, "log level changed");
+    AdminResponse::json(200, serde_json::json!({ "level": req.level }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::CaptureWriter;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn loglevel_endpoint_changes_emitted_lines() {
+        let capture = CaptureWriter::default();
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(filter).with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(capture.clone()),
+        );
+        let state = AdminState {
+            log_filter: Some(handle),
+            ..Default::default()
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("before-toggle");
+            let resp = handle_request(&state, "POST", "/loglevel", None, br#"{"level":"debug"}"#);
+            assert_eq!(resp.status, 200);
+            tracing::debug!("after-toggle");
+
+            let resp = handle_request(&state, "POST", "/loglevel", None, br#"{"level":"warn"}"#);
+            assert_eq!(resp.status, 200);
+            tracing::info!("after-raise");
+        });
+
+        let out = capture.contents();
+        assert!(!out.contains("before-toggle"));
+        assert!(out.contains("after-toggle"));
+        assert!(!out.contains("after-raise"));
+    }
+
+    #[test]
+    fn loglevel_endpoint_rejects_bad_requests() {
+        let (_filter, handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+        let state = AdminState {
+            log_filter: Some(handle),
+            ..Default::default()
+        };
+        assert_eq!(handle_request(&state, "POST", "/loglevel", None, b"nope").status, 400);
+        assert_eq!(
+            handle_request(&state, "POST", "/loglevel", None, br#"{"level":"=[bad"}"#).status,
+            400
+        );
+        assert_eq!(handle_request(&state, "GET", "/loglevel", None, b"").status, 405);
+        assert_eq!(handle_request(&state, "GET", "/missing", None, b"").status, 404);
+        assert_eq!(
+            handle_request(&AdminState::default(), "POST", "/loglevel", None, br#"{"level":"debug"}"#).status,
+            503
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_endpoints_require_the_shared_secret() {
+        let raw = serde_json::json!({ "settings": { "default_upstream": "127.0.0.1:1" } });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = crate::matcher::RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(arc_swap::ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        let unprotected = AdminState {
+            engine: Some(engine.clone()),
+            ..Default::default()
+        };
+        assert_eq!(handle_request(&unprotected, "GET", "/cache/stats", None, b"").status, 403);
+        assert_eq!(handle_request(&unprotected, "POST", "/cache/flush", None, b"").status, 403);
+
+        let state = AdminState {
+            engine: Some(engine),
+            token: Some("s3cret".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(handle_request(&state, "GET", "/cache/stats", None, b"").status, 401);
+        assert_eq!(handle_request(&state, "GET", "/cache/stats", Some("wrong"), b"").status, 401);
+        // 配置密钥后其他接口同样受保护
+        assert_eq!(handle_request(&state, "POST", "/loglevel", None, br#"{"level":"debug"}"#).status, 401);
+
+        let resp = handle_request(&state, "GET", "/cache/stats", Some("s3cret"), b"");
+        assert_eq!(resp.status, 200);
+        let stats: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(stats, serde_json::json!({ "entries": 0, "hits": 0, "misses": 0, "evictions": 0 }));
+
+        let resp = handle_request(&state, "POST", "/cache/flush?qname=example.com", Some("s3cret"), b"");
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, r#"{"qname":"example.com","removed":0}"#);
+        assert_eq!(handle_request(&state, "POST", "/cache/flush?qname=", Some("s3cret"), b"").status, 400);
+        assert_eq!(handle_request(&state, "POST", "/cache/flush", Some("s3cret"), b"").status, 200);
+        assert_eq!(handle_request(&state, "GET", "/cache/flush", Some("s3cret"), b"").status, 405);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_renders_pipeline_labels() {
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "edge\"1", "rules": [{
+                "name": "block",
+                "matchers": [{ "type": "any" }],
+                "actions": [{ "type": "static_response", "rcode": "NXDOMAIN" }]
+            }] }]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = crate::matcher::RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(arc_swap::ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: std::net::SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let mut query = hickory_proto::op::Message::new();
+        query.add_query(hickory_proto::op::Query::query(
+            "ads.example.com.".parse().unwrap(),
+            hickory_proto::rr::RecordType::A,
+        ));
+        let packet = query.to_vec().unwrap();
+        for _ in 0..2 {
+            engine.handle_packet(&packet, peer).await.expect("static answer");
+        }
+        let state = AdminState {
+            engine: Some(engine),
+            ..Default::default()
+        };
+
+        let resp = handle_request(&state, "GET", "/metrics", None, b"");
+        assert_eq!(resp.status, 200);
+        assert!(resp.content_type.starts_with("text/plain"));
+        assert!(resp.body.contains("# TYPE kixdns_pipeline_requests_total counter\n"), "{}", resp.body);
+        assert!(resp.body.contains("kixdns_pipeline_requests_total{pipeline=\"edge\\\"1\"} 2\n"), "{}", resp.body);
+        assert!(resp.body.contains("kixdns_pipeline_servfails_total{pipeline=\"edge\\\"1\"} 0\n"), "{}", resp.body);
+        assert!(resp.body.contains("# TYPE kixdns_upstream_latency_ewma_seconds gauge\n"), "{}", resp.body);
+        assert!(resp.body.contains("kixdns_config_reload_total{result=\"success\"} 0\n"), "{}", resp.body);
+        assert!(resp.body.contains("kixdns_config_reload_total{result=\"failure\"} 0\n"), "{}", resp.body);
+        assert!(resp.body.contains("# TYPE kixdns_config_last_reload_timestamp gauge\n"), "{}", resp.body);
+
+        let resp = handle_request(&state, "GET", "/stats/pipelines", None, b"");
+        assert_eq!(resp.status, 200);
+        let stats: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(stats["pipelines"][0]["pipeline"], "edge\"1");
+        assert_eq!(stats["pipelines"][0]["requests"], 2);
+        assert_eq!(handle_request(&state, "POST", "/metrics", None, b"").status, 405);
+    }
+}
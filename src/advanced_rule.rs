@@ -7,8 +7,9 @@ use hickory_proto::rr::{DNSClass, RecordType};
 use ipnet::IpNet;
 use regex::Regex;
 
-use crate::config::{Action, MatchOperator};
-use crate::engine::{Decision, make_static_ip_answer};
+use crate::config::{Action, MatchOperator, StaticRecord};
+use crate::domain_set::DomainSetFile;
+use crate::engine::{Decision, make_static_ip_answer, make_static_record_answers};
 use crate::matcher::eval_match_chain;
 use crate::matcher::{RuntimeMatcher, RuntimePipeline, RuntimePipelineConfig, RuntimeRule};
 
@@ -17,6 +18,8 @@ pub struct CompiledPipeline {
     pub id: Arc<str>,
     pub rules: Vec<CompiledRule>,
     pub index: RuleIndex,
+    /// 编译时配置的 generation；与当前配置不一致时快速路径不再使用
+    pub generation: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -41,10 +44,10 @@ pub enum CompiledMatcher {
     DomainExact { domain: String },
     DomainSuffix { suffix: String },
     ClientIp { net: IpNet },
-    #[allow(dead_code)]
     QueryType { qtype: RecordType },
     Qclass { qclass: DNSClass },
     Regex { regex: Regex },
+    DomainSet { set: Arc<DomainSetFile> },
     Complex { matcher: RuntimeMatcher },
 }
 
@@ -52,6 +55,7 @@ pub enum CompiledMatcher {
 pub enum PrecomputedAction {
     Static { rcode: ResponseCode },
     StaticIp { ip: String },
+    StaticRecords { records: Vec<StaticRecord> },
 }
 
 #[derive(Debug, Clone, Default)]
@@ -59,6 +63,8 @@ pub struct RuleIndex {
     pub domain_exact: HashMap<String, Vec<usize>>,
     pub domain_suffix: HashMap<String, Vec<usize>>,
     pub query_type: HashMap<RecordType, Vec<usize>>,
+    /// 以 domain_set 建索引的规则：每个集合一次 O(标签数) 查询即可选出候选
+    pub domain_sets: Vec<(Arc<DomainSetFile>, Vec<usize>)>,
     pub always_check: Vec<usize>,
 }
 
@@ -103,6 +109,15 @@ impl RuleIndex {
                     indexed = true;
                     break;
                 }
+                CompiledMatcher::DomainSet { set } => {
+                    // 同一集合被多条规则引用时共用一次查询
+                    match self.domain_sets.iter_mut().find(|(s, _)| Arc::ptr_eq(s, set)) {
+                        Some((_, rules)) => rules.push(rule_idx),
+                        None => self.domain_sets.push((Arc::clone(set), vec![rule_idx])),
+                    }
+                    indexed = true;
+                    break;
+                }
                 _ => {}
             }
         }
@@ -135,6 +150,12 @@ impl RuleIndex {
             candidates.extend_from_slice(indices);
         }
 
+        for (set, indices) in &self.domain_sets {
+            if set.contains(qname) {
+                candidates.extend_from_slice(indices);
+            }
+        }
+
         candidates.sort_unstable();
         candidates.dedup();
         candidates
@@ -142,10 +163,10 @@ impl RuleIndex {
 }
 
 pub fn compile_pipelines(cfg: &RuntimePipelineConfig) -> Vec<CompiledPipeline> {
-    cfg.pipelines.iter().map(|p| compile_pipeline(p)).collect()
+    cfg.pipelines.iter().map(|p| compile_pipeline(p, cfg.generation)).collect()
 }
 
-fn compile_pipeline(p: &RuntimePipeline) -> CompiledPipeline {
+fn compile_pipeline(p: &RuntimePipeline, generation: u64) -> CompiledPipeline {
     let mut rules = Vec::with_capacity(p.rules.len());
     let mut index = RuleIndex::new();
 
@@ -159,6 +180,7 @@ fn compile_pipeline(p: &RuntimePipeline) -> CompiledPipeline {
         id: Arc::from(p.id.as_str()),
         rules,
         index,
+        generation,
     }
 }
 
@@ -198,6 +220,17 @@ fn compile_matcher(m: &RuntimeMatcher) -> CompiledMatcher {
         RuntimeMatcher::EdnsPresent { expect } => CompiledMatcher::Complex {
             matcher: RuntimeMatcher::EdnsPresent { expect: *expect },
         },
+        RuntimeMatcher::QueryType { qtype } => CompiledMatcher::QueryType { qtype: *qtype },
+        RuntimeMatcher::DomainSet { set } => CompiledMatcher::DomainSet { set: Arc::clone(set) },
+        RuntimeMatcher::EdnsAtLeast { .. }
+        | RuntimeMatcher::EcsSubnet { .. }
+        | RuntimeMatcher::ClientGeo { .. }
+        | RuntimeMatcher::ClientAsn { .. }
+        | RuntimeMatcher::NameLength { .. }
+        | RuntimeMatcher::LabelCount { .. }
+        | RuntimeMatcher::Opcode { .. }
+        // group 不提供索引键：只由 group 组成的规则落入 always_check
+        | RuntimeMatcher::Group { .. } => CompiledMatcher::Complex { matcher: m.clone() },
     }
 }
 
@@ -208,6 +241,24 @@ fn precompute_action(rule: &RuntimeRule) -> Option<PrecomputedAction> {
             parse_rcode(rcode).map(|rc| PrecomputedAction::Static { rcode: rc })
         }
         Action::StaticIpResponse { ip } => Some(PrecomputedAction::StaticIp { ip: ip.clone() }),
+        Action::StaticRecordSet { records } => Some(PrecomputedAction::StaticRecords {
+            records: records.clone(),
+        }),
+        Action::StaticHttps {
+            priority,
+            target,
+            params,
+            ttl,
+        } => crate::config::https_rdata(*priority, target, params)
+            .ok()
+            .map(|rdata| PrecomputedAction::StaticRecords {
+                records: vec![StaticRecord { rdata, ttl: *ttl }],
+            }),
+        Action::StaticCname { target, ttl } => crate::config::cname_rdata(target)
+            .ok()
+            .map(|rdata| PrecomputedAction::StaticRecords {
+                records: vec![StaticRecord { rdata, ttl: *ttl }],
+            }),
         Action::Deny => Some(PrecomputedAction::Static {
             rcode: ResponseCode::Refused,
         }),
@@ -221,7 +272,8 @@ pub(crate) fn fast_static_match(
     qtype: RecordType,
     qclass: DNSClass,
     client_ip: IpAddr,
-    edns_present: bool,
+    edns_bufsize: Option<u16>,
+    ecs: Option<IpAddr>,
 ) -> Option<Decision> {
     let candidates = pipeline.index.get_candidates(qname, qtype);
     for idx in candidates {
@@ -229,7 +281,7 @@ pub(crate) fn fast_static_match(
         let matched = eval_match_chain(
             &rule.matchers,
             |m| m.operator,
-            |m| compiled_matcher_matches(&m.matcher, qname, qtype, qclass, client_ip, edns_present),
+            |m| compiled_matcher_matches(&m.matcher, qname, qtype, qclass, client_ip, edns_bufsize, ecs),
         );
         if !matched {
             continue;
@@ -246,6 +298,10 @@ pub(crate) fn fast_static_match(
                     let (rcode, answers) = make_static_ip_answer(qname, ip);
                     return Some(Decision::Static { rcode, answers });
                 }
+                PrecomputedAction::StaticRecords { records } => {
+                    let (rcode, answers) = make_static_record_answers(qname, records);
+                    return Some(Decision::Static { rcode, answers });
+                }
             }
         }
     }
@@ -258,7 +314,8 @@ fn compiled_matcher_matches(
     qtype: RecordType,
     qclass: DNSClass,
     client_ip: IpAddr,
-    edns_present: bool,
+    edns_bufsize: Option<u16>,
+    ecs: Option<IpAddr>,
 ) -> bool {
     match matcher {
         CompiledMatcher::DomainExact { domain } => qname.eq_ignore_ascii_case(domain),
@@ -273,13 +330,26 @@ fn compiled_matcher_matches(
         CompiledMatcher::QueryType { qtype: rt } => *rt == qtype,
         CompiledMatcher::Qclass { qclass: cls } => *cls == qclass,
         CompiledMatcher::Regex { regex } => regex.is_match(qname),
+        CompiledMatcher::DomainSet { set } => set.contains(qname),
         CompiledMatcher::Complex { matcher } => match matcher {
             RuntimeMatcher::Any => true,
             RuntimeMatcher::DomainSuffix { value } => qname.ends_with(value),
             RuntimeMatcher::ClientIp { net } => net.contains(&client_ip),
             RuntimeMatcher::DomainRegex { regex } => regex.is_match(qname),
             RuntimeMatcher::Qclass { value } => *value == qclass,
-            RuntimeMatcher::EdnsPresent { expect } => *expect == edns_present,
+            RuntimeMatcher::EdnsPresent { expect } => *expect == edns_bufsize.is_some(),
+            RuntimeMatcher::EdnsAtLeast { bufsize } => edns_bufsize.is_some_and(|b| b >= *bufsize),
+            RuntimeMatcher::EcsSubnet { net } => ecs.is_some_and(|ip| net.contains(&ip)),
+            RuntimeMatcher::QueryType { qtype: rt } => *rt == qtype,
+            RuntimeMatcher::DomainSet { set } => set.contains(qname),
+            RuntimeMatcher::ClientGeo { country, geo } => {
+                crate::matcher::client_in_country(geo.as_ref(), client_ip, country)
+            }
+            RuntimeMatcher::ClientAsn { asns, asn } => crate::matcher::client_in_asns(asn.as_ref(), client_ip, asns),
+            RuntimeMatcher::NameLength { min, max } => (*min..=*max).contains(&crate::matcher::name_length(qname)),
+            RuntimeMatcher::LabelCount { min, max } => (*min..=*max).contains(&crate::matcher::label_count(qname)),
+            RuntimeMatcher::Opcode { opcode } => *opcode == crate::matcher::OPCODE_QUERY,
+            RuntimeMatcher::Group { .. } => matcher.matches(qname, qtype, qclass, client_ip, edns_bufsize, ecs),
         },
     }
 }
@@ -0,0 +1,128 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// 首次接收错误后的等待时间，之后每次连续错误翻倍
+const BASE_DELAY: Duration = Duration::from_millis(1);
+/// 退避等待上限
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// UDP worker 接收错误的指数退避：避免 socket 持续出错（如已关闭）时接收循环空转；
+/// 连续错误达到 max_consecutive 次后放弃，由 worker 记录错误并退出
+pub struct RecvBackoff {
+    consecutive: u32,
+    max_consecutive: u32,
+    errors: Arc<AtomicU64>,
+}
+
+impl RecvBackoff {
+    /// max_consecutive 为 0 表示只退避、不退出；errors 为累计的 socket 错误计数
+    pub fn new(max_consecutive: u32, errors: Arc<AtomicU64>) -> Self {
+        Self {
+            consecutive: 0,
+            max_consecutive,
+            errors,
+        }
+    }
+
+    /// 接收成功后重置退避
+    #[inline]
+    pub fn reset(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// 第 n 次连续错误后的等待时间
+    fn delay(consecutive: u32) -> Duration {
+        BASE_DELAY
+            .saturating_mul(1u32 << consecutive.saturating_sub(1).min(16))
+            .min(MAX_DELAY)
+    }
+
+    /// 记录一次接收错误并按退避等待；连续错误达到上限时返回 Err
+    pub async fn on_error(&mut self, err: io::Error) -> anyhow::Result<()> {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.consecutive = self.consecutive.saturating_add(1);
+        if self.max_consecutive > 0 && self.consecutive >= self.max_consecutive {
+            anyhow::bail!("{} consecutive udp socket errors, last: {}", self.consecutive, err);
+        }
+        tracing::debug!(error = %err, consecutive = self.consecutive, "udp recv failed, backing off");
+        tokio::time::sleep(Self::delay(self.consecutive)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time::Instant;
+
+    /// 每次接收都立即失败的 socket
+    struct FailingSocket {
+        calls: AtomicUsize,
+    }
+
+    impl FailingSocket {
+        async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Err(io::Error::new(io::ErrorKind::NotConnected, "socket closed"))
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_cap() {
+        assert_eq!(RecvBackoff::delay(1), Duration::from_millis(1));
+        assert_eq!(RecvBackoff::delay(2), Duration::from_millis(2));
+        assert_eq!(RecvBackoff::delay(5), Duration::from_millis(16));
+        assert_eq!(RecvBackoff::delay(11), MAX_DELAY);
+        assert_eq!(RecvBackoff::delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn repeated_errors_back_off_instead_of_spinning() {
+        let socket = FailingSocket { calls: AtomicUsize::new(0) };
+        let errors = Arc::new(AtomicU64::new(0));
+        let mut backoff = RecvBackoff::new(0, Arc::clone(&errors));
+        let mut buf = [0u8; 512];
+        let start = Instant::now();
+        let _ = tokio::time::timeout(Duration::from_millis(200), async {
+            loop {
+                if let Err(err) = socket.recv_from(&mut buf).await {
+                    backoff.on_error(err).await.expect("no error limit");
+                }
+            }
+        })
+        .await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // 1+2+4+...+128ms 已超过 200ms，空转的话会是成千上万次
+        let calls = socket.calls.load(Ordering::Relaxed);
+        assert!((5..=10).contains(&calls), "calls: {calls}");
+        assert_eq!(errors.load(Ordering::Relaxed), calls as u64);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_consecutive_errors() {
+        let socket = FailingSocket { calls: AtomicUsize::new(0) };
+        let errors = Arc::new(AtomicU64::new(0));
+        let mut backoff = RecvBackoff::new(3, Arc::clone(&errors));
+        let mut buf = [0u8; 512];
+        let err = loop {
+            if let Err(err) = socket.recv_from(&mut buf).await
+                && let Err(fatal) = backoff.on_error(err).await
+            {
+                break fatal;
+            }
+        };
+        assert!(err.to_string().contains("3 consecutive"), "{err}");
+        assert_eq!(errors.load(Ordering::Relaxed), 3);
+
+        // 中途成功会重置连续计数
+        let mut backoff = RecvBackoff::new(3, errors);
+        for _ in 0..5 {
+            let err = socket.recv_from(&mut buf).await.unwrap_err();
+            backoff.on_error(err).await.expect("below limit");
+            backoff.reset();
+        }
+    }
+}
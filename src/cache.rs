@@ -1,9 +1,19 @@
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anyhow::Context;
 use bytes::Bytes;
 use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{DNSClass, RecordType};
+use moka::Expiry;
+use moka::notification::RemovalCause;
 use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+
+/// 缓存条目新鲜期上限
+pub const MAX_ENTRY_TTL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, Clone)]
 pub struct CacheEntry {
@@ -14,16 +24,293 @@ pub struct CacheEntry {
     pub qname: Arc<str>,
     pub pipeline_id: Arc<str>,
     pub qtype: u16,
+    pub qclass: u16,
+    /// 新鲜期截止时间（实际 TTL，上限 MAX_ENTRY_TTL）
+    pub expires_at: Instant,
+    /// 过期后仍可在上游故障时返回的截止时间（serve-stale）
+    pub stale_until: Instant,
+    /// 写入时的新鲜期长度；与 expires_at 一起推算剩余 TTL（moka 不提供条目剩余时间）
+    pub original_ttl: Duration,
+    /// 请求是否带 DO 位；与问题一起参与缓存键计算
+    pub dnssec_ok: bool,
+}
+
+impl CacheEntry {
+    /// 校验命中条目确实属于当前问题（防止哈希碰撞）
+    #[inline]
+    pub fn matches(&self, pipeline_id: &str, qname: &str, qtype: RecordType, qclass: DNSClass) -> bool {
+        self.qtype == u16::from(qtype)
+            && self.qclass == u16::from(qclass)
+            && self.qname.as_ref() == qname
+            && self.pipeline_id.as_ref() == pipeline_id
+    }
+
+    #[inline]
+    pub fn is_fresh(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+
+    /// 根据有效 TTL 与陈旧窗口计算 (original_ttl, expires_at, stale_until)
+    #[inline]
+    pub fn deadlines(ttl: Duration, stale_window: Duration) -> (Duration, Instant, Instant) {
+        let ttl = ttl.min(MAX_ENTRY_TTL);
+        let expires_at = Instant::now() + ttl;
+        (ttl, expires_at, expires_at + stale_window)
+    }
+
+    /// 仍新鲜但剩余 TTL 已低于原始 TTL 的 threshold_percent%，应在后台提前刷新；0 表示关闭
+    #[inline]
+    pub fn prefetch_due(&self, threshold_percent: u8) -> bool {
+        if threshold_percent == 0 {
+            return false;
+        }
+        let remaining = self.expires_at.saturating_duration_since(Instant::now());
+        !remaining.is_zero() && remaining.as_millis() * 100 < self.original_ttl.as_millis() * u128::from(threshold_percent.min(100))
+    }
+}
+
+/// 按条目自身的 stale_until 淘汰，过期但未超出陈旧窗口的条目仍保留
+struct EntryExpiry;
+
+impl Expiry<u64, CacheEntry> for EntryExpiry {
+    fn expire_after_create(&self, _key: &u64, value: &CacheEntry, created_at: Instant) -> Option<Duration> {
+        Some(value.stale_until.saturating_duration_since(created_at))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &u64,
+        value: &CacheEntry,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.stale_until.saturating_duration_since(updated_at))
+    }
+}
+
+/// 管理接口 GET /cache/stats 的返回内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// 因容量（条目数或 cache_max_bytes）被淘汰的条目数，不含到期移除
+    pub evictions: u64,
 }
 
 /// Use u64 hash as key to avoid allocation during lookup
 pub type DnsCache = Cache<u64, CacheEntry>;
 
-/// 创建带 TTL 的 DNS 缓存
+/// 创建按条目 TTL 过期的 DNS 缓存：max_bytes 为 0 时最多 max_entries 条；
+/// 否则每个条目按应答报文字节数计权，淘汰以总字节数不超过 max_bytes 为准（大 TXT 与小 A 记录不再同等计数）；
+/// 因容量被淘汰的条目计入 evictions
 #[inline]
-pub fn new_cache(max_capacity: u64, ttl_secs: u64) -> DnsCache {
-    Cache::builder()
-        .max_capacity(max_capacity)
-        .time_to_live(Duration::from_secs(ttl_secs))
+pub fn new_cache(max_entries: u64, max_bytes: u64, evictions: Arc<AtomicU64>) -> DnsCache {
+    let builder = Cache::builder()
+        .expire_after(EntryExpiry)
+        .eviction_listener(move |_key, _value, cause| {
+            if cause == RemovalCause::Size {
+                evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    if max_bytes == 0 {
+        return builder.max_capacity(max_entries).build();
+    }
+    builder
+        .max_capacity(max_bytes)
+        .weigher(|_key: &u64, entry: &CacheEntry| u32::try_from(entry.bytes.len()).unwrap_or(u32::MAX))
         .build()
 }
+
+/// 缓存快照文件格式；键不落盘，加载时由 (pipeline_id, qname, qtype, qclass, dnssec_ok) 重新计算，
+/// 因为 FxHasher 的算法随 rustc-hash 版本变化（1.x 与 2.x 不同），哈希值不保证跨构建稳定
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// 写入时刻（Unix 毫秒），加载时据此扣除停机期间流逝的时间
+    saved_at_ms: u64,
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    bytes: Vec<u8>,
+    rcode: u16,
+    source: String,
+    qname: String,
+    pipeline_id: String,
+    qtype: u16,
+    qclass: u16,
+    #[serde(default)]
+    dnssec_ok: bool,
+    /// 写入时剩余的新鲜期（毫秒），已过期为 0
+    fresh_ms: u64,
+    /// 写入时距 stale_until 的剩余毫秒
+    stale_ms: u64,
+    /// 条目原始新鲜期（毫秒）；旧快照缺省为 0，加载时以剩余新鲜期代替
+    #[serde(default)]
+    original_ttl_ms: u64,
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 将仍在陈旧窗口内的条目写入快照（先写临时文件再改名），返回写入条数
+pub fn save_snapshot(cache: &DnsCache, path: &Path) -> anyhow::Result<usize> {
+    let now = Instant::now();
+    let entries: Vec<SnapshotEntry> = cache
+        .iter()
+        .filter(|(_, e)| e.stale_until > now)
+        .map(|(_, e)| SnapshotEntry {
+            bytes: e.bytes.to_vec(),
+            rcode: u16::from(e.rcode),
+            source: e.source.to_string(),
+            qname: e.qname.to_string(),
+            pipeline_id: e.pipeline_id.to_string(),
+            qtype: e.qtype,
+            qclass: e.qclass,
+            dnssec_ok: e.dnssec_ok,
+            fresh_ms: e.expires_at.saturating_duration_since(now).as_millis() as u64,
+            stale_ms: e.stale_until.saturating_duration_since(now).as_millis() as u64,
+            original_ttl_ms: e.original_ttl.as_millis() as u64,
+        })
+        .collect();
+    let count = entries.len();
+    let snapshot = Snapshot {
+        saved_at_ms: unix_ms(),
+        entries,
+    };
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&snapshot)?)
+        .with_context(|| format!("write cache snapshot {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("rename cache snapshot to {}", path.display()))?;
+    Ok(count)
+}
+
+/// 读取快照并按停机时长缩短剩余时间后写回缓存，丢弃超出陈旧窗口的条目；key 由调用方按条目字段重新计算
+pub fn load_snapshot(cache: &DnsCache, path: &Path, key: impl Fn(&CacheEntry) -> u64) -> anyhow::Result<usize> {
+    let raw = std::fs::read(path).with_context(|| format!("read cache snapshot {}", path.display()))?;
+    let snapshot: Snapshot = serde_json::from_slice(&raw).context("parse cache snapshot")?;
+    let downtime_ms = unix_ms().saturating_sub(snapshot.saved_at_ms);
+    let now = Instant::now();
+    let mut loaded = 0;
+    for e in snapshot.entries {
+        let Some(stale_ms) = e.stale_ms.checked_sub(downtime_ms).filter(|ms| *ms > 0) else {
+            continue;
+        };
+        let fresh_ms = e.fresh_ms.saturating_sub(downtime_ms);
+        let original_ttl_ms = if e.original_ttl_ms == 0 { fresh_ms } else { e.original_ttl_ms };
+        let entry = CacheEntry {
+            bytes: Bytes::from(e.bytes),
+            rcode: <ResponseCode as From<u16>>::from(e.rcode),
+            source: Arc::from(e.source),
+            qname: Arc::from(e.qname),
+            pipeline_id: Arc::from(e.pipeline_id),
+            qtype: e.qtype,
+            qclass: e.qclass,
+            expires_at: now + Duration::from_millis(fresh_ms).min(MAX_ENTRY_TTL),
+            stale_until: now + Duration::from_millis(stale_ms),
+            original_ttl: Duration::from_millis(original_ttl_ms).min(MAX_ENTRY_TTL),
+            dnssec_ok: e.dnssec_ok,
+        };
+        cache.insert(key(&entry), entry);
+        loaded += 1;
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(qname: &str, fresh: Duration, stale: Duration) -> CacheEntry {
+        let now = Instant::now();
+        CacheEntry {
+            bytes: Bytes::from(qname.as_bytes().to_vec()),
+            rcode: ResponseCode::NoError,
+            source: Arc::from("192.0.2.53:53"),
+            qname: Arc::from(qname),
+            pipeline_id: Arc::from("main"),
+            qtype: u16::from(RecordType::A),
+            qclass: u16::from(DNSClass::IN),
+            expires_at: now + fresh,
+            stale_until: now + stale,
+            original_ttl: fresh,
+            dnssec_ok: false,
+        }
+    }
+
+    #[test]
+    fn prefetch_due_once_remaining_ttl_drops_below_threshold() {
+        let mut e = entry("a.example.com", Duration::from_secs(10), Duration::from_secs(10));
+        e.original_ttl = Duration::from_secs(100);
+        assert!(e.prefetch_due(20));
+        assert!(!e.prefetch_due(5));
+        assert!(!e.prefetch_due(0));
+        e.original_ttl = Duration::from_secs(10);
+        assert!(!e.prefetch_due(90));
+        // 已过期的条目由 serve-stale 处理，不再预取
+        let expired = entry("b.example.com", Duration::ZERO, Duration::from_secs(10));
+        assert!(!expired.prefetch_due(100));
+    }
+
+    #[test]
+    fn snapshot_round_trips_live_entries() {
+        let key = |e: &CacheEntry| e.qname.len() as u64 * 1000 + e.qtype as u64;
+        let cache = new_cache(100, 0, Arc::default());
+        for e in [
+            entry("a.example.com", Duration::from_secs(60), Duration::from_secs(120)),
+            entry("bb.example.com", Duration::from_secs(30), Duration::from_secs(30)),
+            // 已过新鲜期但仍在陈旧窗口内
+            entry("ccc.example.com", Duration::ZERO, Duration::from_secs(90)),
+        ] {
+            cache.insert(key(&e), e);
+        }
+        let path = std::env::temp_dir().join(format!("kixdns-snapshot-{}.json", std::process::id()));
+        assert_eq!(save_snapshot(&cache, &path).unwrap(), 3);
+
+        let restored = new_cache(100, 0, Arc::default());
+        assert_eq!(load_snapshot(&restored, &path, key).unwrap(), 3);
+        std::fs::remove_file(&path).ok();
+
+        for (qname, fresh) in [("a.example.com", true), ("bb.example.com", true), ("ccc.example.com", false)] {
+            let hit = restored.get(&(qname.len() as u64 * 1000 + 1)).expect("restored entry");
+            assert!(hit.matches("main", qname, RecordType::A, DNSClass::IN));
+            assert_eq!(hit.bytes.as_ref(), qname.as_bytes());
+            assert_eq!(hit.rcode, ResponseCode::NoError);
+            assert_eq!(&*hit.source, "192.0.2.53:53");
+            assert_eq!(hit.is_fresh(), fresh);
+        }
+        let a = restored.get(&(13 * 1000 + 1)).unwrap();
+        let remaining = a.expires_at.saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(55) && remaining <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn byte_weighted_cache_evicts_by_total_size() {
+        let evictions = Arc::new(AtomicU64::new(0));
+        let cache = new_cache(100, 4096, Arc::clone(&evictions));
+        let big = |qname: &str| CacheEntry {
+            bytes: Bytes::from(vec![0u8; 1500]),
+            ..entry(qname, Duration::from_secs(60), Duration::from_secs(60))
+        };
+        for i in 0..6u64 {
+            cache.insert(i, big(&format!("txt{i}.example.com")));
+        }
+        cache.run_pending_tasks();
+        // 6 条远低于 100 条的上限，但 1500 字节的条目最多只能容纳 2 条
+        assert!(cache.entry_count() <= 2, "entries: {}", cache.entry_count());
+        assert!(cache.weighted_size() <= 4096);
+        assert_eq!(evictions.load(Ordering::Relaxed), 6 - cache.entry_count());
+
+        // 小条目按字节计，同样的预算可容纳更多条目
+        for i in 100..130u64 {
+            cache.insert(i, entry("a.example.com", Duration::from_secs(60), Duration::from_secs(60)));
+        }
+        cache.run_pending_tasks();
+        assert!(cache.entry_count() > 10);
+        assert!(cache.weighted_size() <= 4096);
+    }
+}
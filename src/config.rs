@@ -1,51 +1,250 @@
+use std::collections::HashMap;
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::Path;
+use std::sync::Arc;
+
+use std::str::FromStr;
 
 use anyhow::Context;
 use anyhow::Result;
+use hickory_proto::rr::rdata::svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue, SVCB};
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, HTTPS, MX, NS, PTR, SRV, TXT};
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{Name, RData};
 use ipnet::IpNet;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PipelineConfig {
     #[serde(default)]
     pub version: Option<String>,
-    #[serde(default)]
+    #[serde(default = "default_settings")]
     pub settings: GlobalSettings,
     /// 多维优先级的 pipeline 选择规则（按顺序评估）。
     #[serde(default)]
     pub pipeline_select: Vec<PipelineSelectRule>,
     #[serde(default)]
     pub pipelines: Vec<Pipeline>,
+    /// 本地权威区域：区域内的名字直接以 AA=1 应答，不经过 pipeline 规则与上游。
+    #[serde(default)]
+    pub local_zones: Vec<LocalZone>,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct GlobalSettings {
     /// 最小TTL秒数，缺省0。
     #[serde(default = "default_min_ttl")]
     pub min_ttl: u32,
+    /// 负缓存（NXDOMAIN/NODATA，取自 SOA）TTL 上限秒数，缺省3600。
+    #[serde(default = "default_max_negative_ttl")]
+    pub max_negative_ttl: u32,
     /// UDP监听地址，缺省0.0.0.0:5353，避免1024以下端口权限问题。
     #[serde(default = "default_bind_udp")]
     pub bind_udp: String,
     /// TCP监听地址，缺省0.0.0.0:5353。
     #[serde(default = "default_bind_tcp")]
     pub bind_tcp: String,
+    /// 多个监听入口，各自的地址、协议与标签（供 listener_label 匹配）；非空时取代 bind_udp/bind_tcp 与 --listener-label。修改需重启生效。
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     /// 默认上游DNS。
     #[serde(default = "default_upstream")]
     pub default_upstream: String,
     /// 上游超时（毫秒）。
     #[serde(default = "default_upstream_timeout_ms")]
     pub upstream_timeout_ms: u64,
+    /// UDP 应答被截断（TC）或 UDP 重试均失败后改走 TCP 时使用的超时（毫秒），不受已耗去的 UDP 预算影响；
+    /// 0 表示 TCP 回退沿用上游超时。截断应答总是改走 TCP 重试，本项只决定其超时。
+    #[serde(default)]
+    pub tcp_fallback_timeout_ms: u64,
     /// 响应阶段 Pipeline 跳转上限。
     #[serde(default = "default_response_jump_limit")]
     pub response_jump_limit: u32,
     /// UDP 上游连接池大小。
     #[serde(default = "default_udp_pool_size")]
     pub udp_pool_size: usize,
+    /// UDP 上游连接池源端口范围（如 "1024-65535"），每个 socket 在范围内随机选取端口绑定；缺省由系统分配。仅启动时生效。
+    #[serde(default)]
+    pub upstream_port_range: Option<String>,
     /// TCP 上游连接池大小。
     #[serde(default = "default_tcp_pool_size")]
     pub tcp_pool_size: usize,
+    /// 启动时以及每次配置热加载成功后，为 transport 为 tcp 的转发上游预先建立连接池中的全部连接（已建立的连接不受影响），
+    /// 避免首个查询承担建连延迟；建连失败只记录日志，查询时仍按需重连。
+    #[serde(default)]
+    pub prewarm_tcp: bool,
+    /// 上游 TCP 响应允许的最大字节数，超过则判定该连接失败。
+    #[serde(default = "default_max_upstream_response")]
+    pub max_upstream_response: usize,
+    /// 刚完成的转发结果（含失败应答）在该毫秒窗口内直接复用，吸收 UDP 客户端紧随其后的重传；0 表示关闭，上限 1000。
+    #[serde(default)]
+    pub recent_result_window_ms: u64,
+    /// 缓存过期后仍保留的秒数：窗口内命中过期条目时立即返回陈旧应答（TTL 钳制为 30s）并在后台刷新；0 表示关闭。
+    #[serde(default)]
+    pub serve_stale_secs: u64,
+    /// 缓存预取（refresh-ahead）：新鲜命中的剩余 TTL 低于原始 TTL 的该百分比时，照常返回缓存并在后台向上游刷新；0 表示关闭，超过 100 按 100 计。
+    #[serde(default)]
+    pub prefetch_threshold_percent: u8,
+    /// 缓存快照文件路径：正常退出时写入仍有效的缓存条目，启动时读回并扣除停机时长；缺省不启用。
+    #[serde(default)]
+    pub cache_snapshot_path: Option<String>,
+    /// OTLP/HTTP span 导出地址（如 http://127.0.0.1:4318/v1/traces），需以 otel feature 构建；仅启动时读取。
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// 转发前去掉客户端查询中的 ECS（EDNS Client Subnet）选项，避免上游获知客户端网段。
+    #[serde(default)]
+    pub strip_client_ecs: bool,
+    /// DNS 0x20：转发前随机改写问题名中字母的大小写，上游应答须原样回显，否则按上游失败处理，提高伪造应答投毒的难度；缺省关闭。
+    #[serde(default)]
+    pub qname_0x20: bool,
+    /// 查询日志文件路径（JSONL，追加写入，每个应答一行）；缺省不记录；仅启动时读取。
+    #[serde(default)]
+    pub query_log_path: Option<String>,
+    /// DoT 上游的 SNI 覆盖，键为 upstream 字符串，值为用于证书校验的主机名。
+    #[serde(default)]
+    pub dot_sni: HashMap<String, String>,
+    /// 慢路径中规则评估与缓存查询并行执行，缓存命中时丢弃评估结果；适合缓存命中率低的场景。
+    #[serde(default)]
+    pub parallel_rule_eval: bool,
+    /// 按可注册域名（eTLD+1）将默认上游的查询分片到 upstream_groups。
+    #[serde(default)]
+    pub shard_by_domain: bool,
+    /// 上游组，每组为若干上游地址；开启 shard_by_domain 时替代 default_upstream，组内成员仍按上游策略与故障转移选择，空组忽略。
+    #[serde(default)]
+    pub upstream_groups: Vec<Vec<String>>,
+    /// GeoLite2/GeoIP2 Country 数据库路径（mmdb），供 geo_static_ip 与 client_geo 使用；需要 geoip 特性。
+    #[serde(default)]
+    pub geoip_db: Option<String>,
+    /// GeoLite2-ASN 数据库路径（mmdb），供 client_asn 使用；需要 geoip 特性。
+    #[serde(default)]
+    pub asn_db: Option<String>,
+    /// 管理接口监听地址（如 127.0.0.1:9053），缺省不启用。
+    #[serde(default)]
+    pub admin_bind: Option<String>,
+    /// 管理接口共享密钥，请求需携带 `X-Admin-Token` 头；未配置时 /cache 接口一律拒绝。
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// 单个客户端 IP 同时进行中的查询上限，超出直接返回 REFUSED；0 表示不限制。
+    #[serde(default)]
+    pub max_inflight_per_client: usize,
+    /// Pipeline 跳转超过 response_jump_limit 时返回的 rcode：servfail（缺省）/ refused / nxdomain。
+    #[serde(default)]
+    pub jump_limit_action: JumpLimitAction,
+    /// 跳转超限时附带 EDE（RFC 8914，info-code 0）说明文本；仅当请求携带 EDNS 时添加。
+    #[serde(default)]
+    pub jump_limit_ede: bool,
+    /// 多上游时的尝试策略：failover（按顺序，缺省）、round_robin（每个请求轮换起点）、race（并发查询取最快应答）或 client_affinity（按客户端 IP 固定上游）。
+    #[serde(default)]
+    pub upstream_strategy: UpstreamStrategy,
+    /// 每个上游应答延迟的指数加权移动平均（EWMA）的平滑系数，取值 (0, 1]，越大越偏重最近的样本；缺省（或 0）为 0.2。
+    #[serde(default = "default_upstream_latency_alpha")]
+    pub upstream_latency_alpha: f64,
+    /// upstream_strategy 为 fastest 时改选其他上游探测的概率（百分比，0~100），缺省 5；0 表示始终选最快的上游。
+    #[serde(default = "default_upstream_probe_percent")]
+    pub upstream_probe_percent: u8,
+    /// 多上游时，上游应答 REFUSED/SERVFAIL 也换下一个上游重试；全部如此时返回最后收到的应答。缺省关闭。
+    #[serde(default)]
+    pub retry_on_upstream_refused: bool,
+    /// 上游（含上面的组内重试之后）最终应答 SERVFAIL 时，改向 servfail_retry.upstream 重试；缺省关闭。
+    #[serde(default)]
+    pub servfail_retry: Option<ServfailRetry>,
+    /// 配置了响应匹配/动作、但上游应答无法完整解析时的处理：error（缺省，丢弃请求）/ passthrough（原样返回应答，不缓存）/ servfail。
+    #[serde(default)]
+    pub response_parse_failure: ResponseParseFailure,
+    /// 不健康上游的探测间隔（毫秒）；非 0 时开启上游健康追踪，多上游选择会跳过不健康的上游。缺省0（关闭）。
+    #[serde(default)]
+    pub health_check_interval_ms: u64,
+    /// 定期输出缓存统计日志（target=stats）的间隔（毫秒），便于没有指标采集时观察命中率；0（缺省）表示关闭。
+    #[serde(default)]
+    pub stats_log_interval_ms: u64,
+    /// 上游连续失败多少次后标记为不健康，缺省3。
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// 收到 SIGINT/SIGTERM 后等待进行中请求完成的最长毫秒数，缺省5000。
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+    /// 上游应答 A/AAAA 允许的网段（CIDR）；非空时任一记录不在其中即视为上游失败（SERVFAIL 或执行 response_actions_on_miss）。
+    #[serde(default)]
+    pub answer_ip_allowlist: Vec<String>,
+    /// 这些 qtype（如 TXT、DNSKEY、ANY）经 UDP 转发时直接改用 TCP，省去截断后再重试的往返；DoH/DoT 上游不受影响。
+    #[serde(default)]
+    pub tcp_for_qtypes: Vec<String>,
+    /// 上游应答中同名同类型（RRset）记录的 TTL 不一致时统一改为其中最小值（RFC 2181 §5.2），缺省关闭。
+    #[serde(default)]
+    pub normalize_rrset_ttl: bool,
+    /// 将上游应答段每个 RRset（同名、同类型）内部按 rdata 排序，使同一组记录无论上游顺序如何都编码为相同字节（便于测试比对）；
+    /// RRset 之间的顺序（如 CNAME 链在前）保持不变，缺省关闭。
+    #[serde(default)]
+    pub sort_answers: bool,
+    /// ANY 查询的处理方式：forward 原样转发（缺省）；hinfo 按 RFC 8482 直接返回一条 HINFO；a_aaaa 改为分别查询 A 与 AAAA 并合并应答。
+    #[serde(default)]
+    pub any_policy: AnyPolicy,
+    /// 响应限速（RRL）：同一 (客户端 /24 或 /56, qname, qtype, rcode) 每秒允许的 UDP 应答数，超出部分丢弃或截断；0 表示关闭。TCP 应答不受限制。
+    #[serde(default)]
+    pub rrl_responses_per_second: u32,
+    /// RRL 超限应答中每 N 个改为 TC=1 截断应答（其余丢弃），让被冒用地址之外的真实客户端可改用 TCP；0 表示全部丢弃，缺省2。
+    #[serde(default = "default_rrl_slip")]
+    pub rrl_slip: u32,
+    /// 反复查询被拦截域名的客户端逐级处理：窗口内同一 (客户端, 域名) 的拦截应答超过阈值后延迟应答或暂时封禁该客户端；缺省关闭。
+    #[serde(default)]
+    pub block_escalation: Option<BlockEscalation>,
+    /// 客户端访问控制（CIDR 列表）：非空时只受理来自这些网段的请求，其余请求在解析前直接丢弃、不作应答。
+    #[serde(default)]
+    pub allow_networks: Vec<String>,
+    /// 拒绝的客户端网段（CIDR），优先于 allow_networks；命中的请求直接丢弃，避免被用于反射。
+    #[serde(default)]
+    pub deny_networks: Vec<String>,
+    /// 请求 OPT 记录中允许的 EDNS 选项个数上限，超出时直接应答 FORMERR，避免构造的大量选项消耗解析开销；0 表示不限制。
+    #[serde(default)]
+    pub max_edns_options: usize,
+    /// DNS64（RFC 6147）：AAAA 查询的上游应答为 NODATA 时改查 A，将 IPv4 地址嵌入 dns64_prefix 合成 AAAA 记录（沿用 A 记录的 TTL），缺省关闭。
+    #[serde(default)]
+    pub dns64_enabled: bool,
+    /// DNS64 合成所用的 NAT64 前缀，长度须为 RFC 6052 规定的 32/40/48/56/64/96 之一，缺省 64:ff9b::/96；
+    /// 可由 Pipeline.dns64_prefix 按 pipeline 覆盖。
+    #[serde(default = "default_dns64_prefix")]
+    pub dns64_prefix: String,
+    /// TCP 连接空闲超时（毫秒）：等待下一个请求的长度前缀超过该时间即关闭连接，缺省10000；0 表示不限制。
+    #[serde(default = "default_tcp_idle_timeout_ms")]
+    pub tcp_idle_timeout_ms: u64,
+    /// 同时受理的 TCP 连接数上限（所有入口共享），超出时新连接直接关闭；0 表示不限制。修改需重启生效。
+    #[serde(default)]
+    pub tcp_max_connections: usize,
+    /// UDP worker 连续接收错误达到该次数后记录错误，整个进程按停机流程退出并返回非零状态（期间按 1ms 起翻倍、上限 1s 退避）；
+    /// 0 表示只退避、不退出。
+    #[serde(default)]
+    pub udp_max_consecutive_errors: u32,
+    /// 同一问题（dedupe hash）排队等待首个上游请求结果的重复查询上限，超出后的重复查询各自独立转发；0 表示不限制。
+    #[serde(default)]
+    pub max_inflight_waiters: usize,
+    /// 应答缓存的总字节数上限（按应答报文大小计），0 表示改为按条目数（10000 条）限制；修改需重启生效。
+    #[serde(default)]
+    pub cache_max_bytes: u64,
+    /// DNS Cookie（RFC 7873）服务器密钥，至少 16 字节；配置后对携带 COOKIE 选项的请求校验并签发服务器 cookie，缺省关闭。
+    #[serde(default)]
+    pub cookie_secret: Option<String>,
+    /// 开启 cookie 后，经 UDP 的应答超过该字节数且请求未带有效服务器 cookie 时只返回 TC=1 的应答，迫使客户端改用 TCP；0 表示不限制。
+    #[serde(default)]
+    pub cookie_tc_threshold: usize,
+    /// 是否允许转发 AXFR（全量区域传送）查询，缺省 true；false 时直接返回 REFUSED。
+    #[serde(default = "default_true")]
+    pub allow_axfr: bool,
+    /// 是否允许转发 IXFR（增量区域传送）查询，缺省 true；false 时直接返回 REFUSED。
+    #[serde(default = "default_true")]
+    pub allow_ixfr: bool,
+    /// 本地覆盖记录文件（hosts 格式或 "名字 [TTL] A|AAAA|CNAME 值"，支持 *.example.com 通配），先于 pipeline 规则查询；文件变化时自动重新加载。
+    #[serde(default)]
+    pub static_records_file: Option<String>,
+    /// 本地反向区域（如 10.in-addr.arpa、8.b.d.0.1.0.0.2.ip6.arpa）：其中完整地址的反向名字在 static_records_file 中没有对应
+    /// A/AAAA 记录时直接返回 NXDOMAIN，不转发。有对应记录的 PTR 查询无论是否在这些区域内都直接应答。
+    #[serde(default)]
+    pub static_reverse_zones: Vec<String>,
+    /// 配置文件热加载的去抖窗口（毫秒）：收到文件事件后等到连续这么久没有新事件才重新加载，
+    /// 一次编辑产生的多个事件（truncate、write、rename）只触发一次加载；0 表示每个事件都立即加载。缺省 200。
+    #[serde(default = "default_reload_debounce_ms")]
+    pub reload_debounce_ms: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -53,6 +252,20 @@ pub struct Pipeline {
     pub id: String,
     #[serde(default)]
     pub rules: Vec<Rule>,
+    /// 本 pipeline 内转发的上游超时（毫秒），缺省沿用 settings.upstream_timeout_ms。
+    #[serde(default)]
+    pub upstream_timeout_ms: Option<u64>,
+    /// 本 pipeline 内未指定 transport 的转发所用传输方式，缺省 udp。
+    #[serde(default)]
+    pub default_transport: Option<Transport>,
+    /// 影子模式：命中 static_* / deny 等直接作答的规则时只记录 event = "shadow_match" 日志，
+    /// 查询照常转发并返回上游真实应答，用于上线新的拦截 pipeline 前评估误拦。
+    #[serde(default)]
+    pub shadow_mode: bool,
+    /// 本 pipeline 内转发使用的 DNS64 前缀（格式同 settings.dns64_prefix），设置即对本 pipeline 启用 DNS64；
+    /// 缺省沿用 settings.dns64_enabled / dns64_prefix。
+    #[serde(default)]
+    pub dns64_prefix: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -101,6 +314,80 @@ pub enum Matcher {
     EdnsPresent {
         expect: bool,
     },
+    /// 携带 EDNS 且声明的 UDP 负载大小不小于 bufsize；无 EDNS 的请求不匹配。
+    EdnsAtLeast {
+        bufsize: u16,
+    },
+    /// 请求 ECS（EDNS Client Subnet）选项声明的客户端地址落在 CIDR 内；适用于 KixDNS 位于其他解析器之后、
+    /// 传输层对端都是该解析器的场景。不带 ECS 的请求不匹配。
+    EcsSubnet {
+        cidr: String,
+    },
+    /// 匹配查询记录类型（如 A/AAAA/TXT）。
+    QueryType {
+        value: String,
+    },
+    /// 客户端国家（ISO 3166-1 alpha-2，如 DE），需要 geoip 特性与 settings.geoip_db；库中查不到的 IP 不匹配。
+    ClientGeo {
+        country: String,
+    },
+    /// 客户端所属自治系统号（ASN）在列表中，需要 geoip 特性与 settings.asn_db；库中查不到的 IP 不匹配。
+    ClientAsn {
+        asns: Vec<u32>,
+    },
+    /// 从文件加载的大规模域名集合（每行一个后缀，`full:` 前缀表示精确匹配）；相对路径按配置文件所在目录解析，随主配置热加载重新读取。
+    DomainSet {
+        file: String,
+    },
+    /// 查询名长度（字节数，不含末尾的点）落在 [min, max] 内；超长域名常见于 DNS 隧道。
+    NameLength {
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_name_length")]
+        max: usize,
+    },
+    /// 查询名标签数（按 '.' 分隔，忽略末尾的点，根域为 0）落在 [min, max] 内。
+    LabelCount {
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_label_count")]
+        max: usize,
+    },
+    /// 请求头部 OPCODE（query/iquery/status/notify/update 或 0-15 的数字）。非 QUERY 请求默认应答 NOTIMP，
+    /// 只有同时带 static_response 的 opcode 规则可以改写其 rcode。
+    Opcode {
+        value: String,
+    },
+    /// 嵌套分组：组内按 matchers 自身的 operator 从左到右求值后作为一个整体参与外层链，可表达 (A and B) or (C and D)。
+    /// 组内各项都用缺省 and 时，matcher_operator 作用于每一项（与规则级 matcher_operator 相同）；外层的 operator 仍表示与前一项的连接方式。
+    Group {
+        #[serde(default = "default_match_operator")]
+        matcher_operator: MatchOperator,
+        matchers: Vec<MatcherWithOp>,
+    },
+}
+
+impl Matcher {
+    /// 自身或 group 内（递归）是否存在满足 pred 的匹配器
+    pub fn any(&self, pred: &impl Fn(&Matcher) -> bool) -> bool {
+        match self {
+            Matcher::Group { matchers, .. } => matchers.iter().any(|m| m.matcher.any(pred)),
+            m => pred(m),
+        }
+    }
+}
+
+/// domain_set 的相对路径按配置文件所在目录解析（含 group 内嵌套的匹配器）
+fn resolve_domain_set_paths(matchers: &mut [MatcherWithOp], base_dir: &Path) {
+    for matcher in matchers {
+        match &mut matcher.matcher {
+            Matcher::DomainSet { file } if Path::new(file.as_str()).is_relative() => {
+                *file = base_dir.join(file.as_str()).to_string_lossy().into_owned();
+            }
+            Matcher::Group { matchers, .. } => resolve_domain_set_paths(matchers, base_dir),
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -120,6 +407,12 @@ pub enum PipelineSelectorMatcher {
     Qclass { value: String },
     /// 请求是否携带 EDNS。
     EdnsPresent { expect: bool },
+    /// 请求携带 EDNS 且 UDP 负载大小不小于 bufsize。
+    EdnsAtLeast { bufsize: u16 },
+    /// 客户端国家（ISO 3166-1 alpha-2），需要 geoip 特性与 settings.geoip_db。
+    ClientGeo { country: String },
+    /// 客户端 ASN 在列表中，需要 geoip 特性与 settings.asn_db。
+    ClientAsn { asns: Vec<u32> },
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -176,6 +469,15 @@ pub enum ResponseMatcher {
     ResponseQclass { value: String },
     /// 响应是否携带 EDNS。
     ResponseEdnsPresent { expect: bool },
+    /// 响应置了 TC 位（UDP 应答被截断）；可配合 transport 为 tcp 的 forward 响应动作改用 TCP 重查。
+    ResponseTruncated,
+    /// Answer 段记录数落在 [min, max] 内。
+    ResponseAnswerCount {
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_answer_count")]
+        max: usize,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -187,6 +489,30 @@ pub enum Action {
     StaticResponse { rcode: String },
     /// 返回固定 IP (A/AAAA)。
     StaticIpResponse { ip: String },
+    /// 返回一组固定记录（可混合 A/AAAA/TXT 等），owner 均为查询名，不按 qtype 过滤。
+    StaticRecordSet { records: Vec<StaticRecord> },
+    /// 返回一条指向 target 的 CNAME 记录（owner 为查询名），不按 qtype 过滤，A/AAAA 查询同样只返回 CNAME，由客户端继续解析；target 规范化为 FQDN。
+    StaticCname {
+        target: String,
+        #[serde(default = "default_static_record_ttl")]
+        ttl: u32,
+    },
+    /// 返回一条 HTTPS (type 65) 记录，不按 qtype 过滤；priority 为 0 表示别名模式（不可带 params），target 为 "." 表示查询名本身。
+    StaticHttps {
+        priority: u16,
+        target: String,
+        #[serde(default)]
+        params: HttpsParams,
+        #[serde(default = "default_static_record_ttl")]
+        ttl: u32,
+    },
+    /// 按客户端 IP 所属国家返回固定 IP (A/AAAA)，by_country 键为 ISO 国家代码（如 US），未命中返回 default。
+    /// 需要 geoip 特性与 settings.geoip_db；应答随客户端变化，不进入规则缓存与应答缓存；仅作用于请求阶段。
+    GeoStaticIp {
+        default: String,
+        #[serde(default)]
+        by_country: HashMap<String, String>,
+    },
     /// 跳转到指定 Pipeline 继续处理。
     JumpToPipeline { pipeline: String },
     /// 终止匹配。请求阶段使用默认上游，响应阶段使用当前响应。
@@ -194,13 +520,516 @@ pub enum Action {
     /// 终止并丢弃（返回 REFUSED）。
     Deny,
     /// 透传上游；upstream为空则使用全局默认；transport缺省udp。
+    /// min_ttl/max_ttl 可选：将该规则转发所得应答记录的 TTL 及缓存 TTL 钳制到区间内。
+    /// timeout_ms 可选：该规则转发的上游超时，优先于 pipeline 与全局的 upstream_timeout_ms。
     Forward {
-        upstream: Option<String>,
+        /// 单个上游，或多个上游（数组 / 逗号分隔），按 settings.upstream_strategy 依次尝试。
+        #[serde(default, deserialize_with = "deserialize_upstreams")]
+        upstream: Option<UpstreamGroup>,
         #[serde(default)]
         transport: Option<Transport>,
+        #[serde(default)]
+        min_ttl: Option<u32>,
+        #[serde(default)]
+        max_ttl: Option<u32>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
     },
     /// 继续匹配后续规则。响应阶段会复用当前响应结果。
     Continue,
+    /// 按客户端 IP 令牌桶限速（请求阶段在缓存之前判定）；超限按 mode 返回 REFUSED 或置 TC 位的空响应，未超限则继续后续动作。max_qps 与 burst 均须大于 0。
+    RateLimit {
+        max_qps: u32,
+        burst: u32,
+        #[serde(default)]
+        mode: RateLimitMode,
+    },
+    /// 请求阶段：UDP 查询返回置 TC 位的空响应（在缓存之前判定），迫使客户端改用 TCP；TCP 查询忽略该动作，继续后续动作。
+    ForceTcp,
+    /// 响应阶段：将落在 from 网段内的 A/AAAA 应答地址改写到 to 网段（保留主机位与 TTL）；from/to 须同族且前缀长度相同。
+    RewriteIp {
+        from: String,
+        to: String,
+        /// 由 from/to 解析出的网段，RuntimePipelineConfig::from_config 加载时填入，运行时不再解析。
+        #[serde(skip)]
+        nets: Option<(IpNet, IpNet)>,
+    },
+    /// 响应阶段：任一 A/AAAA 应答地址属于 ips（如运营商劫持页地址）时，将响应改写为 NXDOMAIN。
+    NxdomainIfAnswerIp {
+        ips: Vec<String>,
+        /// 由 ips 解析出的地址，RuntimePipelineConfig::from_config 加载时填入。
+        #[serde(skip)]
+        addrs: Vec<IpAddr>,
+    },
+    /// 响应阶段：将应答段与授权段全部记录的 TTL 改写为 seconds；应答缓存按改写后的 TTL 计时。
+    SetTtl { seconds: u32 },
+    /// 响应阶段：将应答段与授权段记录的 TTL 钳制到 [min, max]（缺省不设下限/上限）；应答缓存按钳制后的 TTL 计时。
+    ClampTtl {
+        #[serde(default)]
+        min: Option<u32>,
+        #[serde(default)]
+        max: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseParseFailure {
+    #[default]
+    Error,
+    Passthrough,
+    Servfail,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JumpLimitAction {
+    #[default]
+    Servfail,
+    Refused,
+    Nxdomain,
+}
+
+impl JumpLimitAction {
+    pub fn rcode(self) -> ResponseCode {
+        match self {
+            Self::Servfail => ResponseCode::ServFail,
+            Self::Refused => ResponseCode::Refused,
+            Self::Nxdomain => ResponseCode::NXDomain,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AnyPolicy {
+    /// 与其他 qtype 一样走规则与上游。
+    #[default]
+    Forward,
+    /// RFC 8482 最小化应答：只含一条 HINFO（CPU "RFC8482"，OS 为空），不访问上游。
+    Hinfo,
+    /// 分别解析 A 与 AAAA（各自走规则、缓存与上游），合并两者的应答记录返回。
+    #[serde(rename = "a_aaaa")]
+    AAndAaaa,
+}
+
+#[derive(Debug, Clone, Deserialize, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitMode {
+    /// 返回 REFUSED。
+    #[default]
+    Refuse,
+    /// 返回置 TC 位的空响应，促使客户端改用 TCP 重试。
+    Truncate,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStrategy {
+    /// 按配置顺序尝试，前一个失败或超时才尝试下一个。
+    #[default]
+    Failover,
+    /// 每个请求轮换起始上游，失败时继续尝试其余上游。
+    RoundRobin,
+    /// 同时向全部（健康的）上游发出查询，取最先成功的应答，其余请求随即取消；整个竞速受 upstream_timeout_ms 约束。
+    Race,
+    /// 按客户端 IP 哈希选择起始上游，同一客户端固定使用同一上游，失败或不健康时才依次换下一个。
+    ClientAffinity,
+    /// 优先使用应答延迟 EWMA 最低的上游（尚无样本的上游最先尝试），以 upstream_probe_percent 的概率改选其他上游，
+    /// 让慢上游的 EWMA 持续更新；与 race 不同，通常只发出一个查询。失败时依次换下一个。
+    Fastest,
+}
+
+/// settings.servfail_retry：上游应答 SERVFAIL（而非超时）时的重试。
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ServfailRetry {
+    /// 重试的上游（可为数组或逗号分隔的多个），缺省为 default_upstream。
+    #[serde(default, deserialize_with = "deserialize_upstreams")]
+    pub upstream: Option<UpstreamGroup>,
+    /// 最多重试次数，缺省 1。
+    #[serde(default = "default_servfail_retry_attempts")]
+    pub attempts: u32,
+    /// 第一次重试前的等待（毫秒），之后每次翻倍；缺省 0 表示立即重试。
+    #[serde(default)]
+    pub backoff_ms: u64,
+}
+
+/// settings.block_escalation：拦截应答（static_response / deny 等不带记录的静态应答）的升级处理，用于压制恶意软件的周期性回连。
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BlockEscalation {
+    /// 窗口内同一客户端对同一域名的拦截应答超过该次数后升级。
+    pub threshold: u32,
+    /// 计数窗口（秒），缺省 60。
+    #[serde(default = "default_block_escalation_window_secs")]
+    pub window_secs: u64,
+    /// 升级后每个拦截应答延迟的毫秒数（tarpit）；0 表示不延迟。
+    #[serde(default)]
+    pub tarpit_ms: u64,
+    /// 升级后在该秒数内丢弃该客户端的全部查询、不作应答；0 表示不封禁。
+    /// 注意 UDP 来源地址可以伪造：攻击者以他人地址反复查询被拦截的域名即可让该地址被封禁，
+    /// 面向不可信网络的监听入口应保持 0，或配合 allow_networks 限定来源。
+    #[serde(default)]
+    pub deny_secs: u64,
+}
+
+/// settings.listeners 中的一个监听入口。
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ListenerConfig {
+    /// 入口标签，pipeline_select 的 listener_label 匹配器据此选择 pipeline。
+    pub label: String,
+    /// 监听地址，UDP 与 TCP 共用（如 10.0.0.1:53）。
+    pub bind: String,
+    /// 启用的协议，缺省 udp 与 tcp。
+    #[serde(default = "default_listener_protocols")]
+    pub protocols: Vec<ListenerProtocol>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerProtocol {
+    Udp,
+    Tcp,
+}
+
+/// 解析后的监听入口：None 表示该协议不监听
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub label: String,
+    pub udp: Option<SocketAddr>,
+    pub tcp: Option<SocketAddr>,
+}
+
+impl GlobalSettings {
+    /// upstream_latency_alpha 的实际取值；未配置 settings 时 Default 得到的 0 按缺省值处理
+    #[inline]
+    pub fn latency_alpha(&self) -> f64 {
+        if self.upstream_latency_alpha > 0.0 {
+            self.upstream_latency_alpha
+        } else {
+            default_upstream_latency_alpha()
+        }
+    }
+
+    /// 解析监听入口；未配置 listeners 时为 bind_udp/bind_tcp 组成的单个入口，标签取 default_label（--listener-label）
+    pub fn listeners(&self, default_label: &str) -> Result<Vec<Listener>> {
+        if self.listeners.is_empty() {
+            return Ok(vec![Listener {
+                label: default_label.to_string(),
+                udp: Some(self.bind_udp.parse().context("parse bind addr")?),
+                tcp: Some(self.bind_tcp.parse().context("parse tcp bind addr")?),
+            }]);
+        }
+        let mut out: Vec<Listener> = Vec::with_capacity(self.listeners.len());
+        for l in &self.listeners {
+            if l.label.trim().is_empty() {
+                anyhow::bail!("listener {} has an empty label", l.bind);
+            }
+            if l.protocols.is_empty() {
+                anyhow::bail!("listener {} ({}) enables no protocol", l.label, l.bind);
+            }
+            let addr: SocketAddr = l
+                .bind
+                .parse()
+                .with_context(|| format!("parse bind addr of listener {}", l.label))?;
+            let udp = l.protocols.contains(&ListenerProtocol::Udp).then_some(addr);
+            let tcp = l.protocols.contains(&ListenerProtocol::Tcp).then_some(addr);
+            // 同一地址同一协议只能属于一个入口，否则标签无法确定
+            if let Some(dup) = out
+                .iter()
+                .find(|o| (udp.is_some() && o.udp == udp) || (tcp.is_some() && o.tcp == tcp))
+            {
+                anyhow::bail!("listeners {} and {} both bind {}", dup.label, l.label, addr);
+            }
+            out.push(Listener {
+                label: l.label.clone(),
+                udp,
+                tcp,
+            });
+        }
+        Ok(out)
+    }
+}
+
+/// 上游可写作字符串（逗号分隔）或数组；数组各项不再按逗号拆分，URL 中含逗号的 DoH 上游须写成数组。
+fn deserialize_upstreams<'de, D>(deserializer: D) -> std::result::Result<Option<UpstreamGroup>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawUpstreams {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Option::<RawUpstreams>::deserialize(deserializer)?.map(|raw| match raw {
+        RawUpstreams::One(s) => UpstreamGroup::parse(&s),
+        RawUpstreams::Many(list) => UpstreamGroup::from_members(list),
+    }))
+}
+
+/// 一个或多个上游，加载配置时即拆分好；Display 还原为逗号分隔形式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamGroup(Arc<[String]>);
+
+impl UpstreamGroup {
+    /// 按逗号拆分单个字符串形式的上游列表
+    pub fn parse(spec: &str) -> Self {
+        Self::from_members(spec.split(',').map(str::to_string).collect())
+    }
+
+    /// 逐项给出的上游，各项只去除首尾空白
+    pub fn from_members(members: Vec<String>) -> Self {
+        let trimmed: Vec<String> = members
+            .iter()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        if trimmed.is_empty() {
+            // 保持原样交给 forward_upstream 报错
+            return Self(Arc::from(vec![members.join(",")]));
+        }
+        Self(Arc::from(trimmed))
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for UpstreamGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+impl PartialEq<&str> for UpstreamGroup {
+    fn eq(&self, other: &&str) -> bool {
+        other.split(',').map(str::trim).eq(self.0.iter().map(String::as_str))
+    }
+}
+
+impl Serialize for UpstreamGroup {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// 静态记录集中的一条记录，加载配置时即解析为 RData。
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawStaticRecord")]
+pub struct StaticRecord {
+    pub rdata: RData,
+    pub ttl: u32,
+}
+
+/// 本地区域定义：顶点 SOA/NS 与区域内的静态记录。
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalZone {
+    /// 区域顶点，如 "corp.example"。
+    pub zone: String,
+    pub soa: SoaConfig,
+    /// 顶点 NS 记录指向的主机名。
+    #[serde(default)]
+    pub ns: Vec<String>,
+    #[serde(default)]
+    pub records: Vec<LocalZoneRecord>,
+}
+
+/// 区域 SOA；minimum 与 ttl 中的较小者作为 NXDOMAIN/NODATA 的负缓存 TTL（RFC 2308）。
+#[derive(Debug, Clone, Deserialize)]
+pub struct SoaConfig {
+    /// 主名称服务器。
+    pub mname: String,
+    /// 管理员邮箱（以 "." 代替 "@"），如 "hostmaster.corp.example"。
+    pub rname: String,
+    #[serde(default = "default_soa_serial")]
+    pub serial: u32,
+    #[serde(default = "default_soa_refresh")]
+    pub refresh: i32,
+    #[serde(default = "default_soa_retry")]
+    pub retry: i32,
+    #[serde(default = "default_soa_expire")]
+    pub expire: i32,
+    #[serde(default = "default_static_record_ttl")]
+    pub minimum: u32,
+    /// SOA 记录本身的 TTL，缺省3600。
+    #[serde(default = "default_soa_ttl")]
+    pub ttl: u32,
+}
+
+/// 本地区域内的一条记录；name 为 "@"（顶点）或区域内的完整域名。
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalZoneRecord {
+    pub name: String,
+    #[serde(flatten)]
+    pub record: StaticRecord,
+}
+
+#[derive(Deserialize)]
+struct RawStaticRecord {
+    /// 记录类型：A/AAAA/CNAME/NS/PTR/TXT/MX/SRV。
+    #[serde(rename = "type")]
+    rtype: String,
+    /// 记录值；MX 为 "优先级 主机"，SRV 为 "优先级 权重 端口 目标"。
+    value: String,
+    #[serde(default = "default_static_record_ttl")]
+    ttl: u32,
+}
+
+impl TryFrom<RawStaticRecord> for StaticRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawStaticRecord) -> Result<Self> {
+        let rdata = parse_static_rdata(&raw.rtype, &raw.value)
+            .with_context(|| format!("invalid static {} record: {}", raw.rtype, raw.value))?;
+        Ok(Self { rdata, ttl: raw.ttl })
+    }
+}
+
+pub(crate) fn parse_static_rdata(rtype: &str, value: &str) -> Result<RData> {
+    let value = value.trim();
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let rdata = match rtype.to_ascii_uppercase().as_str() {
+        "A" => RData::A(A(value.parse()?)),
+        "AAAA" => RData::AAAA(AAAA(value.parse()?)),
+        "CNAME" => RData::CNAME(CNAME(Name::from_str(value)?)),
+        "NS" => RData::NS(NS(Name::from_str(value)?)),
+        "PTR" => RData::PTR(PTR(Name::from_str(value)?)),
+        "TXT" => {
+            // 单个 character-string 最长 255 字节，超长内容拆分为多段
+            RData::TXT(TXT::from_bytes(value.as_bytes().chunks(255).collect()))
+        }
+        "MX" => match fields.as_slice() {
+            [pref, exchange] => RData::MX(MX::new(pref.parse()?, Name::from_str(exchange)?)),
+            _ => anyhow::bail!("expected \"<preference> <exchange>\""),
+        },
+        "SRV" => match fields.as_slice() {
+            [priority, weight, port, target] => RData::SRV(SRV::new(
+                priority.parse()?,
+                weight.parse()?,
+                port.parse()?,
+                Name::from_str(target)?,
+            )),
+            _ => anyhow::bail!("expected \"<priority> <weight> <port> <target>\""),
+        },
+        other => anyhow::bail!("unsupported record type: {}", other),
+    };
+    Ok(rdata)
+}
+
+/// HTTPS 记录的 SvcParams，键名与 RFC 9460 表示格式一致。
+#[derive(Debug, Clone, Deserialize, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub struct HttpsParams {
+    /// ALPN 协议标识，如 ["h3", "h2"]。
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// 客户端不应假定默认 ALPN（http/1.1），需同时配置 alpn。
+    #[serde(default, alias = "no-default-alpn")]
+    pub no_default_alpn: bool,
+    /// 替代端口。
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// IPv4 地址提示。
+    #[serde(default)]
+    pub ipv4hint: Vec<Ipv4Addr>,
+    /// IPv6 地址提示。
+    #[serde(default)]
+    pub ipv6hint: Vec<Ipv6Addr>,
+}
+
+impl HttpsParams {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// 由 static_cname 动作构造 CNAME RData，target 补全为带结尾点的 FQDN；加载配置时调用以提前校验。
+pub fn cname_rdata(target: &str) -> Result<RData> {
+    let mut name = Name::from_str(target.trim()).with_context(|| format!("invalid cname target: {}", target))?;
+    if name.is_root() {
+        anyhow::bail!("cname target must not be the root");
+    }
+    name.set_fqdn(true);
+    Ok(RData::CNAME(CNAME(name)))
+}
+
+/// 由 static_https 动作构造 HTTPS RData；加载配置时调用以提前校验参数。
+pub fn https_rdata(priority: u16, target: &str, params: &HttpsParams) -> Result<RData> {
+    let target = Name::from_str(target.trim()).with_context(|| format!("invalid https target: {}", target))?;
+    if priority == 0 && !params.is_empty() {
+        anyhow::bail!("https alias mode (priority 0) must not carry params");
+    }
+    for id in &params.alpn {
+        if id.is_empty() || id.len() > 255 {
+            anyhow::bail!("invalid alpn id: {:?}", id);
+        }
+    }
+    if params.no_default_alpn && params.alpn.is_empty() {
+        anyhow::bail!("no_default_alpn requires alpn");
+    }
+
+    // SvcParams 必须按 key 升序排列
+    let mut svc_params = Vec::new();
+    if !params.alpn.is_empty() {
+        svc_params.push((SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(params.alpn.clone()))));
+    }
+    if params.no_default_alpn {
+        svc_params.push((SvcParamKey::NoDefaultAlpn, SvcParamValue::NoDefaultAlpn));
+    }
+    if let Some(port) = params.port {
+        svc_params.push((SvcParamKey::Port, SvcParamValue::Port(port)));
+    }
+    if !params.ipv4hint.is_empty() {
+        let hints = params.ipv4hint.iter().map(|ip| A(*ip)).collect();
+        svc_params.push((SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(hints))));
+    }
+    if !params.ipv6hint.is_empty() {
+        let hints = params.ipv6hint.iter().map(|ip| AAAA(*ip)).collect();
+        svc_params.push((SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(hints))));
+    }
+    Ok(RData::HTTPS(HTTPS(SVCB::new(priority, target, svc_params))))
+}
+
+/// 解析 rewrite_ip 的 from/to 网段；加载配置时调用一次，结果存入 Action::RewriteIp::nets。
+pub fn rewrite_ip_nets(from: &str, to: &str) -> Result<(IpNet, IpNet)> {
+    let from_net: IpNet = from.trim().parse().with_context(|| format!("invalid rewrite_ip from: {}", from))?;
+    let to_net: IpNet = to.trim().parse().with_context(|| format!("invalid rewrite_ip to: {}", to))?;
+    match (from_net, to_net) {
+        (IpNet::V4(_), IpNet::V4(_)) | (IpNet::V6(_), IpNet::V6(_)) => {}
+        _ => anyhow::bail!("rewrite_ip from {} and to {} must be the same address family", from, to),
+    }
+    if from_net.prefix_len() != to_net.prefix_len() {
+        anyhow::bail!(
+            "rewrite_ip prefix lengths differ: from /{} vs to /{}",
+            from_net.prefix_len(),
+            to_net.prefix_len()
+        );
+    }
+    Ok((from_net, to_net))
+}
+
+/// 解析 upstream_port_range（"lo-hi"，含两端，端口不可为 0）。
+pub fn parse_port_range(raw: &str) -> Result<(u16, u16)> {
+    let (lo, hi) = raw
+        .split_once('-')
+        .with_context(|| format!("invalid upstream_port_range {}: expected lo-hi", raw))?;
+    let lo: u16 = lo.trim().parse().with_context(|| format!("invalid upstream_port_range start: {}", lo))?;
+    let hi: u16 = hi.trim().parse().with_context(|| format!("invalid upstream_port_range end: {}", hi))?;
+    if lo == 0 || lo > hi {
+        anyhow::bail!("invalid upstream_port_range {}: need 1 <= lo <= hi", raw);
+    }
+    Ok((lo, hi))
+}
+
+/// 解析 dns64_prefix（IPv6 CIDR，长度为 RFC 6052 §2.2 的 32/40/48/56/64/96 之一）。
+pub fn parse_dns64_prefix(raw: &str) -> Result<ipnet::Ipv6Net> {
+    let net: ipnet::Ipv6Net = raw.trim().parse().with_context(|| format!("invalid dns64_prefix {}", raw))?;
+    if ![32, 40, 48, 56, 64, 96].contains(&net.prefix_len()) {
+        anyhow::bail!("invalid dns64_prefix {}: length must be 32, 40, 48, 56, 64 or 96", raw);
+    }
+    Ok(net.trunc())
 }
 
 #[derive(Debug, Clone, Deserialize, Copy, PartialEq, Eq)]
@@ -208,6 +1037,10 @@ pub enum Action {
 pub enum Transport {
     Udp,
     Tcp,
+    /// DNS-over-HTTPS，upstream 为 https://.../dns-query 形式的 URL。
+    Doh,
+    /// DNS-over-TLS，upstream 为 tls://host:853 或 host:853。
+    Dot,
 }
 
 #[derive(Debug, Clone, Deserialize, Copy, PartialEq, Eq)]
@@ -229,19 +1062,67 @@ fn default_match_operator() -> MatchOperator {
     MatchOperator::And
 }
 
+/// 按扩展名选择格式：.yaml/.yml 使用 YAML，其余按 JSON 解析。
+fn parse_config_str(path: &Path, raw: &str) -> Result<PipelineConfig> {
+    let is_yaml = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+    if is_yaml {
+        serde_yaml::from_str(raw).with_context(|| format!("parse yaml config file: {}", path.display()))
+    } else {
+        serde_json::from_str(raw).with_context(|| format!("parse config file: {}", path.display()))
+    }
+}
+
+/// 支持的配置格式主版本范围；version 形如 "1.0"，只比较主版本，次版本只增不减字段。未声明 version 的配置按当前格式处理
+const SUPPORTED_CONFIG_VERSIONS: std::ops::RangeInclusive<u32> = 1..=1;
+
+/// 超出支持范围的配置直接拒绝加载，避免新旧格式字段被静默误读
+fn check_config_version(version: &str) -> Result<()> {
+    let (lo, hi) = (*SUPPORTED_CONFIG_VERSIONS.start(), *SUPPORTED_CONFIG_VERSIONS.end());
+    let major = version
+        .trim()
+        .trim_start_matches(['v', 'V'])
+        .split('.')
+        .next()
+        .and_then(|m| m.parse::<u32>().ok())
+        .with_context(|| format!("invalid config version {version:?}: expected \"MAJOR.MINOR\", e.g. \"{hi}.0\""))?;
+    if major < lo {
+        anyhow::bail!(
+            "config version {version} predates the supported schema ({lo}.x-{hi}.x) and may use fields this release reads differently; \
+             compare it with config/pipeline.json, then set \"version\": \"{hi}.0\""
+        );
+    }
+    if major > hi {
+        anyhow::bail!(
+            "config version {version} is newer than this kixdns supports ({lo}.x-{hi}.x): fields added in {major}.x could be \
+             ignored or misread; upgrade kixdns, or rewrite the config against the {hi}.x schema and set \"version\": \"{hi}.0\""
+        );
+    }
+    Ok(())
+}
+
 pub fn load_config(path: &Path) -> Result<PipelineConfig> {
     let raw = fs::read_to_string(path)
         .with_context(|| format!("read config file: {}", path.display()))?;
-    let mut cfg: PipelineConfig = serde_json::from_str(&raw)
-        .with_context(|| format!("parse config file: {}", path.display()))?;
+    let mut cfg = parse_config_str(path, &raw)?;
 
     if let Some(version) = cfg.version.as_ref() {
+        check_config_version(version).with_context(|| format!("config file: {}", path.display()))?;
         info!(target = "config", version = %version, "config loaded");
     }
 
     // 轻量校验：CIDR提前解析，便于后续快速匹配。
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if let Some(file) = cfg.settings.static_records_file.as_mut()
+        && Path::new(file.as_str()).is_relative()
+    {
+        *file = base_dir.join(file.as_str()).to_string_lossy().into_owned();
+    }
     for pipeline in &mut cfg.pipelines {
         for rule in &mut pipeline.rules {
+            resolve_domain_set_paths(&mut rule.matchers, base_dir);
             for matcher in &rule.matchers {
                 if let Matcher::ClientIp { cidr } = &matcher.matcher {
                     let _parsed: IpNet = cidr.parse()?;
@@ -310,6 +1191,31 @@ mod tests {
         assert!(rule.response_actions_on_miss.is_empty());
     }
 
+    #[test]
+    fn config_version_outside_supported_range_fails_with_guidance() {
+        let dir = std::env::temp_dir().join(format!("kixdns-config-version-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let load = |version: serde_json::Value| {
+            let path = dir.join("pipeline.json");
+            fs::write(&path, json!({ "version": version, "pipelines": [] }).to_string()).unwrap();
+            load_config(&path).map_err(|err| format!("{err:#}"))
+        };
+
+        assert_eq!(load(json!("1.0")).unwrap().version.as_deref(), Some("1.0"));
+        assert!(load(json!("1.7")).is_ok());
+        assert!(load(serde_json::Value::Null).is_ok());
+
+        let err = load(json!("2.0")).unwrap_err();
+        assert!(err.contains("newer than this kixdns supports (1.x-1.x)"), "{err}");
+        assert!(err.contains("upgrade kixdns"), "{err}");
+        let err = load(json!("0.9")).unwrap_err();
+        assert!(err.contains("predates the supported schema"), "{err}");
+        assert!(err.contains("config/pipeline.json"), "{err}");
+        let err = load(json!("latest")).unwrap_err();
+        assert!(err.contains("invalid config version"), "{err}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn rule_operator_defaults_to_and_when_omitted() {
         let raw = serde_json::json!({
@@ -333,12 +1239,250 @@ mod tests {
         assert_eq!(rule.matcher_operator, MatchOperator::And);
         assert_eq!(rule.response_matcher_operator, MatchOperator::And);
     }
+
+    #[test]
+    fn yaml_and_json_configs_load_identically() {
+        let json_src = r#"{
+            "settings": { "min_ttl": 30, "default_upstream": "9.9.9.9:53" },
+            "pipeline_select": [
+                { "pipeline": "main", "matchers": [ { "type": "listener_label", "value": "edge" } ] }
+            ],
+            "pipelines": [
+                {
+                    "id": "main",
+                    "rules": [
+                        {
+                            "name": "block-ads",
+                            "matchers": [ { "type": "domain_suffix", "value": "ads.example.com" } ],
+                            "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+                        },
+                        {
+                            "name": "office",
+                            "matchers": [ { "type": "client_ip", "cidr": "10.0.0.0/8" } ],
+                            "actions": [
+                                { "type": "log", "level": "info" },
+                                { "type": "forward", "upstream": "1.1.1.1:53", "transport": "tcp" }
+                            ]
+                        }
+                    ]
+                }
+            ]
+        }"#;
+        let yaml_src = r#"
+# 与 JSON 版本等价
+settings:
+  min_ttl: 30
+  default_upstream: "9.9.9.9:53"
+pipeline_select:
+  - pipeline: main
+    matchers:
+      - { type: listener_label, value: edge }
+pipelines:
+  - id: main
+    rules:
+      - name: block-ads
+        matchers:
+          - type: domain_suffix
+            value: ads.example.com
+        actions:
+          - type: static_response
+            rcode: NXDOMAIN
+      - name: office
+        matchers:
+          - type: client_ip
+            cidr: 10.0.0.0/8
+        actions:
+          - type: log
+            level: info
+          - type: forward
+            upstream: "1.1.1.1:53"
+            transport: tcp
+"#;
+        let dir = std::env::temp_dir().join(format!("kixdns-config-fmt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let json_path = dir.join("pipeline.json");
+        let yaml_path = dir.join("pipeline.yml");
+        fs::write(&json_path, json_src).unwrap();
+        fs::write(&yaml_path, yaml_src).unwrap();
+
+        let from_json = crate::matcher::RuntimePipelineConfig::from_config(load_config(&json_path).unwrap()).unwrap();
+        let mut from_yaml = crate::matcher::RuntimePipelineConfig::from_config(load_config(&yaml_path).unwrap()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        // RuntimePipelineConfig 含 Regex 等无法比较的类型，用 Debug 输出比较（每个 HashMap 至多一个键，顺序稳定）；
+        // generation 每次编译递增，不参与比较
+        from_yaml.generation = from_json.generation;
+        assert_eq!(format!("{from_json:?}"), format!("{from_yaml:?}"));
+        assert_eq!(from_yaml.settings.min_ttl, 30);
+        assert_eq!(from_yaml.pipelines[0].rules.len(), 2);
+    }
+
+    #[test]
+    fn static_https_params_are_validated() {
+        let ok = https_rdata(
+            1,
+            ".",
+            &HttpsParams {
+                alpn: vec!["h3".into(), "h2".into()],
+                port: Some(8443),
+                ..Default::default()
+            },
+        )
+        .expect("valid https");
+        assert!(matches!(ok, RData::HTTPS(_)));
+
+        let parse = |params: serde_json::Value| {
+            serde_json::from_value::<Action>(serde_json::json!({
+                "type": "static_https", "priority": 1, "target": ".", "params": params
+            }))
+        };
+        assert!(parse(serde_json::json!({ "ipv4hint": ["not-an-ip"] })).is_err());
+        assert!(parse(serde_json::json!({ "alpns": ["h3"] })).is_err());
+        assert!(parse(serde_json::json!({ "port": 70000 })).is_err());
+
+        let alias_with_params = HttpsParams {
+            alpn: vec!["h3".into()],
+            ..Default::default()
+        };
+        assert!(https_rdata(0, "svc.example.com", &alias_with_params).is_err());
+        assert!(https_rdata(1, ".", &HttpsParams { alpn: vec![String::new()], ..Default::default() }).is_err());
+        assert!(https_rdata(1, ".", &HttpsParams { no_default_alpn: true, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn static_record_set_validates_rdata() {
+        let parse = |records: serde_json::Value| {
+            serde_json::from_value::<Action>(serde_json::json!({
+                "type": "static_record_set",
+                "records": records
+            }))
+        };
+        let ok = parse(serde_json::json!([
+            { "type": "mx", "value": "10 mail.example.com" },
+            { "type": "SRV", "value": "0 5 5060 sip.example.com", "ttl": 30 }
+        ]))
+        .expect("valid records");
+        match ok {
+            Action::StaticRecordSet { records } => {
+                assert_eq!(records.len(), 2);
+                assert!(matches!(records[0].rdata, RData::MX(_)));
+                assert_eq!(records[0].ttl, 300);
+                assert_eq!(records[1].ttl, 30);
+            }
+            other => panic!("unexpected action: {other:?}"),
+        }
+
+        assert!(parse(serde_json::json!([{ "type": "A", "value": "not-an-ip" }])).is_err());
+        assert!(parse(serde_json::json!([{ "type": "MX", "value": "mail.example.com" }])).is_err());
+        assert!(parse(serde_json::json!([{ "type": "HINFO", "value": "x" }])).is_err());
+    }
+
+    #[test]
+    fn listeners_fall_back_to_legacy_binds_and_reject_duplicates() {
+        let settings: GlobalSettings =
+            serde_json::from_value(json!({ "bind_udp": "127.0.0.1:5353", "bind_tcp": "127.0.0.1:5354" })).unwrap();
+        assert_eq!(
+            settings.listeners("edge").unwrap(),
+            vec![Listener {
+                label: "edge".to_string(),
+                udp: Some("127.0.0.1:5353".parse().unwrap()),
+                tcp: Some("127.0.0.1:5354".parse().unwrap()),
+            }]
+        );
+
+        let settings: GlobalSettings = serde_json::from_value(json!({
+            "listeners": [
+                { "label": "internal", "bind": "10.0.0.1:53" },
+                { "label": "external", "bind": "203.0.113.1:53", "protocols": ["udp"] },
+                { "label": "external-tcp", "bind": "203.0.113.1:53", "protocols": ["tcp"] }
+            ]
+        }))
+        .unwrap();
+        let listeners = settings.listeners("ignored").unwrap();
+        let labels: Vec<&str> = listeners.iter().map(|l| l.label.as_str()).collect();
+        assert_eq!(labels, ["internal", "external", "external-tcp"]);
+        assert!(listeners[0].udp.is_some() && listeners[0].tcp.is_some());
+        assert!(listeners[1].udp.is_some() && listeners[1].tcp.is_none());
+        assert!(listeners[2].udp.is_none() && listeners[2].tcp.is_some());
+
+        for bad in [
+            json!([{ "label": "a", "bind": "10.0.0.1:53" }, { "label": "b", "bind": "10.0.0.1:53", "protocols": ["tcp"] }]),
+            json!([{ "label": "a", "bind": "10.0.0.1:53", "protocols": [] }]),
+            json!([{ "label": " ", "bind": "10.0.0.1:53" }]),
+            json!([{ "label": "a", "bind": "nope" }]),
+        ] {
+            let settings: GlobalSettings = serde_json::from_value(json!({ "listeners": bad })).unwrap();
+            assert!(settings.listeners("default").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn zone_transfers_are_allowed_by_default() {
+        for raw in [json!({}), json!({ "settings": {} })] {
+            let cfg: PipelineConfig = serde_json::from_value(raw).unwrap();
+            assert!(cfg.settings.allow_axfr && cfg.settings.allow_ixfr);
+        }
+        let cfg: PipelineConfig = serde_json::from_value(json!({ "settings": { "allow_axfr": false } })).unwrap();
+        assert!(!cfg.settings.allow_axfr && cfg.settings.allow_ixfr);
+    }
+
+    #[test]
+    fn forward_upstreams_split_on_commas_only_in_the_string_form() {
+        let upstream = |value: serde_json::Value| {
+            let action: Action = serde_json::from_value(json!({ "type": "forward", "upstream": value })).unwrap();
+            match action {
+                Action::Forward { upstream, .. } => upstream.expect("upstream").members().to_vec(),
+                other => panic!("unexpected action: {other:?}"),
+            }
+        };
+        assert_eq!(upstream(json!("1.1.1.1:53, 8.8.8.8:53")), ["1.1.1.1:53", "8.8.8.8:53"]);
+        assert_eq!(
+            upstream(json!([" https://doh.example/dns-query?a=1,2", "9.9.9.9:53"])),
+            ["https://doh.example/dns-query?a=1,2", "9.9.9.9:53"]
+        );
+    }
 }
 
 fn default_min_ttl() -> u32 {
     0
 }
 
+fn default_static_record_ttl() -> u32 {
+    300
+}
+
+fn default_soa_serial() -> u32 {
+    1
+}
+
+fn default_soa_refresh() -> i32 {
+    3600
+}
+
+fn default_soa_retry() -> i32 {
+    600
+}
+
+fn default_soa_expire() -> i32 {
+    86400
+}
+
+fn default_soa_ttl() -> u32 {
+    3600
+}
+
+fn default_max_negative_ttl() -> u32 {
+    3600
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    5000
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
 fn default_bind_udp() -> String {
     "0.0.0.0:5353".to_string()
 }
@@ -347,6 +1491,51 @@ fn default_bind_tcp() -> String {
     "0.0.0.0:5353".to_string()
 }
 
+fn default_max_name_length() -> usize {
+    255
+}
+
+fn default_max_label_count() -> usize {
+    127
+}
+
+fn default_tcp_idle_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_dns64_prefix() -> String {
+    "64:ff9b::/96".to_string()
+}
+
+fn default_rrl_slip() -> u32 {
+    2
+}
+
+fn default_max_answer_count() -> usize {
+    u16::MAX as usize
+}
+
+fn default_servfail_retry_attempts() -> u32 {
+    1
+}
+
+fn default_block_escalation_window_secs() -> u64 {
+    60
+}
+
+fn default_listener_protocols() -> Vec<ListenerProtocol> {
+    vec![ListenerProtocol::Udp, ListenerProtocol::Tcp]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// 未写 settings 时按空的 settings 反序列化，各字段取 serde 缺省值（而非 Default 的零值）
+fn default_settings() -> GlobalSettings {
+    serde_json::from_value(serde_json::json!({})).expect("empty settings deserialize")
+}
+
 fn default_upstream() -> String {
     "1.1.1.1:53".to_string()
 }
@@ -355,6 +1544,18 @@ fn default_upstream_timeout_ms() -> u64 {
     2000
 }
 
+fn default_upstream_latency_alpha() -> f64 {
+    0.2
+}
+
+fn default_upstream_probe_percent() -> u8 {
+    5
+}
+
+fn default_reload_debounce_ms() -> u64 {
+    200
+}
+
 fn default_response_jump_limit() -> u32 {
     10
 }
@@ -366,3 +1567,7 @@ fn default_udp_pool_size() -> usize {
 fn default_tcp_pool_size() -> usize {
     64
 }
+
+fn default_max_upstream_response() -> usize {
+    u16::MAX as usize
+}
@@ -0,0 +1,166 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+/// EDNS COOKIE 选项（RFC 7873）
+pub const EDNS_OPTION_COOKIE: u16 = 10;
+const CLIENT_COOKIE_LEN: usize = 8;
+/// 服务器 cookie 按 RFC 9018 的布局：版本(1) 保留(3) 时间戳(4) 摘要(8)
+const SERVER_COOKIE_LEN: usize = 16;
+const SERVER_COOKIE_VERSION: u8 = 1;
+/// 服务器 cookie 的有效期（秒），超过后视为无效并重新签发
+const COOKIE_LIFETIME_SECS: u32 = 3600;
+/// 允许客户端回传的时间戳领先本机的秒数（多实例时钟偏差）
+const COOKIE_CLOCK_SKEW_SECS: u32 = 300;
+/// cookie_secret 的最小字节数
+const MIN_SECRET_LEN: usize = 16;
+
+/// 请求中 COOKIE 选项的校验结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieCheck {
+    /// 请求没有 COOKIE 选项
+    Absent,
+    /// 选项长度不合法（RFC 7873 §5.2.2），应答 FORMERR
+    Malformed,
+    /// 只有客户端 cookie，或服务器 cookie 已过期/不是本服务签发
+    Unverified([u8; CLIENT_COOKIE_LEN]),
+    /// 服务器 cookie 校验通过
+    Valid([u8; CLIENT_COOKIE_LEN]),
+}
+
+impl CookieCheck {
+    /// 应答中需要回写的客户端 cookie
+    pub fn client_cookie(&self) -> Option<&[u8; CLIENT_COOKIE_LEN]> {
+        match self {
+            CookieCheck::Unverified(c) | CookieCheck::Valid(c) => Some(c),
+            CookieCheck::Absent | CookieCheck::Malformed => None,
+        }
+    }
+}
+
+/// COOKIE 选项长度既不是单独的客户端 cookie（8 字节），也不是客户端加 8~32 字节服务器 cookie
+#[inline]
+pub fn is_malformed(option: &[u8]) -> bool {
+    option.len() != CLIENT_COOKIE_LEN && !(16..=40).contains(&option.len())
+}
+
+/// 服务端 DNS Cookie：服务器 cookie 为 HMAC-SHA256(secret, 客户端 cookie | 版本 | 保留 | 时间戳 | 客户端 IP) 的前 8 字节，
+/// 同一 cookie_secret 的多个实例可互相校验
+pub struct ServerCookies {
+    key: hmac::Key,
+}
+
+impl std::fmt::Debug for ServerCookies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerCookies").finish_non_exhaustive()
+    }
+}
+
+impl ServerCookies {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            anyhow::bail!("cookie_secret must be at least {MIN_SECRET_LEN} bytes");
+        }
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        })
+    }
+
+    /// 校验 COOKIE 选项数据（客户端 cookie 8 字节，可选服务器 cookie 8~32 字节）
+    pub fn check(&self, option: Option<&[u8]>, client_ip: IpAddr, now: u32) -> CookieCheck {
+        let Some(option) = option else {
+            return CookieCheck::Absent;
+        };
+        if is_malformed(option) {
+            return CookieCheck::Malformed;
+        }
+        let mut client = [0u8; CLIENT_COOKIE_LEN];
+        client.copy_from_slice(&option[..CLIENT_COOKIE_LEN]);
+        let server = &option[CLIENT_COOKIE_LEN..];
+        if server.len() != SERVER_COOKIE_LEN || server[0] != SERVER_COOKIE_VERSION {
+            return CookieCheck::Unverified(client);
+        }
+        let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        // 时间戳按 RFC 1982 序列号比较，可跨越 u32 回绕
+        let age = now.wrapping_sub(timestamp);
+        if age > COOKIE_LIFETIME_SECS && timestamp.wrapping_sub(now) > COOKIE_CLOCK_SKEW_SECS {
+            return CookieCheck::Unverified(client);
+        }
+        let expected = self.digest(&client, timestamp, client_ip);
+        // 逐字节异或累积，比较耗时与摘要内容无关
+        let diff = server[8..].iter().zip(&expected).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff == 0 {
+            CookieCheck::Valid(client)
+        } else {
+            CookieCheck::Unverified(client)
+        }
+    }
+
+    /// 应答中的 COOKIE 选项数据：客户端 cookie 原样回写，附上新签发的服务器 cookie
+    pub fn issue(&self, client: &[u8; CLIENT_COOKIE_LEN], client_ip: IpAddr, now: u32) -> [u8; CLIENT_COOKIE_LEN + SERVER_COOKIE_LEN] {
+        let mut out = [0u8; CLIENT_COOKIE_LEN + SERVER_COOKIE_LEN];
+        out[..CLIENT_COOKIE_LEN].copy_from_slice(client);
+        out[CLIENT_COOKIE_LEN] = SERVER_COOKIE_VERSION;
+        out[CLIENT_COOKIE_LEN + 4..CLIENT_COOKIE_LEN + 8].copy_from_slice(&now.to_be_bytes());
+        out[CLIENT_COOKIE_LEN + 8..].copy_from_slice(&self.digest(client, now, client_ip));
+        out
+    }
+
+    fn digest(&self, client: &[u8; CLIENT_COOKIE_LEN], timestamp: u32, client_ip: IpAddr) -> [u8; 8] {
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(client);
+        ctx.update(&[SERVER_COOKIE_VERSION, 0, 0, 0]);
+        ctx.update(&timestamp.to_be_bytes());
+        match client_ip {
+            IpAddr::V4(ip) => ctx.update(&ip.octets()),
+            IpAddr::V6(ip) => ctx.update(&ip.octets()),
+        }
+        let tag = ctx.sign();
+        let mut out = [0u8; 8];
+        out.copy_from_slice(&tag.as_ref()[..8]);
+        out
+    }
+}
+
+/// 服务器 cookie 使用的 Unix 秒级时间戳
+#[inline]
+pub fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_cookie_validates_for_same_client_only() {
+        let cookies = ServerCookies::new("0123456789abcdef").unwrap();
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let client = *b"clientck";
+        let now = 1_700_000_000;
+        let option = cookies.issue(&client, ip, now);
+
+        assert_eq!(cookies.check(Some(&option), ip, now + 10), CookieCheck::Valid(client));
+        // 其他客户端 IP、篡改的摘要、过期的时间戳均不通过
+        assert_eq!(cookies.check(Some(&option), "192.0.2.8".parse().unwrap(), now), CookieCheck::Unverified(client));
+        let mut tampered = option;
+        tampered[23] ^= 1;
+        assert_eq!(cookies.check(Some(&tampered), ip, now), CookieCheck::Unverified(client));
+        assert_eq!(
+            cookies.check(Some(&option), ip, now + COOKIE_LIFETIME_SECS + 1),
+            CookieCheck::Unverified(client)
+        );
+        // 另一密钥签发的 cookie 不被接受
+        let other = ServerCookies::new("fedcba9876543210").unwrap();
+        assert_eq!(other.check(Some(&option), ip, now), CookieCheck::Unverified(client));
+
+        assert_eq!(cookies.check(None, ip, now), CookieCheck::Absent);
+        assert_eq!(cookies.check(Some(b"clientck"), ip, now), CookieCheck::Unverified(client));
+        assert_eq!(cookies.check(Some(b"short"), ip, now), CookieCheck::Malformed);
+        assert_eq!(cookies.check(Some(&[0u8; 12]), ip, now), CookieCheck::Malformed);
+        assert!(ServerCookies::new("too-short").is_err());
+    }
+}
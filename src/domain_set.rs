@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+
+/// 大规模域名集合（如 10 万级拦截列表），按标签逐级查哈希表，查询代价只与 qname 的标签数有关。
+///
+/// 文件格式：每行一个域名，`#` 开头为注释；普通行匹配该域名及其子域，`full:` 前缀的行只精确匹配。
+#[derive(Default)]
+pub struct DomainSet {
+    exact: HashSet<String>,
+    suffixes: HashSet<String>,
+}
+
+impl std::fmt::Debug for DomainSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DomainSet")
+            .field("exact", &self.exact.len())
+            .field("suffixes", &self.suffixes.len())
+            .finish()
+    }
+}
+
+impl DomainSet {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("read domain set file: {}", path.display()))?;
+        Ok(Self::parse(&raw))
+    }
+
+    pub fn parse(raw: &str) -> Self {
+        let mut set = Self::default();
+        for line in raw.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("full:") {
+                let name = normalize(name);
+                if !name.is_empty() {
+                    set.exact.insert(name);
+                }
+            } else {
+                let name = normalize(line.trim_start_matches("*."));
+                if !name.is_empty() {
+                    set.suffixes.insert(name);
+                }
+            }
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.suffixes.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// qname 需为小写；末尾的点会被忽略
+    pub fn contains(&self, qname: &str) -> bool {
+        self.lookup(qname).0
+    }
+
+    /// 返回是否命中以及哈希查找次数：至多 1 次精确查找加每个标签后缀各 1 次，与集合大小无关
+    fn lookup(&self, qname: &str) -> (bool, usize) {
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+        if self.exact.contains(qname) {
+            return (true, 1);
+        }
+        let mut probes = 1;
+        let mut search_name = qname;
+        loop {
+            probes += 1;
+            if self.suffixes.contains(search_name) {
+                return (true, probes);
+            }
+            match search_name.find('.') {
+                Some(idx) => search_name = &search_name[idx + 1..],
+                None => return (false, probes),
+            }
+        }
+    }
+}
+
+/// domain_set 匹配器引用的列表文件：内容放在 ArcSwap 中，文件变化时只重建该集合并原子替换，无需重新编译 pipeline
+pub struct DomainSetFile {
+    path: PathBuf,
+    set: ArcSwap<DomainSet>,
+}
+
+impl std::fmt::Debug for DomainSetFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DomainSetFile")
+            .field("path", &self.path)
+            .field("set", &*self.set.load())
+            .finish()
+    }
+}
+
+impl DomainSetFile {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let set = DomainSet::load(&path)?;
+        Ok(Self {
+            path,
+            set: ArcSwap::from_pointee(set),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 重新读取文件并替换集合，返回新集合的条目数；读取失败时保留旧内容
+    pub fn reload(&self) -> anyhow::Result<usize> {
+        let set = DomainSet::load(&self.path)?;
+        let len = set.len();
+        self.set.store(std::sync::Arc::new(set));
+        Ok(len)
+    }
+
+    #[inline]
+    pub fn contains(&self, qname: &str) -> bool {
+        self.set.load().contains(qname)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffix_and_exact_entries_match_on_label_boundaries() {
+        let set = DomainSet::parse("# blocklist\nAds.Example.com.\nfull:track.example.net\n*.cdn.test # wildcard\n\n");
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("ads.example.com"));
+        assert!(set.contains("x.ads.example.com."));
+        assert!(!set.contains("badads.example.com"));
+        assert!(set.contains("track.example.net"));
+        assert!(!set.contains("a.track.example.net"));
+        assert!(set.contains("img.cdn.test"));
+        assert!(!set.contains("example.com"));
+    }
+
+    #[test]
+    fn lookup_cost_does_not_grow_with_set_size() {
+        let small = DomainSet::parse(&(0..100).map(|i| format!("host{i}.block.test\n")).collect::<String>());
+        let large = DomainSet::parse(&(0..50_000).map(|i| format!("host{i}.block.test\n")).collect::<String>());
+        assert_eq!(large.len(), 50_000);
+
+        // 查找次数只取决于 qname 的标签数：未命中时为精确查找 1 次加 5 个后缀各 1 次
+        for (qname, hit, probes) in [
+            ("a.b.host50.block.test", true, 4),
+            ("a.b.host99.block.test.", true, 4),
+            ("a.b.miss7.example.org", false, 6),
+        ] {
+            assert_eq!(small.lookup(qname), (hit, probes), "{qname}");
+            assert_eq!(large.lookup(qname), (hit, probes), "{qname}");
+        }
+        assert_eq!(large.lookup("a.b.host49999.block.test"), (true, 4));
+    }
+}
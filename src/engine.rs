@@ -13,44 +13,129 @@ use dashmap::DashMap;
 use rustc_hash::{FxHasher, FxBuildHasher};
 use socket2::{Domain, Protocol, Socket, Type};
 use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
-use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::rdata::{A, AAAA, PTR};
 use hickory_proto::rr::{DNSClass, Name, RData, Record};
 use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
 use moka::sync::Cache;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{
-    TcpStream, UdpSocket,
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{self, pki_types::ServerName};
 use tokio::sync::{Mutex, Semaphore, oneshot};
 use tokio::time::timeout;
-use tracing::{debug, info, warn};
+use tracing::{Instrument, debug, info, info_span, warn};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
 
-use crate::cache::{CacheEntry, DnsCache, new_cache};
+use crate::backoff::RecvBackoff;
+use crate::cache::{CacheEntry, CacheStats, DnsCache, new_cache};
 use crate::advanced_rule::{CompiledPipeline, compile_pipelines, fast_static_match};
-use crate::config::{Action, Transport};
+use crate::cookie::{CookieCheck, EDNS_OPTION_COOKIE};
+use crate::config::{Action, AnyPolicy, HttpsParams, RateLimitMode, ResponseParseFailure, StaticRecord, Transport, UpstreamGroup, UpstreamStrategy};
 use crate::matcher::{
-    RuntimePipeline, RuntimePipelineConfig, RuntimeResponseMatcherWithOp, eval_match_chain,
+    OPCODE_QUERY, RuntimeMatcher, RuntimePipeline, RuntimePipelineConfig, RuntimeResponseMatcherWithOp, eval_match_chain,
+};
+use crate::geoip::GeoLookup;
+use crate::health::{UpstreamHealth, UpstreamLatency};
+use crate::local_zone::find_zone;
+use crate::proto_utils::{client_subnet, echo_question_name, edns_option, error_response, header_opcode, parse_quick, question_case_matches, randomize_question_case, set_edns_option, strip_client_ecs, strip_edns_options, truncated_response};
+use crate::querylog::{QueryLog, QueryLogEntry};
+use crate::ratelimit::{BlockEscalator, RateLimiter, ResponseRateLimiter, RrlVerdict};
+use crate::static_records::reverse_name_ip;
+use crate::watcher::ReloadStats;
+
+// 限速桶闲置超过该时长且已补满时清理
+const RATE_LIMIT_IDLE: Duration = Duration::from_secs(60);
+// recent_result_window_ms 的上限，同时是 recent_results 的 moka TTL
+const RECENT_RESULT_MAX_WINDOW: Duration = Duration::from_secs(1);
+// 不健康上游的探测查询（A 记录），任意应答即视为恢复
+const HEALTH_PROBE_QNAME: &str = "example.com.";
+// 后台任务的间隔配置为 0 时，隔这么久重新读取配置，热加载开启后即恢复运行
+const DISABLED_TASK_POLL: Duration = Duration::from_secs(1);
+// 陈旧应答中应答记录的最大 TTL（秒）
+const STALE_ANSWER_TTL: u32 = 30;
+// any_policy = hinfo 时 RFC 8482 HINFO 记录的 TTL（秒）
+const ANY_HINFO_TTL: u32 = 3600;
+// 按名称清除缓存时尝试的 qtype
+const FLUSH_QTYPES: [hickory_proto::rr::RecordType; 16] = {
+    use hickory_proto::rr::RecordType::*;
+    [A, AAAA, CNAME, MX, TXT, NS, SOA, PTR, SRV, CAA, HTTPS, SVCB, DS, DNSKEY, NAPTR, ANY]
 };
-use crate::proto_utils::parse_quick;
 
 #[derive(Clone)]
 pub struct Engine {
     pipeline: Arc<ArcSwap<RuntimePipelineConfig>>,
     compiled_pipelines: Arc<ArcSwap<Vec<CompiledPipeline>>>,
     cache: DnsCache,
+    /// 与 SocketUpstream 共用的连接池；Engine 自身只在 TCP 预热（tcp_mux）与测试（udp_client）中直接访问
+    #[cfg(test)]
     udp_client: Arc<UdpClient>,
     tcp_mux: Arc<TcpMultiplexer>,
+    /// 所有上游查询（转发、健康探测、DNS64 补查）经由它发送；缺省为共用上面两个连接池的 SocketUpstream
+    upstream: Arc<dyn Upstream>,
     listener_label: Arc<str>,
     // Rule execution result cache: Hash -> (Key, Decision)
     // Key is stored to verify collisions
     rule_cache: Cache<u64, RuleCacheEntry>,
+    // reload_stats.list_reloads() as of the last rule cache flush; a newer count means decisions may cite swapped-out lists
+    rule_cache_list_reloads: Arc<AtomicU64>,
+    // Just-completed forward results by dedupe hash, reused within recent_result_window_ms
+    recent_results: Cache<u64, (std::time::Instant, Bytes)>,
+    // Per-client token buckets for rate_limit actions
+    rate_limiter: Arc<RateLimiter>,
+    // Identical-response counters for settings.rrl_responses_per_second
+    rrl: Arc<ResponseRateLimiter>,
+    blocks: Arc<BlockEscalator>,
+    // Per-client in-flight query counters (max_inflight_per_client)
+    client_inflight: Arc<DashMap<IpAddr, usize, FxBuildHasher>>,
+    // Per-pipeline request/cache/upstream/servfail counters, keyed by pipeline id
+    pipeline_counters: Arc<DashMap<Arc<str>, Arc<PipelineCounters>, FxBuildHasher>>,
+    /// 正在后台刷新的陈旧缓存键，避免同一条目并发刷新
+    stale_refreshing: Arc<DashMap<u64, (), FxBuildHasher>>,
+    // Round-robin cursor for multi-upstream groups
+    upstream_rr: Arc<AtomicUsize>,
+    // Consecutive-failure tracking per upstream (health_check_interval_ms)
+    upstream_health: Arc<UpstreamHealth>,
+    /// 每个上游成功应答延迟的 EWMA（settings.upstream_latency_alpha）
+    upstream_latency: Arc<UpstreamLatency>,
+    /// 主配置热加载的成功/失败计数，由 watcher 写入
+    reload_stats: Arc<ReloadStats>,
+    // Client IP -> country lookup for geo_static_ip (geoip feature)
+    geo: Option<Arc<dyn GeoLookup>>,
+    // JSONL query log (settings.query_log_path), written by a background task
+    query_log: Option<QueryLog>,
     // Runtime metrics for diagnosing concurrency and upstream latency
     pub metrics_inflight: Arc<AtomicUsize>,
     pub metrics_total_requests: Arc<AtomicU64>,
     pub metrics_fastpath_hits: Arc<AtomicU64>,
     pub metrics_upstream_ns_total: Arc<AtomicU64>,
     pub metrics_upstream_calls: Arc<AtomicU64>,
+    // Stale answers served because upstream failed (serve-stale)
+    pub metrics_degraded_responses: Arc<AtomicU64>,
+    // pipeline_select matches whose target pipeline is missing (rejected at load, so should stay 0)
+    pub metrics_dangling_selects: Arc<AtomicU64>,
+    // Requests rejected by rate_limit actions
+    pub metrics_rate_limited: Arc<AtomicU64>,
+    // UDP responses dropped / replaced by TC=1 by response rate limiting
+    pub metrics_rrl_dropped: Arc<AtomicU64>,
+    pub metrics_rrl_truncated: Arc<AtomicU64>,
+    // Requests dropped by allow_networks / deny_networks before parsing
+    pub metrics_acl_dropped: Arc<AtomicU64>,
+    /// settings.block_escalation 升级的拦截应答数，以及封禁期内丢弃的查询数
+    pub metrics_block_escalations: Arc<AtomicU64>,
+    pub metrics_block_denied: Arc<AtomicU64>,
+    // UDP listener socket recv errors (workers back off on repeated errors)
+    pub metrics_udp_socket_errors: Arc<AtomicU64>,
+    // Background refreshes started for fresh cache hits nearing expiry (prefetch_threshold_percent)
+    pub metrics_prefetches: Arc<AtomicU64>,
+    // Deepest waiter list seen on one inflight dedupe hash, and duplicates forwarded on their own past max_inflight_waiters
+    pub metrics_inflight_max_waiters: Arc<AtomicUsize>,
+    pub metrics_inflight_overflows: Arc<AtomicU64>,
+    // Response cache lookups (fast and slow path); background stale refreshes are not counted
+    pub metrics_cache_hits: Arc<AtomicU64>,
+    pub metrics_cache_misses: Arc<AtomicU64>,
+    // Response cache entries evicted for capacity (not expiry)
+    pub metrics_cache_evictions: Arc<AtomicU64>,
     // Per-request id generator for tracing
     pub request_id_counter: Arc<AtomicU64>,
     // In-flight dedupe map: cache_hash -> waiters
@@ -59,43 +144,174 @@ pub struct Engine {
 
 impl Engine {
     pub fn new(pipeline: Arc<ArcSwap<RuntimePipelineConfig>>, listener_label: String) -> Self {
-        // moka 缓存：最大 10000 条，默认 TTL 300 秒（会被实际 TTL 覆盖）
-        let cache = new_cache(10_000, 300);
+        Self::build(pipeline, listener_label, |pipeline, udp_client, tcp_mux| {
+            let max_upstream_response = pipeline.load().settings.max_upstream_response;
+            let tcp_pool_size = pipeline.load().settings.tcp_pool_size;
+            Arc::new(SocketUpstream {
+                pipeline: Arc::clone(pipeline),
+                udp_client: Arc::clone(udp_client),
+                tcp_mux: Arc::clone(tcp_mux),
+                doh_client: DohClient::new(max_upstream_response),
+                dot_mux: DotMultiplexer::new(tcp_pool_size, max_upstream_response),
+            })
+        })
+    }
+
+    /// 使用给定的上游实现构造 Engine，其余与 new 相同；测试据此注入返回固定应答的上游，不需要真实网络
+    #[cfg(test)]
+    pub fn with_upstream(
+        pipeline: Arc<ArcSwap<RuntimePipelineConfig>>,
+        listener_label: String,
+        upstream: Arc<dyn Upstream>,
+    ) -> Self {
+        Self::build(pipeline, listener_label, |_, _, _| upstream)
+    }
+
+    /// make_upstream 以配置与连接池构造上游实现
+    fn build(
+        pipeline: Arc<ArcSwap<RuntimePipelineConfig>>,
+        listener_label: String,
+        make_upstream: impl FnOnce(&Arc<ArcSwap<RuntimePipelineConfig>>, &Arc<UdpClient>, &Arc<TcpMultiplexer>) -> Arc<dyn Upstream>,
+    ) -> Self {
+        // moka 缓存：最大 10000 条（或按 cache_max_bytes 限制总字节数），按实际 TTL 过期（上限 300 秒，另加 serve-stale 窗口）
+        let metrics_cache_evictions = Arc::new(AtomicU64::new(0));
+        let cache = new_cache(10_000, pipeline.load().settings.cache_max_bytes, Arc::clone(&metrics_cache_evictions));
         // Rule cache: 100k entries, 60s TTL
         let rule_cache = Cache::builder()
             .max_capacity(100_000)
             .time_to_live(Duration::from_secs(60))
             .build();
 
+        let recent_results = Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(RECENT_RESULT_MAX_WINDOW)
+            .build();
+
         // UDP socket pool size from config
         let udp_pool_size = pipeline.load().settings.udp_pool_size;
+        let upstream_port_range = pipeline.load().upstream_port_range;
         let tcp_pool_size = pipeline.load().settings.tcp_pool_size;
+        let max_upstream_response = pipeline.load().settings.max_upstream_response;
         let compiled = compile_pipelines(&pipeline.load());
+        let geo = open_geo_lookup(&pipeline.load());
+        let rate_limiter = Arc::new(RateLimiter::new());
+        let rrl = Arc::new(ResponseRateLimiter::new());
+        let blocks = Arc::new(BlockEscalator::new());
+        spawn_rate_limit_pruner(Arc::downgrade(&rate_limiter), Arc::downgrade(&rrl), Arc::downgrade(&blocks));
+        let udp_client = Arc::new(UdpClient::new(udp_pool_size, upstream_port_range));
+        let tcp_mux = Arc::new(TcpMultiplexer::new(tcp_pool_size, max_upstream_response));
+        let upstream = make_upstream(&pipeline, &udp_client, &tcp_mux);
         Self {
             pipeline,
             compiled_pipelines: Arc::new(ArcSwap::from_pointee(compiled)),
             cache,
-            udp_client: Arc::new(UdpClient::new(udp_pool_size)),
-            tcp_mux: Arc::new(TcpMultiplexer::new(tcp_pool_size)),
+            #[cfg(test)]
+            udp_client,
+            tcp_mux,
+            upstream,
             listener_label: Arc::from(listener_label),
             rule_cache,
+            rule_cache_list_reloads: Arc::new(AtomicU64::new(0)),
+            recent_results,
+            rate_limiter,
+            rrl,
+            blocks,
+            client_inflight: Arc::new(DashMap::with_hasher(FxBuildHasher)),
+            pipeline_counters: Arc::new(DashMap::with_hasher(FxBuildHasher)),
+            stale_refreshing: Arc::new(DashMap::with_hasher(FxBuildHasher)),
+            upstream_rr: Arc::new(AtomicUsize::new(0)),
+            upstream_health: Arc::new(UpstreamHealth::new()),
+            upstream_latency: Arc::new(UpstreamLatency::new()),
+            reload_stats: Arc::new(ReloadStats::new()),
+            geo,
+            query_log: None,
             metrics_inflight: Arc::new(AtomicUsize::new(0)),
             metrics_total_requests: Arc::new(AtomicU64::new(0)),
             metrics_fastpath_hits: Arc::new(AtomicU64::new(0)),
             metrics_upstream_ns_total: Arc::new(AtomicU64::new(0)),
             metrics_upstream_calls: Arc::new(AtomicU64::new(0)),
+            metrics_degraded_responses: Arc::new(AtomicU64::new(0)),
+            metrics_dangling_selects: Arc::new(AtomicU64::new(0)),
+            metrics_rate_limited: Arc::new(AtomicU64::new(0)),
+            metrics_rrl_dropped: Arc::new(AtomicU64::new(0)),
+            metrics_rrl_truncated: Arc::new(AtomicU64::new(0)),
+            metrics_acl_dropped: Arc::new(AtomicU64::new(0)),
+            metrics_block_escalations: Arc::new(AtomicU64::new(0)),
+            metrics_block_denied: Arc::new(AtomicU64::new(0)),
+            metrics_prefetches: Arc::new(AtomicU64::new(0)),
+            metrics_inflight_max_waiters: Arc::new(AtomicUsize::new(0)),
+            metrics_inflight_overflows: Arc::new(AtomicU64::new(0)),
+            metrics_udp_socket_errors: Arc::new(AtomicU64::new(0)),
+            metrics_cache_hits: Arc::new(AtomicU64::new(0)),
+            metrics_cache_misses: Arc::new(AtomicU64::new(0)),
+            metrics_cache_evictions,
             request_id_counter: Arc::new(AtomicU64::new(1)),
             inflight: Arc::new(DashMap::with_hasher(FxBuildHasher::default())),
         }
     }
 
+    /// 替换 geo_static_ip 使用的国家查询（缺省由 settings.geoip_db 打开）
+    #[cfg(all(test, feature = "geoip"))]
+    pub fn with_geo_lookup(mut self, geo: Arc<dyn GeoLookup>) -> Self {
+        self.geo = Some(geo);
+        self
+    }
+
+    /// 在另一个监听入口下使用的副本：缓存、上游连接与指标均与原引擎共享，只有 listener_label 不同
+    pub fn with_listener_label(&self, label: &str) -> Self {
+        let mut engine = self.clone();
+        engine.listener_label = Arc::from(label);
+        engine
+    }
+
+    /// 每个应答写一行查询日志（由 main 按 settings.query_log_path 打开）
+    pub fn with_query_log(mut self, log: QueryLog) -> Self {
+        self.query_log = Some(log);
+        self
+    }
+
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn log_query(
+        &self,
+        client_ip: IpAddr,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        rcode: ResponseCode,
+        upstream: &str,
+        cache_hit: bool,
+        latency: Duration,
+    ) {
+        if let Some(log) = &self.query_log {
+            log.record(QueryLogEntry {
+                client_ip,
+                qname: qname.to_string(),
+                qtype,
+                rcode,
+                upstream: upstream.to_string(),
+                cache_hit,
+                latency_ms: latency.as_millis() as u64,
+            });
+        }
+    }
+
     #[inline]
-    fn calculate_cache_hash_for_dedupe(pipeline_id: &str, qname: &str, qtype: hickory_proto::rr::RecordType) -> u64 {
+    fn calculate_cache_hash_for_dedupe(
+        pipeline_id: &str,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        qclass: DNSClass,
+        dnssec_ok: bool,
+    ) -> u64 {
         let mut h = FxHasher::default();
         pipeline_id.hash(&mut h);
         qname.to_ascii_lowercase().hash(&mut h);
         // RecordType implements Copy+Debug, hash by its u16 representation
         u16::from(qtype).hash(&mut h);
+        // QCLASS is part of the question: CH/HS must never share an entry with IN
+        u16::from(qclass).hash(&mut h);
+        // DO=1 的应答带 RRSIG 等记录，不能与 DO=0 的请求合并或共用缓存
+        dnssec_ok.hash(&mut h);
         h.finish()
     }
 
@@ -106,25 +322,603 @@ impl Engine {
         let fast = self.metrics_fastpath_hits.load(Ordering::Relaxed);
         let up_ns = self.metrics_upstream_ns_total.load(Ordering::Relaxed);
         let up_calls = self.metrics_upstream_calls.load(Ordering::Relaxed);
+        let degraded = self.metrics_degraded_responses.load(Ordering::Relaxed);
+        let rate_limited = self.metrics_rate_limited.load(Ordering::Relaxed);
+        let rrl_dropped = self.metrics_rrl_dropped.load(Ordering::Relaxed);
+        let rrl_truncated = self.metrics_rrl_truncated.load(Ordering::Relaxed);
+        let acl_dropped = self.metrics_acl_dropped.load(Ordering::Relaxed);
+        let block_escalations = self.metrics_block_escalations.load(Ordering::Relaxed);
+        let block_denied = self.metrics_block_denied.load(Ordering::Relaxed);
+        let prefetches = self.metrics_prefetches.load(Ordering::Relaxed);
+        let udp_socket_errors = self.metrics_udp_socket_errors.load(Ordering::Relaxed);
+        let inflight_max_waiters = self.metrics_inflight_max_waiters.load(Ordering::Relaxed);
+        let inflight_overflows = self.metrics_inflight_overflows.load(Ordering::Relaxed);
+        let query_log_dropped = self.query_log.as_ref().map_or(0, QueryLog::dropped);
         let avg_up_ns = if up_calls > 0 { up_ns / up_calls } else { 0 };
         format!(
-            "inflight={} total={} fastpath_hits={} upstream_avg_us={}",
+            "inflight={} total={} fastpath_hits={} upstream_avg_us={} degraded={} rate_limited={} rl_buckets={} rrl_dropped={} rrl_truncated={} acl_dropped={} block_escalations={} block_denied={} prefetches={} udp_socket_errors={} inflight_max_waiters={} inflight_overflows={} query_log_dropped={} unhealthy=[{}]",
             inflight,
             total,
             fast,
-            avg_up_ns as f64 / 1000.0
+            avg_up_ns as f64 / 1000.0,
+            degraded,
+            rate_limited,
+            self.rate_limiter.bucket_count(),
+            rrl_dropped,
+            rrl_truncated,
+            acl_dropped,
+            block_escalations,
+            block_denied,
+            prefetches,
+            udp_socket_errors,
+            inflight_max_waiters,
+            inflight_overflows,
+            query_log_dropped,
+            self.upstream_health.unhealthy().join(",")
         )
     }
 
-    /// 快速路径：同步尝试缓存命中
+    /// 后台按 health_check_interval_ms 探测不健康上游（沿用其最近一次失败的传输方式），探测成功即恢复；
+    /// 间隔每个周期重新读取，为 0 时暂停探测，热加载重新开启后继续
+    pub fn spawn_health_checker(&self) {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (interval, timeout_dur) = {
+                    let cfg = engine.pipeline.load();
+                    (Duration::from_millis(cfg.settings.health_check_interval_ms), cfg.upstream_timeout())
+                };
+                if interval.is_zero() {
+                    tokio::time::sleep(DISABLED_TASK_POLL).await;
+                    continue;
+                }
+                tokio::time::sleep(interval).await;
+                for (upstream, transport) in engine.upstream_health.unhealthy_transports() {
+                    let engine = engine.clone();
+                    tokio::spawn(async move {
+                        engine.probe_upstream(&upstream, transport, timeout_dur).await;
+                    });
+                }
+            }
+        });
+    }
+
+    /// 后台按 stats_log_interval_ms 输出一行缓存统计：条目数、本周期命中率与淘汰数、上游平均延迟；
+    /// 间隔每个周期重新读取，为 0 时暂停输出，热加载重新开启后继续
+    pub fn spawn_stats_logger(&self) {
+        let engine = self.clone();
+        let mut last = StatsSample::take(self);
+        tokio::spawn(async move {
+            loop {
+                let interval = Duration::from_millis(engine.pipeline.load().settings.stats_log_interval_ms);
+                if interval.is_zero() {
+                    tokio::time::sleep(DISABLED_TASK_POLL).await;
+                    // 暂停期间的计数不计入恢复后的首个周期
+                    last = StatsSample::take(&engine);
+                    continue;
+                }
+                tokio::time::sleep(interval).await;
+                let now = StatsSample::take(&engine);
+                let hits = now.cache.hits - last.cache.hits;
+                let lookups = hits + now.cache.misses - last.cache.misses;
+                let hit_ratio = if lookups == 0 { 0.0 } else { hits as f64 / lookups as f64 };
+                let upstream_calls = now.upstream_calls - last.upstream_calls;
+                let upstream_avg_us = (now.upstream_ns - last.upstream_ns).checked_div(upstream_calls).unwrap_or(0) / 1000;
+                info!(
+                    target = "stats",
+                    entries = now.cache.entries,
+                    lookups,
+                    hit_ratio = format_args!("{hit_ratio:.3}"),
+                    evictions = now.cache.evictions - last.cache.evictions,
+                    upstream_calls,
+                    upstream_avg_us,
+                    "cache stats"
+                );
+                last = now;
+            }
+        });
+    }
+
+    /// settings.prewarm_tcp：启动时预热一次，之后每次配置热加载成功后再预热，覆盖新增的 tcp 上游与已断开的连接；
+    /// 开关随热加载生效
+    pub fn spawn_tcp_prewarmer(&self) {
+        let engine = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // 先登记等待再预热，预热期间发生的热加载不会被错过
+                let reloaded = engine.reload_stats.reloaded();
+                tokio::pin!(reloaded);
+                reloaded.as_mut().enable();
+                if engine.pipeline.load().settings.prewarm_tcp {
+                    engine.prewarm_tcp().await;
+                }
+                reloaded.await;
+            }
+        });
+    }
+
+    /// 为 tcp_upstreams 中的每个上游建立连接池内的全部连接；失败仅记录，首个查询时仍会按需重连
+    pub async fn prewarm_tcp(&self) {
+        let upstreams = self.pipeline.load().tcp_upstreams();
+        for upstream in upstreams {
+            match self.tcp_mux.prewarm(&upstream).await {
+                Ok(n) => info!(target = "tcp_mux", upstream = %upstream, connections = n, "tcp pool prewarmed"),
+                Err(err) => warn!(target = "tcp_mux", upstream = %upstream, error = %err, "tcp prewarm failed"),
+            }
+        }
+    }
+
+    async fn probe_upstream(&self, upstream: &str, transport: Transport, timeout_dur: Duration) {
+        let mut msg = Message::new();
+        msg.set_id(self.request_id_counter.fetch_add(1, Ordering::Relaxed) as u16);
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(
+            Name::from_ascii(HEALTH_PROBE_QNAME).expect("static probe name"),
+            hickory_proto::rr::RecordType::A,
+        ));
+        let Ok(packet) = msg.to_vec() else { return };
+        match self.send_upstream(&packet, upstream, timeout_dur, transport).await {
+            Ok(_) => {
+                self.upstream_health.record_success(upstream);
+                info!(event = "upstream_recovered", upstream = %upstream, "health probe succeeded");
+            }
+            Err(err) => {
+                debug!(event = "upstream_probe_failed", upstream = %upstream, error = %err, "health probe failed");
+            }
+        }
+    }
+
+    /// 将当前缓存写入快照文件，返回写入条数
+    pub fn save_cache_snapshot(&self, path: &std::path::Path) -> anyhow::Result<usize> {
+        crate::cache::save_snapshot(&self.cache, path)
+    }
+
+    /// 从快照文件恢复缓存，键按当前构建的哈希函数重新计算
+    pub fn load_cache_snapshot(&self, path: &std::path::Path) -> anyhow::Result<usize> {
+        crate::cache::load_snapshot(&self.cache, path, |e| {
+            Self::calculate_cache_hash_for_dedupe(
+                &e.pipeline_id,
+                &e.qname,
+                hickory_proto::rr::RecordType::from(e.qtype),
+                DNSClass::from(e.qclass),
+                e.dnssec_ok,
+            )
+        })
+    }
+
+    /// 清空应答缓存、规则决策缓存与重传复用窗口，返回清除前的应答缓存条目数
+    pub fn flush_cache(&self) -> u64 {
+        self.cache.run_pending_tasks();
+        let entries = self.cache.entry_count();
+        self.cache.invalidate_all();
+        self.rule_cache.invalidate_all();
+        self.recent_results.invalidate_all();
+        entries
+    }
+
+    /// 按名称失效应答缓存：键是哈希，只能对每个 pipeline（含缺省的 "default"）、常见 qtype、qclass 与 DO 位重新计算键后逐一删除。
+    /// 返回实际删除的条目数
+    pub fn flush_cache_qname(&self, qname: &str) -> usize {
+        let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+        let cfg = self.pipeline.load();
+        let pipeline_ids = cfg.pipelines.iter().map(|p| p.id.as_str()).chain(std::iter::once("default"));
+        let mut removed = 0;
+        for pipeline_id in pipeline_ids {
+            for qtype in FLUSH_QTYPES {
+                for qclass in [DNSClass::IN, DNSClass::CH, DNSClass::HS] {
+                    for dnssec_ok in [false, true] {
+                        let hash = Self::calculate_cache_hash_for_dedupe(pipeline_id, &qname, qtype, qclass, dnssec_ok);
+                        // 哈希碰撞时不误删其他名称的条目
+                        if self.cache.get(&hash).is_some_and(|e| e.matches(pipeline_id, &qname, qtype, qclass)) {
+                            self.cache.invalidate(&hash);
+                            removed += 1;
+                        }
+                        self.recent_results.invalidate(&hash);
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    /// 应答缓存条目数与命中/未命中计数
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.run_pending_tasks();
+        CacheStats {
+            entries: self.cache.entry_count(),
+            hits: self.metrics_cache_hits.load(Ordering::Relaxed),
+            misses: self.metrics_cache_misses.load(Ordering::Relaxed),
+            evictions: self.metrics_cache_evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 某个 pipeline 的计数器；首次出现时才分配
+    #[inline]
+    fn pipeline_counters(&self, pipeline_id: &str) -> Arc<PipelineCounters> {
+        if let Some(counters) = self.pipeline_counters.get(pipeline_id) {
+            return Arc::clone(&counters);
+        }
+        Arc::clone(&self.pipeline_counters.entry(Arc::from(pipeline_id)).or_default())
+    }
+
+    /// 各上游成功应答延迟的 EWMA，按上游地址排序
+    pub fn upstream_latencies(&self) -> Vec<(String, Duration)> {
+        self.upstream_latency.snapshot()
+    }
+
+    /// 规则决策缓存；watcher 换入新的 domain_set / static_records 后先整体失效，不再沿用按旧列表得出的决策
+    fn rule_cache(&self) -> &Cache<u64, RuleCacheEntry> {
+        let list_reloads = self.reload_stats.list_reloads();
+        if self.rule_cache_list_reloads.swap(list_reloads, Ordering::Relaxed) != list_reloads {
+            self.rule_cache.invalidate_all();
+        }
+        &self.rule_cache
+    }
+
+    /// 交给 watcher 写入的热加载计数
+    pub fn reload_stats(&self) -> Arc<ReloadStats> {
+        Arc::clone(&self.reload_stats)
+    }
+
+    /// 各 pipeline 的计数快照，按 pipeline id 排序
+    pub fn pipeline_stats(&self) -> Vec<PipelineStats> {
+        let mut stats: Vec<PipelineStats> = self
+            .pipeline_counters
+            .iter()
+            .map(|entry| PipelineStats {
+                pipeline: entry.key().to_string(),
+                requests: entry.requests.load(Ordering::Relaxed),
+                cache_hits: entry.cache_hits.load(Ordering::Relaxed),
+                upstream_forwards: entry.upstream_forwards.load(Ordering::Relaxed),
+                servfails: entry.servfails.load(Ordering::Relaxed),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.pipeline.cmp(&b.pipeline));
+        stats
+    }
+
+    /// 等待进行中的请求完成，最多等待 grace；返回超时后仍未完成的请求数
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + grace;
+        loop {
+            let inflight = self.metrics_inflight.load(Ordering::Relaxed);
+            if inflight == 0 || tokio::time::Instant::now() >= deadline {
+                return inflight;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// 计算缓存条目的新鲜期与 serve-stale 截止时间
+    #[inline]
+    fn cache_deadlines(&self, ttl: Duration) -> (Duration, std::time::Instant, std::time::Instant) {
+        let stale_window = Duration::from_secs(self.pipeline.load().settings.serve_stale_secs);
+        CacheEntry::deadlines(ttl, stale_window)
+    }
+
+    /// 上游失败时查找已过期但仍在陈旧窗口内的条目
+    #[inline]
+    fn lookup_stale(
+        &self,
+        hash: u64,
+        pipeline_id: &str,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        qclass: DNSClass,
+    ) -> Option<CacheEntry> {
+        self.cache
+            .get(&hash)
+            .filter(|hit| hit.matches(pipeline_id, qname, qtype, qclass))
+    }
+
+    /// 请求阶段限速：命中第一条匹配的 rate_limit 规则并消耗令牌，超限时返回替代响应
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn rate_limit_response(
+        &self,
+        pipeline: Option<&RuntimePipeline>,
+        tx_id: u16,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        qclass: DNSClass,
+        client_ip: IpAddr,
+        edns_bufsize: Option<u16>,
+        ecs: Option<IpAddr>,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let Some(pipeline) = pipeline else {
+            return Ok(None);
+        };
+        for &idx in &pipeline.rate_limit_rules {
+            let rule = &pipeline.rules[idx];
+            let matched = eval_match_chain(
+                &rule.matchers,
+                |m| m.operator,
+                |m| matcher_matches(&m.matcher, qname, qtype, qclass, client_ip, edns_bufsize, ecs),
+            );
+            if !matched {
+                continue;
+            }
+            let Some((max_qps, burst, mode)) = rule.actions.iter().find_map(|a| match a {
+                Action::RateLimit { max_qps, burst, mode } => Some((*max_qps, *burst, *mode)),
+                _ => None,
+            }) else {
+                continue;
+            };
+            if self.rate_limiter.check(client_ip, max_qps, burst) {
+                return Ok(None);
+            }
+            self.metrics_rate_limited.fetch_add(1, Ordering::Relaxed);
+            debug!(rule = %rule.name, client_ip = %client_ip, qname = %qname, "rate limited");
+            let rcode = match mode {
+                RateLimitMode::Refuse => ResponseCode::Refused,
+                RateLimitMode::Truncate => ResponseCode::NoError,
+            };
+            let resp = build_fast_static_response(tx_id, qname, u16::from(qtype), u16::from(qclass), rcode, &Vec::new())?;
+            if mode == RateLimitMode::Truncate {
+                let mut buf = resp.to_vec();
+                buf[2] |= 0x02; // TC
+                return Ok(Some(Bytes::from(buf)));
+            }
+            return Ok(Some(resp));
+        }
+        Ok(None)
+    }
+
+    /// force_tcp 规则命中时返回置 TC 位的空应答（仅用于 UDP 查询），客户端据此改用 TCP 重试
+    #[allow(clippy::too_many_arguments)]
+    fn force_tcp_response(
+        &self,
+        pipeline: Option<&RuntimePipeline>,
+        tx_id: u16,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        qclass: DNSClass,
+        client_ip: IpAddr,
+        edns_bufsize: Option<u16>,
+        ecs: Option<IpAddr>,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let Some(pipeline) = pipeline else {
+            return Ok(None);
+        };
+        let Some(rule) = pipeline.force_tcp_rules.iter().map(|&idx| &pipeline.rules[idx]).find(|rule| {
+            eval_match_chain(
+                &rule.matchers,
+                |m| m.operator,
+                |m| matcher_matches(&m.matcher, qname, qtype, qclass, client_ip, edns_bufsize, ecs),
+            )
+        }) else {
+            return Ok(None);
+        };
+        debug!(rule = %rule.name, client_ip = %client_ip, qname = %qname, "force tcp");
+        let resp = build_fast_static_response(tx_id, qname, u16::from(qtype), u16::from(qclass), ResponseCode::NoError, &Vec::new())?;
+        let mut buf = resp.to_vec();
+        buf[2] |= 0x02; // TC
+        Ok(Some(Bytes::from(buf)))
+    }
+
+    /// 非 QUERY 请求（IQUERY/STATUS/NOTIFY/UPDATE 等）不走解析与转发：默认回显 opcode 应答 NOTIMP，
+    /// 所选 pipeline 中首条命中且带 static_response 的 opcode 规则可改写 rcode。QUERY 请求返回 None
+    fn opcode_response(&self, packet: &[u8], client_ip: IpAddr) -> anyhow::Result<Option<Bytes>> {
+        let Some(opcode) = header_opcode(packet) else {
+            return Ok(None);
+        };
+        if opcode == OPCODE_QUERY {
+            return Ok(None);
+        }
+        let mut rcode = ResponseCode::NotImp;
+        let mut qname_buf = [0u8; 256];
+        if let Some(q) = parse_quick(packet, &mut qname_buf) {
+            let cfg = self.pipeline.load();
+            let qtype = hickory_proto::rr::RecordType::from(q.qtype);
+            let qclass = DNSClass::from(q.qclass);
+            let ecs = client_subnet(packet);
+            let (pipeline_opt, _) = select_pipeline(
+                &cfg,
+                q.qname,
+                client_ip,
+                qclass,
+                q.edns_bufsize,
+                &self.listener_label,
+                &self.metrics_dangling_selects,
+            );
+            let static_rcode = pipeline_opt.and_then(|p| {
+                p.opcode_rules.iter().map(|&idx| &p.rules[idx]).find_map(|rule| {
+                    let matched = eval_match_chain(
+                        &rule.matchers,
+                        |m| m.operator,
+                        |m| match &m.matcher {
+                            RuntimeMatcher::Opcode { opcode: want } => *want == q.opcode,
+                            other => matcher_matches(other, q.qname, qtype, qclass, client_ip, q.edns_bufsize, ecs),
+                        },
+                    );
+                    if !matched {
+                        return None;
+                    }
+                    rule.actions.iter().find_map(|a| match a {
+                        Action::StaticResponse { rcode } => parse_rcode(rcode),
+                        _ => None,
+                    })
+                })
+            });
+            if let Some(code) = static_rcode {
+                rcode = code;
+            }
+        }
+        debug!(opcode, rcode = ?rcode, client_ip = %client_ip, "non-query opcode");
+        Ok(error_response(packet, u16::from(rcode) as u8))
+    }
+
+    /// 直接应答 FORMERR 的畸形请求：QDCOUNT > 1（缓存键与规则都只看得到第一个问题，与常见递归服务器一致不按其处理），
+    /// EDNS 选项个数超过 settings.max_edns_options，或开启 cookie 时 COOKIE 选项长度不合法
+    fn formerr_response(&self, packet: &[u8], client_ip: IpAddr, reason: &str) -> anyhow::Result<Bytes> {
+        debug!(client_ip = %client_ip, reason, "malformed query, formerr");
+        error_response(packet, u16::from(ResponseCode::FormErr) as u8).context("formerr response")
+    }
+
+    /// settings.cookie_secret 开启且请求的 COOKIE 选项长度不合法（RFC 7873 §5.2.2）
+    #[inline]
+    fn malformed_cookie(&self, packet: &[u8]) -> bool {
+        self.pipeline.load().cookies.is_some()
+            && edns_option(packet, EDNS_OPTION_COOKIE).is_some_and(crate::cookie::is_malformed)
+    }
+
+    /// settings.max_edns_options 开启且请求超出上限
+    #[inline]
+    fn too_many_edns_options(&self, edns_options: usize) -> bool {
+        let limit = self.pipeline.load().settings.max_edns_options;
+        limit > 0 && edns_options > limit
+    }
+
+    /// 快速路径：同步尝试缓存命中（仅用于 UDP 查询）
     /// 返回 Ok(Some(bytes)) 表示缓存命中，可直接返回
     /// 返回 Ok(None) 表示需要异步处理（上游转发）
-    /// 返回 Err 表示解析错误
+    /// 返回 Err 表示解析错误、客户端被 ACL 拒绝，或应答被响应限速丢弃（均不发送应答）
     #[inline]
     pub fn handle_packet_fast(&self, packet: &[u8], peer: SocketAddr) -> anyhow::Result<Option<Bytes>> {
+        self.check_acl(peer.ip())?;
+        let resp = if self.malformed_cookie(packet) {
+            self.formerr_response(packet, peer.ip(), "malformed cookie option")?
+        } else {
+            let Some(resp) = self.fast_path(packet, peer)? else {
+                return Ok(None);
+            };
+            resp
+        };
+        let resp = self.apply_rrl(packet, resp, peer.ip())?;
+        Ok(Some(self.apply_server_cookie(packet, resp, peer.ip(), false)))
+    }
+
+    /// settings.allow_networks / deny_networks：在任何解析之前按来源地址过滤，被拒绝时返回 Err（不作应答）；
+    /// 因 block_escalation 处于封禁期的客户端同样丢弃
+    #[inline]
+    fn check_acl(&self, client_ip: IpAddr) -> anyhow::Result<()> {
+        if self.pipeline.load().client_denied(client_ip) {
+            self.metrics_acl_dropped.fetch_add(1, Ordering::Relaxed);
+            debug!(client_ip = %client_ip, "client denied by acl, dropping");
+            anyhow::bail!("client denied by acl");
+        }
+        if self.blocks.is_denied(client_ip) {
+            self.metrics_block_denied.fetch_add(1, Ordering::Relaxed);
+            debug!(client_ip = %client_ip, "client denied after repeated blocked queries, dropping");
+            anyhow::bail!("client temporarily denied by block_escalation");
+        }
+        Ok(())
+    }
+
+    /// settings.block_escalation 开启时，拦截应答（不带记录的静态应答，SERVFAIL 除外）需计数与升级
+    #[inline]
+    fn escalates_block(cfg: &RuntimePipelineConfig, rcode: ResponseCode, answers: &[Record]) -> bool {
+        cfg.settings.block_escalation.is_some() && is_block_answer(rcode, answers)
+    }
+
+    /// 快速路径即将返回拦截应答（is_block）时计数；本次会升级且需要 tarpit 时不计数并返回 false，交由慢路径延迟作答
+    #[inline]
+    fn fast_block_allowed(&self, cfg: &RuntimePipelineConfig, is_block: bool, client_ip: IpAddr, qname: &str) -> bool {
+        let Some(esc) = cfg.settings.block_escalation.as_ref().filter(|_| is_block) else {
+            return true;
+        };
+        let key = BlockEscalator::key(client_ip, qname);
+        if esc.tarpit_ms > 0 && self.blocks.count(key) >= esc.threshold {
+            return false;
+        }
+        self.record_block(esc, key, client_ip, qname);
+        true
+    }
+
+    /// 记录一次拦截应答；窗口内次数超过阈值时按配置封禁客户端并延迟本次应答
+    async fn escalate_block(&self, cfg: &RuntimePipelineConfig, client_ip: IpAddr, qname: &str) {
+        let Some(esc) = cfg.settings.block_escalation.as_ref() else {
+            return;
+        };
+        if self.record_block(esc, BlockEscalator::key(client_ip, qname), client_ip, qname) && esc.tarpit_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(esc.tarpit_ms)).await;
+        }
+    }
+
+    /// 计数一次拦截应答，超过阈值时记录升级并按 deny_secs 封禁客户端；返回本次是否升级
+    fn record_block(&self, esc: &crate::config::BlockEscalation, key: u64, client_ip: IpAddr, qname: &str) -> bool {
+        let count = self.blocks.record(key, Duration::from_secs(esc.window_secs));
+        if count <= esc.threshold {
+            return false;
+        }
+        self.metrics_block_escalations.fetch_add(1, Ordering::Relaxed);
+        info!(
+            event = "block_escalated",
+            client_ip = %client_ip,
+            qname = %qname,
+            count,
+            tarpit_ms = esc.tarpit_ms,
+            deny_secs = esc.deny_secs,
+            "repeated blocked queries"
+        );
+        if esc.deny_secs > 0 {
+            self.blocks.deny(client_ip, Duration::from_secs(esc.deny_secs));
+        }
+        true
+    }
+
+    /// 响应限速（settings.rrl_responses_per_second，仅用于 UDP 应答）：超限时按 rrl_slip 改为截断应答，
+    /// 或返回 Err 表示不发送
+    fn apply_rrl(&self, packet: &[u8], resp: Bytes, client_ip: IpAddr) -> anyhow::Result<Bytes> {
+        let (limit, slip) = {
+            let cfg = self.pipeline.load();
+            (cfg.settings.rrl_responses_per_second, cfg.settings.rrl_slip)
+        };
+        if limit == 0 || resp.len() < 4 {
+            return Ok(resp);
+        }
+        let mut qname_buf = [0u8; 256];
+        let Some(q) = parse_quick(packet, &mut qname_buf) else {
+            return Ok(resp);
+        };
+        let key = ResponseRateLimiter::key(client_ip, q.qname, q.qtype, resp[3] & 0x0F);
+        match self.rrl.check(key, limit, slip) {
+            RrlVerdict::Allow => Ok(resp),
+            RrlVerdict::Truncate => {
+                self.metrics_rrl_truncated.fetch_add(1, Ordering::Relaxed);
+                Ok(truncated_response(&resp))
+            }
+            RrlVerdict::Drop => {
+                self.metrics_rrl_dropped.fetch_add(1, Ordering::Relaxed);
+                debug!(client_ip = %client_ip, qname = %q.qname, "response rate limited, dropping");
+                anyhow::bail!("response rate limited")
+            }
+        }
+    }
+
+    /// settings.cookie_secret 开启时处理 DNS Cookie：回写客户端 cookie 并附上新签发的服务器 cookie；
+    /// 经 UDP 的应答超过 cookie_tc_threshold 且请求未带有效服务器 cookie 时改为 TC=1 应答
+    fn apply_server_cookie(&self, packet: &[u8], resp: Bytes, client_ip: IpAddr, tcp: bool) -> Bytes {
+        let cfg = self.pipeline.load();
+        let Some(cookies) = cfg.cookies.as_ref() else {
+            return resp;
+        };
+        let now = crate::cookie::unix_now();
+        let check = cookies.check(edns_option(packet, EDNS_OPTION_COOKIE), client_ip, now);
+        let threshold = cfg.settings.cookie_tc_threshold;
+        let resp = if !tcp && threshold > 0 && resp.len() > threshold && !matches!(check, CookieCheck::Valid(_)) {
+            debug!(client_ip = %client_ip, size = resp.len(), "large udp answer without valid cookie, truncating");
+            truncated_response(&resp)
+        } else {
+            resp
+        };
+        let Some(client) = check.client_cookie() else {
+            return resp;
+        };
+        match set_edns_option(&resp, EDNS_OPTION_COOKIE, &cookies.issue(client, client_ip, now)) {
+            Some(out) => Bytes::from(out),
+            None => resp,
+        }
+    }
+
+    #[inline]
+    fn fast_path(&self, packet: &[u8], peer: SocketAddr) -> anyhow::Result<Option<Bytes>> {
         // 快速解析，避免完整 Message 解析和大量分配
         // 使用栈上缓冲区避免 String 分配
         let mut qname_buf = [0u8; 256];
+        if let Some(resp) = self.opcode_response(packet, peer.ip())? {
+            return Ok(Some(resp));
+        }
         let req_id = self.request_id_counter.fetch_add(1, Ordering::Relaxed);
         let t_start = std::time::Instant::now();
         let q = match parse_quick(packet, &mut qname_buf) {
@@ -136,33 +930,95 @@ impl Engine {
                 return Ok(None);
             }
         };
+        if q.multiple_questions {
+            return Ok(Some(self.formerr_response(packet, peer.ip(), "multiple questions")?));
+        }
+        if self.too_many_edns_options(q.edns_options) {
+            return Ok(Some(self.formerr_response(packet, peer.ip(), "too many edns options")?));
+        }
+        // 区域传送策略统一由慢路径判定
+        if matches!(
+            hickory_proto::rr::RecordType::from(q.qtype),
+            hickory_proto::rr::RecordType::AXFR | hickory_proto::rr::RecordType::IXFR
+        ) {
+            return Ok(None);
+        }
         // Count incoming quick-parsed requests
         self.metrics_total_requests.fetch_add(1, Ordering::Relaxed);
         let t_after_parse = t_start.elapsed();
         
         // 获取 pipeline ID
         let cfg = self.pipeline.load();
+        // 本地区域的权威应答由慢路径生成
+        if find_zone(&cfg.local_zones, q.qname).is_some() {
+            return Ok(None);
+        }
+        // ANY 的 hinfo / a_aaaa 处理由慢路径完成
+        if q.qtype == u16::from(hickory_proto::rr::RecordType::ANY) && cfg.settings.any_policy != AnyPolicy::Forward {
+            return Ok(None);
+        }
         let qclass = DNSClass::from(q.qclass);
-        let edns_present = false;
-        let (_pipeline_opt, pipeline_id) = select_pipeline(
+        let edns_bufsize = q.edns_bufsize;
+        // 只有带 EDNS 选项的请求才需要查找 ECS
+        let ecs = if q.edns_options > 0 { client_subnet(packet) } else { None };
+        let (pipeline_opt, pipeline_id) = select_pipeline(
             &cfg,
             q.qname,
             peer.ip(),
             qclass,
-            edns_present,
+            edns_bufsize,
             &self.listener_label,
+            &self.metrics_dangling_selects,
         );
-        
+        let counters = self.pipeline_counters(&pipeline_id);
+        // 只统计快速路径直接作答的请求；返回 None 的请求由慢路径统计
+        let answered = |resp: Bytes| {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(resp))
+        };
+
+        // 0. static_records_file 的本地覆盖先于缓存与规则
+        if let Some(Decision::Static { rcode, answers }) =
+            static_record_decision(&cfg, q.qname, hickory_proto::rr::RecordType::from(q.qtype), qclass)
+        {
+            let resp = build_fast_static_response(q.tx_id, q.qname, q.qtype, q.qclass, rcode, &answers)?;
+            self.metrics_fastpath_hits.fetch_add(1, Ordering::Relaxed);
+            self.log_query(peer.ip(), q.qname, hickory_proto::rr::RecordType::from(q.qtype), rcode, "static_records", false, t_start.elapsed());
+            return answered(resp);
+        }
+
         // 1. Check Response Cache (L2)
         // TODO: Optimize CacheKey to avoid Arc allocation on lookup?
         // Currently we still allocate Arc<str> in CacheKey::new.
         // But we saved the String allocation in parse_quick.
         let qtype = hickory_proto::rr::RecordType::from(q.qtype);
-        let cache_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, q.qname, qtype);
+        let cache_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, q.qname, qtype, qclass, q.dnssec_ok);
+
+        // 0. Rate limit（仅在有 rate_limit 规则时生效）。快速路径无法作答时不消耗令牌，交由慢路径统一判定
+        let needs_rate_limit = pipeline_opt.is_some_and(|p| !p.rate_limit_rules.is_empty());
+        let rate_limited = |engine: &Self| {
+            if !needs_rate_limit {
+                return Ok(None);
+            }
+            engine.rate_limit_response(pipeline_opt, q.tx_id, q.qname, qtype, qclass, peer.ip(), edns_bufsize, ecs)
+        };
+
+        if pipeline_opt.is_some_and(|p| !p.force_tcp_rules.is_empty())
+            && let Some(resp) =
+                self.force_tcp_response(pipeline_opt, q.tx_id, q.qname, qtype, qclass, peer.ip(), edns_bufsize, ecs)?
+        {
+            return answered(resp);
+        }
         
         if let Some(hit) = self.cache.get(&cache_hash) {
-            // Verify collision
-            if hit.qtype == u16::from(qtype) && hit.qname.as_ref() == q.qname && hit.pipeline_id.as_ref() == pipeline_id {
+            // Verify collision; stale entries are only served by the slow path on upstream failure
+            if hit.matches(&pipeline_id, q.qname, qtype, qclass)
+                && hit.is_fresh()
+                && self.fast_block_allowed(&cfg, is_cached_block(&hit), peer.ip(), q.qname)
+            {
+                if let Some(resp) = rate_limited(self)? {
+                    return answered(resp);
+                }
                 // 复制 ID 到缓存响应中
                 let mut resp = hit.bytes.to_vec();
                 if resp.len() >= 2 {
@@ -170,15 +1026,22 @@ impl Engine {
                     resp[0] = id_bytes[0];
                     resp[1] = id_bytes[1];
                 }
+                echo_question_name(packet, &mut resp);
                 self.metrics_fastpath_hits.fetch_add(1, Ordering::Relaxed);
+                self.metrics_cache_hits.fetch_add(1, Ordering::Relaxed);
+                counters.cache_hits.fetch_add(1, Ordering::Relaxed);
                 let elapsed = t_after_parse.as_nanos();
                 tracing::info!(request_id = req_id, phase = "cache_hit", elapsed_ns = elapsed, "fastpath cache hit");
-                return Ok(Some(Bytes::from(resp)));
+                self.log_query(peer.ip(), q.qname, qtype, hit.rcode, &hit.source, true, t_start.elapsed());
+                self.maybe_prefetch(&hit, cfg.settings.prefetch_threshold_percent, cache_hash, packet, peer);
+                return answered(Bytes::from(resp));
             }
         }
 
-        // 2. Compiled rule fast-path for static decisions
-        if let Some(compiled) = self.compiled_for(&pipeline_id) {
+        // 2. Compiled rule fast-path for static decisions（影子模式的 pipeline 由慢路径记录并转发）
+        if pipeline_opt.is_none_or(|p| !p.shadow_mode)
+            && let Some(compiled) = self.compiled_for(&cfg, &pipeline_id)
+        {
             let qclass = DNSClass::from(q.qclass);
             if let Some(decision) = fast_static_match(
                 &compiled,
@@ -186,9 +1049,15 @@ impl Engine {
                 qtype,
                 qclass,
                 peer.ip(),
-                false,
+                edns_bufsize,
+                ecs,
             ) {
-                if let Decision::Static { rcode, answers } = decision {
+                if let Decision::Static { rcode, answers } = decision
+                    && self.fast_block_allowed(&cfg, is_block_answer(rcode, &answers), peer.ip(), q.qname)
+                {
+                    if let Some(resp) = rate_limited(self)? {
+                        return answered(resp);
+                    }
                     let resp = build_fast_static_response(
                         q.tx_id,
                         q.qname,
@@ -200,17 +1069,24 @@ impl Engine {
                     self.metrics_fastpath_hits.fetch_add(1, Ordering::Relaxed);
                     let elapsed_ns = t_start.elapsed().as_nanos();
                     tracing::info!(request_id = req_id, phase = "fast_static", elapsed_ns = elapsed_ns, "fast static match");
-                    return Ok(Some(resp));
+                    self.log_query(peer.ip(), q.qname, qtype, rcode, "static", false, t_start.elapsed());
+                    return answered(resp);
                 }
             }
         }
 
         // 3. Check Rule Cache (L1) for Static Responses
         // Zero-allocation lookup using hash
-        let rule_hash = calculate_rule_hash(&pipeline_id, q.qname, peer.ip());
-        if let Some(entry) = self.rule_cache.get(&rule_hash) {
-            if entry.matches(&pipeline_id, q.qname, peer.ip()) {
-                if let Decision::Static { rcode, answers } = &entry.decision {
+        let rule_ecs = ecs.filter(|_| pipeline_opt.is_some_and(|p| p.uses_ecs));
+        let rule_hash = calculate_rule_hash(&pipeline_id, q.qname, qtype, qclass, peer.ip(), edns_bufsize, rule_ecs);
+        if let Some(entry) = self.rule_cache().get(&rule_hash) {
+            if entry.matches(&pipeline_id, q.qname, qtype, qclass, peer.ip(), edns_bufsize, rule_ecs) {
+                if let Decision::Static { rcode, answers } = &entry.decision
+                    && self.fast_block_allowed(&cfg, is_block_answer(*rcode, answers), peer.ip(), q.qname)
+                {
+                    if let Some(resp) = rate_limited(self)? {
+                        return answered(resp);
+                    }
                     let resp = build_fast_static_response(
                         q.tx_id,
                         q.qname,
@@ -222,7 +1098,8 @@ impl Engine {
                     self.metrics_fastpath_hits.fetch_add(1, Ordering::Relaxed);
                     let elapsed_ns = t_start.elapsed().as_nanos();
                     tracing::info!(request_id = req_id, phase = "rule_cache_hit", elapsed_ns = elapsed_ns, "rule cache hit");
-                    return Ok(Some(resp));
+                    self.log_query(peer.ip(), q.qname, qtype, *rcode, "static", false, t_start.elapsed());
+                    return answered(resp);
                 }
             }
         }
@@ -234,11 +1111,184 @@ impl Engine {
         Ok(None)
     }
 
+    /// 处理经 UDP 到达的查询
     #[inline]
     pub async fn handle_packet(&self, packet: &[u8], peer: SocketAddr) -> anyhow::Result<Bytes> {
-        // Track requests and inflight concurrency for diagnostics.
-        let _req_id = self.request_id_counter.fetch_add(1, Ordering::Relaxed);
+        self.handle_packet_with(packet, peer, false).await
+    }
+
+    /// settings.tcp_idle_timeout_ms，随热加载生效；0 表示不限制
+    pub fn tcp_idle_timeout(&self) -> Option<Duration> {
+        let ms = self.pipeline.load().settings.tcp_idle_timeout_ms;
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+
+    /// UDP worker 的接收错误退避：连续错误上限取 settings.udp_max_consecutive_errors（worker 启动时读取），计入 metrics_udp_socket_errors
+    pub fn udp_recv_backoff(&self) -> RecvBackoff {
+        let max_errors = self.pipeline.load().settings.udp_max_consecutive_errors;
+        RecvBackoff::new(max_errors, Arc::clone(&self.metrics_udp_socket_errors))
+    }
+
+    /// 处理经 TCP 到达的查询：force_tcp 规则不再返回截断应答
+    #[inline]
+    pub async fn handle_packet_tcp(&self, packet: &[u8], peer: SocketAddr) -> anyhow::Result<Bytes> {
+        self.handle_packet_with(packet, peer, true).await
+    }
+
+    async fn handle_packet_with(&self, packet: &[u8], peer: SocketAddr, tcp: bool) -> anyhow::Result<Bytes> {
+        self.check_acl(peer.ip())?;
         self.metrics_total_requests.fetch_add(1, Ordering::Relaxed);
+        // 每个查询一个 span，字段在处理过程中补齐；默认日志级别下 span 被过滤，不产生开销
+        let span = info_span!(
+            "dns_query",
+            qname = tracing::field::Empty,
+            qtype = tracing::field::Empty,
+            pipeline = tracing::field::Empty,
+            upstream = tracing::field::Empty,
+            rcode = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        let start = (!span.is_disabled()).then(std::time::Instant::now);
+        let mut counters = None;
+        let result = if self.malformed_cookie(packet) {
+            self.formerr_response(packet, peer.ip(), "malformed cookie option")
+        } else {
+            self.resolve(packet, peer, tcp, false, &mut counters).instrument(span.clone()).await
+        };
+        if let (Some(counters), Ok(resp)) = (&counters, &result)
+            && resp.len() >= 4
+            && resp[3] & 0x0F == ResponseCode::ServFail.low()
+        {
+            counters.servfails.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(start) = start {
+            span.record("latency_ms", start.elapsed().as_millis() as u64);
+            if let Ok(resp) = &result
+                && resp.len() >= 4
+            {
+                span.record("rcode", tracing::field::debug(ResponseCode::from(0, resp[3] & 0x0f)));
+            }
+        }
+        // TCP 客户端无法伪造源地址，不做响应限速
+        let resp = if tcp { result? } else { self.apply_rrl(packet, result?, peer.ip())? };
+        Ok(self.apply_server_cookie(packet, resp, peer.ip(), tcp))
+    }
+
+    /// settings.any_policy 为 hinfo / a_aaaa 且规则决定转发时的 ANY 查询。a_aaaa 将请求改写为 A 与 AAAA 两个查询并发解析
+    /// （各自走规则、缓存与上游），合并去重后的应答记录；合并结果本身不写缓存。任一子查询 NOERROR 即整体 NOERROR，
+    /// 任一子应答截断则整体置 TC；合并后没有应答记录时沿用所取 rcode 那一子应答的权威段（如 SOA）
+    #[allow(clippy::too_many_arguments)]
+    async fn resolve_any(
+        &self,
+        packet: &[u8],
+        peer: SocketAddr,
+        tcp: bool,
+        refresh: bool,
+        policy: AnyPolicy,
+        qname: &str,
+        qclass: DNSClass,
+        tx_id: u16,
+    ) -> anyhow::Result<Bytes> {
+        use hickory_proto::rr::RecordType;
+
+        if policy == AnyPolicy::Hinfo {
+            let hinfo = RData::HINFO(hickory_proto::rr::rdata::HINFO::new("RFC8482".to_string(), String::new()));
+            let record = Record::from_rdata(Name::from_str(qname)?, ANY_HINFO_TTL, hinfo);
+            self.log_query(peer.ip(), qname, RecordType::ANY, ResponseCode::NoError, "static", false, Duration::ZERO);
+            return build_fast_static_response(tx_id, qname, u16::from(RecordType::ANY), u16::from(qclass), ResponseCode::NoError, &vec![record]);
+        }
+
+        let req = Message::from_bytes(packet).context("parse request")?;
+        let question = req.queries().first().context("empty question")?.clone();
+        let rewrite = |rtype: RecordType| -> anyhow::Result<Vec<u8>> {
+            let mut sub = req.clone();
+            sub.take_queries();
+            let mut q = question.clone();
+            q.set_query_type(rtype);
+            sub.add_query(q);
+            Ok(sub.to_vec()?)
+        };
+        let (a_packet, aaaa_packet) = (rewrite(RecordType::A)?, rewrite(RecordType::AAAA)?);
+        // ANY 本身已计入所选 pipeline，子查询的计数器不再回传
+        let (mut a_counters, mut aaaa_counters) = (None, None);
+        let (a_res, aaaa_res) = futures::join!(
+            Box::pin(self.resolve(&a_packet, peer, tcp, refresh, &mut a_counters)),
+            Box::pin(self.resolve(&aaaa_packet, peer, tcp, refresh, &mut aaaa_counters)),
+        );
+        let parts: Vec<Message> = match (a_res, aaaa_res) {
+            (Err(err), Err(_)) => return Err(err),
+            (a, aaaa) => a
+                .into_iter()
+                .chain(aaaa)
+                .map(|raw| Message::from_bytes(&raw).context("parse sub-response"))
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        let mut msg = Message::new();
+        msg.set_id(tx_id);
+        msg.set_message_type(MessageType::Response);
+        msg.set_op_code(OpCode::Query);
+        msg.set_recursion_desired(req.recursion_desired());
+        msg.set_recursion_available(true);
+        msg.add_query(question);
+        let primary = parts.iter().find(|m| m.response_code() == ResponseCode::NoError).unwrap_or(&parts[0]);
+        msg.set_response_code(primary.response_code());
+        // 截断的子应答（force_tcp、truncate 模式限速）不能被另一半的应答掩盖，客户端需改用 TCP 重试
+        msg.set_truncated(parts.iter().any(|m| m.truncated()));
+        for part in &parts {
+            for record in part.answers() {
+                // CNAME 链在两个子应答中各出现一次
+                if !msg.answers().contains(record) {
+                    msg.add_answer(record.clone());
+                }
+            }
+        }
+        if msg.answers().is_empty() {
+            msg.insert_name_servers(primary.name_servers().to_vec());
+        }
+        if let Some(edns) = parts.iter().find_map(|m| m.extensions().clone()) {
+            msg.set_edns(edns);
+        }
+        Ok(Bytes::from(msg.to_vec()?))
+    }
+
+    /// 命中陈旧条目（或需预取的新鲜条目）后在后台重新解析：结果照常写回缓存，每个缓存键同时只有一个刷新任务；
+    /// 刷新失败不写缓存，原条目保留至自身过期。返回是否启动了新的刷新任务
+    fn spawn_stale_refresh(&self, dedupe_hash: u64, packet: &[u8], peer: SocketAddr) -> bool {
+        if self.stale_refreshing.insert(dedupe_hash, ()).is_some() {
+            return false;
+        }
+        let engine = self.clone();
+        let packet = packet.to_vec();
+        tokio::spawn(async move {
+            if let Err(err) = engine.resolve(&packet, peer, false, true, &mut None).await {
+                debug!(error = %err, "stale refresh failed");
+            }
+            engine.stale_refreshing.remove(&dedupe_hash);
+        });
+        true
+    }
+
+    /// refresh-ahead：新鲜命中的剩余 TTL 低于 prefetch_threshold_percent 时照常返回缓存，同时在后台刷新
+    #[inline]
+    fn maybe_prefetch(&self, hit: &CacheEntry, threshold_percent: u8, dedupe_hash: u64, packet: &[u8], peer: SocketAddr) {
+        if hit.prefetch_due(threshold_percent) && self.spawn_stale_refresh(dedupe_hash, packet, peer) {
+            self.metrics_prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// tcp 表示查询经 TCP 到达；refresh 为 true 表示 serve-stale 的后台刷新：跳过客户端并发/限速检查，也不再返回陈旧条目。
+    /// 选定 pipeline 后将其计数器写入 counters（后台刷新不计），供调用方按最终 rcode 统计
+    async fn resolve(
+        &self,
+        packet: &[u8],
+        peer: SocketAddr,
+        tcp: bool,
+        refresh: bool,
+        counters: &mut Option<Arc<PipelineCounters>>,
+    ) -> anyhow::Result<Bytes> {
+        // Track requests and inflight concurrency for diagnostics.
+        let _req_id = self.request_id_counter.fetch_add(1, Ordering::Relaxed);
         struct InflightGuard(Arc<AtomicUsize>);
         impl Drop for InflightGuard {
             fn drop(&mut self) {
@@ -247,15 +1297,29 @@ impl Engine {
         }
         self.metrics_inflight.fetch_add(1, Ordering::Relaxed);
         let _inflight_guard = InflightGuard(self.metrics_inflight.clone());
-        let cfg = self.pipeline.load();
+        let cfg = self.pipeline.load_full();
         let min_ttl = cfg.min_ttl();
+        let max_negative_ttl = cfg.settings.max_negative_ttl as u64;
         let upstream_timeout = cfg.upstream_timeout();
         let response_jump_limit = cfg.settings.response_jump_limit as usize;
 
+        if let Some(resp) = self.opcode_response(packet, peer.ip())? {
+            return Ok(resp);
+        }
+
         // Lazy Parse: Use quick parse first
         let mut qname_buf = [0u8; 256];
-        let (qname, qtype, qclass, tx_id, edns_present) = if let Some(q) = parse_quick(packet, &mut qname_buf) {
-            (q.qname.to_string(), hickory_proto::rr::RecordType::from(q.qtype), DNSClass::from(q.qclass), q.tx_id, false) // TODO: check EDNS in quick parse
+        let (qname, qtype, qclass, tx_id, edns_bufsize, dnssec_ok, multiple_questions, edns_options) = if let Some(q) = parse_quick(packet, &mut qname_buf) {
+            (
+                q.qname.to_string(),
+                hickory_proto::rr::RecordType::from(q.qtype),
+                DNSClass::from(q.qclass),
+                q.tx_id,
+                q.edns_bufsize,
+                q.dnssec_ok,
+                q.multiple_questions,
+                q.edns_options,
+            )
         } else {
             // Fallback to full parse if quick parse fails (unlikely for standard queries)
             let req = Message::from_bytes(packet).context("parse request")?;
@@ -265,9 +1329,63 @@ impl Engine {
                 question.query_type(),
                 question.query_class(),
                 req.id(),
-                req.extensions().is_some(),
+                req.extensions().as_ref().map(|e| e.max_payload()),
+                req.extensions().as_ref().is_some_and(|e| e.dnssec_ok()),
+                req.queries().len() > 1,
+                req.extensions().as_ref().map_or(0, |e| e.options().as_ref().len()),
             )
         };
+        if multiple_questions {
+            return self.formerr_response(packet, peer.ip(), "multiple questions");
+        }
+        if self.too_many_edns_options(edns_options) {
+            return self.formerr_response(packet, peer.ip(), "too many edns options");
+        }
+        let ecs = if edns_options > 0 { client_subnet(packet) } else { None };
+
+        // 区域传送按 qtype 分别受 allow_axfr / allow_ixfr 控制
+        let transfer_allowed = match qtype {
+            hickory_proto::rr::RecordType::AXFR => Some(cfg.settings.allow_axfr),
+            hickory_proto::rr::RecordType::IXFR => Some(cfg.settings.allow_ixfr),
+            _ => None,
+        };
+        if transfer_allowed == Some(false) {
+            info!(
+                event = "dns_response",
+                qname = %qname,
+                qtype = ?qtype,
+                rcode = ?ResponseCode::Refused,
+                client_ip = %peer.ip(),
+                "zone transfer refused"
+            );
+            self.log_query(peer.ip(), &qname, qtype, ResponseCode::Refused, "static", false, Duration::ZERO);
+            return build_fast_static_response(
+                tx_id,
+                &qname,
+                u16::from(qtype),
+                u16::from(qclass),
+                ResponseCode::Refused,
+                &Vec::new(),
+            );
+        }
+
+        if qclass == DNSClass::IN
+            && let Some(zone) = find_zone(&cfg.local_zones, &qname)
+        {
+            let answer = zone.answer(&qname, qtype);
+            info!(
+                event = "dns_response",
+                qname = %qname,
+                qtype = ?qtype,
+                rcode = ?answer.rcode,
+                client_ip = %peer.ip(),
+                zone = %zone.apex,
+                "local zone answer"
+            );
+            self.log_query(peer.ip(), &qname, qtype, answer.rcode, "local_zone", false, Duration::ZERO);
+            let req = Message::from_bytes(packet).context("parse request")?;
+            return build_authoritative_response(&req, answer.rcode, answer.answers, answer.authority);
+        }
 
         let start = std::time::Instant::now();
 
@@ -276,22 +1394,94 @@ impl Engine {
             &qname,
             peer.ip(),
             qclass,
-            edns_present,
+            edns_bufsize,
             &self.listener_label,
+            &self.metrics_dangling_selects,
         );
+        tracing::Span::current()
+            .record("qname", qname.as_str())
+            .record("qtype", tracing::field::debug(qtype))
+            .record("pipeline", pipeline_id.as_str());
+        let pipeline_counters = self.pipeline_counters(&pipeline_id);
+        if !refresh {
+            pipeline_counters.requests.fetch_add(1, Ordering::Relaxed);
+            *counters = Some(Arc::clone(&pipeline_counters));
+        }
+
+        // 单客户端并发上限：guard 持有到本次查询结束
+        let _client_guard = if cfg.settings.max_inflight_per_client > 0 && !refresh {
+            match ClientInflightGuard::acquire(&self.client_inflight, peer.ip(), cfg.settings.max_inflight_per_client) {
+                Some(guard) => Some(guard),
+                None => {
+                    debug!(client_ip = %peer.ip(), qname = %qname, "client in-flight limit reached");
+                    return build_fast_static_response(
+                        tx_id,
+                        &qname,
+                        u16::from(qtype),
+                        u16::from(qclass),
+                        ResponseCode::Refused,
+                        &Vec::new(),
+                    );
+                }
+            }
+        } else {
+            None
+        };
+
+        // 限速先于缓存与规则评估，被限速的客户端不会触发上游转发
+        if !refresh
+            && let Some(resp) =
+                self.rate_limit_response(pipeline_opt, tx_id, &qname, qtype, qclass, peer.ip(), edns_bufsize, ecs)?
+        {
+            return Ok(resp);
+        }
 
-        let dedupe_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, &qname, qtype);
-        // moka 同步缓存自动处理过期，无需检查 expires_at
+        // force_tcp 同样先于缓存：UDP 客户端即使命中缓存也只拿到截断应答
+        if !tcp
+            && !refresh
+            && let Some(resp) =
+                self.force_tcp_response(pipeline_opt, tx_id, &qname, qtype, qclass, peer.ip(), edns_bufsize, ecs)?
+        {
+            return Ok(resp);
+        }
+
+        let dedupe_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, &qname, qtype, qclass, dnssec_ok);
+
+        // 可选：规则评估与缓存查询并行。评估本身不会转发，转发只在确认缓存未命中后发起，避免重复转发
+        let speculative = if cfg.settings.parallel_rule_eval && pipeline_opt.is_some() {
+            let engine = self.clone();
+            let cfg = Arc::clone(&cfg);
+            let pipeline_id = pipeline_id.clone();
+            let qname = qname.clone();
+            let client_ip = peer.ip();
+            Some(tokio::spawn(async move {
+                let p = cfg.pipelines.iter().find(|p| p.id == pipeline_id)?;
+                Some(engine.apply_rules(&cfg, p, client_ip, &qname, qtype, qclass, edns_bufsize, ecs, None))
+            }))
+        } else {
+            None
+        };
+
+        // moka 只淘汰超出陈旧窗口的条目，新鲜度需检查 expires_at；后台刷新（含预取）跳过新鲜条目，直接请求上游
         if let Some(hit) = self.cache.get(&dedupe_hash) {
-            if hit.qtype == u16::from(qtype) && hit.qname.as_ref() == qname && hit.pipeline_id.as_ref() == pipeline_id {
+            if !refresh && hit.matches(&pipeline_id, &qname, qtype, qclass) && hit.is_fresh() {
+                if let Some(handle) = &speculative {
+                    handle.abort();
+                }
+                if cfg.settings.block_escalation.is_some() && is_cached_block(&hit) {
+                    self.escalate_block(&cfg, peer.ip(), &qname).await;
+                }
+                self.metrics_cache_hits.fetch_add(1, Ordering::Relaxed);
+                pipeline_counters.cache_hits.fetch_add(1, Ordering::Relaxed);
                 let latency = start.elapsed();
-                // clone bytes and rewrite transaction ID to match requester
+                // clone bytes and rewrite transaction ID (and question casing) to match requester
                 let mut resp_vec = hit.bytes.to_vec();
                 if resp_vec.len() >= 2 {
                     let id_bytes = tx_id.to_be_bytes();
                     resp_vec[0] = id_bytes[0];
                     resp_vec[1] = id_bytes[1];
                 }
+                echo_question_name(packet, &mut resp_vec);
                 let resp_bytes = Bytes::from(resp_vec);
                 info!(
                     event = "dns_response",
@@ -305,20 +1495,69 @@ impl Engine {
                     cache = true,
                     "cache hit"
                 );
+                self.log_query(peer.ip(), &qname, qtype, hit.rcode, &hit.source, true, latency);
+                self.maybe_prefetch(&hit, cfg.settings.prefetch_threshold_percent, dedupe_hash, packet, peer);
+                return Ok(resp_bytes);
+            }
+            // 陈旧窗口内：立即返回旧应答，后台刷新缓存
+            if !refresh
+                && cfg.settings.serve_stale_secs > 0
+                && hit.matches(&pipeline_id, &qname, qtype, qclass)
+                && hit.stale_until > std::time::Instant::now()
+            {
+                if let Some(handle) = &speculative {
+                    handle.abort();
+                }
+                self.metrics_degraded_responses.fetch_add(1, Ordering::Relaxed);
+                self.metrics_cache_hits.fetch_add(1, Ordering::Relaxed);
+                pipeline_counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+                let resp_bytes = stale_response(&hit, tx_id, packet);
+                info!(
+                    event = "dns_response",
+                    upstream = %hit.source,
+                    qname = %qname,
+                    qtype = ?qtype,
+                    rcode = ?hit.rcode,
+                    client_ip = %peer.ip(),
+                    pipeline = %pipeline_id,
+                    cache = true,
+                    stale = true,
+                    "serving stale, refreshing in background"
+                );
+                self.log_query(peer.ip(), &qname, qtype, hit.rcode, &hit.source, true, start.elapsed());
+                self.spawn_stale_refresh(dedupe_hash, packet, peer);
                 return Ok(resp_bytes);
             }
         }
+        if !refresh {
+            self.metrics_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if !refresh
+            && let Some(resp) = self.recent_result(dedupe_hash, tx_id, packet, cfg.settings.recent_result_window_ms)
+        {
+            if let Some(handle) = &speculative {
+                handle.abort();
+            }
+            debug!(qname = %qname, client_ip = %peer.ip(), pipeline = %pipeline_id, "reused recent result");
+            return Ok(resp);
+        }
 
         let mut skip_rules = HashSet::new();
         let mut current_pipeline_id = pipeline_id.clone();
-        let mut dedupe_hash = Self::calculate_cache_hash_for_dedupe(&current_pipeline_id, &qname, qtype);
+        let mut dedupe_hash = Self::calculate_cache_hash_for_dedupe(&current_pipeline_id, &qname, qtype, qclass, dnssec_ok);
         let mut dedupe_registered = false;
         let mut reused_response: Option<ResponseContext> = None;
 
-        let mut decision = match pipeline_opt {
-            Some(p) => self.apply_rules(&cfg, p, peer.ip(), &qname, qtype, qclass, edns_present, None),
-            None => Decision::Forward {
-                upstream: cfg.settings.default_upstream.clone(),
+        let speculative_decision = match speculative {
+            Some(handle) => handle.await.ok().flatten(),
+            None => None,
+        };
+        let mut decision = match (speculative_decision, pipeline_opt) {
+            (Some(d), _) => d,
+            (None, Some(p)) => self.apply_rules(&cfg, p, peer.ip(), &qname, qtype, qclass, edns_bufsize, ecs, None),
+            (None, None) => static_record_decision(&cfg, &qname, qtype, qclass).unwrap_or_else(|| Decision::Forward {
+                upstream: cfg.default_upstream_for(&qname).clone(),
                 response_matchers: Vec::new(),
                 response_matcher_operator: crate::config::MatchOperator::And,
                 response_actions_on_match: Vec::new(),
@@ -328,7 +1567,9 @@ impl Engine {
                 continue_on_match: false,
                 continue_on_miss: false,
                 allow_reuse: false,
-            },
+                ttl_clamp: None,
+                upstream_timeout: None,
+            }),
         };
 
         struct InflightCleanupGuard {
@@ -361,16 +1602,14 @@ impl Engine {
                 if let Decision::Jump { pipeline } = &decision {
                     jump_count += 1;
                     if jump_count > response_jump_limit {
-                        warn!("max jump limit reached");
-                        decision = Decision::Static {
-                            rcode: ResponseCode::ServFail,
-                            answers: Vec::new(),
-                        };
-                        break;
+                        warn!(qname = %qname, pipeline = %current_pipeline_id, "max jump limit reached");
+                        // 跳转链配置错误，不缓存
+                        let req = Message::from_bytes(packet).context("parse request for static")?;
+                        return build_jump_limit_response(&req, &cfg.settings);
                     }
                     if let Some(p) = cfg.pipelines.iter().find(|p| p.id == *pipeline) {
                         current_pipeline_id = pipeline.clone();
-                        dedupe_hash = Self::calculate_cache_hash_for_dedupe(&current_pipeline_id, &qname, qtype);
+                        dedupe_hash = Self::calculate_cache_hash_for_dedupe(&current_pipeline_id, &qname, qtype, qclass, dnssec_ok);
                         dedupe_registered = false;
                         skip_rules.clear();
                         decision = self.apply_rules(
@@ -380,7 +1619,8 @@ impl Engine {
                             &qname,
                             qtype,
                             qclass,
-                            edns_present,
+                            edns_bufsize,
+                            ecs,
                             None,
                         );
                         continue;
@@ -401,11 +1641,33 @@ impl Engine {
             Decision::Jump { .. } => {
                 anyhow::bail!("unresolved pipeline jump");
             }
+            Decision::ClientStatic { rcode, answers } => {
+                let req = Message::from_bytes(packet).context("parse request for static")?;
+                let resp_bytes = build_response(&req, rcode, answers)?;
+                info!(
+                    event = "dns_response",
+                    upstream = "static",
+                    qname = %qname,
+                    qtype = ?qtype,
+                    rcode = ?rcode,
+                    latency_ms = start.elapsed().as_millis() as u64,
+                    client_ip = %peer.ip(),
+                    pipeline = %current_pipeline_id,
+                    cache = false,
+                    "static response"
+                );
+                self.log_query(peer.ip(), &qname, qtype, rcode, "static", false, start.elapsed());
+                return Ok(resp_bytes);
+            }
             Decision::Static { rcode, answers } => {
+                if Self::escalates_block(&cfg, rcode, &answers) {
+                    self.escalate_block(&cfg, peer.ip(), &qname).await;
+                }
                 // Need full request for building response
                 let req = Message::from_bytes(packet).context("parse request for static")?;
                 let resp_bytes = build_response(&req, rcode, answers)?;
                 if min_ttl > Duration::from_secs(0) {
+                    let (original_ttl, expires_at, stale_until) = self.cache_deadlines(min_ttl);
                     let entry = CacheEntry {
                         bytes: resp_bytes.clone(),
                         rcode,
@@ -413,6 +1675,11 @@ impl Engine {
                         qname: Arc::from(qname.as_str()),
                         pipeline_id: Arc::from(current_pipeline_id.as_str()),
                         qtype: u16::from(qtype),
+                        qclass: u16::from(qclass),
+                        expires_at,
+                        stale_until,
+                        original_ttl,
+                        dnssec_ok,
                     };
                     self.cache.insert(dedupe_hash, entry);
                 }
@@ -429,10 +1696,17 @@ impl Engine {
                     cache = false,
                     "static response"
                 );
+                self.log_query(peer.ip(), &qname, qtype, rcode, "static", false, latency);
                 return Ok(resp_bytes);
             }
+            // ANY 到这里已通过限速、并发上限与请求阶段规则，本应转发时才按 any_policy 处理；
+            // 子查询各自占用并发名额，先释放本次查询的
+            Decision::Forward { .. } if qtype == hickory_proto::rr::RecordType::ANY && cfg.settings.any_policy != AnyPolicy::Forward => {
+                drop(_client_guard);
+                return self.resolve_any(packet, peer, tcp, refresh, cfg.settings.any_policy, &qname, qclass, tx_id).await;
+            }
             Decision::Forward {
-                upstream,
+                upstream: upstream_group,
                 response_matchers,
                 response_matcher_operator: _response_matcher_operator,
                 response_actions_on_match,
@@ -442,26 +1716,26 @@ impl Engine {
                 continue_on_match: _,
                 continue_on_miss: _,
                 allow_reuse,
+                ttl_clamp,
+                upstream_timeout: pipeline_timeout,
             } => {
+                let upstream_timeout = pipeline_timeout.unwrap_or(upstream_timeout);
+                // 成功后替换为实际应答的上游，失败日志中保留整个组
+                let mut upstream = upstream_group.to_string();
                 let mut cleanup_guard = None;
                 let resp = if allow_reuse {
                     if let Some(ctx) = reused_response.take() {
                         Ok(ctx.raw)
                     } else {
                         if !dedupe_registered {
-                            use dashmap::mapref::entry::Entry;
-                            let rx = match self.inflight.entry(dedupe_hash) {
-                                Entry::Occupied(mut entry) => {
-                                    let (tx, rx) = oneshot::channel();
-                                    entry.get_mut().push(tx);
-                                    Some(rx)
-                                }
-                                Entry::Vacant(entry) => {
-                                    entry.insert(Vec::new());
+                            let rx = match self.join_inflight(dedupe_hash) {
+                                InflightSlot::Waiter(rx) => Some(rx),
+                                InflightSlot::Leader => {
                                     dedupe_registered = true;
                                     cleanup_guard = Some(InflightCleanupGuard::new(self.inflight.clone(), dedupe_hash));
                                     None
                                 }
+                                InflightSlot::Overflow => None,
                             };
 
                             if let Some(rx) = rx {
@@ -473,6 +1747,7 @@ impl Engine {
                                             resp_vec[0] = id_bytes[0];
                                             resp_vec[1] = id_bytes[1];
                                         }
+                                        echo_question_name(packet, &mut resp_vec);
                                         return Ok(Bytes::from(resp_vec));
                                     }
                                     Ok(Err(e)) => return Err(e),
@@ -482,26 +1757,27 @@ impl Engine {
                                 }
                             }
                         }
-                        self.forward_upstream(packet, &upstream, upstream_timeout, transport).await
+                        self.pipeline_counters(&current_pipeline_id).upstream_forwards.fetch_add(1, Ordering::Relaxed);
+                        self.forward_group(packet, &upstream_group, upstream_timeout, transport, peer.ip(), cfg.dns64_prefix_for(&current_pipeline_id))
+                        .await
+                        .map(|(raw, used)| {
+                            upstream = used;
+                            raw
+                        })
                     }
                 } else {
                     // If reuse is not allowed (e.g. explicit Forward action), we must clear any reused response
                     // and force a new request.
                     
                     if !dedupe_registered {
-                        use dashmap::mapref::entry::Entry;
-                        let rx = match self.inflight.entry(dedupe_hash) {
-                            Entry::Occupied(mut entry) => {
-                                let (tx, rx) = oneshot::channel();
-                                entry.get_mut().push(tx);
-                                Some(rx)
-                            }
-                            Entry::Vacant(entry) => {
-                                entry.insert(Vec::new());
+                        let rx = match self.join_inflight(dedupe_hash) {
+                            InflightSlot::Waiter(rx) => Some(rx),
+                            InflightSlot::Leader => {
                                 dedupe_registered = true;
                                 cleanup_guard = Some(InflightCleanupGuard::new(self.inflight.clone(), dedupe_hash));
                                 None
                             }
+                            InflightSlot::Overflow => None,
                         };
 
                         if let Some(rx) = rx {
@@ -513,6 +1789,7 @@ impl Engine {
                                         resp_vec[0] = id_bytes[0];
                                         resp_vec[1] = id_bytes[1];
                                     }
+                                    echo_question_name(packet, &mut resp_vec);
                                     return Ok(Bytes::from(resp_vec));
                                 }
                                 Ok(Err(e)) => return Err(e),
@@ -522,27 +1799,61 @@ impl Engine {
                             }
                         }
                     }
-                    self.forward_upstream(packet, &upstream, upstream_timeout, transport).await
+                    self.pipeline_counters(&current_pipeline_id).upstream_forwards.fetch_add(1, Ordering::Relaxed);
+                    self.forward_group(packet, &upstream_group, upstream_timeout, transport, peer.ip(), cfg.dns64_prefix_for(&current_pipeline_id))
+                        .await
+                        .map(|(raw, used)| {
+                            upstream = used;
+                            raw
+                        })
                 };
 
                 match resp {
                     Ok(raw) => {
                         // Optimization: Use quick response parse if no complex matching is needed
-                        let (rcode, ttl_secs, msg_opt) = if response_matchers.is_empty() && response_actions_on_match.is_empty() && response_actions_on_miss.is_empty() {
-                            if let Some(qr) = crate::proto_utils::parse_response_quick(&raw) {
-                                (qr.rcode, qr.min_ttl as u64, None)
-                            } else {
-                                // Fallback
-                                let msg = Message::from_bytes(&raw).context("parse upstream response")?;
-                                let ttl = extract_ttl(&msg);
-                                (msg.response_code(), ttl, Some(msg))
-                            }
+                        let quick = if ttl_clamp.is_none()
+                            && response_matchers.is_empty()
+                            && response_actions_on_match.is_empty()
+                            && response_actions_on_miss.is_empty()
+                        {
+                            crate::proto_utils::parse_response_quick(&raw, max_negative_ttl as u32)
                         } else {
-                            let msg = Message::from_bytes(&raw).context("parse upstream response")?;
-                            let ttl = extract_ttl(&msg);
-                            (msg.response_code(), ttl, Some(msg))
+                            None
+                        };
+                        let (raw, rcode, mut ttl_secs, msg_opt) = match quick {
+                            Some(qr) => (raw, qr.rcode, qr.min_ttl as u64, None),
+                            None => match parse_upstream_response(&raw, ttl_clamp) {
+                                Ok((raw, msg)) => {
+                                    let ttl = extract_ttl(&msg, max_negative_ttl);
+                                    (raw, msg.response_code(), ttl, Some(msg))
+                                }
+                                Err(err) => {
+                                    // 无法钳制 TTL 或评估响应规则：不缓存，等待同一问题的请求拿到同样的结果
+                                    warn!(
+                                        event = "dns_response",
+                                        upstream = %upstream,
+                                        qname = %qname,
+                                        qtype = ?qtype,
+                                        client_ip = %peer.ip(),
+                                        pipeline = %pipeline_id,
+                                        error = %err,
+                                        policy = ?cfg.settings.response_parse_failure,
+                                        "upstream response failed to parse"
+                                    );
+                                    let fallback = response_parse_fallback(cfg.settings.response_parse_failure, packet, raw, err)?;
+                                    if let Some(g) = cleanup_guard.as_mut() { g.defuse(); }
+                                    self.notify_inflight_waiters(dedupe_hash, &fallback).await;
+                                    let rcode = crate::proto_utils::parse_response_quick(&fallback, 0)
+                                        .map_or(ResponseCode::ServFail, |qr| qr.rcode);
+                                    self.log_query(peer.ip(), &qname, qtype, rcode, &upstream, false, start.elapsed());
+                                    return Ok(fallback);
+                                }
+                            },
                         };
 
+                        if let Some((lo, hi)) = ttl_clamp {
+                            ttl_secs = ttl_secs.clamp(lo as u64, hi as u64);
+                        }
                         let effective_ttl = Duration::from_secs(ttl_secs.max(min_ttl.as_secs()));
 
                         let (resp_match_ok, msg) = if let Some(m) = msg_opt {
@@ -571,6 +1882,7 @@ impl Engine {
 
                         if actions_to_run.is_empty() {
                             if effective_ttl > Duration::from_secs(0) {
+                                let (original_ttl, expires_at, stale_until) = self.cache_deadlines(effective_ttl);
                                 let entry = CacheEntry {
                                     bytes: raw.clone(),
                                     rcode,
@@ -578,6 +1890,11 @@ impl Engine {
                                     qname: Arc::from(qname.as_str()),
                                     pipeline_id: Arc::from(pipeline_id.as_str()),
                                     qtype: u16::from(qtype),
+                                    qclass: u16::from(qclass),
+                                    expires_at,
+                                    stale_until,
+                                    original_ttl,
+                                    dnssec_ok,
                                 };
                                 self.cache.insert(dedupe_hash, entry);
                             }
@@ -598,6 +1915,7 @@ impl Engine {
                                 transport = ?transport,
                                 "forwarded"
                             );
+                            self.log_query(peer.ip(), &qname, qtype, rcode, &upstream, false, latency);
                             return Ok(raw);
                         }
                         
@@ -626,7 +1944,7 @@ impl Engine {
                                 qtype,
                                 qclass,
                                 peer.ip(),
-                                cfg.settings.default_upstream.as_str(),
+                                cfg.default_upstream_for(&qname),
                                 &pipeline_id,
                                 &rule_name,
                                 response_jump_limit,
@@ -635,10 +1953,11 @@ impl Engine {
 
                         match action_result {
                             ResponseActionResult::Upstream { ctx, resp_match } => {
-                                let ttl_secs = extract_ttl(&ctx.msg);
+                                let ttl_secs = extract_ttl(&ctx.msg, max_negative_ttl);
                                 let effective_ttl =
                                     Duration::from_secs(ttl_secs.max(min_ttl.as_secs()));
                                 if effective_ttl > Duration::from_secs(0) {
+                                    let (original_ttl, expires_at, stale_until) = self.cache_deadlines(effective_ttl);
                                     let entry = CacheEntry {
                                         bytes: ctx.raw.clone(),
                                         rcode: ctx.msg.response_code(),
@@ -646,6 +1965,11 @@ impl Engine {
                                         qname: Arc::from(qname.as_str()),
                                         pipeline_id: Arc::from(pipeline_id.as_str()),
                                         qtype: u16::from(qtype),
+                                        qclass: u16::from(qclass),
+                                        expires_at,
+                                        stale_until,
+                                        original_ttl,
+                                        dnssec_ok,
                                     };
                                     self.cache.insert(dedupe_hash, entry);
                                 }
@@ -666,6 +1990,7 @@ impl Engine {
                                     transport = ?ctx.transport,
                                     "forwarded"
                                 );
+                                self.log_query(peer.ip(), &qname, qtype, ctx.msg.response_code(), &ctx.upstream, false, latency);
                                 return Ok(ctx.raw);
                             }
                             ResponseActionResult::Static {
@@ -674,6 +1999,7 @@ impl Engine {
                                 source,
                             } => {
                                 if min_ttl > Duration::from_secs(0) {
+                                    let (original_ttl, expires_at, stale_until) = self.cache_deadlines(min_ttl);
                                     let entry = CacheEntry {
                                         bytes: bytes.clone(),
                                         rcode,
@@ -681,6 +2007,11 @@ impl Engine {
                                         qname: Arc::from(qname.as_str()),
                                         pipeline_id: Arc::from(current_pipeline_id.as_str()),
                                         qtype: u16::from(qtype),
+                                        qclass: u16::from(qclass),
+                                        expires_at,
+                                        stale_until,
+                                        original_ttl,
+                                        dnssec_ok,
                                     };
                                     self.cache.insert(dedupe_hash, entry);
                                 }
@@ -701,6 +2032,15 @@ impl Engine {
                                     transport = ?transport,
                                     "response_action_static"
                                 );
+                                self.log_query(peer.ip(), &qname, qtype, rcode, source, false, latency);
+                                return Ok(bytes);
+                            }
+                            ResponseActionResult::Unparsed { bytes } => {
+                                if let Some(g) = cleanup_guard.as_mut() { g.defuse(); }
+                                self.notify_inflight_waiters(dedupe_hash, &bytes).await;
+                                let rcode = crate::proto_utils::parse_response_quick(&bytes, 0)
+                                    .map_or(ResponseCode::ServFail, |qr| qr.rcode);
+                                self.log_query(peer.ip(), &qname, qtype, rcode, "response_action", false, start.elapsed());
                                 return Ok(bytes);
                             }
                                 ResponseActionResult::Jump { pipeline, remaining_jumps } => {
@@ -716,9 +2056,8 @@ impl Engine {
                                         &qname,
                                         qtype,
                                         qclass,
-                                        edns_present,
+                                        edns_bufsize,
                                         min_ttl,
-                                        upstream_timeout,
                                     )
                                     .await?;
                                 if let Some(g) = cleanup_guard.as_mut() { g.defuse(); }
@@ -749,7 +2088,8 @@ impl Engine {
                                         &qname,
                                         qtype,
                                         qclass,
-                                        edns_present,
+                                        edns_bufsize,
+                                        ecs,
                                         skip_ref,
                                     );
                                     continue 'decision_loop;
@@ -758,6 +2098,31 @@ impl Engine {
                     }
                     Err(err) => {
                         if response_actions_on_miss.is_empty() {
+                            if !refresh
+                                && let Some(stale) =
+                                    self.lookup_stale(dedupe_hash, &pipeline_id, &qname, qtype, qclass)
+                            {
+                                self.metrics_degraded_responses.fetch_add(1, Ordering::Relaxed);
+                                let resp_bytes = stale_response(&stale, tx_id, packet);
+                                warn!(
+                                    event = "dns_response",
+                                    upstream = %upstream,
+                                    qname = %qname,
+                                    qtype = ?qtype,
+                                    rcode = ?stale.rcode,
+                                    client_ip = %peer.ip(),
+                                    error = %err,
+                                    pipeline = %current_pipeline_id,
+                                    transport = ?transport,
+                                    cache = true,
+                                    degraded = true,
+                                    "upstream failed, serving stale"
+                                );
+                                self.log_query(peer.ip(), &qname, qtype, stale.rcode, &upstream, true, start.elapsed());
+                                if let Some(g) = cleanup_guard.as_mut() { g.defuse(); }
+                                self.notify_inflight_waiters(dedupe_hash, &resp_bytes).await;
+                                return Ok(resp_bytes);
+                            }
                             let rcode = ResponseCode::ServFail;
                             warn!(
                                 event = "dns_response",
@@ -771,6 +2136,7 @@ impl Engine {
                                 transport = ?transport,
                                 "upstream failed"
                             );
+                            self.log_query(peer.ip(), &qname, qtype, rcode, &upstream, false, start.elapsed());
                             let req = Message::from_bytes(packet).context("parse request")?;
                             let resp_bytes = build_response(&req, rcode, Vec::new())?;
                             if let Some(g) = cleanup_guard.as_mut() { g.defuse(); }
@@ -790,7 +2156,7 @@ impl Engine {
                                     qtype,
                                     qclass,
                                     peer.ip(),
-                                    cfg.settings.default_upstream.as_str(),
+                                    cfg.default_upstream_for(&qname),
                                     &pipeline_id,
                                     &rule_name,
                                     response_jump_limit,
@@ -798,10 +2164,11 @@ impl Engine {
                                 .await?;
                             match action_result {
                                     ResponseActionResult::Upstream { ctx, resp_match } => {
-                                        let ttl_secs = extract_ttl(&ctx.msg);
+                                        let ttl_secs = extract_ttl(&ctx.msg, max_negative_ttl);
                                         let effective_ttl =
                                             Duration::from_secs(ttl_secs.max(min_ttl.as_secs()));
                                         if resp_match && effective_ttl > Duration::from_secs(0) {
+                                            let (original_ttl, expires_at, stale_until) = self.cache_deadlines(effective_ttl);
                                             let entry = CacheEntry {
                                                 bytes: ctx.raw.clone(),
                                                 rcode: ctx.msg.response_code(),
@@ -809,13 +2176,18 @@ impl Engine {
                                                 qname: Arc::from(qname.as_str()),
                                                 pipeline_id: Arc::from(pipeline_id.as_str()),
                                                 qtype: u16::from(qtype),
+                                                qclass: u16::from(qclass),
+                                                expires_at,
+                                                stale_until,
+                                                original_ttl,
+                                                dnssec_ok,
                                             };
                                             self.cache.insert(dedupe_hash, entry);
                                         }
                                         self.notify_inflight_waiters(dedupe_hash, &ctx.raw).await;
                                         return Ok(ctx.raw);
                                     }
-                                    ResponseActionResult::Static { bytes, .. } => {
+                                    ResponseActionResult::Static { bytes, .. } | ResponseActionResult::Unparsed { bytes } => {
                                         self.notify_inflight_waiters(dedupe_hash, &bytes).await;
                                         return Ok(bytes);
                                     }
@@ -832,9 +2204,8 @@ impl Engine {
                                                 &qname,
                                                 qtype,
                                                 qclass,
-                                                edns_present,
+                                                edns_bufsize,
                                                 min_ttl,
-                                                upstream_timeout,
                                             )
                                             .await?;
                                         self.notify_inflight_waiters(dedupe_hash, &resp_bytes).await;
@@ -861,7 +2232,8 @@ impl Engine {
                                             &qname,
                                             qtype,
                                             qclass,
-                                            edns_present,
+                                            edns_bufsize,
+                                            ecs,
                                             skip_ref,
                                         );
                                         continue 'decision_loop;
@@ -876,6 +2248,7 @@ impl Engine {
 }
 
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     fn apply_rules(
         &self,
         cfg: &RuntimePipelineConfig,
@@ -884,26 +2257,34 @@ impl Engine {
         qname: &str,
         qtype: hickory_proto::rr::RecordType,
         qclass: DNSClass,
-        edns_present: bool,
+        edns_bufsize: Option<u16>,
+        ecs: Option<IpAddr>,
         skip_rules: Option<&HashSet<String>>,
     ) -> Decision {
+        // 0. static_records_file 中的本地覆盖优先于所有规则；不进入规则缓存，文件重新加载后立即生效
+        if let Some(d) = static_record_decision(cfg, qname, qtype, qclass) {
+            return d;
+        }
+
         // 1. Check Rule Cache
+        // 没有 ecs_subnet 的 pipeline 不需要 ECS，也不按它拆分规则缓存
+        let ecs = ecs.filter(|_| pipeline.uses_ecs);
         // Use hash for lookup to avoid cloning String for key on every lookup
-        let rule_hash = calculate_rule_hash(&pipeline.id, qname, client_ip);
+        let rule_hash = calculate_rule_hash(&pipeline.id, qname, qtype, qclass, client_ip, edns_bufsize, ecs);
         let allow_rule_cache_lookup = skip_rules.map_or(true, |set| set.is_empty());
         
         if allow_rule_cache_lookup {
-            if let Some(entry) = self.rule_cache.get(&rule_hash) {
-                if entry.matches(&pipeline.id, qname, client_ip) {
+            if let Some(entry) = self.rule_cache().get(&rule_hash) {
+                if entry.matches(&pipeline.id, qname, qtype, qclass, client_ip, edns_bufsize, ecs) {
                     return entry.decision.clone();
                 }
             }
         }
 
-        let upstream_default = cfg.settings.default_upstream.clone();
+        let upstream_default = cfg.default_upstream_for(qname);
 
         // 2. Candidate Selection (compiled index if available)
-        let mut candidate_indices = if let Some(compiled) = self.compiled_for(&pipeline.id) {
+        let mut candidate_indices = if let Some(compiled) = self.compiled_for(cfg, &pipeline.id) {
             compiled.index.get_candidates(qname, qtype)
         } else {
             Vec::new()
@@ -931,6 +2312,8 @@ impl Engine {
         }
 
         // 3. Execute Rules
+        // 影子模式下首个直接作答的规则只记录日志；之后的决定不写入规则缓存，保证每次查询都会记录
+        let mut shadowed = false;
         'rules: for idx in candidate_indices {
             let rule = &pipeline.rules[idx];
             if skip_rules.map_or(false, |set| set.contains(&rule.name)) {
@@ -939,11 +2322,28 @@ impl Engine {
             let req_match = eval_match_chain(
                 &rule.matchers,
                 |m| m.operator,
-                |m| matcher_matches(&m.matcher, qname, qclass, client_ip, edns_present),
+                |m| matcher_matches(&m.matcher, qname, qtype, qclass, client_ip, edns_bufsize, ecs),
             );
 
             if req_match {
                 for action in &rule.actions {
+                    if pipeline.shadow_mode
+                        && let Some(rcode) = shadow_rcode(action)
+                    {
+                        if !shadowed {
+                            tracing::info!(
+                                event = "shadow_match",
+                                pipeline = %pipeline.id,
+                                rule = %rule.name,
+                                rcode = %rcode,
+                                qname = %qname,
+                                client_ip = %client_ip,
+                                "rule would answer; forwarding in shadow mode"
+                            );
+                            shadowed = true;
+                        }
+                        continue 'rules;
+                    }
                     match action {
                         Action::StaticResponse { rcode } => {
                             let code = parse_rcode(&rcode).unwrap_or(ResponseCode::NXDomain);
@@ -957,6 +2357,10 @@ impl Engine {
                                     pipeline_id: Arc::from(pipeline.id.as_str()),
                                     qname_hash: fast_hash_str(qname),
                                     client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
                                     decision: d.clone(),
                                 },
                             );
@@ -980,6 +2384,10 @@ impl Engine {
                                             pipeline_id: Arc::from(pipeline.id.as_str()),
                                             qname_hash: fast_hash_str(qname),
                                             client_ip,
+                                            qclass,
+                                            qtype,
+                                            edns_bufsize,
+                                            ecs,
                                             decision: d.clone(),
                                         },
                                     );
@@ -996,6 +2404,69 @@ impl Engine {
                                     pipeline_id: Arc::from(pipeline.id.as_str()),
                                     qname_hash: fast_hash_str(qname),
                                     client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
+                                    decision: d.clone(),
+                                },
+                            );
+                            return d;
+                        }
+                        Action::StaticRecordSet { records } => {
+                            let (rcode, answers) = make_static_record_answers(qname, records);
+                            let d = Decision::Static { rcode, answers };
+                            self.rule_cache.insert(
+                                rule_hash,
+                                RuleCacheEntry {
+                                    pipeline_id: Arc::from(pipeline.id.as_str()),
+                                    qname_hash: fast_hash_str(qname),
+                                    client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
+                                    decision: d.clone(),
+                                },
+                            );
+                            return d;
+                        }
+                        Action::StaticCname { target, ttl } => {
+                            let (rcode, answers) = make_static_cname_answer(qname, target, *ttl);
+                            let d = Decision::Static { rcode, answers };
+                            self.rule_cache.insert(
+                                rule_hash,
+                                RuleCacheEntry {
+                                    pipeline_id: Arc::from(pipeline.id.as_str()),
+                                    qname_hash: fast_hash_str(qname),
+                                    client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
+                                    decision: d.clone(),
+                                },
+                            );
+                            return d;
+                        }
+                        Action::StaticHttps {
+                            priority,
+                            target,
+                            params,
+                            ttl,
+                        } => {
+                            let (rcode, answers) = make_static_https_answer(qname, *priority, target, params, *ttl);
+                            let d = Decision::Static { rcode, answers };
+                            self.rule_cache.insert(
+                                rule_hash,
+                                RuleCacheEntry {
+                                    pipeline_id: Arc::from(pipeline.id.as_str()),
+                                    qname_hash: fast_hash_str(qname),
+                                    client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
                                     decision: d.clone(),
                                 },
                             );
@@ -1005,12 +2476,19 @@ impl Engine {
                             let d = Decision::Jump {
                                 pipeline: target.clone(),
                             };
+                            if shadowed {
+                                return d;
+                            }
                             self.rule_cache.insert(
                                 rule_hash,
                                 RuleCacheEntry {
                                     pipeline_id: Arc::from(pipeline.id.as_str()),
                                     qname_hash: fast_hash_str(qname),
                                     client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
                                     decision: d.clone(),
                                 },
                             );
@@ -1024,17 +2502,26 @@ impl Engine {
                                 response_actions_on_match: Vec::new(),
                                 response_actions_on_miss: Vec::new(),
                                 rule_name: rule.name.clone(),
-                                transport: Transport::Udp,
+                                transport: pipeline.default_transport,
                                 continue_on_match: false,
                                 continue_on_miss: false,
                                 allow_reuse: true,
+                                ttl_clamp: None,
+                                upstream_timeout: pipeline.upstream_timeout,
                             };
+                            if shadowed {
+                                return d;
+                            }
                             self.rule_cache.insert(
                                 rule_hash,
                                 RuleCacheEntry {
                                     pipeline_id: Arc::from(pipeline.id.as_str()),
                                     qname_hash: fast_hash_str(qname),
                                     client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
                                     decision: d.clone(),
                                 },
                             );
@@ -1051,6 +2538,10 @@ impl Engine {
                                     pipeline_id: Arc::from(pipeline.id.as_str()),
                                     qname_hash: fast_hash_str(qname),
                                     client_ip,
+                                    qclass,
+                                    qtype,
+                                    edns_bufsize,
+                                    ecs,
                                     decision: d.clone(),
                                 },
                             );
@@ -1059,11 +2550,11 @@ impl Engine {
                         Action::Forward {
                             upstream,
                             transport,
+                            min_ttl,
+                            max_ttl,
+                            timeout_ms,
                         } => {
-                            let upstream_addr = upstream
-                                .as_ref()
-                                .cloned()
-                                .unwrap_or_else(|| upstream_default.clone());
+                            let upstream_addr = upstream.clone().unwrap_or_else(|| upstream_default.clone());
                             let continue_on_match = contains_continue(&rule.response_actions_on_match);
                             let continue_on_miss = contains_continue(&rule.response_actions_on_miss);
                             let d = Decision::Forward {
@@ -1073,18 +2564,24 @@ impl Engine {
                                 response_actions_on_match: rule.response_actions_on_match.clone(),
                                 response_actions_on_miss: rule.response_actions_on_miss.clone(),
                                 rule_name: rule.name.clone(),
-                                transport: transport.unwrap_or(Transport::Udp),
+                                transport: transport.unwrap_or(pipeline.default_transport),
                                 continue_on_match,
                                 continue_on_miss,
                                 allow_reuse: false,
+                                ttl_clamp: ttl_clamp(*min_ttl, *max_ttl),
+                                upstream_timeout: timeout_ms.map(Duration::from_millis).or(pipeline.upstream_timeout),
                             };
-                            if !continue_on_match && !continue_on_miss {
+                            if !continue_on_match && !continue_on_miss && !shadowed {
                                 self.rule_cache.insert(
                                     rule_hash,
                                     RuleCacheEntry {
                                         pipeline_id: Arc::from(pipeline.id.as_str()),
                                         qname_hash: fast_hash_str(qname),
                                         client_ip,
+                                        qclass,
+                                        qtype,
+                                        edns_bufsize,
+                                        ecs,
                                         decision: d.clone(),
                                     },
                                 );
@@ -1102,95 +2599,459 @@ impl Engine {
                         Action::Continue => {
                             continue 'rules;
                         }
+                        Action::RateLimit { .. } | Action::ForceTcp => {
+                            // 已在请求入口判定，这里仅继续后续动作
+                        }
+                        Action::RewriteIp { .. }
+                        | Action::NxdomainIfAnswerIp { .. }
+                        | Action::SetTtl { .. }
+                        | Action::ClampTtl { .. } => {
+                            // 仅作用于响应阶段
+                        }
+                        Action::GeoStaticIp { default, by_country } => {
+                            // 应答随客户端变化，不写入规则缓存
+                            let country = self.geo.as_ref().and_then(|geo| geo.country(client_ip));
+                            let ip = country
+                                .as_deref()
+                                .and_then(|c| by_country.iter().find(|(k, _)| k.eq_ignore_ascii_case(c)))
+                                .map_or(default, |(_, ip)| ip);
+                            let (rcode, answers) = make_static_ip_answer(qname, ip);
+                            return Decision::ClientStatic { rcode, answers };
+                        }
                     }
                 }
             }
         }
 
         let d = Decision::Forward {
-            upstream: upstream_default,
+            upstream: upstream_default.clone(),
             response_matchers: Vec::new(),
             response_matcher_operator: crate::config::MatchOperator::And,
             response_actions_on_match: Vec::new(),
             response_actions_on_miss: Vec::new(),
             rule_name: "default".to_string(),
-            transport: Transport::Udp,
+            transport: pipeline.default_transport,
             continue_on_match: false,
             continue_on_miss: false,
             allow_reuse: false,
+            ttl_clamp: None,
+            upstream_timeout: pipeline.upstream_timeout,
         };
+        if shadowed {
+            return d;
+        }
         self.rule_cache.insert(
             rule_hash,
             RuleCacheEntry {
                 pipeline_id: Arc::from(pipeline.id.as_str()),
                 qname_hash: fast_hash_str(qname),
                 client_ip,
+                qclass,
+                qtype,
+                edns_bufsize,
+                ecs,
                 decision: d.clone(),
             },
         );
         d
     }
 
-    async fn forward_upstream(
+    /// 转发到上游组；dns64 为所在 pipeline 的 DNS64 前缀，启用时对 AAAA 的 NODATA 应答补查 A 合成 AAAA
+    async fn forward_group(
         &self,
         packet: &[u8],
-        upstream: &str,
+        group: &UpstreamGroup,
         timeout_dur: Duration,
         transport: Transport,
-    ) -> anyhow::Result<Bytes> {
-        let start = std::time::Instant::now();
-        let res = match transport {
-            Transport::Udp => self.forward_udp_smart(packet, upstream, timeout_dur).await,
-            Transport::Tcp => self.tcp_mux.send(packet, upstream, timeout_dur).await,
+        client_ip: IpAddr,
+        dns64: Option<ipnet::Ipv6Net>,
+    ) -> anyhow::Result<(Bytes, String)> {
+        let (raw, used) = self.forward_group_with_retry(packet, group, timeout_dur, transport, client_ip).await?;
+        let Some(prefix) = dns64 else {
+            return Ok((raw, used));
         };
-        if let Ok(_) = &res {
-            let dur = start.elapsed();
-            self.metrics_upstream_calls.fetch_add(1, Ordering::Relaxed);
-            self.metrics_upstream_ns_total.fetch_add(dur.as_nanos() as u64, Ordering::Relaxed);
-            tracing::debug!(upstream=%upstream, upstream_ns = dur.as_nanos() as u64, "upstream call latency");
-        } else if let Err(e) = &res {
-            let dur = start.elapsed();
-            tracing::warn!(upstream=%upstream, error=%e, elapsed_ns = dur.as_nanos() as u64, "upstream call failed");
-        }
-        res
+        let raw = self.dns64_synthesize(packet, raw, prefix, &used, timeout_dur, transport).await;
+        Ok((raw, used))
     }
 
-    /// UDP forwarder with hedged retry and TCP fallback for better tail latency.
-    async fn forward_udp_smart(
+    /// 最终应答为 SERVFAIL 且配置了 settings.servfail_retry 时，按退避改向重试上游再问，
+    /// 中间的 SERVFAIL 不返回也不缓存，重试全部失败时以最后一个 SERVFAIL 作答
+    async fn forward_group_with_retry(
         &self,
         packet: &[u8],
-        upstream: &str,
+        group: &UpstreamGroup,
         timeout_dur: Duration,
-    ) -> anyhow::Result<Bytes> {
-        // Split timeout: first attempt uses half budget, second uses full budget.
-        let hedge_timeout = timeout_dur
-            .checked_div(2)
-            .unwrap_or_else(|| Duration::from_millis(50).max(timeout_dur));
-        let attempts = [hedge_timeout, timeout_dur];
-
-        for (idx, dur) in attempts.iter().enumerate() {
-            match self.udp_client.send(packet, upstream, *dur).await {
-                Ok(bytes) => return Ok(bytes),
+        transport: Transport,
+        client_ip: IpAddr,
+    ) -> anyhow::Result<(Bytes, String)> {
+        let (raw, used) = self.forward_group_once(packet, group, timeout_dur, transport, client_ip).await?;
+        if !is_servfail(&raw) {
+            return Ok((raw, used));
+        }
+        let (retry, default_upstream) = {
+            let cfg = self.pipeline.load();
+            (cfg.settings.servfail_retry.clone(), cfg.default_upstream.clone())
+        };
+        let Some(retry) = retry else {
+            return Ok((raw, used));
+        };
+        let retry_group = retry.upstream.unwrap_or(default_upstream);
+        let mut last = (raw, used);
+        let mut delay = Duration::from_millis(retry.backoff_ms);
+        for attempt in 1..=retry.attempts {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+            }
+            debug!(event = "servfail_retry", upstream = %last.1, retry_upstream = %retry_group, attempt, "upstream answered SERVFAIL, retrying");
+            match self.forward_group_once(packet, &retry_group, timeout_dur, transport, client_ip).await {
+                Ok((raw, used)) if is_servfail(&raw) => last = (raw, used),
+                Ok(resp) => return Ok(resp),
+                Err(err) => debug!(event = "servfail_retry", retry_upstream = %retry_group, attempt, error = %err, "servfail retry failed"),
+            }
+        }
+        Ok(last)
+    }
+
+    /// 按 upstream_strategy 依次尝试组内上游（每个上游各自享有完整超时），返回应答与实际应答的上游；
+    /// client_ip 供 client_affinity 选择起始上游
+    async fn forward_group_once(
+        &self,
+        packet: &[u8],
+        group: &UpstreamGroup,
+        timeout_dur: Duration,
+        transport: Transport,
+        client_ip: IpAddr,
+    ) -> anyhow::Result<(Bytes, String)> {
+        let members = group.members();
+        if let [only] = members {
+            let raw = self.forward_upstream(packet, only, timeout_dur, transport).await?;
+            return Ok((raw, only.clone()));
+        }
+        let (strategy, track_health, retry_refused, probe_percent) = {
+            let cfg = self.pipeline.load();
+            (
+                cfg.settings.upstream_strategy,
+                cfg.settings.health_check_interval_ms > 0,
+                cfg.settings.retry_on_upstream_refused,
+                cfg.settings.upstream_probe_percent,
+            )
+        };
+        let start = match strategy {
+            UpstreamStrategy::Failover | UpstreamStrategy::Race => 0,
+            UpstreamStrategy::RoundRobin => self.upstream_rr.fetch_add(1, Ordering::Relaxed) % members.len(),
+            UpstreamStrategy::ClientAffinity => {
+                let mut hasher = DefaultHasher::new();
+                client_ip.hash(&mut hasher);
+                (hasher.finish() % members.len() as u64) as usize
+            }
+            UpstreamStrategy::Fastest => self.fastest_start(members, probe_percent),
+        };
+        let ordered = (0..members.len()).map(|offset| &members[(start + offset) % members.len()]);
+        // 跳过不健康的上游，避免每个请求都先付出一次超时；全部不健康时仍按原顺序尝试
+        let candidates: Vec<&String> = if track_health {
+            let healthy: Vec<&String> = ordered.clone().filter(|u| self.upstream_health.is_healthy(u)).collect();
+            if healthy.is_empty() { ordered.collect() } else { healthy }
+        } else {
+            ordered.collect()
+        };
+        if strategy == UpstreamStrategy::Race {
+            return self
+                .race_upstreams(packet, &candidates, timeout_dur, transport, retry_refused)
+                .await;
+        }
+        let mut last_err = None;
+        // 上游确实应答了 REFUSED/SERVFAIL：先记下，其余上游都失败时仍以它作答
+        let mut refused: Option<(Bytes, String)> = None;
+        for upstream in candidates {
+            match self.forward_upstream(packet, upstream, timeout_dur, transport).await {
+                Ok(raw) if retry_refused && is_refused_or_servfail(&raw) => {
+                    debug!(event = "upstream_failover", upstream = %upstream, rcode = raw[3] & 0x0F, "upstream refused, trying next upstream");
+                    refused = Some((raw, upstream.clone()));
+                }
+                Ok(raw) => return Ok((raw, upstream.clone())),
                 Err(err) => {
-                    debug!(
-                        event = "udp_forward_retry",
-                        upstream = %upstream,
-                        attempt = idx + 1,
-                        timeout_ms = dur.as_millis() as u64,
-                        error = %err,
-                        "udp forward attempt failed",
-                    );
-                    if idx + 1 == attempts.len() {
-                        // Last UDP attempt, try TCP fallback before failing.
-                        debug!(event = "udp_forward_fallback_tcp", upstream = %upstream, "falling back to tcp");
-                        return self.tcp_mux.send(packet, upstream, timeout_dur).await;
+                    debug!(event = "upstream_failover", upstream = %upstream, error = %err, "trying next upstream");
+                    last_err = Some(err);
+                }
+            }
+        }
+        if let Some(resp) = refused {
+            return Ok(resp);
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no upstream configured")))
+    }
+
+    /// upstream_strategy = fastest：起始上游取 EWMA 最低者；以 probe_percent 的概率在其余上游中均匀改选一个，
+    /// 避免慢上游没有新样本、恢复后也一直不被选中。members 至少有两个
+    fn fastest_start(&self, members: &[String], probe_percent: u8) -> usize {
+        use ring::rand::SecureRandom;
+        let fastest = self.upstream_latency.fastest(members);
+        let mut roll = [0u8; 4];
+        if probe_percent > 0 && ring::rand::SystemRandom::new().fill(&mut roll).is_ok() {
+            let roll = u32::from_le_bytes(roll);
+            if roll % 100 < u32::from(probe_percent) {
+                let others = members.len() - 1;
+                return (fastest + 1 + (roll / 100) as usize % others) % members.len();
+            }
+        }
+        fastest
+    }
+
+    /// upstream_strategy = race：并发转发到所有候选上游，返回最先成功的应答；
+    /// 返回时丢弃 FuturesUnordered，未完成的转发随之取消（UDP 池的 inflight 条目由 PendingGuard 清理）
+    async fn race_upstreams(
+        &self,
+        packet: &[u8],
+        candidates: &[&String],
+        timeout_dur: Duration,
+        transport: Transport,
+        retry_refused: bool,
+    ) -> anyhow::Result<(Bytes, String)> {
+        let mut racing: FuturesUnordered<_> = candidates
+            .iter()
+            .map(|upstream| async move {
+                let res = self.forward_upstream(packet, upstream, timeout_dur, transport).await;
+                (res, *upstream)
+            })
+            .collect();
+        let mut last_err = None;
+        let mut refused: Option<(Bytes, String)> = None;
+        let race = async {
+            while let Some((res, upstream)) = racing.next().await {
+                match res {
+                    Ok(raw) if retry_refused && is_refused_or_servfail(&raw) => {
+                        refused = Some((raw, upstream.clone()));
+                    }
+                    Ok(raw) => return Some((raw, upstream.clone())),
+                    Err(err) => {
+                        debug!(event = "upstream_race", upstream = %upstream, error = %err, "racing upstream failed");
+                        last_err = Some(err);
                     }
                 }
             }
+            None
+        };
+        // 单个转发各自受 timeout_dur 限制，整体再设一次上限，截断后改走 TCP 的重试也不会拖过期限
+        let winner = tokio::time::timeout(timeout_dur, race).await;
+        if let Ok(Some((raw, upstream))) = winner {
+            info!(event = "upstream_race", winner = %upstream, racers = candidates.len(), "upstream race won");
+            return Ok((raw, upstream));
         }
+        if let Some(resp) = refused {
+            return Ok(resp);
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("upstream race timed out")))
+    }
 
-        // Should never reach here because we either return on success or fallback.
-        anyhow::bail!("udp forward failed")
+    async fn forward_upstream(
+        &self,
+        packet: &[u8],
+        upstream: &str,
+        timeout_dur: Duration,
+        transport: Transport,
+    ) -> anyhow::Result<Bytes> {
+        let start = std::time::Instant::now();
+        let stripped = {
+            let cfg = self.pipeline.load();
+            let mut stripped = if cfg.settings.strip_client_ecs {
+                strip_client_ecs(packet)
+            } else {
+                None
+            };
+            // 客户端 cookie 是发给本服务的，不转发给上游
+            if cfg.cookies.is_some()
+                && let Some(out) = strip_edns_options(stripped.as_deref().unwrap_or(packet), &[EDNS_OPTION_COOKIE])
+            {
+                stripped = Some(out);
+            }
+            stripped
+        };
+        let packet = stripped.as_deref().unwrap_or(packet);
+        let transport = self.transport_for_qtype(packet, upstream, transport);
+        // settings.qname_0x20：发出的问题名大小写随机化，应答须原样回显
+        let randomized = if self.pipeline.load().settings.qname_0x20 {
+            randomize_qname_case(packet)
+        } else {
+            None
+        };
+        // 记录到查询 span 上（多次尝试时保留最后一个），转发本身为子 span
+        tracing::Span::current().record("upstream", upstream);
+        let span = info_span!("forward", upstream = %upstream, transport = ?transport);
+        let res = self
+            .send_upstream(randomized.as_deref().unwrap_or(packet), upstream, timeout_dur, transport)
+            .instrument(span)
+            .await;
+        // 健康状态只反映传输层结果，应答被白名单拒绝不计入失败
+        let threshold = {
+            let cfg = self.pipeline.load();
+            (cfg.settings.health_check_interval_ms > 0).then_some(cfg.settings.unhealthy_threshold)
+        };
+        if let Some(threshold) = threshold {
+            if res.is_ok() {
+                self.upstream_health.record_success(upstream);
+            } else if self.upstream_health.record_failure(upstream, transport, threshold) {
+                warn!(event = "upstream_unhealthy", upstream = %upstream, failures = threshold, "upstream marked unhealthy");
+            }
+        }
+        let res = res.and_then(|raw| {
+            // UDP/TCP/DoT 已在匹配应答时丢弃 0x20 回显不一致的应答，这里兜底 DoH 与注入的 Upstream 实现；
+            // 一致时问题名恢复为客户端原样
+            let raw = match &randomized {
+                Some(sent) => {
+                    if !question_case_matches(sent, &raw) {
+                        anyhow::bail!("upstream answer does not echo the 0x20 qname case");
+                    }
+                    let mut restored = raw.to_vec();
+                    echo_question_name(packet, &mut restored);
+                    Bytes::from(restored)
+                }
+                None => raw,
+            };
+            let cfg = self.pipeline.load();
+            // 应答 IP 白名单：任一 A/AAAA 不在允许网段内即按上游失败处理
+            if !cfg.answer_ip_allowlist.is_empty() {
+                check_answer_allowlist(&raw, &cfg.answer_ip_allowlist)?;
+            }
+            let (normalize, sort) = (cfg.settings.normalize_rrset_ttl, cfg.settings.sort_answers);
+            if (normalize || sort)
+                && let Some(rewritten) = postprocess_upstream_records(&raw, normalize, sort)?
+            {
+                return Ok(rewritten);
+            }
+            Ok(raw)
+        });
+        if let Ok(_) = &res {
+            let dur = start.elapsed();
+            self.metrics_upstream_calls.fetch_add(1, Ordering::Relaxed);
+            self.metrics_upstream_ns_total.fetch_add(dur.as_nanos() as u64, Ordering::Relaxed);
+            let alpha = self.pipeline.load().settings.latency_alpha();
+            self.upstream_latency.record(upstream, dur, alpha);
+            tracing::debug!(upstream=%upstream, upstream_ns = dur.as_nanos() as u64, "upstream call latency");
+        } else if let Err(e) = &res {
+            let dur = start.elapsed();
+            // 失败按整个超时计入 EWMA，fastest 策略不会一直先选已经失效的上游
+            let alpha = self.pipeline.load().settings.latency_alpha();
+            self.upstream_latency.record(upstream, dur.max(timeout_dur), alpha);
+            tracing::warn!(upstream=%upstream, error=%e, elapsed_ns = dur.as_nanos() as u64, "upstream call failed");
+        }
+        res
+    }
+
+    /// DNS64：AAAA 查询得到 NODATA 时向同一上游补查 A，用其中的 A 记录合成 AAAA（保留 owner 与 TTL，CNAME 原样保留）。
+    /// 补查与普通转发一样经过应答 IP 白名单与记录后处理；补查失败、被白名单拒绝或没有 A 记录时返回原应答
+    async fn dns64_synthesize(
+        &self,
+        packet: &[u8],
+        raw: Bytes,
+        prefix: ipnet::Ipv6Net,
+        upstream: &str,
+        timeout_dur: Duration,
+        transport: Transport,
+    ) -> Bytes {
+        use hickory_proto::rr::RecordType;
+
+        let mut qname_buf = [0u8; 256];
+        if parse_quick(packet, &mut qname_buf).is_none_or(|q| q.qtype != u16::from(RecordType::AAAA)) {
+            return raw;
+        }
+        let Ok(resp) = Message::from_bytes(&raw) else {
+            return raw;
+        };
+        if resp.response_code() != ResponseCode::NoError || resp.answers().iter().any(|r| r.record_type() == RecordType::AAAA) {
+            return raw;
+        }
+        let Ok(mut a_query) = Message::from_bytes(packet) else {
+            return raw;
+        };
+        let queries = a_query.take_queries();
+        a_query.add_queries(queries.into_iter().map(|mut q| {
+            q.set_query_type(RecordType::A);
+            q
+        }));
+        let Ok(a_packet) = a_query.to_vec() else {
+            return raw;
+        };
+        let a_resp = match self.forward_upstream(&a_packet, upstream, timeout_dur, transport).await {
+            Ok(a_raw) => match Message::from_bytes(&a_raw) {
+                Ok(msg) => msg,
+                Err(_) => return raw,
+            },
+            Err(err) => {
+                debug!(upstream = %upstream, error = %err, "dns64 A lookup failed");
+                return raw;
+            }
+        };
+        if !a_resp.answers().iter().any(|r| r.record_type() == RecordType::A) {
+            return raw;
+        }
+        let mut out = resp.clone();
+        out.take_answers();
+        for record in a_resp.answers() {
+            match record.data() {
+                Some(RData::A(a)) => {
+                    let aaaa = RData::AAAA(AAAA(dns64_embed(prefix, a.0)));
+                    out.add_answer(Record::from_rdata(record.name().clone(), record.ttl(), aaaa));
+                }
+                _ => {
+                    out.add_answer(record.clone());
+                }
+            }
+        }
+        // 合成应答不再携带 NODATA 的 SOA
+        out.take_name_servers();
+        out.to_vec().map(Bytes::from).unwrap_or(raw)
+    }
+
+    /// tcp_for_qtypes 中的类型原本走 UDP 时改用 TCP
+    #[inline]
+    fn transport_for_qtype(&self, packet: &[u8], upstream: &str, transport: Transport) -> Transport {
+        let cfg = self.pipeline.load();
+        if cfg.tcp_for_qtypes.is_empty() || effective_transport(transport, upstream) != Transport::Udp {
+            return transport;
+        }
+        let mut qname_buf = [0u8; 256];
+        match parse_quick(packet, &mut qname_buf) {
+            Some(q) if cfg.tcp_for_qtypes.contains(&hickory_proto::rr::RecordType::from(q.qtype)) => Transport::Tcp,
+            _ => transport,
+        }
+    }
+
+    /// 按传输方式发送到单个上游，不做统计与应答校验
+    async fn send_upstream(
+        &self,
+        packet: &[u8],
+        upstream: &str,
+        timeout_dur: Duration,
+        transport: Transport,
+    ) -> anyhow::Result<Bytes> {
+        self.upstream
+            .query(packet, upstream, effective_transport(transport, upstream), timeout_dur)
+            .await
+    }
+
+    /// 登记到 inflight 去重表：首个请求成为 Leader 负责转发，其余排队等待其结果；
+    /// 等待队列达到 settings.max_inflight_waiters 时返回 Overflow，由调用方独立转发
+    fn join_inflight(&self, dedupe_hash: u64) -> InflightSlot {
+        use dashmap::mapref::entry::Entry;
+        match self.inflight.entry(dedupe_hash) {
+            Entry::Occupied(mut entry) => {
+                let max_waiters = self.pipeline.load().settings.max_inflight_waiters;
+                let waiters = entry.get_mut();
+                if max_waiters > 0 && waiters.len() >= max_waiters {
+                    self.metrics_inflight_overflows.fetch_add(1, Ordering::Relaxed);
+                    return InflightSlot::Overflow;
+                }
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                self.metrics_inflight_max_waiters.fetch_max(waiters.len(), Ordering::Relaxed);
+                InflightSlot::Waiter(rx)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Vec::new());
+                InflightSlot::Leader
+            }
+        }
     }
 
     async fn notify_inflight_waiters(&self, dedupe_hash: u64, bytes: &Bytes) {
@@ -1198,6 +3059,28 @@ impl Engine {
         for tx in waiters {
             let _ = tx.send(Ok(bytes.clone()));
         }
+        if self.pipeline.load().settings.recent_result_window_ms > 0 {
+            self.recent_results
+                .insert(dedupe_hash, (std::time::Instant::now(), bytes.clone()));
+        }
+    }
+
+    /// 窗口内刚完成的同一问题直接复用结果（改写事务 ID），与 inflight 去重一样只按 dedupe hash 匹配
+    fn recent_result(&self, dedupe_hash: u64, tx_id: u16, packet: &[u8], window_ms: u64) -> Option<Bytes> {
+        if window_ms == 0 {
+            return None;
+        }
+        let window = Duration::from_millis(window_ms).min(RECENT_RESULT_MAX_WINDOW);
+        let (at, bytes) = self.recent_results.get(&dedupe_hash)?;
+        if at.elapsed() > window {
+            return None;
+        }
+        let mut resp = bytes.to_vec();
+        if resp.len() >= 2 {
+            resp[0..2].copy_from_slice(&tx_id.to_be_bytes());
+        }
+        echo_question_name(packet, &mut resp);
+        Some(Bytes::from(resp))
     }
 
     async fn apply_response_actions(
@@ -1212,7 +3095,7 @@ impl Engine {
         qtype: hickory_proto::rr::RecordType,
         qclass: DNSClass,
         client_ip: IpAddr,
-        upstream_default: &str,
+        upstream_default: &UpstreamGroup,
         pipeline_id: &str,
         rule_name: &str,
         remaining_jumps: usize,
@@ -1243,12 +3126,45 @@ impl Engine {
                         source: "response_action",
                     });
                 }
+                Action::StaticRecordSet { records } => {
+                    let (rcode, answers) = make_static_record_answers(qname, records);
+                    let bytes = build_response(req, rcode, answers)?;
+                    return Ok(ResponseActionResult::Static {
+                        bytes,
+                        rcode,
+                        source: "response_action",
+                    });
+                }
+                Action::StaticCname { target, ttl } => {
+                    let (rcode, answers) = make_static_cname_answer(qname, target, *ttl);
+                    let bytes = build_response(req, rcode, answers)?;
+                    return Ok(ResponseActionResult::Static {
+                        bytes,
+                        rcode,
+                        source: "response_action",
+                    });
+                }
+                Action::StaticHttps {
+                    priority,
+                    target,
+                    params,
+                    ttl,
+                } => {
+                    let (rcode, answers) = make_static_https_answer(qname, *priority, target, params, *ttl);
+                    let bytes = build_response(req, rcode, answers)?;
+                    return Ok(ResponseActionResult::Static {
+                        bytes,
+                        rcode,
+                        source: "response_action",
+                    });
+                }
                 Action::JumpToPipeline { pipeline } => {
                     if remaining_jumps == 0 {
-                        let bytes = build_response(req, ResponseCode::ServFail, Vec::new())?;
+                        let settings = &self.pipeline.load().settings;
+                        let bytes = build_jump_limit_response(req, settings)?;
                         return Ok(ResponseActionResult::Static {
                             bytes,
-                            rcode: ResponseCode::ServFail,
+                            rcode: settings.jump_limit_action.rcode(),
                             source: "response_action",
                         });
                     }
@@ -1284,9 +3200,51 @@ impl Engine {
                 Action::Continue => {
                     return Ok(ResponseActionResult::Continue { ctx: ctx_opt });
                 }
+                Action::RateLimit { .. } | Action::ForceTcp => {
+                    // 限速与 force_tcp 只作用于请求阶段
+                }
+                Action::GeoStaticIp { .. } => {
+                    // 仅作用于请求阶段
+                }
+                Action::RewriteIp { nets, .. } => {
+                    if let Some(ctx) = ctx_opt.as_mut()
+                        && let Some((from, to)) = *nets
+                        && rewrite_answer_ips(&mut ctx.msg, from, to)
+                    {
+                        // 改写后重新编码，TTL 与其余段保持不变
+                        ctx.raw = Bytes::from(ctx.msg.to_bytes().context("encode rewritten response")?);
+                    }
+                }
+                Action::SetTtl { seconds } => {
+                    if let Some(ctx) = ctx_opt.as_mut() {
+                        clamp_record_ttls(ctx, (*seconds, *seconds))?;
+                    }
+                }
+                Action::ClampTtl { min, max } => {
+                    if let Some(ctx) = ctx_opt.as_mut()
+                        && let Some(clamp) = ttl_clamp(*min, *max)
+                    {
+                        clamp_record_ttls(ctx, clamp)?;
+                    }
+                }
+                Action::NxdomainIfAnswerIp { addrs, .. } => {
+                    if let Some(ctx) = ctx_opt.as_ref()
+                        && answers_contain_ip(&ctx.msg, addrs)
+                    {
+                        let bytes = build_response(req, ResponseCode::NXDomain, Vec::new())?;
+                        return Ok(ResponseActionResult::Static {
+                            bytes,
+                            rcode: ResponseCode::NXDomain,
+                            source: "response_action",
+                        });
+                    }
+                }
                 Action::Forward {
                     upstream,
                     transport,
+                    min_ttl,
+                    max_ttl,
+                    timeout_ms,
                 } => {
                     forward_attempts += 1;
                     if forward_attempts > MAX_RESPONSE_FORWARDS {
@@ -1307,22 +3265,33 @@ impl Engine {
                         });
                     }
 
-                    let upstream_addr = upstream.as_ref().cloned().unwrap_or_else(|| {
-                        ctx_opt
-                            .as_ref()
-                            .map(|ctx| ctx.upstream.clone())
-                            .unwrap_or_else(|| upstream_default.to_string())
+                    // 未指定上游时沿用当前应答的上游，没有应答时使用默认上游
+                    let group = match (upstream, ctx_opt.as_ref()) {
+                        (Some(group), _) => group.clone(),
+                        (None, Some(ctx)) => UpstreamGroup::from_members(vec![ctx.upstream.clone()]),
+                        (None, None) => upstream_default.clone(),
+                    };
+                    let use_transport = transport.unwrap_or_else(|| {
+                        let cfg = self.pipeline.load();
+                        cfg.pipelines.iter().find(|p| p.id == pipeline_id).map_or(Transport::Udp, |p| p.default_transport)
                     });
-                    let use_transport = transport.unwrap_or(Transport::Udp);
-                    let raw = match self
-                        .forward_upstream(packet, &upstream_addr, upstream_timeout, use_transport)
+                    self.pipeline_counters(pipeline_id).upstream_forwards.fetch_add(1, Ordering::Relaxed);
+                    let (raw, upstream_addr) = match self
+                        .forward_group(
+                            packet,
+                            &group,
+                            timeout_ms.map_or(upstream_timeout, Duration::from_millis),
+                            use_transport,
+                            client_ip,
+                            self.pipeline.load().dns64_prefix_for(pipeline_id),
+                        )
                         .await
                     {
-                        Ok(bytes) => bytes,
+                        Ok(resp) => resp,
                         Err(err) => {
                             warn!(
                                 event = "dns_response",
-                                upstream = %upstream_addr,
+                                upstream = %group,
                                 qname = %qname,
                                 qtype = ?qtype,
                                 client_ip = %client_ip,
@@ -1339,7 +3308,26 @@ impl Engine {
                             });
                         }
                     };
-                    let msg = Message::from_bytes(&raw).context("parse upstream response")?;
+                    let (raw, msg) = match parse_upstream_response(&raw, ttl_clamp(*min_ttl, *max_ttl)) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            let policy = self.pipeline.load().settings.response_parse_failure;
+                            warn!(
+                                event = "dns_response",
+                                upstream = %upstream_addr,
+                                qname = %qname,
+                                qtype = ?qtype,
+                                client_ip = %client_ip,
+                                pipeline = %pipeline_id,
+                                rule = %rule_name,
+                                error = %err,
+                                policy = ?policy,
+                                "response action forward failed to parse"
+                            );
+                            let bytes = response_parse_fallback(policy, packet, raw, err)?;
+                            return Ok(ResponseActionResult::Unparsed { bytes });
+                        }
+                    };
                     ctx_opt = Some(ResponseContext {
                         raw,
                         msg,
@@ -1379,10 +3367,12 @@ impl Engine {
         qname: &str,
         qtype: hickory_proto::rr::RecordType,
         qclass: DNSClass,
-        edns_present: bool,
+        edns_bufsize: Option<u16>,
         min_ttl: Duration,
-        upstream_timeout: Duration,
     ) -> anyhow::Result<Bytes> {
+        let max_negative_ttl = cfg.settings.max_negative_ttl as u64;
+        let dnssec_ok = req.extensions().as_ref().is_some_and(|e| e.dnssec_ok());
+        let ecs = client_subnet(packet);
         struct InflightCleanupGuard {
             inflight: Arc<DashMap<u64, Vec<oneshot::Sender<anyhow::Result<Bytes>>>, FxBuildHasher>>,
             hash: u64,
@@ -1414,7 +3404,7 @@ impl Engine {
 
         loop {
             if remaining_jumps == 0 {
-                let resp_bytes = build_response(req, ResponseCode::ServFail, Vec::new())?;
+                let resp_bytes = build_jump_limit_response(req, &cfg.settings)?;
                 for g in &mut cleanup_guards { g.defuse(); }
                 for h in &inflight_hashes { self.notify_inflight_waiters(*h, &resp_bytes).await; }
                 return Ok(resp_bytes);
@@ -1427,7 +3417,7 @@ impl Engine {
                 return Ok(resp_bytes);
             };
 
-            let dedupe_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, qname, qtype);
+            let dedupe_hash = Self::calculate_cache_hash_for_dedupe(&pipeline_id, qname, qtype, qclass, dnssec_ok);
             
             let mut decision = self.apply_rules(
                 cfg,
@@ -1436,7 +3426,8 @@ impl Engine {
                 qname,
                 qtype,
                 qclass,
-                edns_present,
+                edns_bufsize,
+                ecs,
                 if skip_rules.is_empty() {
                     None
                 } else {
@@ -1449,7 +3440,7 @@ impl Engine {
             loop {
                 if let Decision::Jump { pipeline } = decision {
                     if local_jumps == 0 {
-                        let resp_bytes = build_response(req, ResponseCode::ServFail, Vec::new())?;
+                        let resp_bytes = build_jump_limit_response(req, &cfg.settings)?;
                         for g in &mut cleanup_guards { g.defuse(); }
                         for h in &inflight_hashes { self.notify_inflight_waiters(*h, &resp_bytes).await; }
                         return Ok(resp_bytes);
@@ -1465,7 +3456,8 @@ impl Engine {
                             qname,
                             qtype,
                             qclass,
-                            edns_present,
+                            edns_bufsize,
+                            ecs,
                             None,
                         );
                         continue;
@@ -1482,8 +3474,15 @@ impl Engine {
             remaining_jumps = local_jumps;
 
             match decision {
+                Decision::ClientStatic { rcode, answers } => {
+                    let resp_bytes = build_response(req, rcode, answers)?;
+                    for g in &mut cleanup_guards { g.defuse(); }
+                    for h in &inflight_hashes { self.notify_inflight_waiters(*h, &resp_bytes).await; }
+                    return Ok(resp_bytes);
+                }
                 Decision::Static { rcode, answers } => {
                     let resp_bytes = build_response(req, rcode, answers)?;
+                    let (original_ttl, expires_at, stale_until) = self.cache_deadlines(min_ttl);
                     let entry = CacheEntry {
                         bytes: resp_bytes.clone(),
                         rcode,
@@ -1491,6 +3490,11 @@ impl Engine {
                         qname: Arc::from(qname),
                         pipeline_id: Arc::from(pipeline_id.as_str()),
                         qtype: u16::from(qtype),
+                        qclass: u16::from(qclass),
+                        expires_at,
+                        stale_until,
+                        original_ttl,
+                        dnssec_ok,
                     };
                     self.cache.insert(dedupe_hash, entry);
                     for g in &mut cleanup_guards { g.defuse(); }
@@ -1498,7 +3502,7 @@ impl Engine {
                     return Ok(resp_bytes);
                 }
                 Decision::Forward {
-                    upstream,
+                    upstream: upstream_group,
                     response_matchers,
                     response_matcher_operator: _response_matcher_operator,
                     response_actions_on_match,
@@ -1508,25 +3512,25 @@ impl Engine {
                     continue_on_match: _,
                     continue_on_miss: _,
                     allow_reuse,
+                    ttl_clamp,
+                    upstream_timeout: pipeline_timeout,
                 } => {
+                    // 跳转目标 pipeline 未配置超时时回到全局值，而不是沿用跳转来源的超时
+                    let upstream_timeout = pipeline_timeout.unwrap_or_else(|| cfg.upstream_timeout());
+                    let mut upstream = upstream_group.to_string();
                     let resp = if allow_reuse {
                         if let Some(ctx) = reused_response.take() {
                             Ok(ctx.raw)
                         } else {
                             {
-                                use dashmap::mapref::entry::Entry;
-                                let rx = match self.inflight.entry(dedupe_hash) {
-                                    Entry::Occupied(mut entry) => {
-                                        let (tx, rx) = oneshot::channel();
-                                        entry.get_mut().push(tx);
-                                        Some(rx)
-                                    }
-                                    Entry::Vacant(entry) => {
-                                        entry.insert(Vec::new());
+                                let rx = match self.join_inflight(dedupe_hash) {
+                                    InflightSlot::Waiter(rx) => Some(rx),
+                                    InflightSlot::Leader => {
                                         cleanup_guards.push(InflightCleanupGuard::new(self.inflight.clone(), dedupe_hash));
                                         inflight_hashes.push(dedupe_hash);
                                         None
                                     }
+                                    InflightSlot::Overflow => None,
                                 };
 
                                 if let Some(rx) = rx {
@@ -1539,6 +3543,7 @@ impl Engine {
                                                 resp_vec[0] = id_bytes[0];
                                                 resp_vec[1] = id_bytes[1];
                                             }
+                                            echo_question_name(packet, &mut resp_vec);
                                             let resp_bytes = Bytes::from(resp_vec);
 
                                             for g in &mut cleanup_guards { g.defuse(); }
@@ -1552,26 +3557,27 @@ impl Engine {
                                     }
                                 }
                             }
-                            self.forward_upstream(packet, &upstream, upstream_timeout, transport).await
+                            self.pipeline_counters(&pipeline_id).upstream_forwards.fetch_add(1, Ordering::Relaxed);
+                            self.forward_group(packet, &upstream_group, upstream_timeout, transport, peer.ip(), cfg.dns64_prefix_for(&pipeline_id))
+                        .await
+                        .map(|(raw, used)| {
+                            upstream = used;
+                            raw
+                        })
                         }
                     } else {
                         // If reuse is not allowed (e.g. explicit Forward action), we must clear any reused response
                         // and force a new request.
                         
                         {
-                            use dashmap::mapref::entry::Entry;
-                            let rx = match self.inflight.entry(dedupe_hash) {
-                                Entry::Occupied(mut entry) => {
-                                    let (tx, rx) = oneshot::channel();
-                                    entry.get_mut().push(tx);
-                                    Some(rx)
-                                }
-                                Entry::Vacant(entry) => {
-                                    entry.insert(Vec::new());
+                            let rx = match self.join_inflight(dedupe_hash) {
+                                InflightSlot::Waiter(rx) => Some(rx),
+                                InflightSlot::Leader => {
                                     cleanup_guards.push(InflightCleanupGuard::new(self.inflight.clone(), dedupe_hash));
                                     inflight_hashes.push(dedupe_hash);
                                     None
                                 }
+                                InflightSlot::Overflow => None,
                             };
 
                             if let Some(rx) = rx {
@@ -1584,6 +3590,7 @@ impl Engine {
                                             resp_vec[0] = id_bytes[0];
                                             resp_vec[1] = id_bytes[1];
                                         }
+                                        echo_question_name(packet, &mut resp_vec);
                                         let resp_bytes = Bytes::from(resp_vec);
 
                                         for g in &mut cleanup_guards { g.defuse(); }
@@ -1597,13 +3604,41 @@ impl Engine {
                                 }
                             }
                         }
-                        self.forward_upstream(packet, &upstream, upstream_timeout, transport).await
+                        self.pipeline_counters(&pipeline_id).upstream_forwards.fetch_add(1, Ordering::Relaxed);
+                        self.forward_group(packet, &upstream_group, upstream_timeout, transport, peer.ip(), cfg.dns64_prefix_for(&pipeline_id))
+                        .await
+                        .map(|(raw, used)| {
+                            upstream = used;
+                            raw
+                        })
                     };
 
                     match resp {
                         Ok(raw) => {
-                            let msg = Message::from_bytes(&raw).context("parse upstream response")?;
-                            let ttl_secs = extract_ttl(&msg);
+                            let (raw, msg) = match parse_upstream_response(&raw, ttl_clamp) {
+                                Ok(parsed) => parsed,
+                                Err(err) => {
+                                    warn!(
+                                        event = "dns_response",
+                                        upstream = %upstream,
+                                        qname = %qname,
+                                        qtype = ?qtype,
+                                        client_ip = %peer.ip(),
+                                        pipeline = %pipeline_id,
+                                        error = %err,
+                                        policy = ?cfg.settings.response_parse_failure,
+                                        "upstream response failed to parse"
+                                    );
+                                    let fallback = response_parse_fallback(cfg.settings.response_parse_failure, packet, raw, err)?;
+                                    for g in &mut cleanup_guards { g.defuse(); }
+                                    for h in &inflight_hashes { self.notify_inflight_waiters(*h, &fallback).await; }
+                                    return Ok(fallback);
+                                }
+                            };
+                            let mut ttl_secs = extract_ttl(&msg, max_negative_ttl);
+                            if let Some((lo, hi)) = ttl_clamp {
+                                ttl_secs = ttl_secs.clamp(lo as u64, hi as u64);
+                            }
                             let effective_ttl = Duration::from_secs(ttl_secs.max(min_ttl.as_secs()));
 
                             let resp_match_ok = eval_match_chain(
@@ -1626,6 +3661,7 @@ impl Engine {
 
                             if actions_to_run.is_empty() {
                                 if resp_match_ok && effective_ttl > Duration::from_secs(0) {
+                                    let (original_ttl, expires_at, stale_until) = self.cache_deadlines(effective_ttl);
                                     let entry = CacheEntry {
                                         bytes: raw.clone(),
                                         rcode: msg.response_code(),
@@ -1633,6 +3669,11 @@ impl Engine {
                                         qname: Arc::from(qname),
                                         pipeline_id: Arc::from(pipeline_id.as_str()),
                                         qtype: u16::from(qtype),
+                                        qclass: u16::from(qclass),
+                                        expires_at,
+                                        stale_until,
+                                        original_ttl,
+                                        dnssec_ok,
                                     };
                                     self.cache.insert(dedupe_hash, entry);
                                 }
@@ -1659,7 +3700,7 @@ impl Engine {
                                     qtype,
                                     qclass,
                                     peer.ip(),
-                                    cfg.settings.default_upstream.as_str(),
+                                    cfg.default_upstream_for(qname),
                                     &pipeline_id,
                                     &rule_name,
                                     remaining_jumps,
@@ -1668,10 +3709,11 @@ impl Engine {
 
                             match action_result {
                                 ResponseActionResult::Upstream { ctx, resp_match } => {
-                                    let ttl_secs = extract_ttl(&ctx.msg);
+                                    let ttl_secs = extract_ttl(&ctx.msg, max_negative_ttl);
                                     let effective_ttl =
                                         Duration::from_secs(ttl_secs.max(min_ttl.as_secs()));
                                     if resp_match && effective_ttl > Duration::from_secs(0) {
+                                        let (original_ttl, expires_at, stale_until) = self.cache_deadlines(effective_ttl);
                                         let entry = CacheEntry {
                                             bytes: ctx.raw.clone(),
                                             rcode: ctx.msg.response_code(),
@@ -1679,6 +3721,11 @@ impl Engine {
                                             qname: Arc::from(qname),
                                             pipeline_id: Arc::from(pipeline_id.as_str()),
                                             qtype: u16::from(qtype),
+                                            qclass: u16::from(qclass),
+                                            expires_at,
+                                            stale_until,
+                                            original_ttl,
+                                            dnssec_ok,
                                         };
                                         self.cache.insert(dedupe_hash, entry);
                                     }
@@ -1686,7 +3733,7 @@ impl Engine {
                                     for h in &inflight_hashes { self.notify_inflight_waiters(*h, &ctx.raw).await; }
                                     return Ok(ctx.raw);
                                 }
-                                ResponseActionResult::Static { bytes, .. } => {
+                                ResponseActionResult::Static { bytes, .. } | ResponseActionResult::Unparsed { bytes } => {
                                     for g in &mut cleanup_guards { g.defuse(); }
                                     for h in &inflight_hashes { self.notify_inflight_waiters(*h, &bytes).await; }
                                     return Ok(bytes);
@@ -1717,7 +3764,7 @@ impl Engine {
                         remaining_jumps -= 1;
                         continue;
                     } else {
-                        let resp_bytes = build_response(req, ResponseCode::ServFail, Vec::new())?;
+                        let resp_bytes = build_jump_limit_response(req, &cfg.settings)?;
                         return Ok(resp_bytes);
                     }
                 }
@@ -1731,19 +3778,28 @@ fn select_pipeline<'a>(
     qname: &str,
     client_ip: IpAddr,
     qclass: DNSClass,
-    edns_present: bool,
+    edns_bufsize: Option<u16>,
     listener_label: &str,
+    dangling_selects: &AtomicU64,
 ) -> (Option<&'a RuntimePipeline>, String) {
     for rule in &cfg.pipeline_select {
         let matched = eval_match_chain(
             &rule.matchers,
             |m| m.operator,
-            |m| m.matcher.matches(listener_label, client_ip, qname, qclass, edns_present),
+            |m| m.matcher.matches(listener_label, client_ip, qname, qclass, edns_bufsize),
         );
         if matched {
             if let Some(p) = cfg.pipelines.iter().find(|p| p.id == rule.pipeline) {
                 return (Some(p), p.id.clone());
             }
+            // from_config 已拒绝悬空目标；运行时仍出现说明配置未经校验，记录后继续匹配
+            dangling_selects.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                event = "pipeline_select_dangling",
+                pipeline = %rule.pipeline,
+                qname = %qname,
+                "pipeline_select matched a missing pipeline"
+            );
         }
     }
 
@@ -1754,57 +3810,234 @@ fn select_pipeline<'a>(
 }
 
 impl Engine {
+    /// 编译后的 pipeline；其 generation 与 cfg 不一致（热加载后尚未重新编译）时返回 None，
+    /// 调用方回退到基于 RuntimePipeline 的慢路径，避免按过期的规则索引作答
     #[inline]
-    fn compiled_for(&self, pipeline_id: &str) -> Option<CompiledPipeline> {
+    fn compiled_for(&self, cfg: &RuntimePipelineConfig, pipeline_id: &str) -> Option<CompiledPipeline> {
         let compiled = self.compiled_pipelines.load();
         compiled
             .iter()
-            .find(|p| p.id.as_ref() == pipeline_id)
+            .find(|p| p.id.as_ref() == pipeline_id && p.generation == cfg.generation)
             .cloned()
     }
 }
 
+/// 单个 pipeline 的计数；热路径上只做 Relaxed 自增
+#[derive(Debug, Default)]
+struct PipelineCounters {
+    requests: AtomicU64,
+    cache_hits: AtomicU64,
+    upstream_forwards: AtomicU64,
+    servfails: AtomicU64,
+}
+
+/// 管理接口 GET /stats/pipelines 与 /metrics 中的一个 pipeline；requests 按入口选中的 pipeline 计，
+/// upstream_forwards 按实际发起转发的 pipeline（含跳转目标）计
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PipelineStats {
+    pub pipeline: String,
+    pub requests: u64,
+    pub cache_hits: u64,
+    pub upstream_forwards: u64,
+    pub servfails: u64,
+}
+
 struct UdpSocketState {
     socket: Arc<UdpSocket>,
     // Key: Upstream ID (newly generated)
-    // Value: (Original ID, Upstream Address, Sender)
-    inflight: Arc<DashMap<u16, (u16, SocketAddr, oneshot::Sender<anyhow::Result<Bytes>>)>>,
+    inflight: Arc<DashMap<u16, UdpPending>>,
     next_id: AtomicU16,
 }
 
-/// 高性能 UDP 客户端池，使用 channel 分发 socket
-struct UdpClient {
-    pool: Vec<UdpSocketState>,
-    next_idx: AtomicUsize,
+struct UdpPending {
+    original_id: u16,
+    addr: SocketAddr,
+    /// 开启 0x20 时为发出的报文，应答须原样回显其问题名大小写
+    sent: Option<Vec<u8>>,
+    tx: oneshot::Sender<anyhow::Result<Bytes>>,
 }
 
-impl UdpClient {
-    fn new(size: usize) -> Self {
-        let mut pool = Vec::with_capacity(size);
-        if size > 0 {
-            for _ in 0..size {
-                // Use socket2 to set buffer sizes
-                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).expect("create socket");
-                // Set buffer sizes to 4MB to prevent packet loss under load
-                if let Err(e) = socket.set_recv_buffer_size(4 * 1024 * 1024) {
-                    warn!("failed to set udp recv buffer size: {}", e);
-                }
-                if let Err(e) = socket.set_send_buffer_size(4 * 1024 * 1024) {
-                    warn!("failed to set udp send buffer size: {}", e);
-                }
-                socket.bind(&"0.0.0.0:0".parse::<SocketAddr>().unwrap().into()).expect("bind");
-                socket.set_nonblocking(true).expect("set nonblocking");
-                
-                let std_sock: std::net::UdpSocket = socket.into();
-                let socket = Arc::new(tokio::net::UdpSocket::from_std(std_sock).expect("from_std"));
-                let inflight = Arc::new(DashMap::new());
-                
-                let state = UdpSocketState {
-                    socket: socket.clone(),
-                    inflight: inflight.clone(),
-                    next_id: AtomicU16::new(0),
-                };
-                pool.push(state);
+/// 应答是否属于 sent 对应的请求：未要求校验时只看 ID，否则问题名大小写也须一致
+#[inline]
+fn echoes_question(sent: Option<&[u8]>, response: &[u8]) -> bool {
+    sent.is_none_or(|sent| question_case_matches(sent, response))
+}
+
+/// 0x20 随机化后的请求副本；取不到随机数或问题名无法改写时返回 None（按原样转发）
+fn randomize_qname_case(packet: &[u8]) -> Option<Vec<u8>> {
+    use ring::rand::SecureRandom;
+    let mut bits = [0u8; 32];
+    ring::rand::SystemRandom::new().fill(&mut bits).ok()?;
+    let mut out = packet.to_vec();
+    randomize_question_case(&mut out, &bits).then_some(out)
+}
+
+/// 在 [lo, hi] 内随机选端口绑定上游 socket；端口被占用时重新抽取，多次失败后退回系统分配
+fn bind_random_port(socket: &Socket, (lo, hi): (u16, u16)) {
+    use ring::rand::SecureRandom;
+    const MAX_ATTEMPTS: usize = 64;
+    let rng = ring::rand::SystemRandom::new();
+    let span = u32::from(hi - lo) + 1;
+    for _ in 0..MAX_ATTEMPTS {
+        let mut buf = [0u8; 4];
+        if rng.fill(&mut buf).is_err() {
+            break;
+        }
+        let port = lo + (u32::from_be_bytes(buf) % span) as u16;
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        match socket.bind(&addr.into()) {
+            Ok(()) => return,
+            Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => continue,
+            Err(err) => {
+                warn!(port, error = %err, "failed to bind upstream udp socket in upstream_port_range");
+                break;
+            }
+        }
+    }
+    warn!(lo, hi, "no free port in upstream_port_range, falling back to an OS-assigned port");
+    socket.bind(&"0.0.0.0:0".parse::<SocketAddr>().unwrap().into()).expect("bind");
+}
+
+/// 连接池的源端口是否逐个递增（系统按顺序分配临时端口时，源端口可被预测）
+fn ports_look_sequential(ports: &[u16]) -> bool {
+    ports.len() >= 3 && ports.windows(2).all(|w| w[1] == w[0].wrapping_add(1))
+}
+
+/// 上游查询：把 packet 以给定传输方式发给 upstream 并返回原始应答，不做统计与应答校验。
+/// Engine 缺省使用基于 socket 的 SocketUpstream；测试可经 Engine::with_upstream 注入返回固定应答的实现
+pub trait Upstream: Send + Sync {
+    fn query<'a>(
+        &'a self,
+        packet: &'a [u8],
+        upstream: &'a str,
+        transport: Transport,
+        timeout_dur: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>>;
+}
+
+/// 缺省上游：UDP（对冲重试并回退 TCP）、TCP 多路复用、DoH 与 DoT；UDP/TCP 连接池与 Engine 共用
+struct SocketUpstream {
+    pipeline: Arc<ArcSwap<RuntimePipelineConfig>>,
+    udp_client: Arc<UdpClient>,
+    tcp_mux: Arc<TcpMultiplexer>,
+    doh_client: DohClient,
+    dot_mux: DotMultiplexer,
+}
+
+impl Upstream for SocketUpstream {
+    fn query<'a>(
+        &'a self,
+        packet: &'a [u8],
+        upstream: &'a str,
+        transport: Transport,
+        timeout_dur: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+        Box::pin(async move {
+            // settings.qname_0x20：UDP/TCP/DoT 在匹配应答时即丢弃大小写不一致的伪造应答，真正的应答仍可完成等待
+            let check_case = self.pipeline.load().settings.qname_0x20;
+            match transport {
+                Transport::Udp => self.forward_udp_smart(packet, upstream, timeout_dur, check_case).await,
+                Transport::Tcp => self.tcp_mux.send(packet, upstream, timeout_dur, check_case).await,
+                // DoH 不经过 forward_udp_smart，因此不会触发 TCP 回退
+                Transport::Doh => self.doh_client.send(packet, upstream, timeout_dur).await,
+                Transport::Dot => {
+                    let sni = self.pipeline.load().settings.dot_sni.get(upstream).cloned();
+                    self.dot_mux.send(packet, upstream, sni.as_deref(), timeout_dur, check_case).await
+                }
+            }
+        })
+    }
+}
+
+impl SocketUpstream {
+    /// UDP forwarder with hedged retry and TCP fallback for better tail latency.
+    async fn forward_udp_smart(
+        &self,
+        packet: &[u8],
+        upstream: &str,
+        timeout_dur: Duration,
+        check_case: bool,
+    ) -> anyhow::Result<Bytes> {
+        // Split timeout: first attempt uses half budget, second uses full budget.
+        let hedge_timeout = timeout_dur
+            .checked_div(2)
+            .unwrap_or_else(|| Duration::from_millis(50).max(timeout_dur));
+        let attempts = [hedge_timeout, timeout_dur];
+        // settings.tcp_fallback_timeout_ms：TCP 回退使用独立的超时，0 沿用上游超时
+        let fallback_ms = self.pipeline.load().settings.tcp_fallback_timeout_ms;
+        let tcp_timeout = if fallback_ms > 0 { Duration::from_millis(fallback_ms) } else { timeout_dur };
+
+        for (idx, dur) in attempts.iter().enumerate() {
+            match self.udp_client.send(packet, upstream, *dur, check_case).await {
+                // 截断的应答改走 TCP 取完整结果
+                Ok(bytes) if bytes.len() > 2 && bytes[2] & 0x02 != 0 => {
+                    debug!(event = "udp_truncated_fallback_tcp", upstream = %upstream, "udp answer truncated, retrying over tcp");
+                    return self.tcp_mux.send(packet, upstream, tcp_timeout, check_case).await;
+                }
+                Ok(bytes) => return Ok(bytes),
+                Err(err) => {
+                    debug!(
+                        event = "udp_forward_retry",
+                        upstream = %upstream,
+                        attempt = idx + 1,
+                        timeout_ms = dur.as_millis() as u64,
+                        error = %err,
+                        "udp forward attempt failed",
+                    );
+                    if idx + 1 == attempts.len() {
+                        // Last UDP attempt, try TCP fallback before failing.
+                        debug!(event = "udp_forward_fallback_tcp", upstream = %upstream, "falling back to tcp");
+                        return self.tcp_mux.send(packet, upstream, tcp_timeout, check_case).await;
+                    }
+                }
+            }
+        }
+
+        // Should never reach here because we either return on success or fallback.
+        anyhow::bail!("udp forward failed")
+    }
+}
+
+/// 高性能 UDP 客户端池，使用 channel 分发 socket
+struct UdpClient {
+    pool: Vec<UdpSocketState>,
+    next_idx: AtomicUsize,
+}
+
+impl UdpClient {
+    fn new(size: usize, port_range: Option<(u16, u16)>) -> Self {
+        let mut pool = Vec::with_capacity(size);
+        let mut ports = Vec::with_capacity(size);
+        if size > 0 {
+            for _ in 0..size {
+                // Use socket2 to set buffer sizes
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).expect("create socket");
+                // Set buffer sizes to 4MB to prevent packet loss under load
+                if let Err(e) = socket.set_recv_buffer_size(4 * 1024 * 1024) {
+                    warn!("failed to set udp recv buffer size: {}", e);
+                }
+                if let Err(e) = socket.set_send_buffer_size(4 * 1024 * 1024) {
+                    warn!("failed to set udp send buffer size: {}", e);
+                }
+                match port_range {
+                    Some(range) => bind_random_port(&socket, range),
+                    None => socket.bind(&"0.0.0.0:0".parse::<SocketAddr>().unwrap().into()).expect("bind"),
+                }
+                if let Some(port) = socket.local_addr().ok().and_then(|a| a.as_socket()).map(|a| a.port()) {
+                    ports.push(port);
+                }
+                socket.set_nonblocking(true).expect("set nonblocking");
+                
+                let std_sock: std::net::UdpSocket = socket.into();
+                let socket = Arc::new(tokio::net::UdpSocket::from_std(std_sock).expect("from_std"));
+                let inflight = Arc::new(DashMap::new());
+                
+                let state = UdpSocketState {
+                    socket: socket.clone(),
+                    inflight: inflight.clone(),
+                    next_id: AtomicU16::new(0),
+                };
+                pool.push(state);
 
                 let socket_clone = socket.clone();
                 let inflight_clone = inflight.clone();
@@ -1815,15 +4048,15 @@ impl UdpClient {
                             Ok((len, src)) => {
                                 if len >= 2 {
                                     let id = u16::from_be_bytes([buf[0], buf[1]]);
-                                    if let Some((_, (original_id, expected_addr, tx))) = inflight_clone.remove(&id) {
-                                        if src == expected_addr {
-                                            // Restore original ID
-                                            let mut resp_data = buf[..len].to_vec();
-                                            let orig_bytes = original_id.to_be_bytes();
-                                            resp_data[0] = orig_bytes[0];
-                                            resp_data[1] = orig_bytes[1];
-                                            let _ = tx.send(Ok(Bytes::from(resp_data)));
-                                        }
+                                    // 来源或问题名大小写不符的应答直接丢弃，条目保留给真正的应答
+                                    let resp = &buf[..len];
+                                    if let Some((_, pending)) = inflight_clone.remove_if(&id, |_, p| {
+                                        src == p.addr && echoes_question(p.sent.as_deref(), resp)
+                                    }) {
+                                        // Restore original ID
+                                        let mut resp_data = resp.to_vec();
+                                        resp_data[0..2].copy_from_slice(&pending.original_id.to_be_bytes());
+                                        let _ = pending.tx.send(Ok(Bytes::from(resp_data)));
                                     }
                                 }
                             }
@@ -1836,6 +4069,9 @@ impl UdpClient {
                 });
             }
         }
+        if port_range.is_none() && ports_look_sequential(&ports) {
+            warn!(ports = ?ports, "OS assigned sequential source ports to the upstream UDP pool; consider setting upstream_port_range");
+        }
         Self {
             pool,
             next_idx: AtomicUsize::new(0),
@@ -1848,7 +4084,9 @@ impl UdpClient {
         packet: &[u8],
         upstream: &str,
         timeout_dur: Duration,
+        check_case: bool,
     ) -> anyhow::Result<Bytes> {
+        let sent = check_case.then_some(packet);
         if self.pool.is_empty() {
             // Use a fresh socket for every request to avoid race conditions
             // caused by sharing sockets in the pool without a dispatcher.
@@ -1875,7 +4113,7 @@ impl UdpClient {
                     // Since we connected to the upstream, we only receive packets from it.
                     // And since it's a fresh socket, any packet is likely our response.
                     if size >= 2 && packet.len() >= 2 {
-                        if buf[0] == packet[0] && buf[1] == packet[1] {
+                        if buf[0] == packet[0] && buf[1] == packet[1] && echoes_question(sent, &buf[..size]) {
                             return Ok::<_, anyhow::Error>(Bytes::copy_from_slice(&buf[..size]));
                         }
                     } else {
@@ -1918,27 +4156,38 @@ impl UdpClient {
             }
         }
 
-        let (tx, rx) = oneshot::channel();
-        state.inflight.insert(new_id, (original_id, addr, tx));
-
         // Rewrite packet with new ID
         let mut new_packet = packet.to_vec();
-        let id_bytes = new_id.to_be_bytes();
-        new_packet[0] = id_bytes[0];
-        new_packet[1] = id_bytes[1];
+        new_packet[0..2].copy_from_slice(&new_id.to_be_bytes());
 
-        if let Err(e) = state.socket.send_to(&new_packet, addr).await {
-            state.inflight.remove(&new_id);
-            return Err(e.into());
+        let (tx, rx) = oneshot::channel();
+        state.inflight.insert(new_id, UdpPending {
+            original_id,
+            addr,
+            sent: sent.map(|_| new_packet.clone()),
+            tx,
+        });
+        // 发送失败、超时或调用方被取消（如 race 策略中落败的上游）时都移除 inflight 条目
+        struct PendingGuard<'a> {
+            inflight: &'a DashMap<u16, UdpPending>,
+            id: u16,
+        }
+        impl Drop for PendingGuard<'_> {
+            fn drop(&mut self) {
+                self.inflight.remove(&self.id);
+            }
         }
+        let _pending = PendingGuard {
+            inflight: &state.inflight,
+            id: new_id,
+        };
+
+        state.socket.send_to(&new_packet, addr).await?;
 
         match timeout(timeout_dur, rx).await {
             Ok(Ok(res)) => res,
             Ok(Err(_)) => Err(anyhow::anyhow!("channel closed")),
-            Err(_) => {
-                state.inflight.remove(&new_id);
-                Err(anyhow::anyhow!("upstream timeout"))
-            }
+            Err(_) => Err(anyhow::anyhow!("upstream timeout")),
         }
     }
 }
@@ -1947,6 +4196,7 @@ impl UdpClient {
 struct TcpMultiplexer {
     pools: dashmap::DashMap<String, Arc<TcpConnectionPool>>,
     pool_size: usize,
+    max_response: usize,
 }
 
 struct TcpConnectionPool {
@@ -1954,45 +4204,270 @@ struct TcpConnectionPool {
     next_idx: AtomicUsize,
 }
 
+impl TcpConnectionPool {
+    fn new(pool_size: usize, make_client: impl Fn() -> TcpMuxClient) -> Self {
+        let size = if pool_size == 0 { 1 } else { pool_size };
+        Self {
+            clients: (0..size).map(|_| Arc::new(make_client())).collect(),
+            next_idx: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn next(&self) -> &Arc<TcpMuxClient> {
+        let idx = self.next_idx.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        &self.clients[idx]
+    }
+}
+
 impl TcpMultiplexer {
-    fn new(pool_size: usize) -> Self {
+    fn new(pool_size: usize, max_response: usize) -> Self {
         Self {
             pools: dashmap::DashMap::new(),
             pool_size,
+            max_response,
         }
     }
 
+    fn pool(&self, upstream: &str) -> Arc<TcpConnectionPool> {
+        self.pools
+            .entry(upstream.to_string())
+            .or_insert_with(|| {
+                Arc::new(TcpConnectionPool::new(self.pool_size, || {
+                    TcpMuxClient::new(upstream.to_string(), self.max_response)
+                }))
+            })
+            .clone()
+    }
+
     #[inline]
     async fn send(
         &self,
         packet: &[u8],
         upstream: &str,
         timeout_dur: Duration,
+        check_case: bool,
     ) -> anyhow::Result<Bytes> {
-        let pool = self
-            .pools
-            .entry(upstream.to_string())
-            .or_insert_with(|| {
-                let mut clients = Vec::with_capacity(self.pool_size);
-                let size = if self.pool_size == 0 { 1 } else { self.pool_size };
-                for _ in 0..size {
-                    clients.push(Arc::new(TcpMuxClient::new(upstream.to_string())));
-                }
-                Arc::new(TcpConnectionPool {
-                    clients,
-                    next_idx: AtomicUsize::new(0),
-                })
-            })
-            .clone();
-        
-        let idx = pool.next_idx.fetch_add(1, Ordering::Relaxed) % pool.clients.len();
-        pool.clients[idx].send(packet, timeout_dur).await
+        self.pool(upstream).next().send(packet, timeout_dur, check_case).await
+    }
+
+    /// 预先建立该上游连接池中的全部连接，返回连接数
+    async fn prewarm(&self, upstream: &str) -> anyhow::Result<usize> {
+        let pool = self.pool(upstream);
+        for client in &pool.clients {
+            client.ensure_conn().await?;
+        }
+        Ok(pool.clients.len())
+    }
+}
+
+/// DoT (RFC 7858) 连接复用器：与 TcpMultiplexer 相同的长度前缀帧与连接池，底层为 TLS
+struct DotMultiplexer {
+    pools: dashmap::DashMap<String, Arc<TcpConnectionPool>>,
+    pool_size: usize,
+    max_response: usize,
+    tls: TlsConnector,
+}
+
+impl DotMultiplexer {
+    fn new(pool_size: usize, max_response: usize) -> Self {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Self::with_roots(pool_size, max_response, roots)
+    }
+
+    fn with_roots(pool_size: usize, max_response: usize, roots: rustls::RootCertStore) -> Self {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("default tls protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            pools: dashmap::DashMap::new(),
+            pool_size,
+            max_response,
+            tls: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    /// upstream 接受 tls://host:port 或 host:port，端口缺省 853；sni 为显式覆盖的证书主机名
+    #[inline]
+    async fn send(
+        &self,
+        packet: &[u8],
+        upstream: &str,
+        sni: Option<&str>,
+        timeout_dur: Duration,
+        check_case: bool,
+    ) -> anyhow::Result<Bytes> {
+        let key = match sni {
+            Some(name) => format!("{}#{}", upstream, name),
+            None => upstream.to_string(),
+        };
+        let pool = match self.pools.get(&key) {
+            Some(pool) => pool.clone(),
+            None => {
+                let (addr, host) = parse_dot_upstream(upstream)?;
+                let server_name = ServerName::try_from(sni.unwrap_or(&host).to_string())
+                    .with_context(|| format!("invalid dot server name for {}", upstream))?;
+                let connector = UpstreamConnector::Tls {
+                    connector: self.tls.clone(),
+                    server_name,
+                };
+                self.pools
+                    .entry(key)
+                    .or_insert_with(|| {
+                        Arc::new(TcpConnectionPool::new(self.pool_size, || {
+                            TcpMuxClient::with_connector(addr.clone(), self.max_response, connector.clone())
+                        }))
+                    })
+                    .clone()
+            }
+        };
+
+        pool.next().send(packet, timeout_dur, check_case).await
+    }
+}
+
+/// 默认的 Udp 传输遇到带 scheme 的上游时按 scheme 选择（tls:// -> DoT，https:// -> DoH）
+#[inline]
+fn effective_transport(transport: Transport, upstream: &str) -> Transport {
+    match transport {
+        Transport::Udp if upstream.starts_with("tls://") => Transport::Dot,
+        Transport::Udp if upstream.starts_with("https://") => Transport::Doh,
+        other => other,
+    }
+}
+
+/// 解析 DoT 上游，返回 (连接地址, 证书校验主机名)
+fn parse_dot_upstream(upstream: &str) -> anyhow::Result<(String, String)> {
+    let rest = upstream.strip_prefix("tls://").unwrap_or(upstream);
+    let rest = rest.trim_end_matches('/');
+    if rest.is_empty() {
+        anyhow::bail!("empty dot upstream");
+    }
+    if let Ok(addr) = rest.parse::<SocketAddr>() {
+        return Ok((addr.to_string(), addr.ip().to_string()));
+    }
+    if let Ok(ip) = rest.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Ok((SocketAddr::new(ip, 853).to_string(), ip.to_string()));
+    }
+    match rest.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>()
+                .with_context(|| format!("invalid dot upstream port: {}", upstream))?;
+            Ok((rest.to_string(), host.to_string()))
+        }
+        None => Ok((format!("{}:853", rest), rest.to_string())),
+    }
+}
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// 上游长连接的建立方式：明文 TCP 或 TLS (DoT)
+#[derive(Clone)]
+enum UpstreamConnector {
+    Tcp,
+    Tls {
+        connector: TlsConnector,
+        server_name: ServerName<'static>,
+    },
+}
+
+impl UpstreamConnector {
+    async fn connect(&self, upstream: &str) -> anyhow::Result<(BoxedReader, BoxedWriter)> {
+        let stream = TcpStream::connect(upstream).await?;
+        match self {
+            UpstreamConnector::Tcp => {
+                let (read_half, write_half) = stream.into_split();
+                Ok((Box::new(read_half), Box::new(write_half)))
+            }
+            UpstreamConnector::Tls {
+                connector,
+                server_name,
+            } => {
+                // 证书按 server_name 校验，失败即连接失败
+                let tls = connector
+                    .connect(server_name.clone(), stream)
+                    .await
+                    .context("tls handshake")?;
+                let (read_half, write_half) = tokio::io::split(tls);
+                Ok((Box::new(read_half), Box::new(write_half)))
+            }
+        }
+    }
+}
+
+/// DoH 客户端，按 URL 复用 reqwest::Client（内部自带 HTTP/2 连接池）
+struct DohClient {
+    clients: dashmap::DashMap<String, reqwest::Client>,
+    max_response: usize,
+}
+
+impl DohClient {
+    fn new(max_response: usize) -> Self {
+        Self {
+            clients: dashmap::DashMap::new(),
+            max_response,
+        }
+    }
+
+    fn client_for(&self, url: &str) -> anyhow::Result<reqwest::Client> {
+        if let Some(client) = self.clients.get(url) {
+            return Ok(client.clone());
+        }
+        let client = reqwest::Client::builder()
+            .build()
+            .context("build doh client")?;
+        Ok(self
+            .clients
+            .entry(url.to_string())
+            .or_insert(client)
+            .clone())
+    }
+
+    #[inline]
+    async fn send(&self, packet: &[u8], url: &str, timeout_dur: Duration) -> anyhow::Result<Bytes> {
+        let client = self.client_for(url)?;
+        let req = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/dns-message")
+            .header(reqwest::header::ACCEPT, "application/dns-message")
+            .body(packet.to_vec());
+        let fut = async {
+            let mut resp = req.send().await.context("doh request")?;
+            let status = resp.status();
+            if !status.is_success() {
+                anyhow::bail!("doh upstream returned http {}", status);
+            }
+            if let Some(len) = resp.content_length()
+                && len as usize > self.max_response
+            {
+                anyhow::bail!("doh response too large: {} > {}", len, self.max_response);
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = resp.chunk().await.context("read doh body")? {
+                if body.len() + chunk.len() > self.max_response {
+                    anyhow::bail!("doh response too large: > {}", self.max_response);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(Bytes::from(body))
+        };
+        match timeout(timeout_dur, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow::anyhow!("upstream timeout")),
+        }
     }
 }
 
 struct TcpMuxClient {
     upstream: String,
-    conn: Arc<Mutex<Option<OwnedWriteHalf>>>,
+    max_response: usize,
+    connector: UpstreamConnector,
+    conn: Arc<Mutex<Option<BoxedWriter>>>,
     pending: Arc<dashmap::DashMap<u16, Pending>>,
     next_id: AtomicU16,
     inflight_limit: Arc<Semaphore>,
@@ -2001,13 +4476,21 @@ struct TcpMuxClient {
 
 struct Pending {
     original_id: u16,
+    /// 开启 0x20 时为发出的报文，见 UdpPending::sent
+    sent: Option<Vec<u8>>,
     tx: oneshot::Sender<anyhow::Result<Bytes>>,
 }
 
 impl TcpMuxClient {
-    fn new(upstream: String) -> Self {
+    fn new(upstream: String, max_response: usize) -> Self {
+        Self::with_connector(upstream, max_response, UpstreamConnector::Tcp)
+    }
+
+    fn with_connector(upstream: String, max_response: usize, connector: UpstreamConnector) -> Self {
         Self {
             upstream,
+            max_response,
+            connector,
             conn: Arc::new(Mutex::new(None)),
             pending: Arc::new(dashmap::DashMap::new()),
             next_id: AtomicU16::new(1),
@@ -2021,18 +4504,18 @@ impl TcpMuxClient {
         if guard.is_some() {
             return Ok(());
         }
-        let stream = TcpStream::connect(&self.upstream).await?;
-        let (read_half, write_half) = stream.into_split();
+        let (read_half, write_half) = self.connector.connect(&self.upstream).await?;
         *guard = Some(write_half);
         drop(guard);
         self.spawn_reader(read_half).await;
         Ok(())
     }
 
-    async fn spawn_reader(&self, mut reader: OwnedReadHalf) {
+    async fn spawn_reader(&self, mut reader: BoxedReader) {
         let pending = Arc::clone(&self.pending);
         let upstream = self.upstream.clone();
         let conn = Arc::clone(&self.conn);
+        let max_response = self.max_response;
         tokio::spawn(async move {
             loop {
                 let mut len_buf = [0u8; 2];
@@ -2043,6 +4526,13 @@ impl TcpMuxClient {
                     break;
                 }
                 let resp_len = u16::from_be_bytes(len_buf) as usize;
+                if resp_len > max_response {
+                    // 无法在不读取报文的情况下定位对应请求，且帧同步已不可信，直接断开连接
+                    warn!(target = "tcp_mux", upstream = %upstream, resp_len, max_response, "tcp response exceeds limit");
+                    Self::fail_all_async(&pending, anyhow::anyhow!("tcp response too large: {} > {}", resp_len, max_response), &conn)
+                        .await;
+                    break;
+                }
                 let mut buf = vec![0u8; resp_len];
                 if let Err(err) = reader.read_exact(&mut buf).await {
                     debug!(target = "tcp_mux", upstream = %upstream, error = %err, "tcp read body failed");
@@ -2055,9 +4545,11 @@ impl TcpMuxClient {
                     continue;
                 }
                 let resp_id = u16::from_be_bytes([buf[0], buf[1]]);
-                if let Some((_, p)) = pending.remove(&resp_id) {
+                if let Some((_, p)) = pending.remove_if(&resp_id, |_, p| echoes_question(p.sent.as_deref(), &buf)) {
                     buf[0..2].copy_from_slice(&p.original_id.to_be_bytes());
                     let _ = p.tx.send(Ok(Bytes::from(buf)));
+                } else if pending.contains_key(&resp_id) {
+                    debug!(target = "tcp_mux", upstream = %upstream, resp_id, "response does not echo the 0x20 qname case");
                 } else {
                     debug!(target = "tcp_mux", upstream = %upstream, resp_id, "response with unknown id");
                 }
@@ -2065,7 +4557,7 @@ impl TcpMuxClient {
         });
     }
 
-    async fn send(&self, packet: &[u8], timeout_dur: Duration) -> anyhow::Result<Bytes> {
+    async fn send(&self, packet: &[u8], timeout_dur: Duration, check_case: bool) -> anyhow::Result<Bytes> {
         let start = tokio::time::Instant::now();
         if packet.len() < 2 {
             anyhow::bail!("dns packet too short for tcp");
@@ -2086,7 +4578,8 @@ impl TcpMuxClient {
         let (mut new_packet, new_id) = self.rewrite_id(packet).await?;
 
         let (tx, rx) = oneshot::channel();
-        self.pending.insert(new_id, Pending { original_id, tx });
+        let sent = check_case.then(|| new_packet.clone());
+        self.pending.insert(new_id, Pending { original_id, sent, tx });
 
         // 2. Ensure connection and write with remaining timeout
         let write_res = timeout(remaining, async {
@@ -2164,7 +4657,7 @@ impl TcpMuxClient {
     async fn fail_all_async(
         pending: &Arc<dashmap::DashMap<u16, Pending>>,
         err: anyhow::Error,
-        conn: &Arc<Mutex<Option<OwnedWriteHalf>>>,
+        conn: &Arc<Mutex<Option<BoxedWriter>>>,
     ) {
         let err_msg = err.to_string();
         let keys: Vec<u16> = pending.iter().map(|item| *item.key()).collect();
@@ -2176,20 +4669,52 @@ impl TcpMuxClient {
         Self::reset_conn(conn).await;
     }
 
-    async fn reset_conn(conn: &Arc<Mutex<Option<OwnedWriteHalf>>>) {
+    async fn reset_conn(conn: &Arc<Mutex<Option<BoxedWriter>>>) {
         let mut cg = conn.lock().await;
-        *cg = None;
+        if let Some(mut writer) = cg.take() {
+            // TLS 写半部被丢弃时不会关闭底层连接，显式 shutdown 让旧连接尽快结束
+            tokio::spawn(async move {
+                let _ = writer.shutdown().await;
+            });
+        }
     }
 }
 
 fn matcher_matches(
     matcher: &crate::matcher::RuntimeMatcher,
     qname: &str,
+    qtype: hickory_proto::rr::RecordType,
     qclass: DNSClass,
     client_ip: IpAddr,
-    edns_present: bool,
+    edns_bufsize: Option<u16>,
+    ecs: Option<IpAddr>,
 ) -> bool {
-    matcher.matches(qname, qclass, client_ip, edns_present)
+    matcher.matches(qname, qtype, qclass, client_ip, edns_bufsize, ecs)
+}
+
+/// 拦截应答：不带任何记录的静态应答（static_response / deny 等），SERVFAIL 视为故障而非拦截
+#[inline]
+fn is_block_answer(rcode: ResponseCode, answers: &[Record]) -> bool {
+    answers.is_empty() && rcode != ResponseCode::ServFail
+}
+
+/// 缓存中由规则直接作答的拦截应答；block_escalation 开启时命中同样计数
+fn is_cached_block(hit: &CacheEntry) -> bool {
+    &*hit.source == "static" && hit.bytes.get(6..8) == Some(&[0, 0]) && hit.rcode != ResponseCode::ServFail
+}
+
+/// 影子模式下被拦下的动作及其本应返回的 rcode；转发、跳转等不直接作答的动作返回 None
+fn shadow_rcode(action: &Action) -> Option<ResponseCode> {
+    match action {
+        Action::StaticResponse { rcode } => Some(parse_rcode(rcode).unwrap_or(ResponseCode::NXDomain)),
+        Action::Deny => Some(ResponseCode::Refused),
+        Action::StaticIpResponse { .. }
+        | Action::StaticRecordSet { .. }
+        | Action::StaticCname { .. }
+        | Action::StaticHttps { .. }
+        | Action::GeoStaticIp { .. } => Some(ResponseCode::NoError),
+        _ => None,
+    }
 }
 
 fn log_match(level: Option<&str>, rule_name: &str, qname: &str, client_ip: IpAddr) {
@@ -2212,7 +4737,77 @@ fn log_match(level: Option<&str>, rule_name: &str, qname: &str, client_ip: IpAdd
     }
 }
 
+/// RFC 6052 §2.2：按前缀长度把 IPv4 地址嵌入 IPv6 地址，跳过第 64-71 位（u 字节，须为 0）
+fn dns64_embed(prefix: ipnet::Ipv6Net, v4: std::net::Ipv4Addr) -> std::net::Ipv6Addr {
+    let mut octets = prefix.network().octets();
+    let mut pos = usize::from(prefix.prefix_len() / 8);
+    for byte in v4.octets() {
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = byte;
+        pos += 1;
+    }
+    std::net::Ipv6Addr::from(octets)
+}
+
 #[inline]
+/// 校验上游应答中所有 A/AAAA 记录均落在白名单网段内
+fn check_answer_allowlist(raw: &[u8], allowlist: &[ipnet::IpNet]) -> anyhow::Result<()> {
+    let msg = Message::from_bytes(raw).context("parse upstream response for answer allowlist")?;
+    for record in msg.answers() {
+        let ip = match record.data() {
+            Some(RData::A(a)) => IpAddr::V4(a.0),
+            Some(RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+            _ => continue,
+        };
+        if !allowlist.iter().any(|net| net.contains(&ip)) {
+            anyhow::bail!("answer ip {} for {} not in answer_ip_allowlist", ip, record.name());
+        }
+    }
+    Ok(())
+}
+
+/// 按 settings.geoip_db 打开国家数据库；配置已为 client_geo 打开时直接复用，失败时返回 None
+fn open_geo_lookup(cfg: &RuntimePipelineConfig) -> Option<Arc<dyn GeoLookup>> {
+    if let Some(geo) = &cfg.geo {
+        return Some(Arc::clone(geo));
+    }
+    let path = cfg.settings.geoip_db.as_deref()?;
+    match crate::geoip::open(path) {
+        Ok(lookup) => Some(lookup),
+        Err(err) => {
+            warn!(error = %err, "geoip db unavailable, geo_static_ip answers default");
+            None
+        }
+    }
+}
+
+/// 周期清理闲置限速桶、RRL 窗口与过期的拦截计数；Engine 全部释放后任务自行退出
+fn spawn_rate_limit_pruner(
+    limiter: std::sync::Weak<RateLimiter>,
+    rrl: std::sync::Weak<ResponseRateLimiter>,
+    blocks: std::sync::Weak<BlockEscalator>,
+) {
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    handle.spawn(async move {
+        let mut ticker = tokio::time::interval(RATE_LIMIT_IDLE);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let (Some(limiter), Some(rrl), Some(blocks)) = (limiter.upgrade(), rrl.upgrade(), blocks.upgrade()) else {
+                break;
+            };
+            limiter.prune(RATE_LIMIT_IDLE);
+            rrl.prune(RATE_LIMIT_IDLE);
+            blocks.prune();
+        }
+    });
+}
+
+/// 快速路径的静态应答；与 build_response 一样不受客户端 UDP 负载限制，超出时由 UDP 发送端的 truncate_for_udp 截断（TCP 共用同一应答）
 fn build_fast_static_response(
     tx_id: u16,
     qname: &str,
@@ -2251,28 +4846,121 @@ fn build_fast_static_response(
     Ok(Bytes::from(out))
 }
 
-pub(crate) fn make_static_ip_answer(qname: &str, ip: &str) -> (ResponseCode, Vec<Record>) {
-    if let Ok(ip_addr) = ip.parse::<IpAddr>() {
-        if let Ok(name) = Name::from_str(qname) {
-            let rdata = match ip_addr {
-                IpAddr::V4(v4) => RData::A(A(v4)),
-                IpAddr::V6(v6) => RData::AAAA(AAAA(v6)),
-            };
-            let record = Record::from_rdata(name, 300, rdata);
-            return (ResponseCode::NoError, vec![record]);
-        }
+/// static_https：参数已在加载时校验，构造失败时返回 SERVFAIL
+pub(crate) fn make_static_https_answer(
+    qname: &str,
+    priority: u16,
+    target: &str,
+    params: &HttpsParams,
+    ttl: u32,
+) -> (ResponseCode, Vec<Record>) {
+    match crate::config::https_rdata(priority, target, params) {
+        Ok(rdata) => make_static_record_answers(qname, &[StaticRecord { rdata, ttl }]),
+        Err(_) => (ResponseCode::ServFail, Vec::new()),
     }
-    (ResponseCode::ServFail, Vec::new())
 }
 
-#[cfg(test)]
-#[allow(unnameable_test_items)]
-mod tests {
-    use super::*;
+pub(crate) fn make_static_cname_answer(qname: &str, target: &str, ttl: u32) -> (ResponseCode, Vec<Record>) {
+    match crate::config::cname_rdata(target) {
+        Ok(rdata) => make_static_record_answers(qname, &[StaticRecord { rdata, ttl }]),
+        Err(_) => (ResponseCode::ServFail, Vec::new()),
+    }
+}
+
+/// 静态记录集：所有记录以查询名为 owner 一并返回
+pub(crate) fn make_static_record_answers(qname: &str, records: &[StaticRecord]) -> (ResponseCode, Vec<Record>) {
+    match Name::from_str(qname) {
+        Ok(name) => {
+            let answers = records
+                .iter()
+                .map(|r| Record::from_rdata(name.clone(), r.ttl, r.rdata.clone()))
+                .collect();
+            (ResponseCode::NoError, answers)
+        }
+        Err(_) => (ResponseCode::ServFail, Vec::new()),
+    }
+}
+
+/// static_records_file 命中时的静态决策（仅 IN 类）
+fn static_record_decision(
+    cfg: &RuntimePipelineConfig,
+    qname: &str,
+    qtype: hickory_proto::rr::RecordType,
+    qclass: DNSClass,
+) -> Option<Decision> {
+    if qclass != DNSClass::IN {
+        return None;
+    }
+    if let Some(ip) = reverse_name_ip(qname) {
+        return reverse_record_decision(cfg, qname, ip, qtype);
+    }
+    let answers = cfg.static_records.as_ref()?.lookup(qname, qtype)?;
+    Some(Decision::Static {
+        rcode: ResponseCode::NoError,
+        answers,
+    })
+}
+
+/// 完整地址的反向名字：static_records_file 中有该地址时 PTR 查询返回其名字（其他类型 NODATA）；
+/// 没有时若位于 static_reverse_zones 内返回 NXDOMAIN，否则照常转发
+fn reverse_record_decision(
+    cfg: &RuntimePipelineConfig,
+    qname: &str,
+    ip: IpAddr,
+    qtype: hickory_proto::rr::RecordType,
+) -> Option<Decision> {
+    let (rcode, answers) = match cfg.static_records.as_ref().and_then(|records| records.reverse(ip)) {
+        Some((target, ttl)) if qtype == hickory_proto::rr::RecordType::PTR => make_static_ptr_answer(qname, &target, ttl),
+        Some(_) => (ResponseCode::NoError, Vec::new()),
+        None if in_reverse_zone(&cfg.reverse_zones, qname) => (ResponseCode::NXDomain, Vec::new()),
+        None => return None,
+    };
+    Some(Decision::Static { rcode, answers })
+}
+
+#[inline]
+fn in_reverse_zone(zones: &[String], qname: &str) -> bool {
+    let qname = qname.strip_suffix('.').unwrap_or(qname);
+    zones.iter().any(|zone| {
+        qname
+            .strip_suffix(zone.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    })
+}
+
+/// 反向名字的 PTR 应答，target 规范化为 FQDN
+pub(crate) fn make_static_ptr_answer(qname: &str, target: &str, ttl: u32) -> (ResponseCode, Vec<Record>) {
+    if let (Ok(name), Ok(mut target)) = (Name::from_str(qname), Name::from_str(target)) {
+        target.set_fqdn(true);
+        return (ResponseCode::NoError, vec![Record::from_rdata(name, ttl, RData::PTR(PTR(target)))]);
+    }
+    (ResponseCode::ServFail, Vec::new())
+}
+
+pub(crate) fn make_static_ip_answer(qname: &str, ip: &str) -> (ResponseCode, Vec<Record>) {
+    if let Ok(ip_addr) = ip.parse::<IpAddr>() {
+        if let Ok(name) = Name::from_str(qname) {
+            let rdata = match ip_addr {
+                IpAddr::V4(v4) => RData::A(A(v4)),
+                IpAddr::V6(v6) => RData::AAAA(AAAA(v6)),
+            };
+            let record = Record::from_rdata(name, 300, rdata);
+            return (ResponseCode::NoError, vec![record]);
+        }
+    }
+    (ResponseCode::ServFail, Vec::new())
+}
+
+#[cfg(test)]
+#[allow(unnameable_test_items)]
+mod tests {
+    use super::*;
     use crate::config::{GlobalSettings, MatchOperator};
     use hickory_proto::rr::RecordType;
     use std::net::Ipv4Addr;
+    use std::sync::atomic::AtomicBool;
     use crate::matcher::RuntimeResponseMatcher;
+    use crate::test_util::CaptureWriter;
     use futures::future::join_all;
     use tokio::time::{timeout, Duration};
 
@@ -2295,12 +4983,13 @@ mod tests {
     #[tokio::test]
     async fn tcp_mux_rewrite_id_no_deadlock_under_contention() {
         // Prepare a client with many pending IDs to force contention on the pending lock.
-        let client = Arc::new(TcpMuxClient::new("127.0.0.1:0".to_string()));
+        let client = Arc::new(TcpMuxClient::new("127.0.0.1:0".to_string(), u16::MAX as usize));
         for id in 1u16..200u16 {
             client.pending.insert(
                 id,
                 Pending {
                     original_id: id,
+                    sent: None,
                     tx: oneshot::channel().0,
                 },
             );
@@ -2328,6 +5017,319 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn tcp_mux_rejects_oversized_upstream_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.expect("accept");
+            let mut len_buf = [0u8; 2];
+            stream.read_exact(&mut len_buf).await.expect("read len");
+            let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+            stream.read_exact(&mut query).await.expect("read query");
+            // Claim a huge body but never send it.
+            stream.write_all(&u16::MAX.to_be_bytes()).await.expect("write len");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = TcpMuxClient::new(addr.to_string(), 512);
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        let started = std::time::Instant::now();
+        let err = client
+            .send(&packet, Duration::from_secs(2), false)
+            .await
+            .expect_err("oversized response must fail");
+        assert!(err.to_string().contains("too large"), "unexpected error: {err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(client.pending.is_empty());
+        assert!(client.conn.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn udp_pool_binds_within_upstream_port_range() {
+        let client = UdpClient::new(4, Some((41000, 41099)));
+        assert_eq!(client.pool.len(), 4);
+        for state in &client.pool {
+            let port = state.socket.local_addr().expect("local addr").port();
+            assert!((41000..=41099).contains(&port), "port {port} outside range");
+        }
+
+        assert_eq!(crate::config::parse_port_range(" 1024-65535 ").unwrap(), (1024, 65535));
+        assert!(crate::config::parse_port_range("2000-1000").is_err());
+        assert!(crate::config::parse_port_range("0-100").is_err());
+        assert!(crate::config::parse_port_range("1024").is_err());
+        let bad = serde_json::json!({ "settings": { "upstream_port_range": "70000-80000" } });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(bad).expect("parse");
+        assert!(RuntimePipelineConfig::from_config(cfg).is_err());
+
+        assert!(ports_look_sequential(&[40000, 40001, 40002, 40003]));
+        assert!(!ports_look_sequential(&[40000, 51234, 40002]));
+        assert!(!ports_look_sequential(&[40000, 40001]));
+    }
+
+    #[tokio::test]
+    async fn conflicting_rrset_ttls_are_normalized_to_the_minimum() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let name = req.queries()[0].name().clone();
+            let target = Name::from_str("edge.example.net.").unwrap();
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(name, 300, RData::CNAME(hickory_proto::rr::rdata::CNAME(target.clone()))));
+            resp.add_answer(Record::from_rdata(target.clone(), 300, RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+            resp.add_answer(Record::from_rdata(target.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 2)))));
+            resp.add_answer(Record::from_rdata(target, 120, RData::A(A(Ipv4Addr::new(192, 0, 2, 3)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "normalize_rrset_ttl": true }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("www.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("resolve");
+        let ttls: Vec<(RecordType, u32)> = Message::from_bytes(&resp)
+            .unwrap()
+            .answers()
+            .iter()
+            .map(|r| (r.record_type(), r.ttl()))
+            .collect();
+        // A RRset 统一为 60；CNAME 属于另一个 RRset，保持 300
+        assert_eq!(
+            ttls,
+            vec![(RecordType::CNAME, 300), (RecordType::A, 60), (RecordType::A, 60), (RecordType::A, 60)]
+        );
+
+        assert!(!normalize_rrset_ttls(&mut Message::from_bytes(&resp).unwrap()));
+    }
+
+    #[test]
+    fn sorted_answers_encode_identically_regardless_of_upstream_order() {
+        let name = Name::from_str("multi.example.com.").unwrap();
+        let records = [
+            Record::from_rdata(name.clone(), 60, RData::AAAA(AAAA("2001:db8::1".parse().unwrap()))),
+            Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 9)))),
+            Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))),
+            Record::from_rdata(name, 60, RData::A(A(Ipv4Addr::new(198, 51, 100, 4)))),
+        ];
+        let encode = |order: &[usize]| {
+            let mut msg = Message::new();
+            msg.set_id(7);
+            msg.set_message_type(MessageType::Response);
+            for &i in order {
+                msg.add_answer(records[i].clone());
+            }
+            msg.to_vec().unwrap()
+        };
+        let first = postprocess_upstream_records(&encode(&[0, 1, 2, 3]), false, true).unwrap().expect("reordered");
+        let second = postprocess_upstream_records(&encode(&[0, 3, 2, 1]), false, true).unwrap().expect("reordered");
+        assert_eq!(first, second);
+
+        let addrs: Vec<String> = Message::from_bytes(&first)
+            .unwrap()
+            .answers()
+            .iter()
+            .map(|r| r.data().unwrap().to_string())
+            .collect();
+        // 只在 RRset 内部排序：AAAA 仍在 A 之前
+        assert_eq!(addrs, ["2001:db8::1", "192.0.2.1", "192.0.2.9", "198.51.100.4"]);
+        // 已有序时不重新编码
+        assert!(postprocess_upstream_records(&first, false, true).unwrap().is_none());
+    }
+
+    #[test]
+    fn sorting_answers_keeps_the_cname_chain_first() {
+        let alias = Name::from_str("www.example.com.").unwrap();
+        let target = Name::from_str("edge.example.net.").unwrap();
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Response);
+        msg.add_answer(Record::from_rdata(alias, 60, RData::CNAME(hickory_proto::rr::rdata::CNAME(target.clone()))));
+        msg.add_answer(Record::from_rdata(target.clone(), 60, RData::A(A(Ipv4Addr::new(198, 51, 100, 4)))));
+        msg.add_answer(Record::from_rdata(target, 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+
+        let sorted = postprocess_upstream_records(&msg.to_vec().unwrap(), false, true).unwrap().expect("reordered");
+        let answers: Vec<String> = Message::from_bytes(&sorted)
+            .unwrap()
+            .answers()
+            .iter()
+            .map(|r| r.data().unwrap().to_string())
+            .collect();
+        assert_eq!(answers, ["edge.example.net.", "192.0.2.1", "198.51.100.4"]);
+    }
+
+    #[tokio::test]
+    async fn overlong_names_are_refused_on_the_fast_path() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "tunnel",
+                            "matchers": [ { "type": "name_length", "min": 64 } ],
+                            "actions": [ { "type": "static_response", "rcode": "REFUSED" } ]
+                        },
+                        {
+                            "name": "ok",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "static_ip_response", "ip": "192.0.2.8" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // 52 字节标签 + ".example.com" 正好 64 字节；少一个字节则放行
+        let tunnel = format!("{}.example.com", "a".repeat(52));
+        let normal = format!("{}.example.com", "a".repeat(51));
+        let rcode_of = |name: &str| {
+            let packet = build_query_packet(name, RecordType::A, DNSClass::IN);
+            let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("static answer");
+            Message::from_bytes(&resp).unwrap().response_code()
+        };
+        assert_eq!(rcode_of(&tunnel), ResponseCode::Refused);
+        assert_eq!(rcode_of(&normal), ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn tcp_for_qtypes_skips_udp_for_listed_types() {
+        let (upstream, udp_hits) = spawn_counting_udp_upstream().await;
+        // 同一地址的 TCP 端，统计经 TCP 到达的查询
+        let (_, tcp_hits, _) = spawn_tcp_upstream_with(upstream, Duration::ZERO, |_| Message::new()).await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "tcp_for_qtypes": ["dnskey", "ANY"] }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let dnskey = build_query_packet("example.com", RecordType::DNSKEY, DNSClass::IN);
+        engine.handle_packet(&dnskey, peer).await.expect("dnskey over tcp");
+        assert_eq!((tcp_hits.load(Ordering::SeqCst), udp_hits.load(Ordering::SeqCst)), (1, 0));
+
+        let a = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        engine.handle_packet(&a, peer).await.expect("a over udp");
+        assert_eq!((tcp_hits.load(Ordering::SeqCst), udp_hits.load(Ordering::SeqCst)), (1, 1));
+
+        let bad = serde_json::json!({ "settings": { "tcp_for_qtypes": ["NOPE"] } });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(bad).expect("parse");
+        assert!(RuntimePipelineConfig::from_config(cfg).is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answer_is_requeried_over_tcp_by_response_rule() {
+        // UDP 端只回 TC=1 的空应答，同一地址的 TCP 端给出完整应答
+        let (upstream, udp_hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| {
+            let mut resp = Message::new();
+            resp.set_truncated(true);
+            resp
+        })
+        .await;
+        spawn_tcp_upstream_with(upstream, Duration::ZERO, answer_192_0_2_80).await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string() },
+            "pipelines": [ { "id": "main", "rules": [ {
+                "name": "tc_upgrade",
+                "matchers": [ { "type": "any" } ],
+                "actions": [ { "type": "forward" } ],
+                "response_matchers": [ { "type": "response_truncated" } ],
+                "response_actions_on_match": [ { "type": "forward", "transport": "tcp" } ]
+            } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        let packet = build_query_packet("big.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, "127.0.0.1:5300".parse().unwrap()).await.expect("response");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(udp_hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn prewarm_tcp_connects_pool_before_first_query() {
+        let (addr, _queries, accepted) = spawn_tcp_upstream_with(([127, 0, 0, 1], 0).into(), Duration::ZERO, |_| Message::new()).await;
+        let raw = serde_json::json!({
+            "settings": { "prewarm_tcp": true, "tcp_pool_size": 2 },
+            "pipelines": [
+                { "id": "p", "rules": [ { "name": "tcp", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "forward", "upstream": addr.to_string(), "transport": "tcp" } ] } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        assert_eq!(runtime.tcp_upstreams(), vec![addr.to_string()]);
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        engine.prewarm_tcp().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        for name in ["w1.example.com", "w2.example.com"] {
+            let packet = build_query_packet(name, RecordType::A, DNSClass::IN);
+            let resp = engine.handle_packet(&packet, peer).await.expect("tcp forward");
+            assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NoError);
+        }
+        // 查询复用预热的连接，未再建连
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn tcp_prewarmer_connects_new_upstreams_after_reload() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                open.push(stream);
+            }
+        });
+        let runtime = |upstream: &str| {
+            let raw = serde_json::json!({
+                "settings": { "prewarm_tcp": true, "tcp_pool_size": 2 },
+                "pipelines": [
+                    { "id": "p", "rules": [ { "name": "tcp", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "forward", "upstream": upstream, "transport": "tcp" } ] } ] }
+                ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        // 启动时的 tcp 上游无法连接：只记录日志，预热任务继续等待热加载
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime("127.0.0.1:1"))), "lbl".to_string());
+        engine.spawn_tcp_prewarmer();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+
+        engine.pipeline.store(Arc::new(runtime(&addr.to_string())));
+        engine.reload_stats().record_success();
+        for _ in 0..100 {
+            if accepted.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // 再次热加载：已建立的连接保持不变
+        engine.reload_stats().record_success();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
     #[test]
     fn make_static_ip_answer_rejects_invalid_input() {
         let (rcode, answers) = make_static_ip_answer("example.com", "not-an-ip");
@@ -2355,13 +5357,47 @@ mod tests {
             "any.example.com",
             "127.0.0.1".parse().unwrap(),
             hickory_proto::rr::DNSClass::IN,
-            false,
+            None,
             "edge",
+            &AtomicU64::new(0),
         );
         assert!(opt.is_some());
         assert_eq!(id, "p2");
     }
 
+    #[tokio::test]
+    async fn listener_label_clones_select_their_own_pipeline() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                { "id": "internal", "rules": [ { "name": "lan", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "10.0.0.10" } ] } ] },
+                { "id": "external", "rules": [ { "name": "wan", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "203.0.113.10" } ] } ] }
+            ],
+            "pipeline_select": [
+                { "pipeline": "internal", "matchers": [ { "type": "listener_label", "value": "internal" } ] },
+                { "pipeline": "external", "matchers": [ { "type": "listener_label", "value": "external" } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "default".to_string());
+        let internal = engine.with_listener_label("internal");
+        let external = engine.with_listener_label("external");
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("www.example.com", RecordType::A, DNSClass::IN);
+
+        for (listener, expected) in [(&internal, Ipv4Addr::new(10, 0, 0, 10)), (&external, Ipv4Addr::new(203, 0, 113, 10))] {
+            let resp = Message::from_bytes(&listener.handle_packet(&packet, peer).await.expect("static")).unwrap();
+            match resp.answers().first().map(|r| r.data()) {
+                Some(Some(RData::A(a))) => assert_eq!(a.0, expected),
+                other => panic!("unexpected answer: {other:?}"),
+            }
+        }
+        // 副本共享指标等状态
+        assert_eq!(engine.metrics_total_requests.load(Ordering::Relaxed), 2);
+    }
+
     #[test]
     fn pipeline_select_respects_match_operator_or() {
         let raw = serde_json::json!({
@@ -2389,13 +5425,40 @@ mod tests {
             "example.com",
             "127.0.0.1".parse().unwrap(),
             hickory_proto::rr::DNSClass::IN,
-            false,
+            None,
             "edge",
+            &AtomicU64::new(0),
         );
         assert!(opt.is_some());
         assert_eq!(id, "p2");
     }
 
+    #[test]
+    fn pipeline_select_dangling_target_is_counted_at_runtime() {
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "p1", "rules": [] }, { "id": "p2", "rules": [] } ],
+            "pipeline_select": [ { "pipeline": "p2", "matchers": [ { "type": "any" } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let mut runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        // Simulate a config that bypassed load-time validation.
+        runtime.pipelines.retain(|p| p.id != "p2");
+
+        let dangling = AtomicU64::new(0);
+        let (opt, id) = select_pipeline(
+            &runtime,
+            "example.com",
+            "127.0.0.1".parse().unwrap(),
+            hickory_proto::rr::DNSClass::IN,
+            None,
+            "edge",
+            &dangling,
+        );
+        assert!(opt.is_some());
+        assert_eq!(id, "p1");
+        assert_eq!(dangling.load(Ordering::Relaxed), 1);
+    }
+
     #[allow(dead_code)]
     #[tokio::test]
     async fn apply_rules_static_and_forward_allow_jump() {
@@ -2430,7 +5493,8 @@ mod tests {
             "a.example.com",
             hickory_proto::rr::RecordType::A,
             hickory_proto::rr::DNSClass::IN,
-            false,
+            None,
+            None,
             None,
         );
         match decision {
@@ -2438,105 +5502,3985 @@ mod tests {
             _ => panic!("expected static"),
         }
 
-        // Now test Forward action returns Forward with provided upstream and response matchers
-        let raw2 = serde_json::json!({
-            "settings": { "default_upstream": "1.1.1.1:53" },
+        // Now test Forward action returns Forward with provided upstream and response matchers
+        let raw2 = serde_json::json!({
+            "settings": { "default_upstream": "1.1.1.1:53" },
+            "pipelines": [
+                {
+                    "id": "p2",
+                    "rules": [
+                        {
+                            "name": "fwd",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": "8.8.8.8:53" } ],
+                            "response_matchers": [ { "type": "upstream_equals", "value": "8.8.8.8:53" } ],
+                            "response_matcher_operator": "and"
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg2: crate::config::PipelineConfig = serde_json::from_value(raw2).expect("parse");
+        let runtime2 = RuntimePipelineConfig::from_config(cfg2.clone()).expect("runtime");
+        let arc2 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime2.clone()));
+        let engine2 = Engine::new(arc2.clone(), "lbl".to_string());
+
+        let decision2 = engine2.apply_rules(
+            &runtime2,
+            &runtime2.pipelines[0],
+            "127.0.0.1".parse().unwrap(),
+            "x.example.com",
+            hickory_proto::rr::RecordType::A,
+            hickory_proto::rr::DNSClass::IN,
+            None,
+            None,
+            None,
+        );
+        match decision2 {
+            Decision::Forward {
+                upstream,
+                response_matchers,
+                response_matcher_operator,
+                ..
+            } => {
+                assert_eq!(upstream, "8.8.8.8:53");
+                assert_eq!(response_matchers.len(), 1);
+                assert_eq!(response_matcher_operator, crate::config::MatchOperator::And);
+            }
+            _ => panic!("expected forward"),
+        }
+
+        // Allow action -> forward to default upstream
+        let raw3 = serde_json::json!({
+            "settings": { "default_upstream": "1.2.3.4:53" },
+            "pipelines": [ { "id": "p3", "rules": [ { "name": "a", "matchers": [ { "type": "any" } ], "actions": [ { "type": "allow" } ] } ] } ]
+        });
+        let cfg3: crate::config::PipelineConfig = serde_json::from_value(raw3).expect("parse");
+        let runtime3 = RuntimePipelineConfig::from_config(cfg3.clone()).expect("runtime");
+        let arc3 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime3.clone()));
+        let engine3 = Engine::new(arc3.clone(), "lbl".to_string());
+
+        let decision3 = engine3.apply_rules(
+            &runtime3,
+            &runtime3.pipelines[0],
+            "127.0.0.1".parse().unwrap(),
+            "y.example.com",
+            hickory_proto::rr::RecordType::A,
+            hickory_proto::rr::DNSClass::IN,
+            None,
+            None,
+            None,
+        );
+        match decision3 {
+            Decision::Forward { upstream, .. } => assert_eq!(upstream, "1.2.3.4:53"),
+            _ => panic!("expected forward from allow"),
+        }
+
+        // JumpToPipeline
+        let raw4 = serde_json::json!({
+            "pipelines": [
+                { "id": "p4", "rules": [ { "name": "j", "matchers": [ { "type": "any" } ], "actions": [ { "type": "jump_to_pipeline", "pipeline": "other" } ] } ] },
+                { "id": "other", "rules": [] }
+            ]
+        });
+        let cfg4: crate::config::PipelineConfig = serde_json::from_value(raw4).expect("parse");
+        let runtime4 = RuntimePipelineConfig::from_config(cfg4.clone()).expect("runtime");
+        let arc4 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime4.clone()));
+        let engine4 = Engine::new(arc4.clone(), "lbl".to_string());
+
+        let decision4 = engine4.apply_rules(
+            &runtime4,
+            &runtime4.pipelines[0],
+            "127.0.0.1".parse().unwrap(),
+            "z.example.com",
+            hickory_proto::rr::RecordType::A,
+            hickory_proto::rr::DNSClass::IN,
+            None,
+            None,
+            None,
+        );
+        match decision4 {
+            Decision::Jump { pipeline } => assert_eq!(pipeline, "other"),
+            _ => panic!("expected jump"),
+        }
+    }
+
+    #[tokio::test]
+    async fn jump_limit_returns_configured_rcode_and_ede() {
+        let raw = serde_json::json!({
+            "settings": { "response_jump_limit": 2, "jump_limit_action": "nxdomain", "jump_limit_ede": true },
+            "pipelines": [
+                { "id": "a", "rules": [ { "name": "ja", "matchers": [ { "type": "any" } ], "actions": [ { "type": "jump_to_pipeline", "pipeline": "b" } ] } ] },
+                { "id": "b", "rules": [ { "name": "jb", "matchers": [ { "type": "any" } ], "actions": [ { "type": "jump_to_pipeline", "pipeline": "a" } ] } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("loop.example.com", RecordType::A, DNSClass::IN);
+        let resp = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.extensions().is_none());
+
+        // 带 EDNS 的请求附带 EDE 说明
+        let mut req = Message::from_bytes(&packet).unwrap();
+        req.set_edns(hickory_proto::op::Edns::new());
+        let resp = Message::from_bytes(&engine.handle_packet(&req.to_vec().unwrap(), peer).await.expect("response")).unwrap();
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        let ede = resp
+            .extensions()
+            .as_ref()
+            .and_then(|e| e.option(hickory_proto::rr::rdata::opt::EdnsCode::Unknown(15)))
+            .cloned()
+            .expect("ede option");
+        let hickory_proto::rr::rdata::opt::EdnsOption::Unknown(15, data) = ede else {
+            panic!("unexpected option {ede:?}");
+        };
+        assert_eq!(&data[..2], &[0, 0]);
+        assert_eq!(&data[2..], b"response jump limit exceeded");
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn query_span_is_exported_with_attributes_and_forward_child() {
+        use opentelemetry::Value;
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let subscriber = tracing_subscriber::registry().with(crate::otel::layer_with_provider(&provider));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (upstream, _hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 500 },
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let packet = build_query_packet("traced.example.com", RecordType::A, DNSClass::IN);
+        engine.handle_packet(&packet, "127.0.0.1:5300".parse().unwrap()).await.expect("response");
+        provider.force_flush().expect("flush");
+
+        let spans = exporter.get_finished_spans().expect("spans");
+        let query = spans.iter().find(|s| s.name == "dns_query").expect("dns_query span");
+        let attr = |key: &str| {
+            query
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attr("qname"), Some(Value::from("traced.example.com")));
+        assert_eq!(attr("qtype"), Some(Value::from("A")));
+        assert_eq!(attr("pipeline"), Some(Value::from("default")));
+        assert_eq!(attr("upstream"), Some(Value::from(upstream.to_string())));
+        assert_eq!(attr("rcode"), Some(Value::from("NoError")));
+        assert!(attr("latency_ms").is_some());
+
+        let forward = spans.iter().find(|s| s.name == "forward").expect("forward span");
+        assert_eq!(forward.parent_span_id, query.span_context.span_id());
+        assert_eq!(forward.span_context.trace_id(), query.span_context.trace_id());
+    }
+
+    #[tokio::test]
+    async fn client_geo_routes_pipeline_selection_and_rules() {
+        #[derive(Debug)]
+        struct MockGeo;
+        impl GeoLookup for MockGeo {
+            fn country(&self, ip: IpAddr) -> Option<String> {
+                match ip.to_string().as_str() {
+                    "198.51.100.2" => Some("DE".into()),
+                    "198.51.100.3" => Some("FR".into()),
+                    _ => None,
+                }
+            }
+        }
+        let raw = serde_json::json!({
+            "pipeline_select": [ { "pipeline": "eu", "matchers": [ { "type": "client_geo", "country": "de" } ] } ],
+            "pipelines": [
+                { "id": "default", "rules": [
+                    { "name": "fr", "matchers": [ { "type": "client_geo", "country": "FR" } ],
+                      "actions": [ { "type": "static_ip_response", "ip": "192.0.2.30" } ] },
+                    { "name": "rest", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "static_ip_response", "ip": "192.0.2.1" } ] }
+                ] },
+                { "id": "eu", "rules": [ { "name": "eu", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "192.0.2.20" } ] } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config_with_geo(cfg, Some(Arc::new(MockGeo))).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        let packet = build_query_packet("www.example.com", RecordType::A, DNSClass::IN);
+        // 10.0.0.1 不在库中：不匹配任何 client_geo，落到默认 pipeline 的兜底规则
+        for (client, expected) in [
+            ("198.51.100.2", Ipv4Addr::new(192, 0, 2, 20)),
+            ("198.51.100.3", Ipv4Addr::new(192, 0, 2, 30)),
+            ("10.0.0.1", Ipv4Addr::new(192, 0, 2, 1)),
+        ] {
+            let peer = SocketAddr::new(client.parse().unwrap(), 5300);
+            let resp = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+            assert_eq!(resp.answers()[0].data(), Some(&RData::A(A(expected))), "client {client}");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_asn_routes_pipeline_selection_and_rules() {
+        #[derive(Debug)]
+        struct MockAsn;
+        impl crate::geoip::AsnLookup for MockAsn {
+            fn asn(&self, ip: IpAddr) -> Option<u32> {
+                match ip.to_string().as_str() {
+                    "203.0.113.10" => Some(64496),
+                    "203.0.113.20" => Some(64511),
+                    _ => None,
+                }
+            }
+        }
+        let raw = serde_json::json!({
+            "pipeline_select": [ { "pipeline": "hosting", "matchers": [ { "type": "client_asn", "asns": [64496, 64497] } ] } ],
+            "pipelines": [
+                { "id": "default", "rules": [
+                    { "name": "partner", "matchers": [ { "type": "client_asn", "asns": [64511] } ],
+                      "actions": [ { "type": "static_ip_response", "ip": "192.0.2.30" } ] },
+                    { "name": "rest", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "static_ip_response", "ip": "192.0.2.1" } ] }
+                ] },
+                { "id": "hosting", "rules": [ { "name": "strict", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_response", "rcode": "REFUSED" } ] } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw.clone()).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config_with_lookups(cfg, None, Some(Arc::new(MockAsn))).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        let packet = build_query_packet("www.example.com", RecordType::A, DNSClass::IN);
+        let query = |client: &str| {
+            let engine = engine.clone();
+            let packet = packet.clone();
+            let peer = SocketAddr::new(client.parse().unwrap(), 5300);
+            async move { Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap() }
+        };
+        assert_eq!(query("203.0.113.10").await.response_code(), ResponseCode::Refused);
+        assert_eq!(query("203.0.113.20").await.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(192, 0, 2, 30)))));
+        // 库中查不到的客户端不匹配任何 client_asn
+        assert_eq!(query("10.0.0.1").await.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+
+        // 未配置 asn_db 时拒绝加载
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).unwrap_err();
+        assert!(format!("{err:#}").contains("requires settings.asn_db"), "{err:#}");
+    }
+
+    #[cfg(feature = "geoip")]
+    #[tokio::test]
+    async fn geo_static_ip_answers_by_client_country() {
+        #[derive(Debug)]
+        struct MockGeo;
+        impl GeoLookup for MockGeo {
+            fn country(&self, ip: IpAddr) -> Option<String> {
+                match ip.to_string().as_str() {
+                    "198.51.100.1" => Some("US".into()),
+                    "198.51.100.2" => Some("DE".into()),
+                    _ => None,
+                }
+            }
+        }
+        let raw = serde_json::json!({
+            "settings": { "min_ttl": 60 },
+            "pipelines": [
+                { "id": "p", "rules": [ { "name": "geo", "matchers": [ { "type": "domain_suffix", "value": "cdn.example.com" } ],
+                  "actions": [ { "type": "geo_static_ip", "default": "192.0.2.1", "by_country": { "us": "192.0.2.10", "DE": "192.0.2.20" } } ] } ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+            .with_geo_lookup(Arc::new(MockGeo));
+
+        let packet = build_query_packet("cdn.example.com", RecordType::A, DNSClass::IN);
+        // 同一 qname 依次由不同国家的客户端查询，答案不能被缓存串用
+        for (client, expected) in [
+            ("198.51.100.1", Ipv4Addr::new(192, 0, 2, 10)),
+            ("198.51.100.2", Ipv4Addr::new(192, 0, 2, 20)),
+            ("203.0.113.9", Ipv4Addr::new(192, 0, 2, 1)),
+            ("198.51.100.1", Ipv4Addr::new(192, 0, 2, 10)),
+        ] {
+            let peer = SocketAddr::new(client.parse().unwrap(), 5300);
+            let resp = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+            assert_eq!(resp.answers()[0].data(), Some(&RData::A(A(expected))), "client {client}");
+        }
+    }
+
+    #[tokio::test]
+    async fn query_type_rule_only_blocks_matching_qtype() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "1.1.1.1:53" },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "block_aaaa",
+                            "matchers": [ { "type": "query_type", "value": "aaaa" } ],
+                            "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime.clone())), "lbl".to_string());
+        let client_ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // The query_type-only rule must be dispatched through the compiled qtype index.
+        let compiled = engine.compiled_for(&engine.pipeline.load(), "p").expect("compiled pipeline");
+        assert_eq!(compiled.index.query_type.get(&RecordType::AAAA), Some(&vec![0]));
+        assert!(compiled.index.always_check.is_empty());
+
+        let decision_a = engine.apply_rules(
+            &runtime,
+            &runtime.pipelines[0],
+            client_ip,
+            "example.com",
+            RecordType::A,
+            DNSClass::IN,
+            None,
+            None,
+            None,
+        );
+        assert!(matches!(decision_a, Decision::Forward { .. }));
+
+        // Same name/client but a different qtype must not reuse the cached A decision.
+        let decision_aaaa = engine.apply_rules(
+            &runtime,
+            &runtime.pipelines[0],
+            client_ip,
+            "example.com",
+            RecordType::AAAA,
+            DNSClass::IN,
+            None,
+            None,
+            None,
+        );
+        match decision_aaaa {
+            Decision::Static { rcode, .. } => assert_eq!(rcode, ResponseCode::NXDomain),
+            _ => panic!("expected static for AAAA"),
+        }
+
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let a_packet = build_query_packet("other.example.com", RecordType::A, DNSClass::IN);
+        assert!(engine.handle_packet_fast(&a_packet, peer).expect("fast").is_none());
+        let aaaa_packet = build_query_packet("other.example.com", RecordType::AAAA, DNSClass::IN);
+        assert!(engine.handle_packet_fast(&aaaa_packet, peer).expect("fast").is_some());
+    }
+
+    const TEST_UPSTREAM: &str = "1.1.1.1:53";
+
+    fn build_query_packet(qname: &str, qtype: RecordType, qclass: DNSClass) -> Vec<u8> {
+        let mut msg = Message::new();
+        msg.set_id(0x1234);
+        msg.set_recursion_desired(true);
+        let mut query = Query::query(Name::from_str(qname).expect("name"), qtype);
+        query.set_query_class(qclass);
+        msg.add_query(query);
+        msg.to_vec().expect("encode query")
+    }
+
+    #[tokio::test]
+    async fn edns_present_matcher_uses_quick_parsed_opt() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "edns_only",
+                            "matchers": [ { "type": "edns_present", "expect": true } ],
+                            "actions": [ { "type": "static_response", "rcode": "REFUSED" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let mut msg = Message::from_bytes(&build_query_packet("example.com", RecordType::A, DNSClass::IN)).unwrap();
+        let mut edns = hickory_proto::op::Edns::new();
+        edns.set_max_payload(1232);
+        msg.set_edns(edns);
+        let edns_packet = msg.to_vec().unwrap();
+
+        let resp = engine.handle_packet_fast(&edns_packet, peer).expect("fast").expect("static hit");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::Refused);
+
+        // The same question without OPT must not reuse the EDNS decision from the rule cache.
+        let plain_packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        assert!(engine.handle_packet_fast(&plain_packet, peer).expect("fast").is_none());
+        let cfg = engine.pipeline.load();
+        let pipeline = &cfg.pipelines[0];
+        let ip = peer.ip();
+        let with_edns = engine.apply_rules(&cfg, pipeline, ip, "example.com", RecordType::A, DNSClass::IN, Some(1232), None, None);
+        let without = engine.apply_rules(&cfg, pipeline, ip, "example.com", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(with_edns, Decision::Static { rcode: ResponseCode::Refused, .. }));
+        assert!(matches!(without, Decision::Forward { .. }));
+    }
+
+    #[tokio::test]
+    async fn non_query_opcodes_get_notimp_unless_an_opcode_rule_answers() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "accept_notify",
+                            "matchers": [ { "type": "opcode", "value": "notify" } ],
+                            "actions": [ { "type": "static_response", "rcode": "NOERROR" } ]
+                        },
+                        {
+                            "name": "blocked_queries",
+                            "matchers": [
+                                { "type": "opcode", "value": "query" },
+                                { "type": "domain_suffix", "value": "blocked.test" }
+                            ],
+                            "actions": [ { "type": "static_response", "rcode": "REFUSED" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let with_opcode = |name: &str, opcode: u8| {
+            let mut packet = build_query_packet(name, RecordType::SOA, DNSClass::IN);
+            packet[2] = (packet[2] & !0x78) | (opcode << 3);
+            packet
+        };
+
+        // UPDATE：没有对应规则，应答 NOTIMP 并回显 opcode 与问题
+        let update = with_opcode("example.com", 5);
+        let resp = engine.handle_packet_fast(&update, peer).expect("fast").expect("notimp");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.op_code(), OpCode::Update);
+        assert_eq!(msg.response_code(), ResponseCode::NotImp);
+        assert_eq!(msg.id(), Message::from_bytes(&update).unwrap().id());
+        assert_eq!(msg.queries()[0].name().to_string(), "example.com.");
+        // 非 QUERY 请求不会触发上游转发或写入缓存
+        assert!(engine.cache.get(&Engine::calculate_cache_hash_for_dedupe("p", "example.com", RecordType::SOA, DNSClass::IN, false)).is_none());
+
+        // NOTIFY 命中 opcode 规则，按 static_response 应答；慢路径（TCP）同样处理
+        let notify = with_opcode("example.com", 4);
+        let resp = engine.handle_packet(&notify, peer).await.expect("notify");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.op_code(), OpCode::Notify);
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+
+        // 不带问题段的 IQUERY：只回头部
+        let mut iquery = vec![0u8; 12];
+        iquery[0..2].copy_from_slice(&0x1234u16.to_be_bytes());
+        iquery[2] = 1 << 3;
+        let resp = engine.handle_packet(&iquery, peer).await.expect("iquery");
+        assert_eq!(resp.as_ref(), &[0x12, 0x34, 0x80 | (1 << 3), 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        // 普通查询上 opcode=query 照常匹配
+        let resp = engine
+            .handle_packet_fast(&build_query_packet("www.blocked.test", RecordType::A, DNSClass::IN), peer)
+            .expect("fast")
+            .expect("static");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.op_code(), OpCode::Query);
+        assert_eq!(msg.response_code(), ResponseCode::Refused);
+    }
+
+    #[tokio::test]
+    async fn multi_question_queries_get_formerr() {
+        let engine = build_test_engine();
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let mut msg = Message::from_bytes(&build_query_packet("example.com", RecordType::A, DNSClass::IN)).unwrap();
+        msg.add_query(Query::query(Name::from_str("example.net.").unwrap(), RecordType::AAAA));
+        let packet = msg.to_vec().unwrap();
+
+        let mut buf = [0u8; 256];
+        assert!(parse_quick(&packet, &mut buf).expect("parse").multiple_questions);
+
+        let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("formerr");
+        let reply = Message::from_bytes(&resp).unwrap();
+        assert_eq!(reply.response_code(), ResponseCode::FormErr);
+        assert_eq!(reply.id(), msg.id());
+        assert_eq!(reply.queries().len(), 1);
+
+        let resp = engine.handle_packet(&packet, peer).await.expect("slow");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::FormErr);
+        // 第一个问题不会被当作普通查询处理
+        let hash = Engine::calculate_cache_hash_for_dedupe("default", "example.com", RecordType::A, DNSClass::IN, false);
+        assert!(engine.cache.get(&hash).is_none());
+    }
+
+    #[tokio::test]
+    async fn queries_with_too_many_edns_options_get_formerr() {
+        let raw = serde_json::json!({
+            "settings": { "max_edns_options": 8 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        { "name": "all", "matchers": [ { "type": "any" } ], "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let with_options = |count: u16| {
+            let mut msg = Message::from_bytes(&build_query_packet("opts.example.com", RecordType::A, DNSClass::IN)).unwrap();
+            let mut edns = hickory_proto::op::Edns::new();
+            for code in 0..count {
+                edns.options_mut()
+                    .insert(hickory_proto::rr::rdata::opt::EdnsOption::Unknown(65001 + code, vec![0; 2]));
+            }
+            msg.set_edns(edns);
+            msg.to_vec().unwrap()
+        };
+
+        let flood = with_options(40);
+        let mut buf = [0u8; 256];
+        assert_eq!(parse_quick(&flood, &mut buf).expect("parse").edns_options, 40);
+        let resp = engine.handle_packet_fast(&flood, peer).expect("fast").expect("formerr");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::FormErr);
+        let resp = engine.handle_packet(&flood, peer).await.expect("slow");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::FormErr);
+
+        // 上限以内照常处理
+        let resp = engine.handle_packet_fast(&with_options(8), peer).expect("fast").expect("static");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn edns_at_least_matcher_uses_advertised_bufsize() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "large_edns",
+                            "matchers": [ { "type": "edns_at_least", "bufsize": 1232 } ],
+                            "actions": [ { "type": "static_ip_response", "ip": "192.0.2.1" } ]
+                        },
+                        {
+                            "name": "fallback",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "static_ip_response", "ip": "192.0.2.2" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let query = |bufsize: Option<u16>| {
+            let mut msg = Message::from_bytes(&build_query_packet("example.com", RecordType::A, DNSClass::IN)).unwrap();
+            if let Some(size) = bufsize {
+                let mut edns = hickory_proto::op::Edns::new();
+                edns.set_max_payload(size);
+                msg.set_edns(edns);
+            }
+            msg.to_vec().unwrap()
+        };
+        // 同一问题依次以不同负载大小查询，规则缓存不能串用决策
+        for (bufsize, expected) in [
+            (Some(512), Ipv4Addr::new(192, 0, 2, 2)),
+            (Some(1232), Ipv4Addr::new(192, 0, 2, 1)),
+            (Some(4096), Ipv4Addr::new(192, 0, 2, 1)),
+            (None, Ipv4Addr::new(192, 0, 2, 2)),
+            (Some(512), Ipv4Addr::new(192, 0, 2, 2)),
+        ] {
+            let resp = engine.handle_packet(&query(bufsize), peer).await.expect("handle");
+            let msg = Message::from_bytes(&resp).unwrap();
+            match msg.answers().first().map(|r| r.data()) {
+                Some(Some(RData::A(a))) => assert_eq!(a.0, expected, "bufsize {bufsize:?}"),
+                other => panic!("unexpected answer for bufsize {bufsize:?}: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn static_record_set_returns_all_records() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "records",
+                            "matchers": [ { "type": "domain_suffix", "value": "svc.example.com" } ],
+                            "actions": [ {
+                                "type": "static_record_set",
+                                "records": [
+                                    { "type": "A", "value": "192.0.2.10", "ttl": 60 },
+                                    { "type": "AAAA", "value": "2001:db8::10", "ttl": 120 },
+                                    { "type": "TXT", "value": "v=spf1 -all" }
+                                ]
+                            } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("svc.example.com", RecordType::A, DNSClass::IN);
+
+        let fast = engine.handle_packet_fast(&packet, peer).expect("fast").expect("fast static");
+        let slow = engine.handle_packet(&packet, peer).await.expect("slow static");
+        for resp in [fast, slow] {
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            let got: Vec<(RecordType, u32)> =
+                msg.answers().iter().map(|r| (r.record_type(), r.ttl())).collect();
+            assert_eq!(
+                got,
+                vec![(RecordType::A, 60), (RecordType::AAAA, 120), (RecordType::TXT, 300)]
+            );
+            assert!(msg.answers().iter().all(|r| r.name().to_string() == "svc.example.com."));
+        }
+    }
+
+    /// big.example.com 返回 60 条 A 记录的静态应答集，超过 512 字节
+    fn large_static_answer_engine() -> Engine {
+        let records: Vec<serde_json::Value> = (0..60)
+            .map(|i| serde_json::json!({ "type": "A", "value": format!("192.0.2.{i}") }))
+            .collect();
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "p", "rules": [{
+                "name": "many",
+                "matchers": [ { "type": "domain_suffix", "value": "big.example.com" } ],
+                "actions": [ { "type": "static_record_set", "records": records } ]
+            }] }]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+    }
+
+    #[tokio::test]
+    async fn large_static_answer_is_truncated_for_udp_clients_without_edns() {
+        let engine = large_static_answer_engine();
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("big.example.com", RecordType::A, DNSClass::IN);
+        let full = engine.handle_packet(&packet, peer).await.expect("static response");
+        assert!(full.len() > crate::proto_utils::MIN_UDP_PAYLOAD);
+        // TCP 直接使用完整应答
+        assert_eq!(Message::from_bytes(&full).unwrap().answers().len(), 60);
+
+        let udp = crate::proto_utils::truncate_for_udp(&packet, full);
+        assert!(udp.len() <= crate::proto_utils::MIN_UDP_PAYLOAD);
+        let msg = Message::from_bytes(&udp).expect("parse truncated");
+        assert!(msg.truncated());
+        assert_eq!(msg.id(), 0x1234);
+        assert_eq!(msg.queries()[0].name().to_string(), "big.example.com.");
+        assert!(msg.answers().is_empty());
+
+        // 声明了更大 UDP 负载的 EDNS 客户端拿到完整应答
+        let mut edns_req = Message::from_bytes(&packet).unwrap();
+        let mut edns = hickory_proto::op::Edns::new();
+        edns.set_max_payload(1232);
+        edns_req.set_edns(edns);
+        let edns_packet = edns_req.to_vec().unwrap();
+        let resp = engine.handle_packet_fast(&edns_packet, peer).expect("fast").expect("static hit");
+        let udp = crate::proto_utils::truncate_for_udp(&edns_packet, resp.clone());
+        assert_eq!(udp, resp);
+        assert!(!Message::from_bytes(&udp).unwrap().truncated());
+    }
+
+    #[tokio::test]
+    async fn static_answer_set_honors_small_advertised_edns_payload() {
+        let engine = large_static_answer_engine();
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // 声明 512 字节（以及低于 512 的 256，按 512 处理）的 EDNS 客户端与无 EDNS 客户端一样被截断
+        for payload in [512u16, 256] {
+            let mut req = Message::from_bytes(&build_query_packet("big.example.com", RecordType::A, DNSClass::IN)).unwrap();
+            let mut edns = hickory_proto::op::Edns::new();
+            edns.set_max_payload(payload);
+            req.set_edns(edns);
+            let packet = req.to_vec().unwrap();
+            assert_eq!(crate::proto_utils::udp_payload_limit(&packet), crate::proto_utils::MIN_UDP_PAYLOAD);
+
+            let slow = engine.handle_packet(&packet, peer).await.expect("static response");
+            let fast = engine.handle_packet_fast(&packet, peer).expect("fast").expect("static hit");
+            for full in [slow, fast] {
+                assert_eq!(Message::from_bytes(&full).unwrap().answers().len(), 60);
+                let udp = crate::proto_utils::truncate_for_udp(&packet, full);
+                assert!(udp.len() <= crate::proto_utils::MIN_UDP_PAYLOAD, "payload {payload}: {} bytes", udp.len());
+                let msg = Message::from_bytes(&udp).expect("parse truncated");
+                assert!(msg.truncated(), "payload {payload}");
+                assert!(msg.answers().is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_inflight_requests_up_to_grace() {
+        let engine = build_test_engine();
+        engine.metrics_inflight.fetch_add(1, Ordering::Relaxed);
+        let inflight = engine.metrics_inflight.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            inflight.fetch_sub(1, Ordering::Relaxed);
+        });
+        assert_eq!(engine.drain(Duration::from_secs(2)).await, 0);
+
+        engine.metrics_inflight.fetch_add(1, Ordering::Relaxed);
+        let start = std::time::Instant::now();
+        assert_eq!(engine.drain(Duration::from_millis(100)).await, 1);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_refuses_before_forwarding() {
+        let (upstream, count) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "min_ttl": 60 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "limited",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [
+                                { "type": "rate_limit", "max_qps": 1, "burst": 2 },
+                                { "type": "forward", "upstream": upstream.to_string() }
+                            ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for qname in ["a.example.com", "b.example.com"] {
+            let packet = build_query_packet(qname, RecordType::A, DNSClass::IN);
+            let resp = engine.handle_packet(&packet, peer).await.expect("allowed");
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+        }
+        let packet = build_query_packet("c.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("limited");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::Refused);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.metrics_rate_limited.load(Ordering::Relaxed), 1);
+
+        // 其他客户端有独立的桶
+        let other: SocketAddr = "127.0.0.2:5300".parse().unwrap();
+        let resp = engine.handle_packet(&packet, other).await.expect("other client");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_truncate_mode_applies_on_fast_path() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "limited",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [
+                                { "type": "rate_limit", "max_qps": 1, "burst": 2, "mode": "truncate" },
+                                { "type": "static_response", "rcode": "NXDOMAIN" }
+                            ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("blocked.example.com", RecordType::A, DNSClass::IN);
+
+        // 首次走慢路径并写入规则缓存；快速路径无法作答时不消耗令牌
+        assert!(engine.handle_packet_fast(&packet, peer).expect("fast").is_none());
+        let resp = engine.handle_packet(&packet, peer).await.expect("slow");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NXDomain);
+
+        let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("rule cache hit");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NXDomain);
+
+        let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("limited");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert!(msg.truncated());
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert!(msg.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn strip_client_ecs_removes_subnet_before_forwarding() {
+        use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+        // 上游按是否收到 ECS 返回不同地址
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let saw_ecs = req.extensions().as_ref().is_some_and(|e| e.option(EdnsCode::Subnet).is_some());
+            let ip = if saw_ecs { Ipv4Addr::new(192, 0, 2, 99) } else { Ipv4Addr::new(192, 0, 2, 53) };
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(ip))));
+            resp
+        })
+        .await;
+        let mut msg = Message::from_bytes(&build_query_packet("ecs.example.com", RecordType::A, DNSClass::IN)).unwrap();
+        let mut edns = hickory_proto::op::Edns::new();
+        edns.options_mut().insert(EdnsOption::Subnet(ClientSubnet::new("198.51.100.7".parse().unwrap(), 24, 0)));
+        msg.set_edns(edns);
+        let packet = msg.to_vec().unwrap();
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for (strip, expected) in [(false, Ipv4Addr::new(192, 0, 2, 99)), (true, Ipv4Addr::new(192, 0, 2, 53))] {
+            let raw = serde_json::json!({
+                "settings": { "default_upstream": upstream.to_string(), "strip_client_ecs": strip }
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+            let resp = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("forward")).unwrap();
+            match resp.answers().first().map(|r| r.data()) {
+                Some(Some(RData::A(a))) => assert_eq!(a.0, expected, "strip_client_ecs={strip}"),
+                other => panic!("unexpected answer: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn any_policy_controls_any_queries() {
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let q = &req.queries()[0];
+            let rdata = match q.query_type() {
+                RecordType::A => RData::A(A(Ipv4Addr::new(192, 0, 2, 53))),
+                RecordType::AAAA => RData::AAAA(AAAA("2001:db8::53".parse().unwrap())),
+                other => panic!("unexpected upstream qtype {other:?}"),
+            };
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(q.name().clone(), 60, rdata));
+            resp
+        })
+        .await;
+        let engine_with = |policy: &str| {
+            let raw = serde_json::json!({
+                "settings": { "default_upstream": upstream.to_string(), "any_policy": policy }
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+        };
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let any = build_query_packet("dual.example.com", RecordType::ANY, DNSClass::IN);
+
+        // a_aaaa：两次上游查询，合并应答，问题仍为 ANY
+        let engine = engine_with("a_aaaa");
+        assert!(engine.handle_packet_fast(&any, peer).expect("fast").is_none());
+        let msg = Message::from_bytes(&engine.handle_packet(&any, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.queries()[0].query_type(), RecordType::ANY);
+        assert_eq!(msg.id(), Message::from_bytes(&any).unwrap().id());
+        let mut types: Vec<RecordType> = msg.answers().iter().map(|r| r.record_type()).collect();
+        types.sort_by_key(|t| u16::from(*t));
+        assert_eq!(types, vec![RecordType::A, RecordType::AAAA]);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        // 子查询各自进入缓存，再次 ANY 不再访问上游
+        let msg = Message::from_bytes(&engine.handle_packet(&any, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.answers().len(), 2);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // hinfo：RFC 8482 最小化应答，不访问上游
+        let engine = engine_with("hinfo");
+        let msg = Message::from_bytes(&engine.handle_packet(&any, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.answers().len(), 1);
+        match msg.answers()[0].data() {
+            Some(RData::HINFO(hinfo)) => assert_eq!(hinfo.cpu(), b"RFC8482"),
+            other => panic!("unexpected answer: {other:?}"),
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn any_policy_applies_after_request_rules() {
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let q = &req.queries()[0];
+            let mut resp = Message::new();
+            if q.name().to_ascii().starts_with("missing.") {
+                resp.set_response_code(ResponseCode::NXDomain);
+                let soa = hickory_proto::rr::rdata::SOA::new(
+                    Name::from_str("ns1.example.com.").unwrap(),
+                    Name::from_str("hostmaster.example.com.").unwrap(),
+                    1,
+                    7200,
+                    3600,
+                    1209600,
+                    900,
+                );
+                resp.add_name_server(Record::from_rdata(Name::from_str("example.com.").unwrap(), 1800, RData::SOA(soa)));
+                return resp;
+            }
+            let rdata = match q.query_type() {
+                RecordType::A => RData::A(A(Ipv4Addr::new(192, 0, 2, 53))),
+                RecordType::AAAA => RData::AAAA(AAAA("2001:db8::53".parse().unwrap())),
+                other => panic!("unexpected upstream qtype {other:?}"),
+            };
+            resp.add_answer(Record::from_rdata(q.name().clone(), 60, rdata));
+            resp
+        })
+        .await;
+        let engine_with = |policy: &str| {
+            let raw = serde_json::json!({
+                "settings": { "any_policy": policy },
+                "pipelines": [
+                    {
+                        "id": "p",
+                        "rules": [
+                            {
+                                "name": "blocked",
+                                "matchers": [ { "type": "domain_suffix", "value": "blocked.example.com" } ],
+                                "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+                            },
+                            {
+                                "name": "tcp_only_a",
+                                "matchers": [
+                                    { "type": "domain_suffix", "value": "tcp.example.com" },
+                                    { "type": "query_type", "value": "A" }
+                                ],
+                                "actions": [
+                                    { "type": "force_tcp" },
+                                    { "type": "forward", "upstream": upstream.to_string() }
+                                ]
+                            },
+                            {
+                                "name": "default",
+                                "matchers": [ { "type": "any" } ],
+                                "actions": [ { "type": "forward", "upstream": upstream.to_string() } ]
+                            }
+                        ]
+                    }
+                ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+        };
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // hinfo 不绕过请求阶段规则：被拦截的域名仍得到拦截应答
+        let engine = engine_with("hinfo");
+        let blocked = build_query_packet("ads.blocked.example.com", RecordType::ANY, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&blocked, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert!(msg.answers().is_empty());
+        let other = build_query_packet("www.example.com", RecordType::ANY, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&other, peer).await.expect("resolve")).unwrap();
+        assert!(matches!(msg.answers()[0].data(), Some(RData::HINFO(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+        // a_aaaa：A 子查询被 force_tcp 截断时，UDP 上的 ANY 整体置 TC，经 TCP 才得到合并应答
+        let engine = engine_with("a_aaaa");
+        let any = build_query_packet("www.tcp.example.com", RecordType::ANY, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&any, peer).await.expect("udp")).unwrap();
+        assert!(msg.truncated());
+        let msg = Message::from_bytes(&engine.handle_packet_tcp(&any, peer).await.expect("tcp")).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.answers().len(), 2);
+
+        // 合并后无应答记录时保留子应答的 SOA
+        let missing = build_query_packet("missing.example.com", RecordType::ANY, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&missing, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert!(msg.answers().is_empty());
+        assert!(matches!(msg.name_servers().first().and_then(|r| r.data()), Some(RData::SOA(_))));
+    }
+
+    #[tokio::test]
+    async fn coalesced_waiters_get_their_own_question_case() {
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            std::thread::sleep(Duration::from_millis(30));
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "min_ttl": 60 }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // Name::from_str 会按 IDNA 规则转为小写，这里用 from_ascii 保留原始大小写
+        let query = |name: &str| {
+            let mut msg = Message::new();
+            msg.set_id(0x4321);
+            msg.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+            msg.to_vec().unwrap()
+        };
+        let (lower, upper) = (query("mixed.example.com."), query("MiXeD.ExAmPlE.CoM."));
+        let (a, b) = futures::join!(engine.handle_packet(&lower, peer), engine.handle_packet(&upper, peer));
+        // 两个请求合并为一次上游查询，但各自收到与请求一致的问题名
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        for (resp, name) in [(a, "mixed.example.com."), (b, "MiXeD.ExAmPlE.CoM.")] {
+            let msg = Message::from_bytes(&resp.expect("resolve")).unwrap();
+            assert_eq!(msg.queries()[0].name().to_string(), name);
+        }
+    }
+
+    #[tokio::test]
+    async fn dnssec_ok_queries_are_not_coalesced_with_plain_ones() {
+        // 上游按是否收到 DO 位返回不同地址，且故意慢一点让并发请求都进入 inflight 合并窗口
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            std::thread::sleep(Duration::from_millis(30));
+            let do_bit = req.extensions().as_ref().is_some_and(|e| e.dnssec_ok());
+            let ip = if do_bit { Ipv4Addr::new(192, 0, 2, 99) } else { Ipv4Addr::new(192, 0, 2, 53) };
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(ip))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "min_ttl": 60 }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let plain = build_query_packet("dnssec.example.com", RecordType::A, DNSClass::IN);
+        let mut msg = Message::from_bytes(&plain).unwrap();
+        let mut edns = hickory_proto::op::Edns::new();
+        edns.set_dnssec_ok(true);
+        msg.set_edns(edns);
+        let with_do = msg.to_vec().unwrap();
+
+        let answer = |resp: Bytes| match Message::from_bytes(&resp).unwrap().answers().first().map(|r| r.data().cloned()) {
+            Some(Some(RData::A(a))) => a.0,
+            other => panic!("unexpected answer: {other:?}"),
+        };
+        let queries: Vec<(&[u8], Ipv4Addr)> = (0..8)
+            .map(|i| {
+                if i % 2 == 0 {
+                    (plain.as_slice(), Ipv4Addr::new(192, 0, 2, 53))
+                } else {
+                    (with_do.as_slice(), Ipv4Addr::new(192, 0, 2, 99))
+                }
+            })
+            .collect();
+        let results = join_all(queries.iter().map(|(packet, _)| engine.handle_packet(packet, peer))).await;
+        for ((_, expected), resp) in queries.iter().zip(results) {
+            assert_eq!(answer(resp.expect("resolve")), *expected);
+        }
+        // 两种请求各合并为一次上游查询
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // 之后的缓存命中同样按 DO 位区分
+        let cached_plain = engine.handle_packet_fast(&plain, peer).expect("fast").expect("cache hit");
+        let cached_do = engine.handle_packet_fast(&with_do, peer).expect("fast").expect("cache hit");
+        assert_eq!(answer(cached_plain), Ipv4Addr::new(192, 0, 2, 53));
+        assert_eq!(answer(cached_do), Ipv4Addr::new(192, 0, 2, 99));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn flush_cache_by_name_only_drops_that_name() {
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "min_ttl": 60 },
+            "pipelines": [ { "id": "p", "rules": [] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let a = build_query_packet("flush.example.com", RecordType::A, DNSClass::IN);
+        let aaaa = build_query_packet("flush.example.com", RecordType::AAAA, DNSClass::IN);
+        let other = build_query_packet("keep.example.com", RecordType::A, DNSClass::IN);
+
+        for packet in [&a, &aaaa, &other] {
+            engine.handle_packet(packet, peer).await.expect("forward");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        engine.handle_packet(&other, peer).await.expect("cached");
+        assert_eq!(engine.cache_stats(), CacheStats { entries: 3, hits: 1, misses: 3, evictions: 0 });
+
+        assert_eq!(engine.flush_cache_qname("Flush.Example.com."), 2);
+        assert!(engine.handle_packet_fast(&a, peer).expect("fast").is_none());
+        assert!(engine.handle_packet_fast(&other, peer).expect("fast").is_some());
+        engine.handle_packet(&a, peer).await.expect("refetch");
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        // 重新取回的 A 与未受影响的 keep.example.com
+        assert_eq!(engine.flush_cache(), 2);
+        assert_eq!(engine.cache_stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn response_rate_limit_drops_or_truncates_repeated_udp_answers() {
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "min_ttl": 60,
+                "rrl_responses_per_second": 2,
+                "rrl_slip": 2
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "198.51.100.7:5300".parse().unwrap();
+        let packet = build_query_packet("rrl.example.com", RecordType::A, DNSClass::IN);
+        let is_truncated = |resp: &[u8]| resp[2] & 0x02 != 0;
+
+        // 前两次放行（第一次走上游，第二次命中缓存）
+        assert!(!is_truncated(&engine.handle_packet(&packet, peer).await.expect("resolve")));
+        let cached = engine.handle_packet_fast(&packet, peer).expect("fast").expect("cache hit");
+        assert!(!is_truncated(&cached));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        // 同一 /24 的其他地址共享计数：超限后按 slip=2 交替丢弃与截断
+        let neighbour: SocketAddr = "198.51.100.200:5300".parse().unwrap();
+        assert!(engine.handle_packet_fast(&packet, neighbour).is_err());
+        let slipped = engine.handle_packet_fast(&packet, neighbour).expect("fast").expect("slip");
+        assert!(is_truncated(&slipped));
+        assert!(Message::from_bytes(&slipped).unwrap().answers().is_empty());
+        assert!(engine.handle_packet(&packet, peer).await.is_err());
+        assert_eq!(engine.metrics_rrl_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(engine.metrics_rrl_truncated.load(Ordering::Relaxed), 1);
+
+        // 其他网段与 TCP 不受影响
+        let other: SocketAddr = "203.0.113.9:5300".parse().unwrap();
+        assert!(engine.handle_packet_fast(&packet, other).expect("fast").is_some());
+        for _ in 0..5 {
+            assert!(!is_truncated(&engine.handle_packet_tcp(&packet, peer).await.expect("tcp")));
+        }
+    }
+
+    #[tokio::test]
+    async fn server_cookie_is_issued_and_required_for_large_udp_answers() {
+        use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+        // 上游收到 COOKIE 选项时返回 192.0.2.99，以此确认客户端 cookie 未被转发
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let saw_cookie = req.extensions().as_ref().is_some_and(|e| e.option(EdnsCode::Cookie).is_some());
+            let ip = if saw_cookie { Ipv4Addr::new(192, 0, 2, 99) } else { Ipv4Addr::new(192, 0, 2, 53) };
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(ip))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "min_ttl": 60,
+                "cookie_secret": "0123456789abcdef0123",
+                "cookie_tc_threshold": 48
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let with_cookie = |cookie: Vec<u8>| {
+            let mut msg = Message::from_bytes(&build_query_packet("cookie.example.com", RecordType::A, DNSClass::IN)).unwrap();
+            let mut edns = hickory_proto::op::Edns::new();
+            edns.options_mut().insert(EdnsOption::Unknown(EDNS_OPTION_COOKIE, cookie));
+            msg.set_edns(edns);
+            msg.to_vec().unwrap()
+        };
+        let cookie_of = |resp: &[u8]| edns_option(resp, EDNS_OPTION_COOKIE).map(<[u8]>::to_vec);
+        let is_truncated = |resp: &[u8]| resp[2] & 0x02 != 0;
+
+        // 只有客户端 cookie：应答超过阈值被截断，但仍签发服务器 cookie
+        let first = engine.handle_packet(&with_cookie(b"clientck".to_vec()), peer).await.expect("resolve");
+        assert!(is_truncated(&first));
+        let issued = cookie_of(&first).expect("server cookie issued");
+        assert_eq!(issued.len(), 24);
+        assert_eq!(&issued[..8], b"clientck");
+
+        // 带回有效服务器 cookie：完整应答，且上游没有收到 cookie
+        let second = engine.handle_packet(&with_cookie(issued.clone()), peer).await.expect("resolve");
+        assert!(!is_truncated(&second));
+        assert_eq!(&cookie_of(&second).expect("cookie refreshed")[..8], b"clientck");
+        match Message::from_bytes(&second).unwrap().answers().first().map(|r| r.data()) {
+            Some(Some(RData::A(a))) => assert_eq!(a.0, Ipv4Addr::new(192, 0, 2, 53)),
+            other => panic!("unexpected answer: {other:?}"),
+        }
+        // 快速路径（缓存命中）同样校验
+        let fast = engine.handle_packet_fast(&with_cookie(issued), peer).expect("fast").expect("cache hit");
+        assert!(!is_truncated(&fast));
+        let fast = engine.handle_packet_fast(&with_cookie(b"clientck".to_vec()), peer).expect("fast").expect("cache hit");
+        assert!(is_truncated(&fast));
+
+        // 长度不合法的 COOKIE 选项：快慢路径均应答 FORMERR，不回写 cookie
+        let malformed = with_cookie(b"short".to_vec());
+        let fast = engine.handle_packet_fast(&malformed, peer).expect("fast").expect("formerr");
+        let slow = engine.handle_packet(&malformed, peer).await.expect("formerr");
+        for resp in [fast, slow] {
+            assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::FormErr);
+            assert!(cookie_of(&resp).is_none());
+        }
+
+        // 没有 COOKIE 选项：不回写 cookie；经 TCP 不截断
+        let plain = build_query_packet("cookie.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet_tcp(&plain, peer).await.expect("tcp");
+        assert!(!is_truncated(&resp));
+        assert!(cookie_of(&resp).is_none());
+    }
+
+    #[tokio::test]
+    async fn query_log_records_forwarded_and_cached_answers() {
+        let (upstream, _count) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "min_ttl": 60 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "fwd",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": upstream.to_string() } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let path = std::env::temp_dir().join(format!("kixdns-engine-querylog-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = QueryLog::open(&path).expect("open query log");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string()).with_query_log(log.clone());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("log.example.com", RecordType::A, DNSClass::IN);
+
+        engine.handle_packet(&packet, peer).await.expect("forward");
+        engine.handle_packet_fast(&packet, peer).expect("fast").expect("cache hit");
+        log.flush().await;
+
+        let raw = std::fs::read_to_string(&path).expect("read query log");
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = raw.lines().map(|l| serde_json::from_str(l).expect("json line")).collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line["client_ip"], "127.0.0.1");
+            assert_eq!(line["qname"], "log.example.com");
+            assert_eq!(line["qtype"], "A");
+            assert_eq!(line["rcode"], "NoError");
+            assert_eq!(line["upstream"], upstream.to_string());
+        }
+        assert_eq!(lines[0]["cache_hit"], false);
+        assert_eq!(lines[1]["cache_hit"], true);
+    }
+
+    #[tokio::test]
+    async fn force_tcp_truncates_udp_and_resolves_over_tcp() {
+        let (upstream, count) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "min_ttl": 60 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "tcp_only",
+                            "matchers": [ { "type": "domain_suffix", "value": "secure.example.com" } ],
+                            "actions": [
+                                { "type": "force_tcp" },
+                                { "type": "forward", "upstream": upstream.to_string() }
+                            ]
+                        },
+                        {
+                            "name": "default",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": upstream.to_string() } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("www.secure.example.com", RecordType::A, DNSClass::IN);
+        let assert_truncated = |resp: &Bytes| {
+            let msg = Message::from_bytes(resp).unwrap();
+            assert!(msg.truncated());
+            assert_eq!(msg.id(), 0x1234);
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            assert!(msg.answers().is_empty());
+        };
+
+        assert_truncated(&engine.handle_packet_fast(&packet, peer).expect("fast").expect("force tcp"));
+        assert_truncated(&engine.handle_packet(&packet, peer).await.expect("udp"));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        let resp = engine.handle_packet_tcp(&packet, peer).await.expect("tcp");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 已缓存的应答也不会经 UDP 返回
+        assert_truncated(&engine.handle_packet_fast(&packet, peer).expect("fast").expect("force tcp"));
+        assert_truncated(&engine.handle_packet(&packet, peer).await.expect("udp"));
+
+        let other = build_query_packet("www.example.com", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&other, peer).await.expect("udp")).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn static_cname_answers_any_qtype_with_fqdn_target() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "alias",
+                            "matchers": [ { "type": "domain_suffix", "value": "svc.corp" } ],
+                            "actions": [ { "type": "static_cname", "target": "real.internal" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for qtype in [RecordType::A, RecordType::AAAA] {
+            let packet = build_query_packet("api.svc.corp", qtype, DNSClass::IN);
+            let fast = engine.handle_packet_fast(&packet, peer).expect("fast").expect("fast static");
+            let slow = engine.handle_packet(&packet, peer).await.expect("slow static");
+            for resp in [fast, slow] {
+                let msg = Message::from_bytes(&resp).expect("parse response");
+                assert_eq!(msg.response_code(), ResponseCode::NoError);
+                assert_eq!(msg.answers().len(), 1);
+                let answer = &msg.answers()[0];
+                assert_eq!(answer.name().to_string(), "api.svc.corp.");
+                let Some(RData::CNAME(cname)) = answer.data() else {
+                    panic!("expected CNAME record: {:?}", msg.answers());
+                };
+                assert_eq!(cname.0.to_string(), "real.internal.");
+            }
+        }
+
+        let bad = serde_json::json!({
+            "pipelines": [ { "id": "p", "rules": [ { "name": "bad-alias", "actions": [ { "type": "static_cname", "target": "bad..name" } ] } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(bad).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("invalid target");
+        assert!(format!("{err:#}").contains("rule bad-alias: invalid static_cname"));
+    }
+
+    #[tokio::test]
+    async fn static_https_returns_svcb_params() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "apex-https",
+                            "matchers": [
+                                { "type": "domain_suffix", "value": "example.com" },
+                                { "type": "query_type", "value": "HTTPS" }
+                            ],
+                            "actions": [ {
+                                "type": "static_https",
+                                "priority": 1,
+                                "target": "cdn.example.net",
+                                "params": { "alpn": ["h3", "h2"], "port": 443, "ipv4hint": ["192.0.2.1"] }
+                            } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("example.com", RecordType::HTTPS, DNSClass::IN);
+
+        let fast = engine.handle_packet_fast(&packet, peer).expect("fast").expect("fast static");
+        let slow = engine.handle_packet(&packet, peer).await.expect("slow static");
+        for resp in [fast, slow] {
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            assert_eq!(msg.answers().len(), 1);
+            let Some(RData::HTTPS(https)) = msg.answers()[0].data() else {
+                panic!("expected HTTPS record: {:?}", msg.answers());
+            };
+            assert_eq!(https.svc_priority(), 1);
+            assert_eq!(https.target_name().to_string(), "cdn.example.net.");
+            let alpn = https.svc_params().iter().find_map(|(_, v)| match v {
+                hickory_proto::rr::rdata::svcb::SvcParamValue::Alpn(a) => Some(a.0.clone()),
+                _ => None,
+            });
+            assert_eq!(alpn, Some(vec!["h3".to_string(), "h2".to_string()]));
+        }
+
+        let bad: crate::config::PipelineConfig = serde_json::from_value(serde_json::json!({
+            "pipelines": [ { "id": "p", "rules": [ {
+                "name": "alias", "matchers": [],
+                "actions": [ { "type": "static_https", "priority": 0, "target": "x.example.net", "params": { "port": 443 } } ]
+            } ] } ]
+        }))
+        .expect("parse");
+        let err = RuntimePipelineConfig::from_config(bad).expect_err("alias with params");
+        assert!(format!("{err:#}").contains("rule alias"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn cache_entries_are_keyed_by_qclass() {
+        let raw = serde_json::json!({
+            "settings": { "min_ttl": 60 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "in_only",
+                            "matchers": [ { "type": "qclass", "value": "IN" } ],
+                            "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let in_packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&in_packet, peer).await.expect("static response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+
+        let in_hash =
+            Engine::calculate_cache_hash_for_dedupe("p", "example.com", RecordType::A, DNSClass::IN, false);
+        let ch_hash =
+            Engine::calculate_cache_hash_for_dedupe("p", "example.com", RecordType::A, DNSClass::CH, false);
+        assert_ne!(in_hash, ch_hash);
+        assert!(engine.cache.get(&in_hash).is_some());
+        assert!(engine.cache.get(&ch_hash).is_none());
+
+        // The CH query must not be answered from the IN entry on the fast path.
+        let ch_packet = build_query_packet("example.com", RecordType::A, DNSClass::CH);
+        let fast = engine.handle_packet_fast(&ch_packet, peer).expect("fast path");
+        assert!(fast.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_answers_served_during_outage_are_counted_as_degraded() {
+        // Reserve a local port and release it so both UDP and TCP upstream attempts fail.
+        let dead_upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": dead_upstream.to_string(),
+                "upstream_timeout_ms": 100,
+                "serve_stale_secs": 60
+            },
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let mut upstream_resp = Message::new();
+        upstream_resp.set_id(0xbeef);
+        upstream_resp.set_message_type(MessageType::Response);
+        upstream_resp.add_answer(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            30,
+            RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+        ));
+        let stale_bytes = Bytes::from(upstream_resp.to_vec().unwrap());
+        let now = std::time::Instant::now();
+        let hash = Engine::calculate_cache_hash_for_dedupe("default", "example.com", RecordType::A, DNSClass::IN, false);
+        engine.cache.insert(
+            hash,
+            CacheEntry {
+                bytes: stale_bytes.clone(),
+                rcode: ResponseCode::NoError,
+                source: Arc::from(dead_upstream.to_string().as_str()),
+                qname: Arc::from("example.com"),
+                pipeline_id: Arc::from("default"),
+                qtype: u16::from(RecordType::A),
+                qclass: u16::from(DNSClass::IN),
+                expires_at: now - Duration::from_secs(1),
+                stale_until: now + Duration::from_secs(60),
+                original_ttl: Duration::from_secs(60),
+                dnssec_ok: false,
+            },
+        );
+
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        // Expired entries never satisfy the fast path.
+        assert!(engine.handle_packet_fast(&packet, peer).expect("fast").is_none());
+        assert_eq!(engine.metrics_degraded_responses.load(Ordering::Relaxed), 0);
+
+        for _ in 0..2 {
+            let resp = engine.handle_packet(&packet, peer).await.expect("stale response");
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.id(), 0x1234);
+            assert_eq!(&resp[2..], &stale_bytes[2..]);
+        }
+        assert_eq!(engine.metrics_degraded_responses.load(Ordering::Relaxed), 2);
+        assert!(engine.metrics_snapshot().contains("degraded=2"));
+
+        // Without a stale entry the outage still surfaces as SERVFAIL and is not counted.
+        let other = build_query_packet("other.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&other, peer).await.expect("servfail response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+        assert_eq!(engine.metrics_degraded_responses.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_hit_is_served_immediately_and_refreshed_in_background() {
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "upstream_timeout_ms": 500,
+                "serve_stale_secs": 60
+            },
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let mut old = Message::new();
+        old.set_message_type(MessageType::Response);
+        old.add_answer(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+        ));
+        let now = std::time::Instant::now();
+        let hash = Engine::calculate_cache_hash_for_dedupe("default", "example.com", RecordType::A, DNSClass::IN, false);
+        engine.cache.insert(
+            hash,
+            CacheEntry {
+                bytes: Bytes::from(old.to_vec().unwrap()),
+                rcode: ResponseCode::NoError,
+                source: Arc::from(upstream.to_string().as_str()),
+                qname: Arc::from("example.com"),
+                pipeline_id: Arc::from("default"),
+                qtype: u16::from(RecordType::A),
+                qclass: u16::from(DNSClass::IN),
+                expires_at: now - Duration::from_secs(1),
+                stale_until: now + Duration::from_secs(60),
+                original_ttl: Duration::from_secs(60),
+                dnssec_ok: false,
+            },
+        );
+
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("stale response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.id(), 0x1234);
+        let answer = &msg.answers()[0];
+        assert_eq!(answer.data(), Some(&RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+        assert_eq!(answer.ttl(), STALE_ANSWER_TTL);
+        assert_eq!(engine.metrics_degraded_responses.load(Ordering::Relaxed), 1);
+
+        // 后台刷新写回新鲜条目
+        for _ in 0..50 {
+            if engine.cache.get(&hash).is_some_and(|e| e.is_fresh()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(engine.stale_refreshing.is_empty());
+
+        let resp = engine.handle_packet(&packet, peer).await.expect("fresh response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.metrics_degraded_responses.load(Ordering::Relaxed), 1);
+    }
+
+    /// Minimal plain-HTTP DoH endpoint: answers every POST with an A record for the question.
+    async fn spawn_doh_server(reply_delay: Duration) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else { break };
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    let (head_end, content_length) = loop {
+                        let n = stream.read(&mut chunk).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                            let head = String::from_utf8_lossy(&buf[..pos]).to_ascii_lowercase();
+                            assert!(head.starts_with("post "));
+                            assert!(head.contains("content-type: application/dns-message"));
+                            let len = head
+                                .lines()
+                                .find_map(|l| l.strip_prefix("content-length:"))
+                                .and_then(|v| v.trim().parse::<usize>().ok())
+                                .unwrap_or(0);
+                            break (pos + 4, len);
+                        }
+                    };
+                    while buf.len() < head_end + content_length {
+                        let n = stream.read(&mut chunk).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    tokio::time::sleep(reply_delay).await;
+                    let req = Message::from_bytes(&buf[head_end..head_end + content_length]).expect("dns body");
+                    let mut resp = Message::new();
+                    resp.set_id(req.id());
+                    resp.set_message_type(MessageType::Response);
+                    resp.add_queries(req.queries().to_vec());
+                    resp.add_answer(Record::from_rdata(
+                        req.queries()[0].name().clone(),
+                        120,
+                        RData::A(A(Ipv4Addr::new(203, 0, 113, 7))),
+                    ));
+                    let body = resp.to_vec().unwrap();
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn doh_transport_forwards_over_http_post() {
+        let addr = spawn_doh_server(Duration::from_millis(0)).await;
+        let url = format!("http://{}/dns-query", addr);
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 2000 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "doh",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": url, "transport": "doh" } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        match &cfg.pipelines[0].rules[0].actions[0] {
+            Action::Forward { transport, .. } => assert_eq!(*transport, Some(Transport::Doh)),
+            other => panic!("unexpected action: {other:?}"),
+        }
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("doh response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.id(), 0x1234);
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        match msg.answers()[0].data() {
+            Some(RData::A(a)) => assert_eq!(a.0, Ipv4Addr::new(203, 0, 113, 7)),
+            other => panic!("unexpected answer: {other:?}"),
+        }
+        assert_eq!(engine.metrics_upstream_calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn doh_client_honors_timeout() {
+        let addr = spawn_doh_server(Duration::from_secs(5)).await;
+        let client = DohClient::new(u16::MAX as usize);
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+        let started = std::time::Instant::now();
+        let err = client
+            .send(&packet, &format!("http://{}/dns-query", addr), Duration::from_millis(200))
+            .await
+            .expect_err("slow doh upstream must time out");
+        assert!(err.to_string().contains("timeout"), "unexpected error: {err}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn parse_dot_upstream_accepts_scheme_and_default_port() {
+        assert_eq!(
+            parse_dot_upstream("tls://1.1.1.1:853").unwrap(),
+            ("1.1.1.1:853".to_string(), "1.1.1.1".to_string())
+        );
+        assert_eq!(
+            parse_dot_upstream("1.1.1.1").unwrap(),
+            ("1.1.1.1:853".to_string(), "1.1.1.1".to_string())
+        );
+        assert_eq!(
+            parse_dot_upstream("tls://dns.google").unwrap(),
+            ("dns.google:853".to_string(), "dns.google".to_string())
+        );
+        assert_eq!(
+            parse_dot_upstream("tls://[2606:4700::1111]:853").unwrap(),
+            ("[2606:4700::1111]:853".to_string(), "2606:4700::1111".to_string())
+        );
+        assert!(parse_dot_upstream("tls://dns.google:abc").is_err());
+        assert_eq!(effective_transport(Transport::Udp, "tls://1.1.1.1"), Transport::Dot);
+        assert_eq!(effective_transport(Transport::Tcp, "1.1.1.1:53"), Transport::Tcp);
+    }
+
+    /// TLS DNS server that answers exactly one framed query per connection and then drops the session.
+    async fn spawn_dot_server(hostname: &str) -> (SocketAddr, rustls::RootCertStore) {
+        use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec![hostname.to_string()]).expect("cert");
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_cfg = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)
+            .expect("server config");
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_cfg));
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone()).expect("root");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else { break };
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(stream).await else { return };
+                    let mut len_buf = [0u8; 2];
+                    if tls.read_exact(&mut len_buf).await.is_err() {
+                        return;
+                    }
+                    let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                    if tls.read_exact(&mut query).await.is_err() {
+                        return;
+                    }
+                    let req = Message::from_bytes(&query).expect("dns query");
+                    let mut resp = Message::new();
+                    resp.set_id(req.id());
+                    resp.set_message_type(MessageType::Response);
+                    resp.add_queries(req.queries().to_vec());
+                    resp.add_answer(Record::from_rdata(
+                        req.queries()[0].name().clone(),
+                        60,
+                        RData::A(A(Ipv4Addr::new(198, 51, 100, 9))),
+                    ));
+                    let body = resp.to_vec().unwrap();
+                    let mut out = (body.len() as u16).to_be_bytes().to_vec();
+                    out.extend_from_slice(&body);
+                    let _ = tls.write_all(&out).await;
+                    let _ = tls.flush().await;
+                });
+            }
+        });
+        (addr, roots)
+    }
+
+    #[tokio::test]
+    async fn dot_mux_verifies_sni_and_reconnects_after_session_drop() {
+        let (addr, roots) = spawn_dot_server("dot.test").await;
+        let mux = DotMultiplexer::with_roots(1, u16::MAX as usize, roots);
+        let upstream = format!("tls://{}", addr);
+        let packet = build_query_packet("example.com", RecordType::A, DNSClass::IN);
+
+        for _ in 0..2 {
+            let resp = mux
+                .send(&packet, &upstream, Some("dot.test"), Duration::from_secs(2), false)
+                .await
+                .expect("dot response");
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.id(), 0x1234);
+            match msg.answers()[0].data() {
+                Some(RData::A(a)) => assert_eq!(a.0, Ipv4Addr::new(198, 51, 100, 9)),
+                other => panic!("unexpected answer: {other:?}"),
+            }
+            // Give the reader time to observe the dropped session and reset the connection.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // Without the override the certificate is checked against 127.0.0.1 and must be rejected.
+        let err = mux
+            .send(&packet, &upstream, None, Duration::from_secs(2), false)
+            .await
+            .expect_err("certificate name mismatch must fail");
+        assert!(err.to_string().contains("tls handshake"), "unexpected error: {err}");
+    }
+
+    /// UDP upstream that counts queries and answers each with a single A record.
+    async fn spawn_counting_udp_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                60,
+                RData::A(A(Ipv4Addr::new(192, 0, 2, 53))),
+            ));
+            resp
+        })
+        .await
+    }
+
+    /// UDP upstream that counts queries; `respond` builds the answer, id/flags/question are filled in.
+    /// Each answer is sent `reply_delay` after its query arrives, without holding up later queries.
+    async fn spawn_counting_udp_upstream_with(
+        reply_delay: Duration,
+        respond: fn(&Message) -> Message,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("bind"));
+        let addr = sock.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let Ok((n, from)) = sock.recv_from(&mut buf).await else { break };
+                counter.fetch_add(1, Ordering::SeqCst);
+                let req = Message::from_bytes(&buf[..n]).expect("dns query");
+                let mut resp = respond(&req);
+                resp.set_id(req.id());
+                resp.set_message_type(MessageType::Response);
+                resp.add_queries(req.queries().to_vec());
+                let out = resp.to_vec().unwrap();
+                if reply_delay.is_zero() {
+                    let _ = sock.send_to(&out, from).await;
+                    continue;
+                }
+                let sock = Arc::clone(&sock);
+                tokio::spawn(async move {
+                    tokio::time::sleep(reply_delay).await;
+                    let _ = sock.send_to(&out, from).await;
+                });
+            }
+        });
+        (addr, hits)
+    }
+
+    /// Length-prefixed TCP upstream bound to `bind` (port 0 for a fresh address, or a UDP upstream's address to
+    /// serve both); answers like spawn_counting_udp_upstream_with. Returns the address, query and connection counts.
+    async fn spawn_tcp_upstream_with(
+        bind: SocketAddr,
+        reply_delay: Duration,
+        respond: fn(&Message) -> Message,
+    ) -> (SocketAddr, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind(bind).await.expect("bind tcp");
+        let addr = listener.local_addr().unwrap();
+        let (queries, accepted) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (query_counter, accept_counter) = (Arc::clone(&queries), Arc::clone(&accepted));
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accept_counter.fetch_add(1, Ordering::SeqCst);
+                let query_counter = Arc::clone(&query_counter);
+                tokio::spawn(async move {
+                    let mut len_buf = [0u8; 2];
+                    while stream.read_exact(&mut len_buf).await.is_ok() {
+                        let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                        stream.read_exact(&mut query).await.expect("read query");
+                        query_counter.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(reply_delay).await;
+                        let req = Message::from_bytes(&query).expect("dns query");
+                        let mut resp = respond(&req);
+                        resp.set_id(req.id());
+                        resp.set_message_type(MessageType::Response);
+                        resp.add_queries(req.queries().to_vec());
+                        let out = resp.to_vec().unwrap();
+                        stream.write_all(&(out.len() as u16).to_be_bytes()).await.expect("write len");
+                        stream.write_all(&out).await.expect("write body");
+                    }
+                });
+            }
+        });
+        (addr, queries, accepted)
+    }
+
+    /// 对问题名应答 192.0.2.80
+    fn answer_192_0_2_80(req: &Message) -> Message {
+        let mut resp = Message::new();
+        resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 80)))));
+        resp
+    }
+
+    #[tokio::test]
+    async fn prefetch_refreshes_hot_entry_and_keeps_it_on_failure() {
+        // 第 1 次应答 192.0.2.1，第 2 次 SERVFAIL（预取失败），第 3 次 192.0.2.2
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let mut resp = Message::new();
+            let last = match CALLS.fetch_add(1, Ordering::SeqCst) {
+                0 => 1,
+                1 => {
+                    resp.set_response_code(ResponseCode::ServFail);
+                    return resp;
+                }
+                _ => 2,
+            };
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 2, RData::A(A(Ipv4Addr::new(192, 0, 2, last)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "prefetch_threshold_percent": 90 },
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("hot.example.com", RecordType::A, DNSClass::IN);
+        let answer = |bytes: Bytes| {
+            let msg = Message::from_bytes(&bytes).unwrap();
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            match msg.answers()[0].data() {
+                Some(RData::A(a)) => a.0,
+                other => panic!("unexpected answer {other:?}"),
+            }
+        };
+
+        assert_eq!(answer(engine.handle_packet(&packet, peer).await.unwrap()), Ipv4Addr::new(192, 0, 2, 1));
+        // 剩余 TTL 尚高于 90%，不预取
+        assert_eq!(answer(engine.handle_packet(&packet, peer).await.unwrap()), Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(engine.metrics_prefetches.load(Ordering::Relaxed), 0);
+
+        // 剩余不足 90%：立即返回旧应答，后台刷新得到 SERVFAIL，原条目不受影响
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(answer(engine.handle_packet(&packet, peer).await.unwrap()), Ipv4Addr::new(192, 0, 2, 1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(answer(engine.handle_packet(&packet, peer).await.unwrap()), Ipv4Addr::new(192, 0, 2, 1));
+
+        // 上一次命中再次触发预取，这次成功续期
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(answer(engine.handle_packet(&packet, peer).await.unwrap()), Ipv4Addr::new(192, 0, 2, 2));
+        assert_eq!(engine.metrics_prefetches.load(Ordering::Relaxed), 2);
+        assert!(engine.metrics_snapshot().contains("prefetches=2"));
+    }
+
+    #[tokio::test]
+    async fn retransmit_within_recent_window_reuses_result() {
+        // TTL 0 的应答不进入缓存，只能由 recent 窗口吸收重传
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 0, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "recent_result_window_ms": 100 }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let mut packet = build_query_packet("retransmit.example.com", RecordType::A, DNSClass::IN);
+        engine.handle_packet(&packet, peer).await.expect("first");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        packet[0..2].copy_from_slice(&0x4321u16.to_be_bytes());
+        let resp = engine.handle_packet(&packet, peer).await.expect("retransmit");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.id(), 0x4321);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        engine.handle_packet(&packet, peer).await.expect("after window");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn answer_ip_allowlist_rejects_out_of_range_answers() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let name = req.queries()[0].name().clone();
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            if name.to_string().starts_with("out.") {
+                resp.add_answer(Record::from_rdata(name, 60, RData::A(A(Ipv4Addr::new(198, 51, 100, 7)))));
+            }
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "answer_ip_allowlist": ["192.0.2.0/24", "2001:db8::/32"]
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("in.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("allowed");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+
+        let packet = build_query_packet("out.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("rejected");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+        assert!(msg.answers().is_empty());
+
+        let bad: crate::config::PipelineConfig =
+            serde_json::from_value(serde_json::json!({ "settings": { "answer_ip_allowlist": ["nope"] } }))
+                .expect("parse");
+        assert!(RuntimePipelineConfig::from_config(bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn client_acl_drops_denied_and_unlisted_clients() {
+        let engine_with = |settings: serde_json::Value| {
+            let raw = serde_json::json!({
+                "settings": settings,
+                "pipelines": [
+                    {
+                        "id": "p",
+                        "rules": [
+                            { "name": "all", "matchers": [ { "type": "any" } ], "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+                        ]
+                    }
+                ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+        };
+        let packet = build_query_packet("acl.example.com", RecordType::A, DNSClass::IN);
+        let peer = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 5300);
+
+        // deny 优先于 allow；不在 allow 中的客户端同样丢弃
+        let engine = engine_with(serde_json::json!({ "allow_networks": ["10.0.0.0/8"], "deny_networks": ["10.1.0.0/16"] }));
+        assert!(engine.handle_packet_fast(&packet, peer("10.2.3.4")).expect("allowed").is_some());
+        assert!(engine.handle_packet_fast(&packet, peer("10.1.2.3")).is_err());
+        assert!(engine.handle_packet_fast(&packet, peer("192.0.2.1")).is_err());
+        assert!(engine.handle_packet(&packet, peer("10.1.2.3")).await.is_err());
+        assert!(engine.handle_packet(&packet, peer("10.2.3.4")).await.is_ok());
+        assert_eq!(engine.metrics_acl_dropped.load(Ordering::Relaxed), 3);
+        // [::] 双栈监听上的 IPv4 客户端以 v4-mapped 地址到达
+        assert!(engine.handle_packet_fast(&packet, peer("::ffff:10.2.3.4")).expect("allowed").is_some());
+        assert!(engine.handle_packet_fast(&packet, peer("::ffff:10.1.2.3")).is_err());
+
+        // 只有 deny 列表时其余客户端不受限制
+        let engine = engine_with(serde_json::json!({ "deny_networks": ["192.0.2.0/24", "2001:db8::/32"] }));
+        assert!(engine.handle_packet_fast(&packet, peer("192.0.2.1")).is_err());
+        assert!(engine.handle_packet_fast(&packet, peer("2001:db8::1")).is_err());
+        assert!(engine.handle_packet_fast(&packet, peer("198.51.100.1")).expect("allowed").is_some());
+        assert!(engine.metrics_snapshot().contains("acl_dropped=2"));
+
+        let bad: crate::config::PipelineConfig =
+            serde_json::from_value(serde_json::json!({ "settings": { "allow_networks": ["10.0.0.0/33"] } })).expect("parse");
+        assert!(RuntimePipelineConfig::from_config(bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn dns64_synthesizes_aaaa_from_a_on_nodata() {
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let q = &req.queries()[0];
+            let mut resp = Message::new();
+            match q.query_type() {
+                RecordType::A => {
+                    resp.add_answer(Record::from_rdata(q.name().clone(), 77, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+                }
+                RecordType::AAAA if q.name().to_string().starts_with("dual.") => {
+                    resp.add_answer(Record::from_rdata(q.name().clone(), 60, RData::AAAA(AAAA("2001:db8::1".parse().unwrap()))));
+                }
+                _ => {}
+            }
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "dns64_enabled": true }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("v4only.example.com", RecordType::AAAA, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.queries()[0].query_type(), RecordType::AAAA);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(msg.answers()[0].ttl(), 77);
+        assert_eq!(msg.answers()[0].data(), Some(&RData::AAAA(AAAA("64:ff9b::c000:235".parse().unwrap()))));
+        // AAAA 一次加补查 A 一次；合成结果进入缓存
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(engine.handle_packet_fast(&packet, peer).expect("fast").is_some());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // 已有 AAAA 时不合成
+        let packet = build_query_packet("dual.example.com", RecordType::AAAA, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.answers()[0].data(), Some(&RData::AAAA(AAAA("2001:db8::1".parse().unwrap()))));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // RFC 6052 §2.4 的示例
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        let embed = |prefix: &str| dns64_embed(crate::config::parse_dns64_prefix(prefix).unwrap(), v4).to_string();
+        assert_eq!(embed("2001:db8::/32"), "2001:db8:c000:221::");
+        assert_eq!(embed("2001:db8:100::/40"), "2001:db8:1c0:2:21::");
+        assert_eq!(embed("2001:db8:122:300::/56"), "2001:db8:122:3c0:0:221::");
+        assert_eq!(embed("2001:db8:122:344::/64"), "2001:db8:122:344:c0:2:2100:0");
+        assert_eq!(embed("64:ff9b::/96"), "64:ff9b::c000:221");
+        assert!(crate::config::parse_dns64_prefix("64:ff9b::/80").is_err());
+    }
+
+    #[tokio::test]
+    async fn dns64_prefix_is_per_pipeline_and_a_lookup_honors_the_allowlist() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let q = &req.queries()[0];
+            let mut resp = Message::new();
+            if q.query_type() == RecordType::A {
+                resp.add_answer(Record::from_rdata(q.name().clone(), 77, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            }
+            resp
+        })
+        .await;
+        let config = |pipeline: serde_json::Value, allowlist: &[&str]| {
+            let mut pipeline = pipeline;
+            pipeline["rules"] = serde_json::json!([
+                { "name": "all", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+            ]);
+            let raw = serde_json::json!({
+                "settings": { "default_upstream": upstream.to_string(), "answer_ip_allowlist": allowlist },
+                "pipelines": [ pipeline ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        let v6 = serde_json::json!({ "id": "v6", "dns64_prefix": "2001:db8:64::/96" });
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(config(v6.clone(), &[]))), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let answers = |resp: Bytes| Message::from_bytes(&resp).unwrap().answers().iter().map(|r| r.data().unwrap().to_string()).collect::<Vec<_>>();
+
+        // 全局未启用，pipeline 自己的前缀生效
+        let packet = build_query_packet("one.example.com", RecordType::AAAA, DNSClass::IN);
+        assert_eq!(answers(engine.handle_packet(&packet, peer).await.expect("resolve")), ["2001:db8:64::c000:235"]);
+
+        // 其他 pipeline 不合成
+        engine.pipeline.store(Arc::new(config(serde_json::json!({ "id": "plain" }), &[])));
+        let packet = build_query_packet("two.example.com", RecordType::AAAA, DNSClass::IN);
+        assert!(answers(engine.handle_packet(&packet, peer).await.expect("resolve")).is_empty());
+
+        // 补查 A 的应答同样经过 answer_ip_allowlist：被拒绝时返回原 NODATA
+        engine.pipeline.store(Arc::new(config(v6, &["198.51.100.0/24"])));
+        let packet = build_query_packet("three.example.com", RecordType::AAAA, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert!(msg.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cache_hits_echo_the_requesters_question_casing() {
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({ "settings": { "default_upstream": upstream.to_string() } });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let question = |resp: &[u8]| {
+            let end = 12 + resp[12..].iter().position(|&b| b == 0).unwrap() + 1;
+            resp[12..end].to_vec()
+        };
+        // Name::from_str 会按 IDNA 规则转为小写，这里用 from_ascii 保留原始大小写
+        let query = |name: &str| {
+            let mut msg = Message::new();
+            msg.set_id(0x4321);
+            msg.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+            msg.to_vec().unwrap()
+        };
+
+        let first = query("Case.Example.COM");
+        engine.handle_packet(&first, peer).await.expect("resolve");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // 快速路径与慢路径的缓存命中都回显当前请求的原始大小写
+        let mixed = query("cAsE.eXaMpLe.cOm");
+        let resp = engine.handle_packet_fast(&mixed, peer).expect("fast").expect("cache hit");
+        assert_eq!(question(&resp), question(&mixed));
+        assert_ne!(question(&resp), question(&first));
+        let other = query("CASE.example.com");
+        let resp = engine.handle_packet(&other, peer).await.expect("slow hit");
+        assert_eq!(question(&resp), question(&other));
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.queries()[0].name().to_string(), "CASE.example.com.");
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn pipeline_upstream_timeout_and_transport_override_globals() {
+        // 每个查询延迟 300ms 才应答
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::from_millis(300), |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 100 },
+            "pipeline_select": [
+                { "pipeline": "patient", "matchers": [ { "type": "domain_suffix", "value": "slow.test" } ] },
+                { "pipeline": "global", "matchers": [ { "type": "any" } ] }
+            ],
+            "pipelines": [
+                { "id": "patient", "upstream_timeout_ms": 2000, "default_transport": "udp", "rules": [] },
+                { "id": "global", "rules": [] },
+                { "id": "tcp_default", "default_transport": "tcp", "rules": [
+                    { "name": "fwd", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+                ] }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("a.slow.test", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("patient")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+
+        // 全局 100ms 超时先于上游应答到期
+        let packet = build_query_packet("a.example.com", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("global")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+
+        // 未显式指定 transport 的 forward 使用 pipeline 的 default_transport
+        let cfg = engine.pipeline.load();
+        let tcp_default = cfg.pipelines.iter().find(|p| p.id == "tcp_default").unwrap();
+        let decision = engine.apply_rules(&cfg, tcp_default, peer.ip(), "x.example.com", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { transport: Transport::Tcp, upstream_timeout: None, .. }));
+        let patient = cfg.pipelines.iter().find(|p| p.id == "patient").unwrap();
+        let decision = engine.apply_rules(&cfg, patient, peer.ip(), "x.slow.test", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { transport: Transport::Udp, upstream_timeout: Some(t), .. } if t == Duration::from_millis(2000)));
+    }
+
+    #[tokio::test]
+    async fn stats_logger_emits_cache_summary_each_interval() {
+        let capture = CaptureWriter::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(capture.clone())
+            .finish();
+        // 单线程运行时，后台任务与测试在同一线程上，使用同一个订阅者
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (upstream, _hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "stats_log_interval_ms": 100 }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        engine.spawn_stats_logger();
+
+        // 1 次未命中 + 3 次命中
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("stats.example.com", RecordType::A, DNSClass::IN);
+        for _ in 0..4 {
+            engine.handle_packet(&packet, peer).await.expect("response");
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let out = capture.contents();
+        let lines: Vec<&str> = out.lines().filter(|l| l.contains("cache stats")).collect();
+        assert!(lines.len() >= 2, "{out}");
+        let first = lines[0];
+        for field in ["entries=1", "lookups=4", "hit_ratio=0.750", "evictions=0", "upstream_calls=1", "upstream_avg_us="] {
+            assert!(first.contains(field), "missing {field}: {first}");
+        }
+        // 之后的周期只统计新增的查询
+        assert!(lines[1].contains("lookups=0") && lines[1].contains("hit_ratio=0.000"), "{}", lines[1]);
+    }
+
+    #[tokio::test]
+    async fn stats_logger_starts_when_a_reload_enables_it() {
+        let capture = CaptureWriter::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(capture.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let config = |interval_ms: u64| {
+            let raw = serde_json::json!({ "settings": { "stats_log_interval_ms": interval_ms } });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(config(0))), "lbl".to_string());
+        engine.spawn_stats_logger();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!capture.contents().contains("cache stats"));
+
+        engine.pipeline.store(Arc::new(config(50)));
+        tokio::time::sleep(DISABLED_TASK_POLL + Duration::from_millis(300)).await;
+        assert!(capture.contents().contains("cache stats"), "{}", capture.contents());
+
+        // 再次关闭后不再输出
+        engine.pipeline.store(Arc::new(config(0)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let lines = capture.contents().lines().count();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(capture.contents().lines().count(), lines);
+    }
+
+    #[tokio::test]
+    async fn shadow_mode_logs_would_be_nxdomain_and_still_forwards() {
+        let capture = CaptureWriter::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(capture.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string() },
+            "pipelines": [ { "id": "p", "shadow_mode": true, "rules": [ {
+                "name": "new_blocklist",
+                "matchers": [ { "type": "domain_suffix", "value": "ads.example" } ],
+                "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+            } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "192.0.2.7:5300".parse().unwrap();
+
+        let packet = build_query_packet("tracker.ads.example", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let out = capture.contents();
+        let line = out.lines().find(|l| l.contains("shadow_match")).expect("shadow_match logged");
+        for field in ["rule=new_blocklist", "rcode=Non-Existent Domain", "qname=tracker.ads.example", "client_ip=192.0.2.7"] {
+            assert!(line.contains(field), "missing {field}: {line}");
+        }
+
+        // 决定未写入规则缓存：同一客户端的另一次查询同样记录
+        let cfg = engine.pipeline.load();
+        let decision = engine.apply_rules(&cfg, &cfg.pipelines[0], peer.ip(), "tracker.ads.example", RecordType::AAAA, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { .. }));
+        let decision = engine.apply_rules(&cfg, &cfg.pipelines[0], peer.ip(), "tracker.ads.example", RecordType::AAAA, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { .. }));
+        let out = capture.contents();
+        assert_eq!(out.matches("shadow_match").count(), 3, "{out}");
+    }
+
+    #[tokio::test]
+    async fn repeated_blocked_queries_escalate_to_tarpit_and_deny() {
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": "127.0.0.1:9",
+                "min_ttl": 60,
+                "block_escalation": { "threshold": 2, "tarpit_ms": 150, "deny_secs": 30 }
+            },
+            "pipelines": [ { "id": "p", "rules": [ {
+                "name": "block",
+                "matchers": [ { "type": "domain_suffix", "value": "beacon.example" } ],
+                "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+            } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "192.0.2.7:5300".parse().unwrap();
+        let packet = build_query_packet("c2.beacon.example", RecordType::A, DNSClass::IN);
+
+        // 快速路径照常作答并计数；慢路径作答的拦截应答照常写入应答缓存
+        let fast = engine.handle_packet_fast(&packet, peer).expect("fast path").expect("static answer");
+        assert_eq!(Message::from_bytes(&fast).unwrap().response_code(), ResponseCode::NXDomain);
+        let start = std::time::Instant::now();
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("blocked")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert!(start.elapsed() < Duration::from_millis(150));
+        assert_eq!(engine.cache_stats().entries, 1);
+        assert_eq!(engine.metrics_block_escalations.load(Ordering::Relaxed), 0);
+
+        // 超过阈值：快速路径不作答，慢路径的缓存命中同样计数，本次应答被延迟，之后该客户端的所有查询被丢弃
+        assert!(engine.handle_packet_fast(&packet, peer).expect("fast path").is_none());
+        let start = std::time::Instant::now();
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("tarpitted")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(engine.metrics_block_escalations.load(Ordering::Relaxed), 1);
+
+        let other = build_query_packet("www.example.org", RecordType::A, DNSClass::IN);
+        assert!(engine.handle_packet(&other, peer).await.is_err());
+        assert!(engine.handle_packet_fast(&packet, peer).is_err());
+        assert_eq!(engine.metrics_block_denied.load(Ordering::Relaxed), 2);
+
+        // 其他客户端不受影响
+        let neighbor: SocketAddr = "192.0.2.8:5300".parse().unwrap();
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, neighbor).await.expect("blocked")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn servfail_from_primary_is_retried_on_secondary_and_only_final_answer_cached() {
+        let (primary, primary_hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| {
+            let mut resp = Message::new();
+            resp.set_response_code(ResponseCode::ServFail);
+            resp
+        })
+        .await;
+        let (secondary, secondary_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": primary.to_string(),
+                "servfail_retry": { "upstream": secondary.to_string(), "backoff_ms": 20 }
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("retry.example.com", RecordType::A, DNSClass::IN);
+        let start = std::time::Instant::now();
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!((primary_hits.load(Ordering::SeqCst), secondary_hits.load(Ordering::SeqCst)), (1, 1));
+
+        // 缓存的是重试得到的 NOERROR，而不是中间的 SERVFAIL
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("cached")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!((primary_hits.load(Ordering::SeqCst), secondary_hits.load(Ordering::SeqCst)), (1, 1));
+
+        // 重试上游同样 SERVFAIL：用尽次数后返回 SERVFAIL
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": primary.to_string(), "servfail_retry": { "attempts": 2 } }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        engine.pipeline.store(Arc::new(RuntimePipelineConfig::from_config(cfg).expect("runtime")));
+        let packet = build_query_packet("broken.example.com", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("servfail")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+        assert_eq!(primary_hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn unparsable_response_follows_response_parse_failure_policy() {
+        // 应答的 A 记录 RDLENGTH 为 3：快速解析只看头部与 TTL 能通过，完整解析失败
+        let sock = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let upstream = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = sock.recv_from(&mut buf).await {
+                // 查询只含问题段
+                let mut resp = buf[..n].to_vec();
+                resp[2] |= 0x80;
+                resp[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+                resp.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 3, 192, 0, 2]);
+                let _ = sock.send_to(&resp, from).await;
+            }
+        });
+        let (good, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, answer_192_0_2_80).await;
+        let garbled = upstream.to_string();
+        // 每个需要完整解析上游响应的位置：响应规则、TTL 钳制、响应动作中的 forward、响应动作跳转后的 forward
+        let branches = [
+            (
+                "response rules",
+                serde_json::json!({ "actions": [ { "type": "forward", "upstream": garbled } ],
+                    "response_matchers": [ { "type": "response_rcode", "value": "NOERROR" } ],
+                    "response_actions_on_match": [ { "type": "deny" } ] }),
+            ),
+            ("ttl clamp", serde_json::json!({ "actions": [ { "type": "forward", "upstream": garbled, "max_ttl": 30 } ] })),
+            (
+                "response action forward",
+                serde_json::json!({ "actions": [ { "type": "forward", "upstream": good.to_string() } ],
+                    "response_matchers": [ { "type": "response_rcode", "value": "NOERROR" } ],
+                    "response_actions_on_match": [ { "type": "forward", "upstream": garbled } ] }),
+            ),
+            (
+                "response jump",
+                serde_json::json!({ "actions": [ { "type": "forward", "upstream": good.to_string() } ],
+                    "response_matchers": [ { "type": "response_rcode", "value": "NOERROR" } ],
+                    "response_actions_on_match": [ { "type": "jump_to_pipeline", "pipeline": "next" } ] }),
+            ),
+        ];
+        let config = |policy: &str, rule: &serde_json::Value| {
+            let mut rule = rule.clone();
+            rule["name"] = "inspect".into();
+            rule["matchers"] = serde_json::json!([ { "type": "any" } ]);
+            let raw = serde_json::json!({
+                "settings": { "response_parse_failure": policy },
+                "pipelines": [
+                    { "id": "main", "rules": [ rule ] },
+                    { "id": "next", "rules": [ { "name": "garbled", "matchers": [ { "type": "any" } ],
+                        "actions": [ { "type": "forward", "upstream": garbled } ] } ] }
+                ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("garbled.example.com", RecordType::A, DNSClass::IN);
+
+        for (branch, rule) in &branches {
+            let engine = Engine::new(Arc::new(ArcSwap::from_pointee(config("error", rule))), "lbl".to_string());
+            let err = engine.handle_packet(&packet, peer).await.unwrap_err();
+            assert!(format!("{err:#}").contains("parse upstream response"), "{branch}: {err:#}");
+
+            engine.pipeline.store(Arc::new(config("passthrough", rule)));
+            let resp = engine.handle_packet(&packet, peer).await.expect("passthrough");
+            assert_eq!(&resp[..2], &packet[..2], "{branch}");
+            assert_eq!(resp[resp.len() - 3..], [192, 0, 2], "{branch}");
+            assert!(Message::from_bytes(&resp).is_err(), "{branch}");
+
+            engine.pipeline.store(Arc::new(config("servfail", rule)));
+            let resp = engine.handle_packet(&packet, peer).await.expect("servfail");
+            let msg = Message::from_bytes(&resp).unwrap();
+            assert_eq!(msg.response_code(), ResponseCode::ServFail, "{branch}");
+            assert_eq!(msg.id(), u16::from_be_bytes([packet[0], packet[1]]), "{branch}");
+            // 回退应答都不缓存
+            assert_eq!(engine.cache_stats().entries, 0, "{branch}");
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_rule_timeout_overrides_pipeline_and_global() {
+        // 只收不答的上游
+        let sock = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let upstream = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while sock.recv_from(&mut buf).await.is_ok() {}
+        });
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 2000 },
+            "pipelines": [ { "id": "main", "upstream_timeout_ms": 1500, "rules": [
+                { "name": "fast", "matchers": [ { "type": "domain_suffix", "value": "fast.test" } ],
+                  "actions": [ { "type": "forward", "timeout_ms": 100 } ] },
+                { "name": "plain", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let start = std::time::Instant::now();
+        let packet = build_query_packet("a.fast.test", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("servfail")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+        assert!(start.elapsed() < Duration::from_millis(1000), "took {:?}", start.elapsed());
+
+        let cfg = engine.pipeline.load();
+        let main = &cfg.pipelines[0];
+        let decision = engine.apply_rules(&cfg, main, peer.ip(), "a.fast.test", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { upstream_timeout: Some(t), .. } if t == Duration::from_millis(100)));
+        let decision = engine.apply_rules(&cfg, main, peer.ip(), "other.test", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Forward { upstream_timeout: Some(t), .. } if t == Duration::from_millis(1500)));
+
+        let bad = serde_json::json!({ "pipelines": [ { "id": "main", "rules": [
+            { "name": "zero", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward", "timeout_ms": 0 } ] }
+        ] } ] });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(bad).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).unwrap_err();
+        assert!(err.to_string().contains("timeout_ms must be positive"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn forward_rule_clamps_answer_and_cache_ttl() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let name = req.queries()[0].name().clone();
+            let ttl = if name.to_string().starts_with("long.") { 600 } else { 2 };
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(name, ttl, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "flaky",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ {
+                                "type": "forward",
+                                "upstream": upstream.to_string(),
+                                "min_ttl": 10,
+                                "max_ttl": 60
+                            } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for (qname, expected) in [("long.example.com", 60u32), ("short.example.com", 10u32)] {
+            let packet = build_query_packet(qname, RecordType::A, DNSClass::IN);
+            let resp = engine.handle_packet(&packet, peer).await.expect("forwarded");
+            let msg = Message::from_bytes(&resp).expect("parse response");
+            assert_eq!(msg.answers()[0].ttl(), expected, "{qname}");
+
+            let hash = Engine::calculate_cache_hash_for_dedupe("p", qname, RecordType::A, DNSClass::IN, false);
+            let entry = engine.cache.get(&hash).expect("cached");
+            let remaining = entry.expires_at.saturating_duration_since(std::time::Instant::now());
+            assert!(remaining <= Duration::from_secs(expected as u64), "{qname}: {remaining:?}");
+            assert!(remaining > Duration::from_secs(expected as u64 - 2), "{qname}: {remaining:?}");
+        }
+
+        let bad: crate::config::PipelineConfig = serde_json::from_value(serde_json::json!({
+            "pipelines": [ { "id": "p", "rules": [ {
+                "name": "r", "matchers": [],
+                "actions": [ { "type": "forward", "upstream": null, "min_ttl": 60, "max_ttl": 10 } ]
+            } ] } ]
+        }))
+        .expect("parse");
+        assert!(RuntimePipelineConfig::from_config(bad).is_err());
+    }
+
+    #[tokio::test]
+    async fn failover_skips_timed_out_upstream() {
+        // 只收不回的上游，模拟超时
+        let dead = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let dead_addr = dead.local_addr().unwrap();
+        let (live, live_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 200 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "fallback",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": [dead_addr.to_string(), live.to_string()] } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("failover.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("second upstream answers");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(live_hits.load(Ordering::SeqCst), 1);
+
+        let hash = Engine::calculate_cache_hash_for_dedupe("p", "failover.example.com", RecordType::A, DNSClass::IN, false);
+        assert_eq!(&*engine.cache.get(&hash).expect("cached").source, live.to_string().as_str());
+        drop(dead);
+    }
+
+    #[tokio::test]
+    async fn race_strategy_returns_fastest_upstream_and_cancels_the_rest() {
+        // 慢上游：收到查询 500ms 后才应答 192.0.2.1
+        let (slow_addr, _slow_hits) = spawn_counting_udp_upstream_with(Duration::from_millis(500), |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+            resp
+        })
+        .await;
+        let (fast, fast_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 2000, "upstream_strategy": "race" },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "race",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": [slow_addr.to_string(), fast.to_string()] } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("race.example.com", RecordType::A, DNSClass::IN);
+        let started = std::time::Instant::now();
+        let resp = engine.handle_packet(&packet, peer).await.expect("race winner");
+        assert!(started.elapsed() < Duration::from_millis(400), "waited for slow upstream: {:?}", started.elapsed());
+        match Message::from_bytes(&resp).unwrap().answers().first().map(|r| r.data()) {
+            Some(Some(RData::A(a))) => assert_eq!(a.0, Ipv4Addr::new(192, 0, 2, 53)),
+            other => panic!("unexpected answer: {other:?}"),
+        }
+        assert_eq!(fast_hits.load(Ordering::SeqCst), 1);
+        let hash = Engine::calculate_cache_hash_for_dedupe("p", "race.example.com", RecordType::A, DNSClass::IN, false);
+        assert_eq!(&*engine.cache.get(&hash).expect("cached").source, fast.to_string().as_str());
+        // 落败的转发已取消，UDP 池中不残留等待中的请求
+        assert!(engine.udp_client.pool.iter().all(|state| state.inflight.is_empty()));
+    }
+
+    /// 不经网络的上游：记录每次调用，192.0.2.1 对 poisoned.* 返回私网地址，其余返回 198.51.100.7
+    #[derive(Default)]
+    struct MockUpstream {
+        calls: std::sync::Mutex<Vec<(String, Transport)>>,
+    }
+
+    impl Upstream for MockUpstream {
+        fn query<'a>(
+            &'a self,
+            packet: &'a [u8],
+            upstream: &'a str,
+            transport: Transport,
+            _timeout_dur: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move {
+                self.calls.lock().unwrap().push((upstream.to_string(), transport));
+                let req = Message::from_bytes(packet)?;
+                let name = req.queries()[0].name().clone();
+                let ip = if upstream == "192.0.2.1:53" && name.to_ascii().starts_with("poisoned.") {
+                    Ipv4Addr::new(10, 0, 0, 1)
+                } else {
+                    Ipv4Addr::new(198, 51, 100, 7)
+                };
+                let mut resp = Message::new();
+                resp.set_id(req.id());
+                resp.set_message_type(MessageType::Response);
+                resp.add_queries(req.queries().to_vec());
+                resp.add_answer(Record::from_rdata(name, 60, RData::A(A(ip))));
+                Ok(Bytes::from(resp.to_vec()?))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_upstream_drives_response_actions_on_match() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "192.0.2.1:53" },
+            "pipelines": [ { "id": "main", "rules": [ {
+                "name": "anti_poison",
+                "matchers": [ { "type": "any" } ],
+                "actions": [ { "type": "forward" } ],
+                "response_matchers": [ { "type": "response_answer_ip", "cidr": "10.0.0.0/8" } ],
+                "response_actions_on_match": [ { "type": "forward", "upstream": "192.0.2.2:53", "transport": "tcp" } ]
+            } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let mock = Arc::new(MockUpstream::default());
+        let engine = Engine::with_upstream(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string(), mock.clone());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // 私网应答命中响应匹配器，按响应动作改用 TCP 向第二个上游重查
+        let packet = build_query_packet("poisoned.example.com", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(198, 51, 100, 7)))));
+        assert_eq!(
+            *mock.calls.lock().unwrap(),
+            [("192.0.2.1:53".to_string(), Transport::Udp), ("192.0.2.2:53".to_string(), Transport::Tcp)]
+        );
+
+        // 未命中时直接返回第一个上游的应答
+        mock.calls.lock().unwrap().clear();
+        let packet = build_query_packet("clean.example.com", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(198, 51, 100, 7)))));
+        assert_eq!(*mock.calls.lock().unwrap(), [("192.0.2.1:53".to_string(), Transport::Udp)]);
+        // 每个成功应答的上游都有延迟 EWMA
+        let upstreams: Vec<String> = engine.upstream_latencies().into_iter().map(|(u, _)| u).collect();
+        assert_eq!(upstreams, ["192.0.2.1:53", "192.0.2.2:53"]);
+    }
+
+    /// 把请求原样作为应答回显（可选把问题名改成小写），并记录发出的请求
+    #[derive(Default)]
+    struct EchoUpstream {
+        lowercase: bool,
+        sent: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl Upstream for EchoUpstream {
+        fn query<'a>(
+            &'a self,
+            packet: &'a [u8],
+            _upstream: &'a str,
+            _transport: Transport,
+            _timeout_dur: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move {
+                self.sent.lock().unwrap().push(packet.to_vec());
+                let mut resp = packet.to_vec();
+                resp[2] |= 0x80;
+                if self.lowercase {
+                    let qtype_at = resp.len() - 4;
+                    resp[12..qtype_at].make_ascii_lowercase();
+                }
+                Ok(Bytes::from(resp))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn qname_0x20_rejects_answers_with_mismatched_case() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "192.0.2.1:53", "qname_0x20": true },
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "fwd", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        // 字母足够多，随机化后恰好全小写的概率可以忽略
+        let packet = build_query_packet("abcdefghijklmnopqrstuvwxyz.abcdefghijklmnopqrstuvwxyz.example", RecordType::A, DNSClass::IN);
+
+        // 原样回显：发出的问题名大小写已随机化，返回给客户端的恢复为原样
+        let echo = Arc::new(EchoUpstream::default());
+        let engine = Engine::with_upstream(Arc::new(ArcSwap::from_pointee(runtime.clone())), "lbl".to_string(), echo.clone());
+        let resp = engine.handle_packet(&packet, peer).await.expect("response");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(&resp[12..], &packet[12..]);
+        let sent = echo.sent.lock().unwrap()[0].clone();
+        assert_ne!(&sent[12..], &packet[12..]);
+        assert!(sent[12..].eq_ignore_ascii_case(&packet[12..]));
+
+        // 上游把问题名改成小写：回显不一致，按上游失败处理
+        let lower = Arc::new(EchoUpstream { lowercase: true, ..Default::default() });
+        let engine = Engine::with_upstream(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string(), lower.clone());
+        let resp = engine.handle_packet(&packet, peer).await.expect("response");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::ServFail);
+        assert!(!lower.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn qname_0x20_drops_spoofed_case_and_waits_for_the_real_answer() {
+        // 每个请求先回一个 ID 相同但问题名全小写的伪造应答，再回原样回显的真正应答
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("bind"));
+        let upstream = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let Ok((n, from)) = sock.recv_from(&mut buf).await else { break };
+                let mut real = buf[..n].to_vec();
+                real[2] |= 0x80;
+                let mut spoofed = real.clone();
+                let qtype_at = spoofed.len() - 4;
+                spoofed[12..qtype_at].make_ascii_lowercase();
+                let _ = sock.send_to(&spoofed, from).await;
+                let _ = sock.send_to(&real, from).await;
+            }
+        });
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 1000, "qname_0x20": true },
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "fwd", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("abcdefghijklmnopqrstuvwxyz.abcdefghijklmnopqrstuvwxyz.example", RecordType::A, DNSClass::IN);
+
+        let resp = engine.handle_packet(&packet, peer).await.expect("response");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NoError);
+        assert_eq!(&resp[12..], &packet[12..]);
+    }
+
+    #[tokio::test]
+    async fn response_answer_count_turns_fast_flux_answers_into_nxdomain() {
+        // 应答 A 记录条数取自首个标签，如 n5.example.com 返回 5 条
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let name = req.queries()[0].name().clone();
+            let label = String::from_utf8_lossy(name.iter().next().unwrap()).to_string();
+            let n: u8 = label.trim_start_matches('n').parse().unwrap();
+            let mut resp = Message::new();
+            for i in 0..n {
+                resp.add_answer(Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(198, 51, 100, i)))));
+            }
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 1000 },
+            "pipelines": [ { "id": "main", "rules": [ {
+                "name": "fast_flux",
+                "matchers": [ { "type": "any" } ],
+                "actions": [ { "type": "forward" } ],
+                "response_matchers": [ { "type": "response_answer_count", "min": 5 } ],
+                "response_actions_on_match": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+            } ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for (n, rcode, answers) in [
+            (0, ResponseCode::NoError, 0),
+            (4, ResponseCode::NoError, 4),
+            (5, ResponseCode::NXDomain, 0),
+            (9, ResponseCode::NXDomain, 0),
+        ] {
+            let packet = build_query_packet(&format!("n{n}.example.com"), RecordType::A, DNSClass::IN);
+            let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+            assert_eq!((msg.response_code(), msg.answers().len()), (rcode, answers), "{n} answers");
+        }
+    }
+
+    /// 按上游地址固定延迟应答，并统计每个上游收到的查询数；fast_down 时最快的上游立即失败
+    #[derive(Default)]
+    struct DelayedUpstream {
+        calls: std::sync::Mutex<std::collections::HashMap<String, usize>>,
+        fast_down: AtomicBool,
+    }
+
+    impl Upstream for DelayedUpstream {
+        fn query<'a>(
+            &'a self,
+            packet: &'a [u8],
+            upstream: &'a str,
+            _transport: Transport,
+            _timeout_dur: Duration,
+        ) -> BoxFuture<'a, anyhow::Result<Bytes>> {
+            Box::pin(async move {
+                *self.calls.lock().unwrap().entry(upstream.to_string()).or_default() += 1;
+                if upstream == "192.0.2.1:53" && self.fast_down.load(Ordering::SeqCst) {
+                    anyhow::bail!("connection refused");
+                }
+                if upstream != "192.0.2.1:53" {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                let mut resp = packet.to_vec();
+                resp[2] |= 0x80;
+                Ok(Bytes::from(resp))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn fastest_strategy_prefers_the_lowest_latency_upstream() {
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": "192.0.2.3:53,192.0.2.2:53,192.0.2.1:53",
+                "upstream_strategy": "fastest",
+                "upstream_probe_percent": 10
+            },
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "fwd", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let mock = Arc::new(DelayedUpstream::default());
+        let engine = Engine::with_upstream(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string(), mock.clone());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        engine.upstream_latency.record("192.0.2.1:53", Duration::from_millis(1), 0.2);
+        engine.upstream_latency.record("192.0.2.2:53", Duration::from_millis(30), 0.2);
+        engine.upstream_latency.record("192.0.2.3:53", Duration::from_millis(40), 0.2);
+
+        for i in 0..200 {
+            let packet = build_query_packet(&format!("q{i}.example.com"), RecordType::A, DNSClass::IN);
+            engine.handle_packet(&packet, peer).await.expect("response");
+        }
+        let calls = mock.calls.lock().unwrap().clone();
+        assert_eq!(calls.values().sum::<usize>(), 200);
+        let fast = calls["192.0.2.1:53"];
+        assert!(fast >= 160, "{calls:?}");
+        // 探测使慢上游仍有新样本
+        assert!(calls.get("192.0.2.3:53").is_some_and(|&n| n > 0), "{calls:?}");
+
+        // 探测概率为 0 时只选最快的
+        let members: Vec<String> = ["192.0.2.3:53", "192.0.2.2:53", "192.0.2.1:53"].map(String::from).to_vec();
+        assert!((0..100).all(|_| engine.fastest_start(&members, 0) == 2));
+
+        // 最快的上游失效：失败按超时计入，之后只有探测才会再选到它
+        mock.fast_down.store(true, Ordering::SeqCst);
+        mock.calls.lock().unwrap().clear();
+        for i in 0..100 {
+            let packet = build_query_packet(&format!("down{i}.example.com"), RecordType::A, DNSClass::IN);
+            let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+        }
+        let calls = mock.calls.lock().unwrap().clone();
+        assert!(calls.get("192.0.2.1:53").copied().unwrap_or(0) <= 20, "{calls:?}");
+        assert_ne!(engine.fastest_start(&members, 0), 2);
+    }
+
+    #[tokio::test]
+    async fn ecs_subnet_matches_the_claimed_client_subnet() {
+        use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsOption};
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "branch", "matchers": [ { "type": "ecs_subnet", "cidr": "10.0.0.0/8" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "192.0.2.10" } ] },
+                { "name": "rest", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "192.0.2.20" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        // 传输层对端是前置解析器，不在 10.0.0.0/8 内
+        let peer: SocketAddr = "192.0.2.53:5300".parse().unwrap();
+        let query = |ecs: Option<&str>| {
+            let mut msg = Message::new();
+            msg.set_id(9);
+            msg.add_query(Query::query(Name::from_ascii("www.example.com.").unwrap(), RecordType::A));
+            let mut edns = hickory_proto::op::Edns::new();
+            if let Some(ip) = ecs {
+                edns.options_mut().insert(EdnsOption::Subnet(ClientSubnet::new(ip.parse().unwrap(), 24, 0)));
+            }
+            msg.set_edns(edns);
+            msg.to_vec().unwrap()
+        };
+        let answer = |resp: &[u8]| Message::from_bytes(resp).unwrap().answers()[0].data().cloned();
+
+        // 同一问题按 ECS 得出不同决策，规则缓存也不能串用；快速路径与慢路径一致
+        for (ecs, ip) in [(Some("10.1.2.3"), [192, 0, 2, 10]), (Some("198.51.100.7"), [192, 0, 2, 20]), (None, [192, 0, 2, 20])] {
+            let packet = query(ecs);
+            let want = Some(RData::A(A(Ipv4Addr::from(ip))));
+            let fast = engine.handle_packet_fast(&packet, peer).expect("fast path").expect("static answer");
+            assert_eq!(answer(&fast), want, "fast path, ecs {ecs:?}");
+            assert_eq!(answer(&engine.handle_packet(&packet, peer).await.expect("response")), want, "ecs {ecs:?}");
+        }
+
+        // 不用 ecs_subnet 的 pipeline：不同 ECS 共用同一条规则缓存
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "rest", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "192.0.2.20" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        for ecs in ["10.1.2.3", "198.51.100.7", "203.0.113.9"] {
+            engine.handle_packet(&query(Some(ecs)), peer).await.expect("response");
+        }
+        engine.rule_cache.run_pending_tasks();
+        assert_eq!(engine.rule_cache.entry_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn swapped_domain_set_invalidates_cached_rule_decisions() {
+        let dir = std::env::temp_dir().join(format!("kixdns-rule-cache-sets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let list = dir.join("block.txt");
+        std::fs::write(&list, "other.example.net\n").unwrap();
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "block", "matchers": [ { "type": "domain_set", "file": list } ],
+                  "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] },
+                { "name": "rest", "matchers": [ { "type": "any" } ],
+                  "actions": [ { "type": "static_ip_response", "ip": "192.0.2.20" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let packet = build_query_packet("ads.example.com", RecordType::A, DNSClass::IN);
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let rcode = |resp: &[u8]| Message::from_bytes(resp).unwrap().response_code();
+
+        assert_eq!(rcode(&engine.handle_packet(&packet, peer).await.expect("response")), ResponseCode::NoError);
+        // watcher 换入新列表：缓存中按旧列表得出的决策不再使用
+        std::fs::write(&list, "ads.example.com\n").unwrap();
+        engine.pipeline.load().domain_sets()[0].reload().unwrap();
+        engine.reload_stats().record_list_reload();
+        assert_eq!(rcode(&engine.handle_packet(&packet, peer).await.expect("response")), ResponseCode::NXDomain);
+        let fast = engine.handle_packet_fast(&packet, peer).expect("fast path").expect("static answer");
+        assert_eq!(rcode(&fast), ResponseCode::NXDomain);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn pipeline_counters_track_requests_hits_forwards_and_servfails() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let mut resp = Message::new();
+            let name = req.queries()[0].name().clone();
+            if name.to_ascii().starts_with("fail.") {
+                resp.set_response_code(ResponseCode::ServFail);
+            } else {
+                resp.add_answer(Record::from_rdata(name, 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            }
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 1000 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "fwd",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": upstream.to_string() } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let ok = build_query_packet("ok.example.com", RecordType::A, DNSClass::IN);
+        // 快速路径未命中时返回 None，不计入请求
+        assert!(engine.handle_packet_fast(&ok, peer).expect("fast").is_none());
+        engine.handle_packet(&ok, peer).await.expect("resolve");
+        engine.handle_packet_fast(&ok, peer).expect("fast").expect("cache hit");
+        let fail = build_query_packet("fail.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&fail, peer).await.expect("servfail answer");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::ServFail);
+
+        assert_eq!(
+            engine.pipeline_stats(),
+            vec![PipelineStats {
+                pipeline: "p".to_string(),
+                requests: 3,
+                cache_hits: 1,
+                upstream_forwards: 2,
+                servfails: 1,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_affinity_pins_each_client_to_one_upstream() {
+        let (first, first_hits) = spawn_counting_udp_upstream().await;
+        let (second, second_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 1000, "upstream_strategy": "client_affinity" },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "sticky",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": [first.to_string(), second.to_string()] } ]
+                        }
+                    ]
+                }
+            ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let hits = || (first_hits.load(Ordering::SeqCst), second_hits.load(Ordering::SeqCst));
+
+        // 同一客户端：不同 qname（避免缓存）全部落在同一上游
+        let client: SocketAddr = "198.51.100.10:5300".parse().unwrap();
+        for i in 0..6 {
+            let packet = build_query_packet(&format!("q{i}.sticky.example.com"), RecordType::A, DNSClass::IN);
+            engine.handle_packet(&packet, client).await.expect("resolve");
+        }
+        let (a, b) = hits();
+        assert!((a, b) == (6, 0) || (a, b) == (0, 6), "client split across upstreams: {a}/{b}");
+
+        // 不同客户端分散到两个上游
+        for i in 0..32u8 {
+            let peer = SocketAddr::from((Ipv4Addr::new(203, 0, 113, i), 5300));
+            let packet = build_query_packet(&format!("c{i}.sticky.example.com"), RecordType::A, DNSClass::IN);
+            engine.handle_packet(&packet, peer).await.expect("resolve");
+        }
+        let (a2, b2) = hits();
+        assert!(a2 > a && b2 > b, "clients not spread: {a2}/{b2}");
+    }
+
+    #[tokio::test]
+    async fn upstream_refused_retries_next_upstream_when_enabled() {
+        let (refusing, refused_hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| {
+            let mut resp = Message::new();
+            resp.set_response_code(ResponseCode::Refused);
+            resp
+        })
+        .await;
+        let (live, live_hits) = spawn_counting_udp_upstream().await;
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let packet = build_query_packet("refused.example.com", RecordType::A, DNSClass::IN);
+
+        for (retry, expected, expected_live_hits) in [(false, ResponseCode::Refused, 0), (true, ResponseCode::NoError, 1)] {
+            let raw = serde_json::json!({
+                "settings": {
+                    "default_upstream": format!("{refusing},{live}"),
+                    "retry_on_upstream_refused": retry
+                }
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+            let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("resolve")).unwrap();
+            assert_eq!(msg.response_code(), expected, "retry_on_upstream_refused={retry}");
+            assert_eq!(live_hits.load(Ordering::SeqCst), expected_live_hits);
+        }
+        assert_eq!(refused_hits.load(Ordering::SeqCst), 2);
+
+        // 所有上游都拒绝时返回上游的 REFUSED，而不是 SERVFAIL
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": format!("{refusing},{refusing}"), "retry_on_upstream_refused": true }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("resolve")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::Refused);
+        assert_eq!(refused_hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn unhealthy_upstream_is_skipped_until_probe_succeeds() {
+        // 先只收不回，随后开始应答以模拟恢复
+        let flaky = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("bind"));
+        let flaky_addr = flaky.local_addr().unwrap();
+        let answering = Arc::new(AtomicBool::new(false));
+        let flaky_hits = Arc::new(AtomicUsize::new(0));
+        {
+            let (answering, hits) = (Arc::clone(&answering), Arc::clone(&flaky_hits));
+            tokio::spawn(async move {
+                let mut buf = [0u8; 512];
+                loop {
+                    let Ok((n, from)) = flaky.recv_from(&mut buf).await else { break };
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    if !answering.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let req = Message::from_bytes(&buf[..n]).expect("dns query");
+                    let mut resp = Message::new();
+                    resp.set_id(req.id());
+                    resp.set_message_type(MessageType::Response);
+                    resp.add_queries(req.queries().to_vec());
+                    let _ = flaky.send_to(&resp.to_vec().unwrap(), from).await;
+                }
+            });
+        }
+        let (live, _live_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": format!("{flaky_addr},{live}"),
+                "upstream_timeout_ms": 200,
+                "health_check_interval_ms": 100,
+                "unhealthy_threshold": 2
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for name in ["h1.example.com", "h2.example.com"] {
+            let packet = build_query_packet(name, RecordType::A, DNSClass::IN);
+            engine.handle_packet(&packet, peer).await.expect("failover answers");
+        }
+        assert!(engine.metrics_snapshot().contains(&format!("unhealthy=[{flaky_addr}]")));
+
+        // 不健康期间直接跳过，不再付出超时
+        let hits_before = flaky_hits.load(Ordering::SeqCst);
+        let started = std::time::Instant::now();
+        let packet = build_query_packet("h3.example.com", RecordType::A, DNSClass::IN);
+        engine.handle_packet(&packet, peer).await.expect("live answers");
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(flaky_hits.load(Ordering::SeqCst), hits_before);
+
+        answering.store(true, Ordering::SeqCst);
+        engine.spawn_health_checker();
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(engine.metrics_snapshot().contains("unhealthy=[]"));
+    }
+
+    #[tokio::test]
+    async fn health_checker_follows_reloads_and_probes_over_the_failing_transport() {
+        // 只有 TCP 端的上游：先不监听（连接被拒），之后才开始应答；UDP 探测永远不会成功
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = |interval_ms: u64| {
+            let raw = serde_json::json!({
+                "settings": {
+                    "default_upstream": upstream.to_string(),
+                    "upstream_timeout_ms": 200,
+                    "health_check_interval_ms": interval_ms,
+                    "unhealthy_threshold": 1
+                },
+                "pipelines": [ { "id": "p", "rules": [ { "name": "tcp", "matchers": [ { "type": "any" } ],
+                    "actions": [ { "type": "forward", "transport": "tcp" } ] } ] } ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        // 启动时健康追踪关闭，任务照常启动；热加载开启后生效
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(config(0))), "lbl".to_string());
+        engine.spawn_health_checker();
+        engine.pipeline.store(Arc::new(config(50)));
+
+        let packet = build_query_packet("probe.example.com", RecordType::A, DNSClass::IN);
+        let _ = engine.handle_packet(&packet, "127.0.0.1:5300".parse().unwrap()).await;
+        assert_eq!(engine.upstream_health.unhealthy(), vec![upstream.to_string()]);
+
+        spawn_tcp_upstream_with(upstream, Duration::ZERO, |_| Message::new()).await;
+        for _ in 0..100 {
+            if engine.upstream_health.unhealthy().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        assert!(engine.upstream_health.unhealthy().is_empty());
+    }
+
+    #[tokio::test]
+    async fn round_robin_rotates_starting_upstream() {
+        let (a, a_hits) = spawn_counting_udp_upstream().await;
+        let (b, b_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": format!("{a}, {b}"),
+                "upstream_strategy": "round_robin"
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        for i in 0..4 {
+            let packet = build_query_packet(&format!("rr{i}.example.com"), RecordType::A, DNSClass::IN);
+            engine.handle_packet(&packet, peer).await.expect("forwarded");
+        }
+        assert_eq!(a_hits.load(Ordering::SeqCst), 2);
+        assert_eq!(b_hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicates_past_max_inflight_waiters_forward_on_their_own() {
+        // 延迟 300ms 应答的上游，使首个查询保持进行中，其余重复查询排队
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::from_millis(300), |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(req.queries()[0].name().clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 53)))));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "upstream_timeout_ms": 2000,
+                "max_inflight_waiters": 4
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+        let mut pending = Vec::new();
+        for i in 0..20u8 {
+            let engine = engine.clone();
+            pending.push(tokio::spawn(async move {
+                let packet = build_query_packet("pile.example.com", RecordType::A, DNSClass::IN);
+                let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 1, i)), 5300);
+                engine.handle_packet(&packet, peer).await
+            }));
+        }
+        for handle in pending {
+            let resp = handle.await.unwrap().expect("duplicate query");
+            let msg = Message::from_bytes(&resp).unwrap();
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            assert_eq!(msg.answers().len(), 1);
+        }
+        // 1 个 leader + 4 个等待者共用一次上游请求，其余 15 个各自转发
+        assert_eq!(engine.metrics_inflight_max_waiters.load(Ordering::Relaxed), 4);
+        assert_eq!(engine.metrics_inflight_overflows.load(Ordering::Relaxed), 15);
+        assert_eq!(hits.load(Ordering::SeqCst), 16);
+        assert!(engine.inflight.is_empty());
+        assert!(engine.metrics_snapshot().contains("inflight_max_waiters=4 inflight_overflows=15"));
+    }
+
+    #[tokio::test]
+    async fn client_past_inflight_limit_is_refused() {
+        // 延迟 300ms 应答的上游，使查询保持进行中
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::from_millis(300), |_| Message::new()).await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "upstream_timeout_ms": 2000,
+                "max_inflight_per_client": 2
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let busy: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:5300".parse().unwrap();
+
+        let mut pending = Vec::new();
+        for i in 0..2 {
+            let engine = engine.clone();
+            pending.push(tokio::spawn(async move {
+                let packet = build_query_packet(&format!("slow{i}.example.com"), RecordType::A, DNSClass::IN);
+                engine.handle_packet(&packet, busy).await
+            }));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let packet = build_query_packet("third.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, busy).await.expect("refused");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::Refused);
+
+        let resp = engine.handle_packet(&packet, other).await.expect("other client");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NoError);
+
+        for handle in pending {
+            let resp = handle.await.unwrap().expect("slow query");
+            assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NoError);
+        }
+        assert!(engine.client_inflight.is_empty());
+        let packet = build_query_packet("again.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, busy).await.expect("slot released");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn grouped_matchers_select_rules_through_the_compiled_index() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "127.0.0.1:9" },
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "grouped", "matchers": [
+                    { "type": "group", "matchers": [
+                        { "type": "domain_suffix", "value": "example.com" },
+                        { "type": "query_type", "value": "A" }
+                    ] },
+                    { "operator": "or", "type": "group", "matchers": [
+                        { "type": "domain_suffix", "value": "example.net" },
+                        { "type": "query_type", "value": "AAAA" }
+                    ] }
+                ], "actions": [ { "type": "static_response", "rcode": "REFUSED" } ] },
+                { "name": "rest", "matchers": [ { "type": "any" } ], "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        assert!(engine.compiled_for(&engine.pipeline.load(), "main").unwrap().index.always_check.contains(&0));
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        for (qname, qtype, rcode) in [
+            ("www.example.com", RecordType::A, ResponseCode::Refused),
+            ("www.example.net", RecordType::AAAA, ResponseCode::Refused),
+            ("www.example.com", RecordType::AAAA, ResponseCode::NXDomain),
+            ("www.example.net", RecordType::A, ResponseCode::NXDomain),
+        ] {
+            let packet = build_query_packet(qname, qtype, DNSClass::IN);
+            let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+            assert_eq!(msg.response_code(), rcode, "{qname} {qtype:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_compiled_pipeline_falls_back_to_runtime_rules() {
+        let (upstream, hits) = spawn_counting_udp_upstream().await;
+        let config = |blocked: &str| {
+            let raw = serde_json::json!({
+                "settings": { "default_upstream": upstream.to_string() },
+                "pipelines": [ { "id": "p", "rules": [ {
+                    "name": "block",
+                    "matchers": [ { "type": "domain_suffix", "value": blocked } ],
+                    "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ]
+                } ] } ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(config("blocked.example"))), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        assert!(engine.compiled_for(&engine.pipeline.load(), "p").is_some());
+
+        // 热加载后编译结果仍是旧配置的：快速路径不得再按旧规则返回 NXDOMAIN
+        let reloaded = config("other.example");
+        assert!(reloaded.generation > engine.compiled_pipelines.load()[0].generation);
+        engine.pipeline.store(Arc::new(reloaded));
+        assert!(engine.compiled_for(&engine.pipeline.load(), "p").is_none());
+
+        let packet = build_query_packet("blocked.example", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // 新配置的规则经运行时索引生效
+        let packet = build_query_packet("www.other.example", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.expect("response")).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn static_records_file_overrides_rules_with_exact_before_wildcard() {
+        let path = std::env::temp_dir().join(format!("kixdns-static-records-{}.hosts", std::process::id()));
+        std::fs::write(&path, "*.corp.example A 10.0.0.1\napi.corp.example A 10.0.0.2\n").unwrap();
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "127.0.0.1:9", "static_records_file": path.to_string_lossy() },
+            "pipelines": [ { "id": "main", "rules": [
+                { "name": "deny", "matchers": [ { "type": "any" } ], "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+            ] } ]
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        std::fs::remove_file(&path).ok();
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        let query = |qname: &str, qtype| {
+            let packet = build_query_packet(qname, qtype, DNSClass::IN);
+            let engine = engine.clone();
+            async move { Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap() }
+        };
+
+        let msg = query("api.corp.example", RecordType::A).await;
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(10, 0, 0, 2)))));
+        let msg = query("www.corp.example", RecordType::A).await;
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(10, 0, 0, 1)))));
+        // 无对应类型的记录时照常走 pipeline 规则
+        let msg = query("api.corp.example", RecordType::AAAA).await;
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+
+        // 慢路径的 apply_rules 同样先查本地覆盖
+        let cfg = engine.pipeline.load();
+        let decision = engine.apply_rules(&cfg, &cfg.pipelines[0], peer.ip(), "api.corp.example", RecordType::A, DNSClass::IN, None, None, None);
+        assert!(matches!(decision, Decision::Static { rcode: ResponseCode::NoError, ref answers } if answers.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn ptr_queries_answer_from_static_records_reverse_map() {
+        let path = std::env::temp_dir().join(format!("kixdns-static-ptr-{}.hosts", std::process::id()));
+        std::fs::write(&path, "10.0.0.1 nas.lan nas.home\nprinter.lan 120 A 10.0.0.9\n").unwrap();
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| Message::new()).await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "static_records_file": path.to_string_lossy(),
+                "static_reverse_zones": ["0.0.10.in-addr.arpa."]
+            }
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        std::fs::remove_file(&path).ok();
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        // 快速路径直接应答 hosts 行中第一个名字
+        let packet = build_query_packet("1.0.0.10.in-addr.arpa", RecordType::PTR, DNSClass::IN);
+        let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("answered");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(msg.answers()[0].ttl(), 300);
+        match msg.answers()[0].data() {
+            Some(RData::PTR(ptr)) => assert_eq!(ptr.0.to_ascii(), "nas.lan."),
+            other => panic!("unexpected answer {other:?}"),
+        }
+
+        let packet = build_query_packet("9.0.0.10.in-addr.arpa", RecordType::PTR, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert_eq!(msg.answers()[0].ttl(), 120);
+        assert_eq!(msg.answers()[0].data(), Some(&RData::PTR(PTR(Name::from_ascii("printer.lan.").unwrap()))));
+
+        // 区域内未映射的地址返回 NXDOMAIN；区域外的照常转发
+        let packet = build_query_packet("77.0.0.10.in-addr.arpa", RecordType::PTR, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+        let packet = build_query_packet("1.0.0.192.in-addr.arpa", RecordType::PTR, DNSClass::IN);
+        engine.handle_packet(&packet, peer).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn local_zone_answers_apex_authoritatively() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "127.0.0.1:9" },
+            "local_zones": [ {
+                "zone": "corp.example",
+                "soa": { "mname": "ns1.corp.example", "rname": "hostmaster.corp.example", "serial": 2024, "minimum": 60 },
+                "ns": ["ns1.corp.example", "ns2.corp.example"],
+                "records": [ { "name": "www.corp.example", "type": "A", "value": "10.0.0.10" } ]
+            } ],
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("corp.example", RecordType::SOA, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert!(msg.authoritative());
+        assert_eq!(msg.answers().len(), 1);
+        match msg.answers()[0].data() {
+            Some(RData::SOA(soa)) => {
+                assert_eq!(soa.serial(), 2024);
+                assert_eq!(soa.mname().to_ascii(), "ns1.corp.example.");
+            }
+            other => panic!("unexpected answer {other:?}"),
+        }
+
+        let packet = build_query_packet("corp.example", RecordType::NS, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert!(msg.authoritative());
+        assert_eq!(msg.answers().len(), 2);
+        assert!(msg.answers().iter().all(|r| r.record_type() == RecordType::NS));
+
+        let packet = build_query_packet("www.corp.example", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert!(msg.authoritative());
+        assert_eq!(msg.answers()[0].data(), Some(&RData::A(A(Ipv4Addr::new(10, 0, 0, 10)))));
+
+        // 顶点以下的名字没有 SOA/NS：NODATA，授权段携带区域 SOA，不转发
+        for qtype in [RecordType::SOA, RecordType::NS] {
+            let packet = build_query_packet("www.corp.example", qtype, DNSClass::IN);
+            assert!(engine.handle_packet_fast(&packet, peer).expect("fast").is_none());
+            let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+            assert_eq!(msg.response_code(), ResponseCode::NoError);
+            assert!(msg.authoritative());
+            assert!(msg.answers().is_empty());
+            assert_eq!(msg.name_servers()[0].record_type(), RecordType::SOA);
+            assert_eq!(msg.name_servers()[0].name().to_ascii(), "corp.example.");
+        }
+        assert_eq!(engine.metrics_upstream_calls.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn local_zone_nxdomain_carries_soa_authority() {
+        let raw = serde_json::json!({
+            "settings": { "default_upstream": "127.0.0.1:9" },
+            "local_zones": [ {
+                "zone": "corp.example",
+                "soa": { "mname": "ns1.corp.example", "rname": "hostmaster.corp.example", "minimum": 60, "ttl": 3600 },
+                "records": [ { "name": "www.corp.example", "type": "A", "value": "10.0.0.10" } ]
+            } ],
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("missing.corp.example", RecordType::A, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        assert!(msg.authoritative());
+        assert!(msg.answers().is_empty());
+        assert_eq!(msg.name_servers().len(), 1);
+        let soa = &msg.name_servers()[0];
+        assert_eq!(soa.record_type(), RecordType::SOA);
+        assert_eq!(soa.name().to_ascii(), "corp.example.");
+        // 负缓存 TTL 取 min(ttl, minimum)
+        assert_eq!(soa.ttl(), 60);
+
+        // 名字存在但无该类型：NODATA，同样携带 SOA
+        let packet = build_query_packet("www.corp.example", RecordType::AAAA, DNSClass::IN);
+        let msg = Message::from_bytes(&engine.handle_packet(&packet, peer).await.unwrap()).unwrap();
+        assert_eq!(msg.response_code(), ResponseCode::NoError);
+        assert!(msg.answers().is_empty());
+        assert_eq!(msg.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn zone_transfer_policies_are_per_qtype() {
+        let (upstream, hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| Message::new()).await;
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+        for (allow_axfr, allow_ixfr) in [(false, false), (false, true), (true, false), (true, true)] {
+            let raw = serde_json::json!({
+                "settings": {
+                    "default_upstream": upstream.to_string(),
+                    "allow_axfr": allow_axfr,
+                    "allow_ixfr": allow_ixfr
+                }
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+
+            for (qtype, allowed) in [(RecordType::AXFR, allow_axfr), (RecordType::IXFR, allow_ixfr)] {
+                let packet = build_query_packet("example.com", qtype, DNSClass::IN);
+                assert!(engine.handle_packet_fast(&packet, peer).expect("fast").is_none());
+                let before = hits.load(Ordering::SeqCst);
+                let resp = engine.handle_packet(&packet, peer).await.expect("response");
+                let msg = Message::from_bytes(&resp).expect("parse response");
+                let forwarded = hits.load(Ordering::SeqCst) - before;
+                if allowed {
+                    assert_eq!(msg.response_code(), ResponseCode::NoError, "{qtype:?} {allow_axfr} {allow_ixfr}");
+                    assert_eq!(forwarded, 1, "{qtype:?} {allow_axfr} {allow_ixfr}");
+                } else {
+                    assert_eq!(msg.response_code(), ResponseCode::Refused, "{qtype:?} {allow_axfr} {allow_ixfr}");
+                    assert_eq!(forwarded, 0, "{qtype:?} {allow_axfr} {allow_ixfr}");
+                }
+                assert_eq!(msg.id(), 0x1234);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answer_falls_back_to_tcp_with_dedicated_timeout() {
+        // UDP 端立即回 TC=1，同一地址的 TCP 端在超过上游超时后才应答
+        let (upstream, udp_hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| {
+            let mut resp = Message::new();
+            resp.set_truncated(true);
+            resp
+        })
+        .await;
+        spawn_tcp_upstream_with(upstream, Duration::from_millis(300), answer_192_0_2_80).await;
+        let engine = |fallback_ms: u64| {
+            let raw = serde_json::json!({
+                "settings": { "default_upstream": upstream.to_string(), "upstream_timeout_ms": 150, "tcp_fallback_timeout_ms": fallback_ms },
+                "pipelines": [ { "id": "main", "rules": [ { "name": "fwd", "matchers": [ { "type": "any" } ], "actions": [ { "type": "forward" } ] } ] } ]
+            });
+            let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string())
+        };
+
+        let packet = build_query_packet("big.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine(1000).handle_packet(&packet, "127.0.0.1:5300".parse().unwrap()).await.expect("response");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(udp_hits.load(Ordering::SeqCst), 1);
+
+        // 未配置时同样改走 TCP，但沿用 150ms 的上游超时，等不到 300ms 后的 TCP 应答
+        let resp = engine(0).handle_packet(&packet, "127.0.0.1:5300".parse().unwrap()).await.expect("response");
+        let msg = Message::from_bytes(&resp).unwrap();
+        assert!(!msg.truncated());
+        assert_eq!(msg.response_code(), ResponseCode::ServFail);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_is_cached_for_soa_negative_ttl() {
+        let (upstream, upstream_hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |_| {
+            let mut resp = Message::new();
+            resp.set_response_code(ResponseCode::NXDomain);
+            let soa = hickory_proto::rr::rdata::SOA::new(
+                Name::from_str("ns1.example.com.").unwrap(),
+                Name::from_str("hostmaster.example.com.").unwrap(),
+                1,
+                7200,
+                3600,
+                1209600,
+                900,
+            );
+            resp.add_name_server(Record::from_rdata(
+                Name::from_str("example.com.").unwrap(),
+                1800,
+                RData::SOA(soa),
+            ));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": {
+                "default_upstream": upstream.to_string(),
+                "upstream_timeout_ms": 1000,
+                "max_negative_ttl": 120
+            },
+            "pipelines": []
+        });
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("missing.example.com", RecordType::A, DNSClass::IN);
+        let before = std::time::Instant::now();
+        let resp = engine.handle_packet(&packet, peer).await.expect("nxdomain");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NXDomain);
+
+        // min(SOA ttl 1800, minimum 900) = 900, capped by max_negative_ttl = 120.
+        let hash = Engine::calculate_cache_hash_for_dedupe("default", "missing.example.com", RecordType::A, DNSClass::IN, false);
+        let entry = engine.cache.get(&hash).expect("negative answer cached");
+        let lifetime = entry.expires_at.duration_since(before);
+        assert!(lifetime > Duration::from_secs(119) && lifetime <= Duration::from_secs(121), "lifetime {lifetime:?}");
+
+        let resp = engine.handle_packet_fast(&packet, peer).expect("fast").expect("cache hit");
+        assert_eq!(Message::from_bytes(&resp).unwrap().response_code(), ResponseCode::NXDomain);
+        assert_eq!(upstream_hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn clamp_ttl_response_action_rewrites_answer_and_cache_lifetime() {
+        let (upstream, _hits) = spawn_counting_udp_upstream_with(Duration::ZERO, |req| {
+            let mut resp = Message::new();
+            resp.add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                3600,
+                RData::A(A(Ipv4Addr::new(192, 0, 2, 53))),
+            ));
+            resp
+        })
+        .await;
+        let raw = serde_json::json!({
+            "settings": { "upstream_timeout_ms": 1000 },
             "pipelines": [
                 {
-                    "id": "p2",
+                    "id": "p",
                     "rules": [
                         {
-                            "name": "fwd",
+                            "name": "clamp",
                             "matchers": [ { "type": "any" } ],
-                            "actions": [ { "type": "forward", "upstream": "8.8.8.8:53" } ],
-                            "response_matchers": [ { "type": "upstream_equals", "value": "8.8.8.8:53" } ],
-                            "response_matcher_operator": "and"
+                            "actions": [ { "type": "forward", "upstream": upstream.to_string() } ],
+                            "response_actions_on_match": [ { "type": "clamp_ttl", "max": 300 } ]
                         }
                     ]
                 }
             ]
         });
-        let cfg2: crate::config::PipelineConfig = serde_json::from_value(raw2).expect("parse");
-        let runtime2 = RuntimePipelineConfig::from_config(cfg2.clone()).expect("runtime");
-        let arc2 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime2.clone()));
-        let engine2 = Engine::new(arc2.clone(), "lbl".to_string());
-
-        let decision2 = engine2.apply_rules(
-            &runtime2,
-            &runtime2.pipelines[0],
-            "127.0.0.1".parse().unwrap(),
-            "x.example.com",
-            hickory_proto::rr::RecordType::A,
-            hickory_proto::rr::DNSClass::IN,
-            false,
-            None,
-        );
-        match decision2 {
-            Decision::Forward {
-                upstream,
-                response_matchers,
-                response_matcher_operator,
-                ..
-            } => {
-                assert_eq!(upstream, "8.8.8.8:53");
-                assert_eq!(response_matchers.len(), 1);
-                assert_eq!(response_matcher_operator, crate::config::MatchOperator::And);
-            }
-            _ => panic!("expected forward"),
-        }
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let packet = build_query_packet("long-ttl.example.com", RecordType::A, DNSClass::IN);
+        let before = std::time::Instant::now();
+        let resp = engine.handle_packet(&packet, peer).await.expect("resolve");
+        assert_eq!(Message::from_bytes(&resp).unwrap().answers()[0].ttl(), 300);
+
+        let hash = Engine::calculate_cache_hash_for_dedupe("p", "long-ttl.example.com", RecordType::A, DNSClass::IN, false);
+        let entry = engine.cache.get(&hash).expect("clamped answer cached");
+        let lifetime = entry.expires_at.duration_since(before);
+        assert!(lifetime > Duration::from_secs(299) && lifetime <= Duration::from_secs(301), "lifetime {lifetime:?}");
+        assert_eq!(Message::from_bytes(&entry.bytes).unwrap().answers()[0].ttl(), 300);
+    }
 
-        // Allow action -> forward to default upstream
-        let raw3 = serde_json::json!({
-            "settings": { "default_upstream": "1.2.3.4:53" },
-            "pipelines": [ { "id": "p3", "rules": [ { "name": "a", "matchers": [ { "type": "any" } ], "actions": [ { "type": "allow" } ] } ] } ]
+    #[tokio::test]
+    async fn parallel_rule_eval_cache_hit_short_circuits_forward() {
+        let (upstream, upstream_hits) = spawn_counting_udp_upstream().await;
+        let raw = serde_json::json!({
+            "settings": { "parallel_rule_eval": true, "upstream_timeout_ms": 1000, "udp_pool_size": 1 },
+            "pipelines": [
+                {
+                    "id": "p",
+                    "rules": [
+                        {
+                            "name": "fwd",
+                            "matchers": [ { "type": "any" } ],
+                            "actions": [ { "type": "forward", "upstream": upstream.to_string() } ]
+                        }
+                    ]
+                }
+            ]
         });
-        let cfg3: crate::config::PipelineConfig = serde_json::from_value(raw3).expect("parse");
-        let runtime3 = RuntimePipelineConfig::from_config(cfg3.clone()).expect("runtime");
-        let arc3 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime3.clone()));
-        let engine3 = Engine::new(arc3.clone(), "lbl".to_string());
-
-        let decision3 = engine3.apply_rules(
-            &runtime3,
-            &runtime3.pipelines[0],
-            "127.0.0.1".parse().unwrap(),
-            "y.example.com",
-            hickory_proto::rr::RecordType::A,
-            hickory_proto::rr::DNSClass::IN,
-            false,
-            None,
+        let cfg: crate::config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let engine = Engine::new(Arc::new(ArcSwap::from_pointee(runtime)), "lbl".to_string());
+        let peer: SocketAddr = "127.0.0.1:5300".parse().unwrap();
+
+        let mut cached = Message::new();
+        cached.set_message_type(MessageType::Response);
+        cached.set_response_code(ResponseCode::NXDomain);
+        let cached_bytes = Bytes::from(cached.to_vec().unwrap());
+        let (original_ttl, expires_at, stale_until) = CacheEntry::deadlines(Duration::from_secs(60), Duration::ZERO);
+        engine.cache.insert(
+            Engine::calculate_cache_hash_for_dedupe("p", "cached.example.com", RecordType::A, DNSClass::IN, false),
+            CacheEntry {
+                bytes: cached_bytes,
+                rcode: ResponseCode::NXDomain,
+                source: Arc::from("test"),
+                qname: Arc::from("cached.example.com"),
+                pipeline_id: Arc::from("p"),
+                qtype: u16::from(RecordType::A),
+                qclass: u16::from(DNSClass::IN),
+                expires_at,
+                stale_until,
+                original_ttl,
+                dnssec_ok: false,
+            },
         );
-        match decision3 {
-            Decision::Forward { upstream, .. } => assert_eq!(upstream, "1.2.3.4:53"),
-            _ => panic!("expected forward from allow"),
-        }
-
-        // JumpToPipeline
-        let raw4 = serde_json::json!({
-            "pipelines": [ { "id": "p4", "rules": [ { "name": "j", "matchers": [ { "type": "any" } ], "actions": [ { "type": "jump_to_pipeline", "pipeline": "other" } ] } ] } ]
-        });
-        let cfg4: crate::config::PipelineConfig = serde_json::from_value(raw4).expect("parse");
-        let runtime4 = RuntimePipelineConfig::from_config(cfg4.clone()).expect("runtime");
-        let arc4 = Arc::new(arc_swap::ArcSwap::from_pointee(runtime4.clone()));
-        let engine4 = Engine::new(arc4.clone(), "lbl".to_string());
 
-        let decision4 = engine4.apply_rules(
-            &runtime4,
-            &runtime4.pipelines[0],
-            "127.0.0.1".parse().unwrap(),
-            "z.example.com",
-            hickory_proto::rr::RecordType::A,
-            hickory_proto::rr::DNSClass::IN,
-            false,
-            None,
-        );
-        match decision4 {
-            Decision::Jump { pipeline } => assert_eq!(pipeline, "other"),
-            _ => panic!("expected jump"),
-        }
+        let packet = build_query_packet("cached.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("cached response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.id(), 0x1234);
+        assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(upstream_hits.load(Ordering::SeqCst), 0);
+        assert_eq!(engine.metrics_upstream_calls.load(Ordering::Relaxed), 0);
+        assert!(engine.inflight.is_empty());
+
+        // A miss uses the speculatively evaluated decision and forwards exactly once.
+        let packet = build_query_packet("miss.example.com", RecordType::A, DNSClass::IN);
+        let resp = engine.handle_packet(&packet, peer).await.expect("forwarded response");
+        let msg = Message::from_bytes(&resp).expect("parse response");
+        assert_eq!(msg.answers().len(), 1);
+        assert_eq!(upstream_hits.load(Ordering::SeqCst), 1);
+        assert!(engine.inflight.is_empty());
     }
 
-    const TEST_UPSTREAM: &str = "1.1.1.1:53";
-
     fn build_test_engine() -> Engine {
         let runtime = RuntimePipelineConfig {
             settings: GlobalSettings {
@@ -2545,6 +9489,20 @@ mod tests {
             },
             pipeline_select: Vec::new(),
             pipelines: Vec::new(),
+            default_upstream: UpstreamGroup::parse(TEST_UPSTREAM),
+            upstream_shards: Vec::new(),
+            answer_ip_allowlist: Vec::new(),
+            allow_networks: Vec::new(),
+            deny_networks: Vec::new(),
+            dns64_prefix: None,
+            tcp_for_qtypes: Vec::new(),
+            upstream_port_range: None,
+            cookies: None,
+            local_zones: Vec::new(),
+            static_records: None,
+            reverse_zones: Vec::new(),
+            geo: None,
+            generation: 0,
         };
         let arc = Arc::new(arc_swap::ArcSwap::from_pointee(runtime.clone()));
         Engine::new(arc, "lbl".to_string())
@@ -2589,7 +9547,7 @@ mod tests {
                 RecordType::A,
                 DNSClass::IN,
                 client_ip,
-                TEST_UPSTREAM,
+                &UpstreamGroup::parse(TEST_UPSTREAM),
                 "pipeline",
                 "rule",
                 10,
@@ -2606,6 +9564,126 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn response_rewrite_ip_maps_host_bits_and_keeps_ttl() {
+        let engine = build_test_engine();
+        let name = Name::from_str("vpn.example.com").expect("name");
+        let mut msg = Message::new();
+        msg.set_id(0x4242);
+        msg.add_answer(Record::from_rdata(name.clone(), 120, RData::A(A(Ipv4Addr::new(203, 0, 113, 7)))));
+        msg.add_answer(Record::from_rdata(name.clone(), 90, RData::A(A(Ipv4Addr::new(198, 51, 100, 7)))));
+        msg.add_answer(Record::from_rdata(name, 60, RData::AAAA(AAAA("2001:db8:1::7".parse().unwrap()))));
+        let ctx = ResponseContext {
+            raw: Bytes::from(msg.to_vec().unwrap()),
+            msg,
+            upstream: TEST_UPSTREAM.to_string(),
+            transport: Transport::Udp,
+        };
+        let actions = [
+            ("203.0.113.0/24", "10.8.0.0/24"),
+            ("2001:db8:1::/48", "fd00:8::/48"),
+        ]
+        .map(|(from, to)| Action::RewriteIp {
+            from: from.into(),
+            to: to.into(),
+            nets: Some(crate::config::rewrite_ip_nets(from, to).unwrap()),
+        });
+        let client_ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let result = engine
+            .apply_response_actions(
+                &actions,
+                Some(ctx),
+                &Message::new(),
+                &[0u8],
+                Duration::from_secs(1),
+                &[],
+                "vpn.example.com",
+                RecordType::A,
+                DNSClass::IN,
+                client_ip,
+                &UpstreamGroup::parse(TEST_UPSTREAM),
+                "pipeline",
+                "rule",
+                10,
+            )
+            .await
+            .expect("rewrite succeeds");
+
+        let ResponseActionResult::Upstream { ctx, .. } = result else {
+            panic!("expected upstream result");
+        };
+        let decoded = Message::from_bytes(&ctx.raw).expect("re-encoded response");
+        assert_eq!(decoded.id(), 0x4242);
+        let answers: Vec<(Option<RData>, u32)> = decoded.answers().iter().map(|r| (r.data().cloned(), r.ttl())).collect();
+        assert_eq!(
+            answers,
+            vec![
+                (Some(RData::A(A(Ipv4Addr::new(10, 8, 0, 7)))), 120),
+                (Some(RData::A(A(Ipv4Addr::new(198, 51, 100, 7)))), 90),
+                (Some(RData::AAAA(AAAA("fd00:8::7".parse().unwrap()))), 60),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nxdomain_if_answer_ip_converts_sinkhole_answers() {
+        let engine = build_test_engine();
+        let actions = [Action::NxdomainIfAnswerIp {
+            ips: vec!["198.18.0.9".into(), "2001:db8::53".into()],
+            addrs: vec!["198.18.0.9".parse().unwrap(), "2001:db8::53".parse().unwrap()],
+        }];
+        let client_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut req = Message::new();
+        req.set_id(0x5151);
+        req.add_query(Query::query(Name::from_str("nope.example.com.").unwrap(), RecordType::A));
+
+        for (ip, sinkholed) in [(Ipv4Addr::new(198, 18, 0, 9), true), (Ipv4Addr::new(192, 0, 2, 1), false)] {
+            let mut msg = Message::new();
+            msg.add_answer(Record::from_rdata(Name::from_str("nope.example.com.").unwrap(), 60, RData::A(A(ip))));
+            let ctx = ResponseContext {
+                raw: Bytes::from(msg.to_vec().unwrap()),
+                msg,
+                upstream: TEST_UPSTREAM.to_string(),
+                transport: Transport::Udp,
+            };
+            let result = engine
+                .apply_response_actions(
+                    &actions,
+                    Some(ctx),
+                    &req,
+                    &[0u8],
+                    Duration::from_secs(1),
+                    &[],
+                    "nope.example.com",
+                    RecordType::A,
+                    DNSClass::IN,
+                    client_ip,
+                    &UpstreamGroup::parse(TEST_UPSTREAM),
+                    "pipeline",
+                    "rule",
+                    10,
+                )
+                .await
+                .expect("response actions");
+            match result {
+                ResponseActionResult::Static { bytes, rcode, .. } => {
+                    assert!(sinkholed);
+                    assert_eq!(rcode, ResponseCode::NXDomain);
+                    let msg = Message::from_bytes(&bytes).expect("nxdomain response");
+                    assert_eq!(msg.id(), 0x5151);
+                    assert_eq!(msg.response_code(), ResponseCode::NXDomain);
+                    assert!(msg.answers().is_empty());
+                }
+                ResponseActionResult::Upstream { ctx, .. } => {
+                    assert!(!sinkholed);
+                    assert_eq!(ctx.msg.answers().len(), 1);
+                }
+                _ => panic!("unexpected response action result"),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn response_actions_allow_reports_miss_when_matchers_fail() {
         let engine = build_test_engine();
@@ -2633,7 +9711,7 @@ mod tests {
                 RecordType::A,
                 DNSClass::IN,
                 client_ip,
-                TEST_UPSTREAM,
+                &UpstreamGroup::parse(TEST_UPSTREAM),
                 "pipeline",
                 "rule",
                 10,
@@ -2668,7 +9746,7 @@ mod tests {
                 RecordType::A,
                 DNSClass::IN,
                 client_ip,
-                TEST_UPSTREAM,
+                &UpstreamGroup::parse(TEST_UPSTREAM),
                 "pipeline",
                 "rule",
                 10,
@@ -2698,6 +9776,7 @@ fn parse_rcode(rcode: &str) -> Option<ResponseCode> {
     }
 }
 
+/// 本地构造的应答；UDP 超出客户端声明的 EDNS 负载（无 EDNS 为 512）时由发送端的 truncate_for_udp 置 TC 截断
 #[inline]
 fn build_response(
     req: &Message,
@@ -2727,7 +9806,234 @@ fn build_response(
     Ok(Bytes::from(out))
 }
 
-fn extract_ttl(msg: &Message) -> u64 {
+/// 本地区域的权威应答：AA=1，NXDOMAIN/NODATA 时授权段携带区域 SOA
+fn build_authoritative_response(
+    req: &Message,
+    rcode: ResponseCode,
+    answers: Vec<Record>,
+    authority: Vec<Record>,
+) -> anyhow::Result<Bytes> {
+    let mut msg = Message::new();
+    msg.set_id(req.id());
+    msg.set_message_type(MessageType::Response);
+    msg.set_op_code(OpCode::Query);
+    msg.set_recursion_desired(req.recursion_desired());
+    msg.set_recursion_available(true);
+    msg.set_authoritative(true);
+    msg.set_response_code(rcode);
+    msg.add_queries(req.queries().iter().cloned());
+    msg.add_answers(answers);
+    msg.add_name_servers(authority);
+    Ok(Bytes::from(msg.to_vec()?))
+}
+
+/// response_jump_limit 超限时的应答：rcode 取自 jump_limit_action，开启 jump_limit_ede 且请求带 EDNS 时附带 EDE
+fn build_jump_limit_response(req: &Message, settings: &crate::config::GlobalSettings) -> anyhow::Result<Bytes> {
+    const EDE_OPTION_CODE: u16 = 15;
+    const EDE_INFO_OTHER: u16 = 0;
+    let bytes = build_response(req, settings.jump_limit_action.rcode(), Vec::new())?;
+    let Some(req_edns) = req.extensions().as_ref().filter(|_| settings.jump_limit_ede) else {
+        return Ok(bytes);
+    };
+    let mut msg = Message::from_bytes(&bytes).context("parse jump limit response")?;
+    let mut payload = EDE_INFO_OTHER.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"response jump limit exceeded");
+    let mut edns = hickory_proto::op::Edns::new();
+    edns.set_max_payload(req_edns.max_payload().max(512));
+    edns.options_mut()
+        .insert(hickory_proto::rr::rdata::opt::EdnsOption::Unknown(EDE_OPTION_CODE, payload));
+    msg.set_edns(edns);
+    Ok(Bytes::from(msg.to_bytes().context("encode jump limit response")?))
+}
+
+/// Forward 动作的 min_ttl/max_ttl 组合为钳制区间；均未配置时返回 None
+fn ttl_clamp(min_ttl: Option<u32>, max_ttl: Option<u32>) -> Option<(u32, u32)> {
+    if min_ttl.is_none() && max_ttl.is_none() {
+        return None;
+    }
+    let lo = min_ttl.unwrap_or(0);
+    Some((lo, max_ttl.unwrap_or(u32::MAX).max(lo)))
+}
+
+/// 陈旧应答：改写事务 ID 与问题名大小写，并将应答 TTL 钳制到 STALE_ANSWER_TTL 以促使客户端尽快重查
+fn stale_response(entry: &CacheEntry, tx_id: u16, packet: &[u8]) -> Bytes {
+    let mut resp_vec = match clamp_answer_ttls(&entry.bytes, (0, STALE_ANSWER_TTL)) {
+        Ok((bytes, _)) => bytes.to_vec(),
+        Err(_) => entry.bytes.to_vec(),
+    };
+    if resp_vec.len() >= 2 {
+        let id_bytes = tx_id.to_be_bytes();
+        resp_vec[0] = id_bytes[0];
+        resp_vec[1] = id_bytes[1];
+    }
+    echo_question_name(packet, &mut resp_vec);
+    Bytes::from(resp_vec)
+}
+
+/// 解析上游响应；配置了 TTL 钳制时同时改写应答段的 TTL
+fn parse_upstream_response(raw: &Bytes, clamp: Option<(u32, u32)>) -> anyhow::Result<(Bytes, Message)> {
+    match clamp {
+        Some(clamp) => clamp_answer_ttls(raw, clamp),
+        None => {
+            let msg = Message::from_bytes(raw).context("parse upstream response")?;
+            Ok((raw.clone(), msg))
+        }
+    }
+}
+
+/// 上游响应无法解析时按 settings.response_parse_failure 得到替代应答；Error 策略原样返回解析错误
+fn response_parse_fallback(policy: ResponseParseFailure, packet: &[u8], raw: Bytes, err: anyhow::Error) -> anyhow::Result<Bytes> {
+    match policy {
+        ResponseParseFailure::Error => Err(err),
+        ResponseParseFailure::Passthrough => Ok(raw),
+        ResponseParseFailure::Servfail => {
+            error_response(packet, u16::from(ResponseCode::ServFail) as u8).context("servfail response")
+        }
+    }
+}
+
+/// 将应答段记录的 TTL 钳制到区间内并重新编码
+fn clamp_answer_ttls(raw: &[u8], (lo, hi): (u32, u32)) -> anyhow::Result<(Bytes, Message)> {
+    let mut msg = Message::from_bytes(raw).context("parse upstream response")?;
+    let mut answers = msg.take_answers();
+    for record in &mut answers {
+        record.set_ttl(record.ttl().clamp(lo, hi));
+    }
+    msg.insert_answers(answers);
+    let bytes = msg.to_bytes().context("encode ttl-clamped response")?;
+    Ok((Bytes::from(bytes), msg))
+}
+
+/// 响应动作 set_ttl / clamp_ttl：钳制应答段与授权段记录的 TTL 并重新编码；
+/// 调用方随后按 ctx.msg 重新计算缓存 TTL，因此缓存寿命与改写后的 TTL 一致
+fn clamp_record_ttls(ctx: &mut ResponseContext, (lo, hi): (u32, u32)) -> anyhow::Result<()> {
+    for record in ctx.msg.answers_mut() {
+        record.set_ttl(record.ttl().clamp(lo, hi));
+    }
+    for record in ctx.msg.name_servers_mut() {
+        record.set_ttl(record.ttl().clamp(lo, hi));
+    }
+    ctx.raw = Bytes::from(ctx.msg.to_bytes().context("encode ttl-rewritten response")?);
+    Ok(())
+}
+
+/// settings.normalize_rrset_ttl / sort_answers：改写上游应答的记录；没有任何改动时返回 None，避免重新编码
+fn postprocess_upstream_records(raw: &[u8], normalize: bool, sort: bool) -> anyhow::Result<Option<Bytes>> {
+    let mut msg = Message::from_bytes(raw).context("parse upstream response")?;
+    let mut changed = false;
+    if normalize {
+        changed |= normalize_rrset_ttls(&mut msg);
+    }
+    if sort {
+        changed |= sort_answers(&mut msg);
+    }
+    if !changed {
+        return Ok(None);
+    }
+    Ok(Some(Bytes::from(msg.to_bytes().context("encode rewritten upstream response")?)))
+}
+
+/// 应答段每个 RRset（同名、同类型、同 class）内部按 rdata 编码排序，记录仍放回该 RRset 原来占用的位置，
+/// RRset 之间的顺序（CNAME 链在前、目标地址在后）不变；返回顺序是否变化
+fn sort_answers(msg: &mut Message) -> bool {
+    use hickory_proto::rr::RecordType;
+    type RrsetKey = (Name, RecordType, DNSClass);
+    let mut answers = msg.take_answers();
+    let mut slots: std::collections::HashMap<RrsetKey, Vec<usize>> = std::collections::HashMap::new();
+    for (i, r) in answers.iter().enumerate() {
+        slots.entry((r.name().clone(), r.record_type(), r.dns_class())).or_default().push(i);
+    }
+    let rdata = |r: &Record| r.data().and_then(|d| d.to_bytes().ok()).unwrap_or_default();
+    let mut changed = false;
+    for idx in slots.values().filter(|idx| idx.len() > 1) {
+        let mut members: Vec<_> = idx.iter().map(|&i| (rdata(&answers[i]), answers[i].clone())).collect();
+        if members.is_sorted_by(|a, b| a.0 <= b.0) {
+            continue;
+        }
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        for (&i, (_, r)) in idx.iter().zip(members) {
+            answers[i] = r;
+        }
+        changed = true;
+    }
+    msg.insert_answers(answers);
+    changed
+}
+
+/// 将应答段、授权段与附加段中每个 RRset（同名、同类型、同 class）的 TTL 统一为其最小值；
+/// 返回是否有记录被改动。RRSIG 按所覆盖的类型各自成组，这里不做改动
+fn normalize_rrset_ttls(msg: &mut Message) -> bool {
+    fn normalize(records: &mut [Record]) -> bool {
+        use hickory_proto::rr::RecordType;
+        type RrsetKey = (Name, RecordType, DNSClass);
+        let key = |r: &Record| -> RrsetKey { (r.name().clone(), r.record_type(), r.dns_class()) };
+        let mut min_ttls: std::collections::HashMap<RrsetKey, u32> = std::collections::HashMap::new();
+        for r in records.iter().filter(|r| r.record_type() != RecordType::RRSIG) {
+            let ttl = min_ttls.entry(key(r)).or_insert(r.ttl());
+            *ttl = (*ttl).min(r.ttl());
+        }
+        let mut changed = false;
+        for r in records.iter_mut() {
+            if let Some(&ttl) = min_ttls.get(&key(r))
+                && r.ttl() != ttl
+            {
+                r.set_ttl(ttl);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    normalize(msg.answers_mut()) | normalize(msg.name_servers_mut()) | normalize(msg.additionals_mut())
+}
+
+/// 是否有 A/AAAA 应答地址出现在 ips 中（配置加载时已校验，无法解析的项忽略）
+fn answers_contain_ip(msg: &Message, ips: &[IpAddr]) -> bool {
+    msg.answers().iter().any(|record| {
+        let addr = match record.data() {
+            Some(RData::A(a)) => IpAddr::V4(a.0),
+            Some(RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+            _ => return false,
+        };
+        ips.contains(&addr)
+    })
+}
+
+/// 将落在 from 内的 A/AAAA 应答保留主机位映射到 to 网段；返回是否有记录被改写
+fn rewrite_answer_ips(msg: &mut Message, from: ipnet::IpNet, to: ipnet::IpNet) -> bool {
+    let mut changed = false;
+    for record in msg.answers_mut() {
+        let rewritten = match (record.data(), from, to) {
+            (Some(RData::A(a)), ipnet::IpNet::V4(from), ipnet::IpNet::V4(to)) if from.contains(&a.0) => {
+                let host = u32::from(a.0) & u32::from(from.hostmask());
+                Some(RData::A(A(std::net::Ipv4Addr::from(u32::from(to.network()) | host))))
+            }
+            (Some(RData::AAAA(aaaa)), ipnet::IpNet::V6(from), ipnet::IpNet::V6(to)) if from.contains(&aaaa.0) => {
+                let host = u128::from(aaaa.0) & u128::from(from.hostmask());
+                Some(RData::AAAA(AAAA(std::net::Ipv6Addr::from(u128::from(to.network()) | host))))
+            }
+            _ => None,
+        };
+        if let Some(rdata) = rewritten {
+            record.set_data(Some(rdata));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// 应答记录的最小 TTL；无应答时按 RFC 2308 取授权段 SOA 的 min(TTL, MINIMUM)，并以 max_negative_ttl 封顶
+fn extract_ttl(msg: &Message, max_negative_ttl: u64) -> u64 {
+    if msg.answers().is_empty() {
+        return msg
+            .name_servers()
+            .iter()
+            .find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some((r.ttl() as u64).min(soa.minimum() as u64)),
+                _ => None,
+            })
+            .map_or(0, |ttl| ttl.min(max_negative_ttl));
+    }
     let ttl_answers = msg
         .answers()
         .iter()
@@ -2738,14 +10044,90 @@ fn extract_ttl(msg: &Message) -> u64 {
 
 // 已使用 moka 自动过期缓存，无需手动 GC
 
+/// spawn_stats_logger 每个周期的累计计数快照，相邻两次相减得到本周期的值
+struct StatsSample {
+    cache: CacheStats,
+    upstream_ns: u64,
+    upstream_calls: u64,
+}
+
+impl StatsSample {
+    fn take(engine: &Engine) -> Self {
+        Self {
+            cache: engine.cache_stats(),
+            upstream_ns: engine.metrics_upstream_ns_total.load(Ordering::Relaxed),
+            upstream_calls: engine.metrics_upstream_calls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Engine::join_inflight 的结果
+enum InflightSlot {
+    /// 首个请求：负责转发并通知等待者
+    Leader,
+    /// 等待 Leader 的结果
+    Waiter(oneshot::Receiver<anyhow::Result<Bytes>>),
+    /// 等待队列已满，独立转发
+    Overflow,
+}
+
+/// 单客户端进行中查询计数，Drop 时递减，归零即移除条目
+struct ClientInflightGuard {
+    map: Arc<DashMap<IpAddr, usize, FxBuildHasher>>,
+    ip: IpAddr,
+}
+
+impl ClientInflightGuard {
+    fn acquire(map: &Arc<DashMap<IpAddr, usize, FxBuildHasher>>, ip: IpAddr, limit: usize) -> Option<Self> {
+        let mut count = map.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            map: Arc::clone(map),
+            ip,
+        })
+    }
+}
+
+impl Drop for ClientInflightGuard {
+    fn drop(&mut self) {
+        use dashmap::mapref::entry::Entry;
+        if let Entry::Occupied(mut entry) = self.map.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// 应答头部的 RCODE 为 REFUSED(5) 或 SERVFAIL(2)
+#[inline]
+fn is_refused_or_servfail(raw: &[u8]) -> bool {
+    raw.len() >= 4 && matches!(raw[3] & 0x0F, 2 | 5)
+}
+
+/// 应答头部的 RCODE 为 SERVFAIL(2)
+#[inline]
+fn is_servfail(raw: &[u8]) -> bool {
+    raw.len() >= 4 && raw[3] & 0x0F == 2
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum Decision {
     Static {
         rcode: ResponseCode,
         answers: Vec<Record>,
     },
+    // 按客户端计算的静态应答（geo_static_ip），不进入规则缓存与应答缓存
+    ClientStatic {
+        rcode: ResponseCode,
+        answers: Vec<Record>,
+    },
     Forward {
-        upstream: String,
+        upstream: UpstreamGroup,
         response_matchers: Vec<RuntimeResponseMatcherWithOp>,
         response_matcher_operator: crate::config::MatchOperator,
         response_actions_on_match: Vec<Action>,
@@ -2757,6 +10139,10 @@ pub(crate) enum Decision {
         #[allow(dead_code)]
         continue_on_miss: bool,
         allow_reuse: bool,
+        // 规则级 TTL 钳制 (min, max)
+        ttl_clamp: Option<(u32, u32)>,
+        // 所在 pipeline 的 upstream_timeout_ms；None 时用全局超时
+        upstream_timeout: Option<Duration>,
     },
     Jump {
         pipeline: String,
@@ -2782,6 +10168,10 @@ enum ResponseActionResult {
         rcode: ResponseCode,
         source: &'static str,
     },
+    /// 转发得到的响应无法解析，按 settings.response_parse_failure 得到的替代应答；不缓存
+    Unparsed {
+        bytes: Bytes,
+    },
     Jump {
         pipeline: String,
         remaining_jumps: usize,
@@ -2792,11 +10182,25 @@ enum ResponseActionResult {
 }
 
 #[inline]
-fn calculate_rule_hash(pipeline_id: &str, qname: &str, client_ip: IpAddr) -> u64 {
+fn calculate_rule_hash(
+    pipeline_id: &str,
+    qname: &str,
+    qtype: hickory_proto::rr::RecordType,
+    qclass: DNSClass,
+    client_ip: IpAddr,
+    edns_bufsize: Option<u16>,
+    ecs: Option<IpAddr>,
+) -> u64 {
     let mut hasher = DefaultHasher::new();
     pipeline_id.hash(&mut hasher);
     qname.hash(&mut hasher);
+    u16::from(qtype).hash(&mut hasher);
+    u16::from(qclass).hash(&mut hasher);
     client_ip.hash(&mut hasher);
+    // EdnsPresent / EdnsAtLeast 匹配器会让同一问题因 EDNS 及其负载大小不同得出不同决策
+    edns_bufsize.hash(&mut hasher);
+    // ecs_subnet 同理，按请求声明的 ECS 地址区分
+    ecs.hash(&mut hasher);
     hasher.finish()
 }
 
@@ -2805,13 +10209,31 @@ struct RuleCacheEntry {
     pipeline_id: Arc<str>,
     qname_hash: u64,
     client_ip: IpAddr,
+    qtype: hickory_proto::rr::RecordType,
+    qclass: DNSClass,
+    edns_bufsize: Option<u16>,
+    ecs: Option<IpAddr>,
     decision: Decision,
 }
 
 impl RuleCacheEntry {
     #[inline]
-    fn matches(&self, pipeline_id: &str, qname: &str, client_ip: IpAddr) -> bool {
+    #[allow(clippy::too_many_arguments)]
+    fn matches(
+        &self,
+        pipeline_id: &str,
+        qname: &str,
+        qtype: hickory_proto::rr::RecordType,
+        qclass: DNSClass,
+        client_ip: IpAddr,
+        edns_bufsize: Option<u16>,
+        ecs: Option<IpAddr>,
+    ) -> bool {
         self.client_ip == client_ip
+            && self.qtype == qtype
+            && self.qclass == qclass
+            && self.edns_bufsize == edns_bufsize
+            && self.ecs == ecs
             && self.pipeline_id.as_ref() == pipeline_id
             && self.qname_hash == fast_hash_str(qname)
     }
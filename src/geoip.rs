@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// 客户端 IP 到国家代码（ISO 3166-1 alpha-2，大写）的查询接口
+pub trait GeoLookup: Send + Sync + std::fmt::Debug {
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// 客户端 IP 到自治系统号（ASN）的查询接口
+pub trait AsnLookup: Send + Sync + std::fmt::Debug {
+    fn asn(&self, ip: IpAddr) -> Option<u32>;
+}
+
+/// ASN 查询结果缓存的 IP 数上限
+const ASN_CACHE_CAPACITY: u64 = 65_536;
+
+/// 打开国家数据库；未启用 geoip 特性时报错
+pub fn open(path: &str) -> anyhow::Result<Arc<dyn GeoLookup>> {
+    #[cfg(feature = "geoip")]
+    {
+        Ok(Arc::new(MaxmindLookup::open(path)?))
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        anyhow::bail!("geoip_db {} requires the geoip feature", path)
+    }
+}
+
+/// 打开 ASN 数据库（GeoLite2-ASN），结果按 IP 缓存；未启用 geoip 特性时报错
+pub fn open_asn(path: &str) -> anyhow::Result<Arc<dyn AsnLookup>> {
+    #[cfg(feature = "geoip")]
+    {
+        Ok(Arc::new(CachedAsnLookup::new(Arc::new(MaxmindAsnLookup::open(path)?))))
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        anyhow::bail!("asn_db {} requires the geoip feature", path)
+    }
+}
+
+/// 按 IP 缓存 ASN 查询结果（包括查不到的 None），同一客户端的后续查询不再访问数据库；
+/// 数据库随配置重新加载，缓存也随之重建，因此不设过期时间
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+#[derive(Debug)]
+pub struct CachedAsnLookup {
+    inner: Arc<dyn AsnLookup>,
+    cache: moka::sync::Cache<IpAddr, Option<u32>>,
+}
+
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+impl CachedAsnLookup {
+    pub fn new(inner: Arc<dyn AsnLookup>) -> Self {
+        Self {
+            inner,
+            cache: moka::sync::Cache::new(ASN_CACHE_CAPACITY),
+        }
+    }
+}
+
+impl AsnLookup for CachedAsnLookup {
+    fn asn(&self, ip: IpAddr) -> Option<u32> {
+        self.cache.get_with(ip, || self.inner.asn(ip))
+    }
+}
+
+/// 基于 MaxMind GeoLite2/GeoIP2 Country 数据库的查询
+#[cfg(feature = "geoip")]
+#[derive(Debug)]
+pub struct MaxmindLookup {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxmindLookup {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|err| anyhow::anyhow!("open geoip db {}: {}", path, err))?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLookup for MaxmindLookup {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|c| c.iso_code)
+            .map(|code| code.to_ascii_uppercase())
+    }
+}
+
+/// 基于 MaxMind GeoLite2-ASN 数据库的查询
+#[cfg(feature = "geoip")]
+#[derive(Debug)]
+pub struct MaxmindAsnLookup {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxmindAsnLookup {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|err| anyhow::anyhow!("open asn db {}: {}", path, err))?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl AsnLookup for MaxmindAsnLookup {
+    fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let record: maxminddb::geoip2::Asn = self.reader.lookup(ip).ok()?;
+        record.autonomous_system_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct CountingAsn {
+        lookups: AtomicUsize,
+    }
+
+    impl AsnLookup for CountingAsn {
+        fn asn(&self, ip: IpAddr) -> Option<u32> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            match ip {
+                IpAddr::V4(v4) if !v4.is_private() => Some(64500),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn cached_asn_lookup_hits_the_database_once_per_ip() {
+        let inner = Arc::new(CountingAsn::default());
+        let cached = CachedAsnLookup::new(inner.clone());
+        let public: IpAddr = "198.51.100.7".parse().unwrap();
+        let private: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(cached.asn(public), Some(64500));
+            assert_eq!(cached.asn(private), None);
+        }
+        assert_eq!(inner.lookups.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+use crate::config::Transport;
+
+/// 单个上游的健康状态
+#[derive(Debug, Clone, Copy, Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    unhealthy: bool,
+    /// 最近一次失败所用的传输方式，探测时沿用
+    transport: Option<Transport>,
+}
+
+/// 按上游地址统计连续失败次数；达到阈值后标记为不健康，直到一次成功（请求或探测）将其恢复
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    states: DashMap<String, HealthState>,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        // 健康上游的条目直接移除，常态下表中只保留近期失败过的上游
+        self.states.remove(upstream);
+    }
+
+    /// 返回 true 表示本次失败使该上游转为不健康
+    pub fn record_failure(&self, upstream: &str, transport: Transport, threshold: u32) -> bool {
+        let mut state = self.states.entry(upstream.to_string()).or_default();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        state.transport = Some(transport);
+        if !state.unhealthy && state.consecutive_failures >= threshold.max(1) {
+            state.unhealthy = true;
+            return true;
+        }
+        false
+    }
+
+    #[inline]
+    pub fn is_healthy(&self, upstream: &str) -> bool {
+        self.states.get(upstream).is_none_or(|s| !s.unhealthy)
+    }
+
+    /// 当前不健康的上游（已排序，便于输出与测试）
+    pub fn unhealthy(&self) -> Vec<String> {
+        let mut out: Vec<String> = self
+            .states
+            .iter()
+            .filter(|e| e.unhealthy)
+            .map(|e| e.key().clone())
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// 当前不健康的上游及其最近一次失败所用的传输方式（按地址排序）
+    pub fn unhealthy_transports(&self) -> Vec<(String, Transport)> {
+        let mut out: Vec<(String, Transport)> = self
+            .states
+            .iter()
+            .filter(|e| e.unhealthy)
+            .map(|e| (e.key().clone(), e.transport.unwrap_or(Transport::Udp)))
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+}
+
+/// EWMA 以 1/1024 纳秒为单位的定点数保存，小 alpha 下也不会因取整而停滞
+const LATENCY_FIXED_SHIFT: u32 = 10;
+
+/// 按上游地址统计应答延迟的指数加权移动平均（EWMA，失败的转发按超时计入）：与累计平均不同，旧的慢时段会随新样本逐渐被遗忘
+#[derive(Debug, Default)]
+pub struct UpstreamLatency {
+    ewma: DashMap<String, AtomicU64>,
+}
+
+impl UpstreamLatency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记入一个样本：首个样本直接作为初值，之后 ewma += alpha * (sample - ewma)
+    pub fn record(&self, upstream: &str, sample: Duration, alpha: f64) {
+        let sample = (sample.as_nanos() as u64).saturating_mul(1 << LATENCY_FIXED_SHIFT) as f64;
+        let update = |old: u64| (old as f64 + alpha * (sample - old as f64)).max(0.0) as u64;
+        if let Some(ewma) = self.ewma.get(upstream) {
+            let _ = ewma.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| Some(update(old)));
+            return;
+        }
+        self.ewma
+            .entry(upstream.to_string())
+            .and_modify(|ewma| {
+                let _ = ewma.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| Some(update(old)));
+            })
+            .or_insert_with(|| AtomicU64::new(sample as u64));
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, upstream: &str) -> Option<Duration> {
+        self.ewma
+            .get(upstream)
+            .map(|ewma| Duration::from_nanos(ewma.load(Ordering::Relaxed) >> LATENCY_FIXED_SHIFT))
+    }
+
+    /// members 中 EWMA 最低的下标；尚无样本的上游视为最快，保证每个上游都先被测量一次
+    pub fn fastest(&self, members: &[String]) -> usize {
+        members
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, u)| self.ewma.get(u.as_str()).map(|e| e.load(Ordering::Relaxed)))
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// 全部上游的当前 EWMA（按地址排序，便于输出与测试）
+    pub fn snapshot(&self) -> Vec<(String, Duration)> {
+        let mut out: Vec<(String, Duration)> = self
+            .ewma
+            .iter()
+            .map(|e| (e.key().clone(), Duration::from_nanos(e.value().load(Ordering::Relaxed) >> LATENCY_FIXED_SHIFT)))
+            .collect();
+        out.sort();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consecutive_failures_mark_unhealthy_until_success() {
+        let health = UpstreamHealth::new();
+        assert!(!health.record_failure("a", Transport::Udp, 3));
+        assert!(!health.record_failure("a", Transport::Udp, 3));
+        assert!(health.is_healthy("a"));
+        assert!(health.record_failure("a", Transport::Udp, 3));
+        assert!(!health.record_failure("a", Transport::Tcp, 3));
+        assert!(!health.is_healthy("a"));
+        assert!(health.is_healthy("b"));
+        assert_eq!(health.unhealthy(), vec!["a".to_string()]);
+        assert_eq!(health.unhealthy_transports(), vec![("a".to_string(), Transport::Tcp)]);
+
+        health.record_success("a");
+        assert!(health.is_healthy("a"));
+        // 成功会清零计数，需重新累计
+        assert!(!health.record_failure("a", Transport::Udp, 2));
+        assert!(health.unhealthy().is_empty());
+    }
+
+    #[test]
+    fn latency_ewma_converges_to_recent_samples() {
+        let latency = UpstreamLatency::new();
+        let ms = Duration::from_millis;
+        latency.record("a", ms(100), 0.2);
+        assert_eq!(latency.get("a"), Some(ms(100)));
+        latency.record("a", ms(200), 0.2);
+        assert_eq!(latency.get("a"), Some(ms(120)));
+
+        // 持续变快后旧的慢样本被遗忘（累计平均仍会停在 100ms 附近）
+        for _ in 0..40 {
+            latency.record("a", ms(10), 0.2);
+        }
+        let ewma = latency.get("a").unwrap();
+        assert!(ewma >= ms(10) && ewma < ms(11), "{ewma:?}");
+        // 小 alpha 下每个样本都会推动均值，不会因定点取整停滞
+        latency.record("a", ms(20), 0.001);
+        assert!(latency.get("a").unwrap() > ewma);
+
+        latency.record("b", ms(5), 0.2);
+        assert_eq!(latency.snapshot().iter().map(|(u, _)| u.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(latency.get("c").is_none());
+    }
+
+    #[test]
+    fn fastest_prefers_unmeasured_then_lowest_ewma() {
+        let latency = UpstreamLatency::new();
+        let members: Vec<String> = ["a", "b", "c"].map(String::from).to_vec();
+        latency.record("a", Duration::from_millis(30), 0.2);
+        latency.record("b", Duration::from_millis(10), 0.2);
+        assert_eq!(latency.fastest(&members), 2);
+        latency.record("c", Duration::from_millis(20), 0.2);
+        assert_eq!(latency.fastest(&members), 1);
+        // 并列时取靠前的
+        latency.record("a", Duration::from_millis(10), 1.0);
+        assert_eq!(latency.fastest(&members), 0);
+    }
+}
@@ -1,7 +1,24 @@
+pub mod admin;
 pub mod advanced_rule;
+pub mod backoff;
 pub mod cache;
 pub mod config;
+pub mod cookie;
+pub mod domain_set;
 pub mod engine;
+pub mod geoip;
+pub mod health;
+pub mod local_zone;
 pub mod matcher;
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod proto_utils;
+pub mod querylog;
+pub mod ratelimit;
+pub mod shard;
+pub mod static_records;
+#[cfg(test)]
+mod test_util;
+#[cfg(target_os = "linux")]
+pub mod udp_batch;
 pub mod watcher;
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::Context;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::{NS, SOA};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::config::LocalZone;
+
+/// 本地区域的权威应答：answers 为应答段，authority 为授权段（NXDOMAIN/NODATA 时携带 SOA）
+#[derive(Debug, Clone)]
+pub struct ZoneAnswer {
+    pub rcode: ResponseCode,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+}
+
+/// 编译后的本地区域：记录按 owner（小写、无末尾点）分组，顶点 SOA/NS 也在其中
+#[derive(Debug, Clone)]
+pub struct RuntimeLocalZone {
+    pub apex: String,
+    records: HashMap<String, Vec<Record>>,
+    /// 授权段中的 SOA，TTL 已取 min(ttl, minimum)
+    negative_soa: Record,
+}
+
+/// 规范化为小写、无末尾点的形式，与查询中的 qname 一致
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// name 是否等于 zone 或位于其下
+#[inline]
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || (name.len() > zone.len() && name.ends_with(zone) && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
+
+impl RuntimeLocalZone {
+    pub fn compile(zone: &LocalZone) -> anyhow::Result<Self> {
+        let apex = normalize(&zone.zone);
+        let apex_name = Name::from_str(&apex).with_context(|| format!("invalid local zone {}", zone.zone))?;
+        let soa = &zone.soa;
+        let soa_rdata = SOA::new(
+            Name::from_str(&soa.mname).with_context(|| format!("local zone {apex}: invalid soa mname {}", soa.mname))?,
+            Name::from_str(&soa.rname).with_context(|| format!("local zone {apex}: invalid soa rname {}", soa.rname))?,
+            soa.serial,
+            soa.refresh,
+            soa.retry,
+            soa.expire,
+            soa.minimum,
+        );
+        let mut records: HashMap<String, Vec<Record>> = HashMap::new();
+        records
+            .entry(apex.clone())
+            .or_default()
+            .push(Record::from_rdata(apex_name.clone(), soa.ttl, RData::SOA(soa_rdata.clone())));
+        for ns in &zone.ns {
+            let target = Name::from_str(ns).with_context(|| format!("local zone {apex}: invalid ns {ns}"))?;
+            records
+                .entry(apex.clone())
+                .or_default()
+                .push(Record::from_rdata(apex_name.clone(), soa.ttl, RData::NS(NS(target))));
+        }
+        for r in &zone.records {
+            let owner = if r.name == "@" { apex.clone() } else { normalize(&r.name) };
+            if !in_zone(&owner, &apex) {
+                anyhow::bail!("local zone {apex}: record {} is outside the zone", r.name);
+            }
+            let name = Name::from_str(&owner).with_context(|| format!("local zone {apex}: invalid record name {}", r.name))?;
+            records
+                .entry(owner)
+                .or_default()
+                .push(Record::from_rdata(name, r.record.ttl, r.record.rdata.clone()));
+        }
+        let negative_soa = Record::from_rdata(apex_name, soa.ttl.min(soa.minimum), RData::SOA(soa_rdata));
+        Ok(Self { apex, records, negative_soa })
+    }
+
+    /// 区域内名字的应答：有该类型记录时直接返回，名字存在但无该类型（或为空非终端）时 NODATA，否则 NXDOMAIN；
+    /// 名字只有 CNAME 时对其他类型返回该 CNAME
+    pub fn answer(&self, qname: &str, qtype: RecordType) -> ZoneAnswer {
+        let negative = |rcode| ZoneAnswer {
+            rcode,
+            answers: Vec::new(),
+            authority: vec![self.negative_soa.clone()],
+        };
+        let Some(rrs) = self.records.get(qname) else {
+            let empty_non_terminal = self.records.keys().any(|owner| owner != qname && in_zone(owner, qname));
+            return negative(if empty_non_terminal { ResponseCode::NoError } else { ResponseCode::NXDomain });
+        };
+        let mut answers: Vec<Record> = rrs
+            .iter()
+            .filter(|r| qtype == RecordType::ANY || r.record_type() == qtype)
+            .cloned()
+            .collect();
+        if answers.is_empty() && qtype != RecordType::CNAME {
+            answers = rrs.iter().filter(|r| r.record_type() == RecordType::CNAME).cloned().collect();
+        }
+        if answers.is_empty() {
+            return negative(ResponseCode::NoError);
+        }
+        ZoneAnswer {
+            rcode: ResponseCode::NoError,
+            answers,
+            authority: Vec::new(),
+        }
+    }
+}
+
+/// qname 所属的本地区域（最长顶点优先）
+pub fn find_zone<'a>(zones: &'a [RuntimeLocalZone], qname: &str) -> Option<&'a RuntimeLocalZone> {
+    zones
+        .iter()
+        .filter(|z| in_zone(qname, &z.apex))
+        .max_by_key(|z| z.apex.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(raw: serde_json::Value) -> RuntimeLocalZone {
+        let cfg: LocalZone = serde_json::from_value(raw).expect("parse");
+        RuntimeLocalZone::compile(&cfg).expect("compile")
+    }
+
+    #[test]
+    fn compiles_records_and_rejects_names_outside_the_zone() {
+        let z = zone(serde_json::json!({
+            "zone": "Corp.Example.",
+            "soa": { "mname": "ns1.corp.example", "rname": "hostmaster.corp.example", "minimum": 60 },
+            "ns": ["ns1.corp.example"],
+            "records": [
+                { "name": "@", "type": "MX", "value": "10 mail.corp.example" },
+                { "name": "a.b.corp.example", "type": "A", "value": "10.0.0.1", "ttl": 30 },
+                { "name": "alias.corp.example", "type": "CNAME", "value": "a.b.corp.example" }
+            ]
+        }));
+        assert_eq!(z.apex, "corp.example");
+        let mx = z.answer("corp.example", RecordType::MX);
+        assert_eq!(mx.answers.len(), 1);
+        let a = z.answer("a.b.corp.example", RecordType::A);
+        assert_eq!(a.answers[0].ttl(), 30);
+        // 空非终端与已有名字的其他类型均为 NODATA
+        let ent = z.answer("b.corp.example", RecordType::A);
+        assert_eq!((ent.rcode, ent.answers.len()), (ResponseCode::NoError, 0));
+        assert_eq!(ent.authority[0].ttl(), 60);
+        assert!(z.answer("a.b.corp.example", RecordType::AAAA).answers.is_empty());
+        let alias = z.answer("alias.corp.example", RecordType::A);
+        assert_eq!(alias.answers[0].record_type(), RecordType::CNAME);
+
+        let cfg: LocalZone = serde_json::from_value(serde_json::json!({
+            "zone": "corp.example",
+            "soa": { "mname": "ns1.corp.example", "rname": "hostmaster.corp.example" },
+            "records": [ { "name": "www.example.com", "type": "A", "value": "10.0.0.1" } ]
+        }))
+        .unwrap();
+        let err = RuntimeLocalZone::compile(&cfg).unwrap_err();
+        assert!(err.to_string().contains("outside the zone"), "{err}");
+    }
+
+    #[test]
+    fn find_zone_prefers_the_longest_apex() {
+        let soa = serde_json::json!({ "mname": "ns.example", "rname": "admin.example" });
+        let zones = vec![
+            zone(serde_json::json!({ "zone": "example", "soa": soa })),
+            zone(serde_json::json!({ "zone": "lab.example", "soa": soa })),
+        ];
+        assert_eq!(find_zone(&zones, "x.lab.example").unwrap().apex, "lab.example");
+        assert_eq!(find_zone(&zones, "xlab.example").unwrap().apex, "example");
+        assert!(find_zone(&zones, "example.com").is_none());
+    }
+}
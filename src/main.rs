@@ -1,9 +1,26 @@
+mod admin;
 mod advanced_rule;
+mod backoff;
 mod cache;
 mod config;
+mod cookie;
+mod domain_set;
 mod engine;
+mod geoip;
+mod health;
+mod local_zone;
 mod matcher;
+#[cfg(feature = "otel")]
+mod otel;
 mod proto_utils;
+mod querylog;
+mod ratelimit;
+mod shard;
+mod static_records;
+#[cfg(test)]
+mod test_util;
+#[cfg(target_os = "linux")]
+mod udp_batch;
 mod watcher;
 
 use std::net::SocketAddr;
@@ -15,12 +32,15 @@ use arc_swap::ArcSwap;
 use clap::Parser;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tracing::{error, info};
-use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{EnvFilter, Layer, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt};
 
+use crate::admin::{AdminState, LogReloadHandle};
 use crate::config::load_config;
 use crate::engine::Engine;
 use crate::matcher::RuntimePipelineConfig;
+use crate::querylog::QueryLog;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "KixDNS async DNS with hot-reload pipelines", long_about = None)]
@@ -28,7 +48,7 @@ struct Args {
     /// 配置文件路径（JSON）
     #[arg(short = 'c', long = "config", default_value = "config/pipeline.json")]
     config: PathBuf,
-    /// 监听实例标签，用于 pipeline 选择（可选）。
+    /// 监听实例标签，用于 pipeline 选择（可选）；配置了 settings.listeners 时以各入口的 label 为准。
     #[arg(long = "listener-label", default_value = "default")]
     listener_label: String,
     /// 启用调试日志
@@ -37,26 +57,82 @@ struct Args {
     /// UDP worker 数量（默认 CPU 核心数）
     #[arg(long = "udp-workers", default_value_t = 0)]
     udp_workers: usize,
+    /// 每次系统调用最多接收的 UDP 数据报数（Linux recvmmsg），1 表示逐包接收
+    #[arg(long = "udp-batch-size", default_value_t = 1)]
+    udp_batch_size: usize,
+    /// 仅加载并编译配置后退出（0 表示通过），不绑定任何端口
+    #[arg(long = "check", default_value_t = false)]
+    check: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    init_tracing(args.debug);
+
+    if args.check {
+        init_tracing(args.debug, None)?;
+        match check_config(&args.config) {
+            Ok(()) => {
+                println!("config ok: {}", args.config.display());
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("config check failed: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 
     let cfg = load_config(&args.config).context("load initial config")?;
     let cfg = RuntimePipelineConfig::from_config(cfg).context("compile matchers")?;
-    let bind_addr: SocketAddr = cfg.settings.bind_udp.parse().context("parse bind addr")?;
-    let bind_tcp: SocketAddr = cfg
+    // span 导出需要配置中的 otlp_endpoint，因此日志在加载配置后初始化
+    let log_filter = init_tracing(args.debug, cfg.settings.otlp_endpoint.as_deref())?;
+    let listeners = cfg.settings.listeners(&args.listener_label)?;
+
+    let admin_bind: Option<SocketAddr> = cfg
         .settings
-        .bind_tcp
-        .parse()
-        .context("parse tcp bind addr")?;
+        .admin_bind
+        .as_deref()
+        .map(|s| s.parse().context("parse admin bind addr"))
+        .transpose()?;
 
     let pipeline = Arc::new(ArcSwap::from_pointee(cfg));
-    let engine = Engine::new(pipeline.clone(), args.listener_label.clone());
+    let shutdown = Arc::new(Notify::new());
+    // UDP worker 连续出错放弃接收时触发：整个进程按停机流程退出并返回错误，交由 supervisor 重启，
+    // 而不是少了 worker（或整个 UDP 入口）继续运行
+    let fatal = Arc::new(Notify::new());
+    let query_log = pipeline
+        .load()
+        .settings
+        .query_log_path
+        .as_deref()
+        .map(QueryLog::open)
+        .transpose()?;
+    let mut engine = Engine::new(pipeline.clone(), args.listener_label.clone());
+    if let Some(log) = &query_log {
+        engine = engine.with_query_log(log.clone());
+    }
+    let cache_snapshot = pipeline.load().settings.cache_snapshot_path.clone().map(PathBuf::from);
+    if let Some(path) = cache_snapshot.as_deref().filter(|p| p.exists()) {
+        match engine.load_cache_snapshot(path) {
+            Ok(n) => info!(path = %path.display(), entries = n, "cache snapshot loaded"),
+            Err(err) => warn!(path = %path.display(), error = %err, "cache snapshot load failed"),
+        }
+    }
+    engine.spawn_health_checker();
+    engine.spawn_stats_logger();
+    engine.spawn_tcp_prewarmer();
 
-    watcher::spawn(args.config.clone(), pipeline.clone());
+    watcher::spawn(args.config.clone(), pipeline.clone(), engine.reload_stats());
+
+    if let Some(bind) = admin_bind {
+        let state = AdminState {
+            log_filter: Some(log_filter),
+            engine: Some(engine.clone()),
+            token: pipeline.load().settings.admin_token.clone(),
+        };
+        admin::spawn(bind, state).await.context("start admin api")?;
+    }
 
     // UDP worker 数量：默认为 CPU 核心数，最少 1 个
     let udp_workers = if args.udp_workers > 0 {
@@ -65,8 +141,164 @@ async fn main() -> anyhow::Result<()> {
         num_cpus::get()
     };
 
-    info!(bind_udp = %bind_addr, bind_tcp = %bind_tcp, udp_workers = udp_workers, "dns server started");
+    let udp_batch_size = args.udp_batch_size.max(1);
+    if udp_batch_size > 1 && !cfg!(target_os = "linux") {
+        warn!(udp_batch_size, "udp batch receive requires Linux; falling back to per-packet receive");
+    }
+
+    // TCP 连接上限由所有入口共享
+    let tcp_slots = match pipeline.load().settings.tcp_max_connections {
+        0 => None,
+        n => Some(Arc::new(Semaphore::new(n))),
+    };
+
+    let mut udp_handles = Vec::new();
+    let mut tcp_handles = Vec::new();
+    for listener in &listeners {
+        // 各入口共享同一引擎（缓存、上游连接），只是 listener_label 不同
+        let engine = engine.with_listener_label(&listener.label);
+        info!(
+            label = %listener.label,
+            bind_udp = ?listener.udp,
+            bind_tcp = ?listener.tcp,
+            udp_workers = udp_workers,
+            udp_batch_size,
+            "dns server started"
+        );
+        if let Some(bind_addr) = listener.udp {
+            udp_handles.extend(
+                spawn_udp_workers(bind_addr, udp_workers, udp_batch_size, &engine, &shutdown, &fatal)
+                    .with_context(|| format!("start udp listener {}", listener.label))?,
+            );
+        }
+        if let Some(bind_tcp) = listener.tcp {
+            let tcp_listener = TcpListener::bind(bind_tcp)
+                .await
+                .with_context(|| format!("bind tcp listener {}", listener.label))?;
+            let tcp_shutdown = Arc::clone(&shutdown);
+            let tcp_slots = tcp_slots.clone();
+            tcp_handles.push(tokio::spawn(async move {
+                if let Err(err) = run_tcp(tcp_listener, engine, tcp_shutdown, tcp_slots).await {
+                    error!(error = %err, "tcp server exited");
+                }
+            }));
+        }
+    }
+
+    let failed = tokio::select! {
+        _ = wait_for_signal() => false,
+        _ = fatal.notified() => true,
+    };
+    if failed {
+        error!("udp worker gave up, stopping listeners");
+    } else {
+        info!("shutdown signal received, stopping listeners");
+    }
+    shutdown.notify_waiters();
+    for h in tcp_handles {
+        let _ = h.await;
+    }
+    for h in udp_handles {
+        let _ = h.await;
+    }
+
+    // 停止接收后等待进行中的请求完成
+    let grace = pipeline.load().shutdown_grace();
+    let remaining = engine.drain(grace).await;
+    if remaining > 0 {
+        warn!(inflight = remaining, grace_ms = grace.as_millis() as u64, "shutdown grace expired with requests in flight");
+    }
+    if let Some(path) = cache_snapshot.as_deref() {
+        match engine.save_cache_snapshot(path) {
+            Ok(n) => info!(path = %path.display(), entries = n, "cache snapshot saved"),
+            Err(err) => warn!(path = %path.display(), error = %err, "cache snapshot save failed"),
+        }
+    }
+    if let Some(log) = &query_log {
+        log.flush().await;
+    }
+    if remaining == 0 {
+        info!("shutdown complete");
+    }
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+
+    if failed {
+        anyhow::bail!("udp worker exited after repeated socket errors");
+    }
+    Ok(())
+}
+
+/// --check：走与启动相同的加载/编译流程（含悬空 jump_to_pipeline 检查）
+fn check_config(path: &std::path::Path) -> anyhow::Result<()> {
+    let cfg = load_config(path).context("load config")?;
+    let runtime = RuntimePipelineConfig::from_config(cfg).context("compile matchers")?;
+    let _compiled = advanced_rule::compile_pipelines(&runtime);
+    runtime.settings.listeners("default")?;
+    Ok(())
+}
 
+/// 等待 SIGINT 或 SIGTERM（非 Unix 仅 Ctrl-C）
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(err) => {
+                warn!(error = %err, "install SIGTERM handler failed");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+fn init_tracing(debug: bool, otlp_endpoint: Option<&str>) -> anyhow::Result<LogReloadHandle> {
+    // 为压测降低日志开销：默认禁用 JSON，非 debug 仅 warn
+    let fmt_layer = fmt::layer()
+        .with_target(false)
+        .with_ansi(false)
+        .with_level(debug);
+
+    let level = if debug { "debug" } else { "warn" };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    // 过滤器放在 reload 层中，管理接口可在运行时调整日志级别
+    let (filter, handle) = reload::Layer::new(filter);
+    // 过滤器只作用于 fmt 层，span 导出层有独立的过滤规则
+    let registry = tracing_subscriber::registry().with(fmt_layer.with_filter(filter));
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = otlp_endpoint {
+        registry.with(otel::layer(endpoint)?).init();
+        info!(endpoint = %endpoint, "otlp span export enabled");
+        return Ok(handle);
+    }
+    registry.init();
+    if otlp_endpoint.is_some() && !cfg!(feature = "otel") {
+        warn!("otlp_endpoint is set but this build lacks the otel feature; spans are not exported");
+    }
+    Ok(handle)
+}
+
+/// 为一个 UDP 监听地址启动 worker：Unix 上每个 worker 各自创建 SO_REUSEPORT socket，由内核分发；
+/// 其他平台共享一个 socket
+fn spawn_udp_workers(
+    bind_addr: SocketAddr,
+    udp_workers: usize,
+    udp_batch_size: usize,
+    engine: &Engine,
+    shutdown: &Arc<Notify>,
+    fatal: &Arc<Notify>,
+) -> anyhow::Result<Vec<tokio::task::JoinHandle<()>>> {
     let mut udp_handles = Vec::with_capacity(udp_workers);
 
     #[cfg(unix)]
@@ -74,12 +306,15 @@ async fn main() -> anyhow::Result<()> {
         // On Unix create individual sockets with SO_REUSEPORT so kernel distributes packets
         for worker_id in 0..udp_workers {
             let engine = engine.clone();
+            let shutdown = Arc::clone(shutdown);
+            let fatal = Arc::clone(fatal);
             let std_socket = create_reuseport_udp_socket(bind_addr)
                 .with_context(|| format!("create udp socket for worker {}", worker_id))?;
             let socket = UdpSocket::from_std(std_socket)?;
             let handle = tokio::spawn(async move {
-                if let Err(err) = run_udp_worker(worker_id, Arc::new(socket), engine).await {
+                if let Err(err) = run_udp_worker(worker_id, Arc::new(socket), engine, shutdown, udp_batch_size).await {
                     error!(worker_id, error = %err, "udp worker exited");
+                    fatal.notify_one();
                 }
             });
             udp_handles.push(handle);
@@ -102,48 +337,19 @@ async fn main() -> anyhow::Result<()> {
         for worker_id in 0..udp_workers {
             let engine = engine.clone();
             let socket = Arc::clone(&udp_socket);
+            let shutdown = Arc::clone(shutdown);
+            let fatal = Arc::clone(fatal);
             let handle = tokio::spawn(async move {
-                if let Err(err) = run_udp_worker(worker_id, socket, engine).await {
+                if let Err(err) = run_udp_worker(worker_id, socket, engine, shutdown, udp_batch_size).await {
                     error!(worker_id, error = %err, "udp worker exited");
+                    fatal.notify_one();
                 }
             });
             udp_handles.push(handle);
         }
     }
 
-    // TCP listener
-    let tcp_listener = TcpListener::bind(bind_tcp)
-        .await
-        .context("bind tcp listener")?;
-    let tcp_engine = engine.clone();
-    let tcp_handle = tokio::spawn(async move {
-        if let Err(err) = run_tcp(tcp_listener, tcp_engine).await {
-            error!(error = %err, "tcp server exited");
-        }
-    });
-
-    // 等待所有任务
-    let _ = tcp_handle.await;
-    for h in udp_handles {
-        let _ = h.await;
-    }
-
-    Ok(())
-}
-
-fn init_tracing(debug: bool) {
-    // 为压测降低日志开销：默认禁用 JSON，非 debug 仅 warn
-    let fmt_layer = fmt::layer()
-        .with_target(false)
-        .with_ansi(false)
-        .with_level(debug);
-
-    let level = if debug { "debug" } else { "warn" };
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
-    tracing_subscriber::registry()
-        .with(filter)
-        .with(fmt_layer)
-        .init();
+    Ok(udp_handles)
 }
 
 // 在 Unix 上创建带 SO_REUSEPORT 的 UDP socket；非 Unix 使用标准绑定
@@ -187,7 +393,22 @@ async fn run_udp_worker(
     _worker_id: usize,
     socket: Arc<UdpSocket>,
     engine: Engine,
+    shutdown: Arc<Notify>,
+    batch_size: usize,
 ) -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    if batch_size > 1 {
+        return run_udp_worker_batched(socket, engine, shutdown, batch_size).await;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = batch_size;
+
+    let mut backoff = engine.udp_recv_backoff();
+    // enable 后即使 worker 正在处理请求，notify_waiters 也不会丢失
+    let stopped = shutdown.notified();
+    tokio::pin!(stopped);
+    stopped.as_mut().enable();
+
     // 预分配缓冲区
     // 使用 BytesMut 避免 Bytes::copy_from_slice 的内存分配
     use bytes::BytesMut;
@@ -203,9 +424,14 @@ async fn run_udp_worker(
         // 简单起见，我们先 resize，然后 truncate
         // 性能损耗极小，因为 resize 0u8 也是 memset
         unsafe { buf.set_len(buf.capacity()); }
-        
-        match socket.recv_from(&mut buf).await {
+
+        let received = tokio::select! {
+            _ = &mut stopped => return Ok(()),
+            r = socket.recv_from(&mut buf) => r,
+        };
+        match received {
             Ok((len, peer)) => {
+                backoff.reset();
                 unsafe { buf.set_len(len); }
                 // 零拷贝获取 Bytes
                 let packet_bytes = buf.split().freeze();
@@ -214,6 +440,7 @@ async fn run_udp_worker(
                 match engine.handle_packet_fast(&packet_bytes, peer) {
                     Ok(Some(resp)) => {
                         // 缓存命中，直接发送
+                        let resp = proto_utils::truncate_for_udp(&packet_bytes, resp);
                         let _ = socket.send_to(&resp, peer).await;
                     }
                     Ok(None) => {
@@ -223,6 +450,7 @@ async fn run_udp_worker(
                         let socket = Arc::clone(&socket);
                         tokio::spawn(async move {
                             if let Ok(resp) = engine.handle_packet(&packet_bytes, peer).await {
+                                let resp = proto_utils::truncate_for_udp(&packet_bytes, resp);
                                 let _ = socket.send_to(&resp, peer).await;
                             }
                         });
@@ -236,21 +464,96 @@ async fn run_udp_worker(
                 // 实际上 split() 拿走了所有权，buf 变为空。
                 // 下次循环开头会 reserve。
             }
-            Err(_) => {
-                // 继续接收，不退出
-                // 如果出错，buf 长度可能不对，重置
+            Err(err) => {
+                // 如果出错，buf 长度可能不对，重置；连续出错时退避，超过上限则退出
                 buf.clear();
+                backoff.on_error(err).await?;
+            }
+        }
+    }
+}
+
+/// recvmmsg 批量接收：一次系统调用取回多个数据报，快速路径的应答用 sendmmsg 一并发出，
+/// 缓存未命中的查询仍逐个 spawn 异步处理
+#[cfg(target_os = "linux")]
+async fn run_udp_worker_batched(
+    socket: Arc<UdpSocket>,
+    engine: Engine,
+    shutdown: Arc<Notify>,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    let stopped = shutdown.notified();
+    tokio::pin!(stopped);
+    stopped.as_mut().enable();
+
+    let mut backoff = engine.udp_recv_backoff();
+    let mut batch = udp_batch::RecvBatch::new(batch_size);
+    let mut replies = Vec::with_capacity(batch_size);
+    loop {
+        let received = tokio::select! {
+            _ = &mut stopped => return Ok(()),
+            r = batch.recv(&socket) => r,
+        };
+        if let Err(err) = received {
+            backoff.on_error(err).await?;
+            continue;
+        }
+        backoff.reset();
+        replies.clear();
+        for idx in 0..batch.len() {
+            let Some((packet, peer)) = batch.get(idx) else {
+                continue;
+            };
+            match engine.handle_packet_fast(packet, peer) {
+                Ok(Some(resp)) => replies.push((proto_utils::truncate_for_udp(packet, resp), peer)),
+                Ok(None) => {
+                    // 接收缓冲区下一轮会被覆盖，慢路径需要自有副本
+                    let packet = bytes::Bytes::copy_from_slice(packet);
+                    let engine = engine.clone();
+                    let socket = Arc::clone(&socket);
+                    tokio::spawn(async move {
+                        if let Ok(resp) = engine.handle_packet(&packet, peer).await {
+                            let resp = proto_utils::truncate_for_udp(&packet, resp);
+                            let _ = socket.send_to(&resp, peer).await;
+                        }
+                    });
+                }
+                Err(_) => {}
             }
         }
+        udp_batch::send_batch(&socket, &replies).await;
     }
 }
 
-async fn run_tcp(listener: TcpListener, engine: Engine) -> anyhow::Result<()> {
+/// slots 为 settings.tcp_max_connections 对应的信号量：连接数已满时新连接直接关闭，不再排队等待
+async fn run_tcp(
+    listener: TcpListener,
+    engine: Engine,
+    shutdown: Arc<Notify>,
+    slots: Option<Arc<Semaphore>>,
+) -> anyhow::Result<()> {
+    let stopped = shutdown.notified();
+    tokio::pin!(stopped);
+    stopped.as_mut().enable();
+
     loop {
-        let (stream, peer) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            _ = &mut stopped => return Ok(()),
+            r = listener.accept() => r?,
+        };
+        let permit = match slots.as_ref().map(|s| Arc::clone(s).try_acquire_owned()) {
+            Some(Err(_)) => {
+                warn!(client = %peer, "tcp connection limit reached, closing connection");
+                continue;
+            }
+            Some(Ok(permit)) => Some(permit),
+            None => None,
+        };
         let engine = engine.clone();
+        let shutdown = Arc::clone(&shutdown);
         tokio::spawn(async move {
-            let _ = handle_tcp_conn(stream, peer, engine).await;
+            let _ = handle_tcp_conn(stream, peer, engine, shutdown).await;
+            drop(permit);
         });
     }
 }
@@ -259,12 +562,34 @@ async fn handle_tcp_conn(
     mut stream: TcpStream,
     peer: SocketAddr,
     engine: Engine,
+    shutdown: Arc<Notify>,
 ) -> anyhow::Result<()> {
     const MAX_TCP_FRAME: usize = 64 * 1024;
     let mut len_buf = [0u8; 2];
+    // 关闭时不再读取新请求，已读取的请求照常应答
+    let stopped = shutdown.notified();
+    tokio::pin!(stopped);
+    stopped.as_mut().enable();
 
     loop {
-        if let Err(err) = stream.read_exact(&mut len_buf).await {
+        // 空闲超时只作用于等待下一个请求的长度前缀
+        let idle_timeout = engine.tcp_idle_timeout();
+        let read = tokio::select! {
+            _ = &mut stopped => return Ok(()),
+            r = async {
+                match idle_timeout {
+                    Some(dur) => tokio::time::timeout(dur, stream.read_exact(&mut len_buf)).await,
+                    None => Ok(stream.read_exact(&mut len_buf).await),
+                }
+            } => match r {
+                Ok(r) => r,
+                Err(_) => {
+                    debug!(client = %peer, idle_ms = idle_timeout.unwrap_or_default().as_millis() as u64, "tcp connection idle, closing");
+                    return Ok(());
+                }
+            },
+        };
+        if let Err(err) = read {
             if err.kind() != std::io::ErrorKind::UnexpectedEof {
                 return Err(err.into());
             }
@@ -280,7 +605,7 @@ async fn handle_tcp_conn(
             return Ok(());
         }
 
-        let resp = match engine.handle_packet(&buf, peer).await {
+        let resp = match engine.handle_packet_tcp(&buf, peer).await {
             Ok(r) => r,
             Err(_) => return Ok(()),
         };
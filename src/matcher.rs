@@ -1,18 +1,103 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use anyhow::Context;
 use hickory_proto::op::Message;
 use hickory_proto::rr::{DNSClass, RecordType};
 use ipnet::IpNet;
 use regex::Regex;
 
-use crate::config::{self, Action, MatchOperator, PipelineConfig};
+use crate::config::{self, Action, MatchOperator, PipelineConfig, UpstreamGroup};
+use crate::cookie::ServerCookies;
+use crate::domain_set::DomainSetFile;
+use crate::geoip::{AsnLookup, GeoLookup};
+use crate::local_zone::RuntimeLocalZone;
+use crate::static_records::StaticRecordsFile;
 
 #[derive(Debug, Clone)]
 pub struct RuntimePipelineConfig {
     pub settings: config::GlobalSettings,
     pub pipeline_select: Vec<RuntimePipelineSelectRule>,
     pub pipelines: Vec<RuntimePipeline>,
+    /// settings.default_upstream 拆分后的上游组
+    pub default_upstream: UpstreamGroup,
+    /// settings.upstream_groups 逐组构建的上游组，shard_by_domain 选中整组后仍按上游策略选择成员
+    pub upstream_shards: Vec<UpstreamGroup>,
+    /// settings.answer_ip_allowlist 解析后的网段；为空表示不限制
+    pub answer_ip_allowlist: Vec<IpNet>,
+    /// settings.allow_networks / deny_networks 解析后的客户端网段
+    pub allow_networks: Vec<IpNet>,
+    pub deny_networks: Vec<IpNet>,
+    /// settings.dns64_enabled 时解析后的 NAT64 前缀；关闭时为 None
+    pub dns64_prefix: Option<ipnet::Ipv6Net>,
+    /// settings.tcp_for_qtypes 解析后的记录类型
+    pub tcp_for_qtypes: Vec<RecordType>,
+    /// settings.upstream_port_range 解析后的源端口范围（含两端）
+    pub upstream_port_range: Option<(u16, u16)>,
+    /// settings.cookie_secret 派生的服务器 cookie 密钥；未配置时为 None
+    pub cookies: Option<Arc<ServerCookies>>,
+    /// 编译后的 local_zones
+    pub local_zones: Vec<RuntimeLocalZone>,
+    /// settings.static_records_file 加载后的记录；未配置时为 None
+    pub static_records: Option<Arc<StaticRecordsFile>>,
+    /// settings.static_reverse_zones 规范化后的区域（小写、无末尾点）
+    pub reverse_zones: Vec<String>,
+    /// 存在 client_geo 匹配器时打开的国家数据库，所有匹配器（以及 geo_static_ip）共享
+    pub geo: Option<Arc<dyn GeoLookup>>,
+    /// 配置代数：每次编译配置递增，编译后的 pipeline 记录其来源代数，用于识别过期的快速路径索引
+    pub generation: u64,
+}
+
+/// 进程内配置代数计数器，0 保留给手工构造的配置
+static CONFIG_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// 热加载前后配置的差异摘要，用于日志
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub pipelines_added: Vec<String>,
+    pub pipelines_removed: Vec<String>,
+    /// (pipeline id, 旧规则数, 新规则数)
+    pub rule_counts_changed: Vec<(String, usize, usize)>,
+    pub settings_changed: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.pipelines_added.is_empty()
+            && self.pipelines_removed.is_empty()
+            && self.rule_counts_changed.is_empty()
+            && self.settings_changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no changes");
+        }
+        let mut parts = Vec::new();
+        if !self.pipelines_added.is_empty() {
+            parts.push(format!("pipelines added [{}]", self.pipelines_added.join(",")));
+        }
+        if !self.pipelines_removed.is_empty() {
+            parts.push(format!("pipelines removed [{}]", self.pipelines_removed.join(",")));
+        }
+        if !self.rule_counts_changed.is_empty() {
+            let rules: Vec<String> = self
+                .rule_counts_changed
+                .iter()
+                .map(|(id, old, new)| format!("{id} {old}->{new}"))
+                .collect();
+            parts.push(format!("rules [{}]", rules.join(",")));
+        }
+        if !self.settings_changed.is_empty() {
+            parts.push(format!("settings changed [{}]", self.settings_changed.join(",")));
+        }
+        f.write_str(&parts.join("; "))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +109,22 @@ pub struct RuntimePipeline {
     pub domain_suffix_index: HashMap<String, Vec<usize>>,
     // Rules that are NOT indexed by domain (must always be checked)
     pub always_check_rules: Vec<usize>,
+    // Rules carrying a rate_limit action, evaluated before any cache lookup
+    pub rate_limit_rules: Vec<usize>,
+    // Rules carrying a force_tcp action, answered with TC over UDP before any cache lookup
+    pub force_tcp_rules: Vec<usize>,
+    // Rules with an opcode matcher, the only ones consulted for non-QUERY requests
+    pub opcode_rules: Vec<usize>,
+    /// Pipeline.upstream_timeout_ms；None 时沿用全局 upstream_timeout_ms
+    pub upstream_timeout: Option<std::time::Duration>,
+    /// Pipeline.default_transport，未配置时为 udp
+    pub default_transport: config::Transport,
+    /// Pipeline.shadow_mode：直接作答的规则只记录日志，不生效
+    pub shadow_mode: bool,
+    /// 规则中是否有 ecs_subnet 匹配器；没有时规则缓存不按 ECS 地址区分
+    pub uses_ecs: bool,
+    /// Pipeline.dns64_prefix 解析后的前缀；None 时沿用全局 dns64_prefix
+    pub dns64_prefix: Option<ipnet::Ipv6Net>,
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +156,18 @@ pub enum RuntimeMatcher {
     DomainRegex { regex: Regex },
     Qclass { value: DNSClass },
     EdnsPresent { expect: bool },
+    EdnsAtLeast { bufsize: u16 },
+    /// 请求 ECS 声明的客户端地址
+    EcsSubnet { net: IpNet },
+    QueryType { qtype: RecordType },
+    DomainSet { set: Arc<DomainSetFile> },
+    ClientGeo { country: String, geo: Arc<dyn GeoLookup> },
+    ClientAsn { asns: Vec<u32>, asn: Arc<dyn AsnLookup> },
+    NameLength { min: usize, max: usize },
+    LabelCount { min: usize, max: usize },
+    Opcode { opcode: u8 },
+    /// 嵌套分组，children 的 operator 已按组的 matcher_operator 归一
+    Group { children: Vec<RuntimeMatcherWithOp> },
 }
 
 #[derive(Debug, Clone)]
@@ -72,6 +185,9 @@ pub enum RuntimePipelineSelectorMatcher {
     Any,
     Qclass { value: DNSClass },
     EdnsPresent { expect: bool },
+    EdnsAtLeast { bufsize: u16 },
+    ClientGeo { country: String, geo: Arc<dyn GeoLookup> },
+    ClientAsn { asns: Vec<u32>, asn: Arc<dyn AsnLookup> },
 }
 
 #[derive(Debug, Clone)]
@@ -110,6 +226,11 @@ pub enum RuntimeResponseMatcher {
     ResponseEdnsPresent {
         expect: bool,
     },
+    ResponseTruncated,
+    ResponseAnswerCount {
+        min: usize,
+        max: usize,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -119,30 +240,65 @@ pub struct RuntimeResponseMatcherWithOp {
 }
 
 impl RuntimePipelineConfig {
+    /// 客户端是否被 allow_networks / deny_networks 拒绝：deny 优先，allow 为空表示不限制；
+    /// 双栈监听收到的 IPv4 客户端（::ffff:a.b.c.d）按 IPv4 地址比较
+    #[inline]
+    pub fn client_denied(&self, client_ip: IpAddr) -> bool {
+        let client_ip = client_ip.to_canonical();
+        self.deny_networks.iter().any(|net| net.contains(&client_ip))
+            || (!self.allow_networks.is_empty() && !self.allow_networks.iter().any(|net| net.contains(&client_ip)))
+    }
+
     pub fn from_config(cfg: PipelineConfig) -> anyhow::Result<Self> {
+        // 国家数据库只在有 client_geo 匹配器时打开，每次加载配置打开一次
+        let geo = if uses_client_geo(&cfg) {
+            let path = cfg
+                .settings
+                .geoip_db
+                .as_deref()
+                .context("client_geo matcher requires settings.geoip_db")?;
+            Some(crate::geoip::open(path).context("open geoip_db for client_geo")?)
+        } else {
+            None
+        };
+        let asn = if uses_client_asn(&cfg) {
+            let path = cfg
+                .settings
+                .asn_db
+                .as_deref()
+                .context("client_asn matcher requires settings.asn_db")?;
+            Some(crate::geoip::open_asn(path).context("open asn_db for client_asn")?)
+        } else {
+            None
+        };
+        Self::from_config_with_lookups(cfg, geo, asn)
+    }
+
+    /// 使用给定的国家查询编译配置（测试中可注入替代实现）
+    #[allow(dead_code)]
+    pub fn from_config_with_geo(cfg: PipelineConfig, geo: Option<Arc<dyn GeoLookup>>) -> anyhow::Result<Self> {
+        Self::from_config_with_lookups(cfg, geo, None)
+    }
+
+    /// 使用给定的国家与 ASN 查询编译配置
+    pub fn from_config_with_lookups(
+        cfg: PipelineConfig,
+        geo: Option<Arc<dyn GeoLookup>>,
+        asn: Option<Arc<dyn AsnLookup>>,
+    ) -> anyhow::Result<Self> {
+        let lookups = ClientLookups {
+            geo: geo.as_ref(),
+            asn: asn.as_ref(),
+        };
         let mut pipelines = Vec::new();
         for p in cfg.pipelines {
+            if p.upstream_timeout_ms == Some(0) {
+                anyhow::bail!("pipeline {}: upstream_timeout_ms must be positive", p.id);
+            }
             let mut rules = Vec::new();
-            for r in p.rules {
-                let mut matchers = Vec::new();
-                let mut matchers_all_default = true;
-                for m in r.matchers {
-                    if m.operator != MatchOperator::And {
-                        matchers_all_default = false;
-                    }
-                    matchers.push(RuntimeMatcherWithOp {
-                        operator: m.operator,
-                        matcher: RuntimeMatcher::from_config(m.matcher)?,
-                    });
-                }
-                if matchers_all_default
-                    && !matchers.is_empty()
-                    && r.matcher_operator != MatchOperator::And
-                {
-                    for m in &mut matchers {
-                        m.operator = r.matcher_operator;
-                    }
-                }
+            for mut r in p.rules {
+                let matchers = compile_matchers(r.matchers, r.matcher_operator, lookups)
+                    .with_context(|| format!("pipeline {} rule {}: invalid matcher", p.id, r.name))?;
 
                 let mut response_matchers = Vec::new();
                 let mut resp_all_default = true;
@@ -152,7 +308,9 @@ impl RuntimePipelineConfig {
                     }
                     response_matchers.push(RuntimeResponseMatcherWithOp {
                         operator: rm.operator,
-                        matcher: RuntimeResponseMatcher::from_config(rm.matcher)?,
+                        matcher: RuntimeResponseMatcher::from_config(rm.matcher).with_context(|| {
+                            format!("pipeline {} rule {}: invalid response matcher", p.id, r.name)
+                        })?,
                     });
                 }
                 if resp_all_default
@@ -163,6 +321,78 @@ impl RuntimePipelineConfig {
                         rm.operator = r.response_matcher_operator;
                     }
                 }
+                for action in r
+                    .actions
+                    .iter_mut()
+                    .chain(&mut r.response_actions_on_match)
+                    .chain(&mut r.response_actions_on_miss)
+                {
+                    if let Action::Forward {
+                        min_ttl: Some(min),
+                        max_ttl: Some(max),
+                        ..
+                    } = action
+                        && min > max
+                    {
+                        anyhow::bail!("pipeline {} rule {}: forward min_ttl {} exceeds max_ttl {}", p.id, r.name, min, max);
+                    }
+                    if let Action::RateLimit { max_qps, burst, .. } = action
+                        && (*max_qps == 0 || *burst == 0)
+                    {
+                        anyhow::bail!("pipeline {} rule {}: rate_limit max_qps and burst must be positive", p.id, r.name);
+                    }
+                    if let Action::Forward { timeout_ms: Some(0), .. } = action {
+                        anyhow::bail!("pipeline {} rule {}: forward timeout_ms must be positive", p.id, r.name);
+                    }
+                    if let Action::ClampTtl {
+                        min: Some(min),
+                        max: Some(max),
+                    } = action
+                        && min > max
+                    {
+                        anyhow::bail!("pipeline {} rule {}: clamp_ttl min {} exceeds max {}", p.id, r.name, min, max);
+                    }
+                    if let Action::StaticHttps {
+                        priority,
+                        target,
+                        params,
+                        ..
+                    } = action
+                    {
+                        config::https_rdata(*priority, target, params)
+                            .with_context(|| format!("pipeline {} rule {}: invalid static_https", p.id, r.name))?;
+                    }
+                    if let Action::GeoStaticIp { default, by_country } = action {
+                        if !cfg!(feature = "geoip") {
+                            anyhow::bail!("pipeline {} rule {}: geo_static_ip requires the geoip feature", p.id, r.name);
+                        }
+                        for ip in std::iter::once(&*default).chain(by_country.values()) {
+                            ip.parse::<IpAddr>().with_context(|| {
+                                format!("pipeline {} rule {}: invalid geo_static_ip address {}", p.id, r.name, ip)
+                            })?;
+                        }
+                    }
+                    if let Action::StaticCname { target, .. } = action {
+                        config::cname_rdata(target)
+                            .with_context(|| format!("pipeline {} rule {}: invalid static_cname", p.id, r.name))?;
+                    }
+                    if let Action::RewriteIp { from, to, nets } = action {
+                        *nets = Some(
+                            config::rewrite_ip_nets(from, to)
+                                .with_context(|| format!("pipeline {} rule {}: invalid rewrite_ip", p.id, r.name))?,
+                        );
+                    }
+                    if let Action::NxdomainIfAnswerIp { ips, addrs } = action {
+                        *addrs = ips
+                            .iter()
+                            .map(|ip| {
+                                ip.trim().parse::<IpAddr>().with_context(|| {
+                                    format!("pipeline {} rule {}: invalid nxdomain_if_answer_ip address {}", p.id, r.name, ip)
+                                })
+                            })
+                            .collect::<anyhow::Result<_>>()?;
+                    }
+                }
                 rules.push(RuntimeRule {
                     name: r.name,
                     matcher_operator: r.matcher_operator,
@@ -208,16 +438,59 @@ impl RuntimePipelineConfig {
                 }
             }
 
+            let rate_limit_rules = rules
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.actions.iter().any(|a| matches!(a, Action::RateLimit { .. })))
+                .map(|(idx, _)| idx)
+                .collect();
+
+            let force_tcp_rules = rules
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.actions.iter().any(|a| matches!(a, Action::ForceTcp)))
+                .map(|(idx, _)| idx)
+                .collect();
+
+            let opcode_rules = rules
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.matchers.iter().any(|m| matches!(m.matcher, RuntimeMatcher::Opcode { .. })))
+                .map(|(idx, _)| idx)
+                .collect();
+
+            let uses_ecs = rules.iter().flat_map(|r| &r.matchers).any(|m| m.matcher.uses_ecs());
+            let dns64_prefix = p
+                .dns64_prefix
+                .as_deref()
+                .map(config::parse_dns64_prefix)
+                .transpose()
+                .with_context(|| format!("pipeline {}", p.id))?;
             pipelines.push(RuntimePipeline {
                 id: p.id,
                 rules,
                 domain_suffix_index,
                 always_check_rules,
+                rate_limit_rules,
+                force_tcp_rules,
+                opcode_rules,
+                upstream_timeout: p.upstream_timeout_ms.map(std::time::Duration::from_millis),
+                default_transport: p.default_transport.unwrap_or(config::Transport::Udp),
+                shadow_mode: p.shadow_mode,
+                uses_ecs,
+                dns64_prefix,
             });
         }
 
         let mut pipeline_select = Vec::new();
-        for s in cfg.pipeline_select {
+        for (idx, s) in cfg.pipeline_select.into_iter().enumerate() {
+            if !pipelines.iter().any(|p| p.id == s.pipeline) {
+                anyhow::bail!(
+                    "pipeline_select rule #{} targets unknown pipeline: {}",
+                    idx + 1,
+                    s.pipeline
+                );
+            }
             let mut matchers = Vec::new();
             let mut all_default = true;
             for m in s.matchers {
@@ -226,7 +499,8 @@ impl RuntimePipelineConfig {
                 }
                 matchers.push(RuntimePipelineSelectorMatcherWithOp {
                     operator: m.operator,
-                    matcher: RuntimePipelineSelectorMatcher::from_config(m.matcher)?,
+                    matcher: RuntimePipelineSelectorMatcher::from_config(m.matcher, lookups)
+                        .with_context(|| format!("pipeline_select rule #{}: invalid matcher", idx + 1))?,
                 });
             }
             if all_default && !matchers.is_empty() && s.matcher_operator != MatchOperator::And {
@@ -241,11 +515,112 @@ impl RuntimePipelineConfig {
             });
         }
 
-        Ok(Self {
+        let mut answer_ip_allowlist = Vec::with_capacity(cfg.settings.answer_ip_allowlist.len());
+        for cidr in &cfg.settings.answer_ip_allowlist {
+            let net: IpNet = cidr
+                .trim()
+                .parse()
+                .map_err(|err| anyhow::anyhow!("invalid answer_ip_allowlist entry {cidr}: {err}"))?;
+            answer_ip_allowlist.push(net);
+        }
+        let parse_networks = |name: &str, list: &[String]| {
+            list.iter()
+                .map(|cidr| cidr.trim().parse::<IpNet>().map_err(|err| anyhow::anyhow!("invalid {name} entry {cidr}: {err}")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let allow_networks = parse_networks("allow_networks", &cfg.settings.allow_networks)?;
+        let deny_networks = parse_networks("deny_networks", &cfg.settings.deny_networks)?;
+        let dns64_prefix = cfg
+            .settings
+            .dns64_enabled
+            .then(|| config::parse_dns64_prefix(&cfg.settings.dns64_prefix))
+            .transpose()?;
+        let tcp_for_qtypes = cfg
+            .settings
+            .tcp_for_qtypes
+            .iter()
+            .map(|t| parse_record_type(t.trim()))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("invalid tcp_for_qtypes")?;
+        let upstream_port_range = cfg
+            .settings
+            .upstream_port_range
+            .as_deref()
+            .map(config::parse_port_range)
+            .transpose()?;
+        let cookies = cfg
+            .settings
+            .cookie_secret
+            .as_deref()
+            .map(ServerCookies::new)
+            .transpose()?
+            .map(Arc::new);
+        let local_zones = cfg
+            .local_zones
+            .iter()
+            .map(RuntimeLocalZone::compile)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let static_records = cfg
+            .settings
+            .static_records_file
+            .as_deref()
+            .map(StaticRecordsFile::load)
+            .transpose()?
+            .map(Arc::new);
+        let alpha = cfg.settings.upstream_latency_alpha;
+        if !(0.0..=1.0).contains(&alpha) {
+            anyhow::bail!("upstream_latency_alpha must be within [0, 1], got {alpha}");
+        }
+        if cfg.settings.upstream_probe_percent > 100 {
+            anyhow::bail!("upstream_probe_percent must be within [0, 100], got {}", cfg.settings.upstream_probe_percent);
+        }
+        let reverse_zones = cfg
+            .settings
+            .static_reverse_zones
+            .iter()
+            .map(|zone| {
+                let zone = zone.trim().trim_end_matches('.').to_ascii_lowercase();
+                if !(zone.ends_with("in-addr.arpa") || zone.ends_with("ip6.arpa")) {
+                    anyhow::bail!("static_reverse_zones: {zone} is not under in-addr.arpa or ip6.arpa");
+                }
+                Ok(zone)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let default_upstream = UpstreamGroup::parse(&cfg.settings.default_upstream);
+        let upstream_shards = cfg
+            .settings
+            .upstream_groups
+            .iter()
+            .filter(|group| !group.is_empty())
+            .map(|group| UpstreamGroup::from_members(group.clone()))
+            .collect();
+
+        let runtime = Self {
             settings: cfg.settings,
             pipeline_select,
             pipelines,
-        })
+            default_upstream,
+            upstream_shards,
+            answer_ip_allowlist,
+            allow_networks,
+            deny_networks,
+            dns64_prefix,
+            tcp_for_qtypes,
+            upstream_port_range,
+            cookies,
+            local_zones,
+            static_records,
+            reverse_zones,
+            geo,
+            generation: CONFIG_GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+        };
+        // 跳转目标拼写错误在加载时拒绝，热加载时保留旧配置而不是运行时返回 SERVFAIL
+        let dangling = runtime.dangling_jumps();
+        if !dangling.is_empty() {
+            anyhow::bail!("{}", dangling.join("; "));
+        }
+        Ok(runtime)
     }
 
     pub fn min_ttl(&self) -> std::time::Duration {
@@ -255,10 +630,231 @@ impl RuntimePipelineConfig {
     pub fn upstream_timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.settings.upstream_timeout_ms)
     }
+
+    /// 列出 jump_to_pipeline 指向未定义 pipeline 的规则（请求与响应动作）。
+    pub fn dangling_jumps(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        for p in &self.pipelines {
+            for r in &p.rules {
+                let actions = r
+                    .actions
+                    .iter()
+                    .chain(&r.response_actions_on_match)
+                    .chain(&r.response_actions_on_miss);
+                for action in actions {
+                    if let Action::JumpToPipeline { pipeline } = action
+                        && !self.pipelines.iter().any(|t| &t.id == pipeline)
+                    {
+                        out.push(format!(
+                            "pipeline {} rule {}: jump_to_pipeline targets unknown pipeline: {}",
+                            p.id, r.name, pipeline
+                        ));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// 规则中以明文 TCP 转发的上游（请求与响应动作，多上游逐个展开，去重排序），用于预热连接池。
+    pub fn tcp_upstreams(&self) -> Vec<String> {
+        let mut out: Vec<String> = self
+            .pipelines
+            .iter()
+            .flat_map(|p| &p.rules)
+            .flat_map(|r| r.actions.iter().chain(&r.response_actions_on_match).chain(&r.response_actions_on_miss))
+            .filter_map(|action| match action {
+                Action::Forward {
+                    upstream,
+                    transport: Some(config::Transport::Tcp),
+                    ..
+                } => Some(upstream.as_ref().unwrap_or(&self.default_upstream)),
+                _ => None,
+            })
+            .flat_map(UpstreamGroup::members)
+            .filter(|u| !u.starts_with("tls://") && !u.starts_with("https://"))
+            .cloned()
+            .collect();
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    pub fn shutdown_grace(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.settings.shutdown_grace_ms)
+    }
+
+    /// 与新配置比较：新增/删除的 pipeline、规则数变化的 pipeline 以及变化的 settings 字段。
+    pub fn diff(&self, new: &RuntimePipelineConfig) -> ConfigDiff {
+        let mut diff = ConfigDiff::default();
+        for p in &new.pipelines {
+            match self.pipelines.iter().find(|old| old.id == p.id) {
+                None => diff.pipelines_added.push(p.id.clone()),
+                Some(old) if old.rules.len() != p.rules.len() => {
+                    diff.rule_counts_changed
+                        .push((p.id.clone(), old.rules.len(), p.rules.len()));
+                }
+                Some(_) => {}
+            }
+        }
+        for p in &self.pipelines {
+            if !new.pipelines.iter().any(|n| n.id == p.id) {
+                diff.pipelines_removed.push(p.id.clone());
+            }
+        }
+        // 逐字段比较序列化结果，新增 settings 字段无需同步修改此处
+        if let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) = (
+            serde_json::to_value(&self.settings),
+            serde_json::to_value(&new.settings),
+        ) {
+            for (key, value) in &new {
+                if old.get(key) != Some(value) {
+                    diff.settings_changed.push(key.clone());
+                }
+            }
+        }
+        diff
+    }
+
+    /// 规则中 domain_set 匹配器引用的列表文件（同一文件被多条规则引用时各自一份）
+    pub fn domain_sets(&self) -> Vec<Arc<DomainSetFile>> {
+        let mut out = Vec::new();
+        for p in &self.pipelines {
+            for r in &p.rules {
+                for m in &r.matchers {
+                    m.matcher.collect_domain_sets(&mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// pipeline 内转发使用的 DNS64 前缀：Pipeline.dns64_prefix 优先，否则为全局设置；未启用时为 None
+    pub fn dns64_prefix_for(&self, pipeline_id: &str) -> Option<ipnet::Ipv6Net> {
+        self.pipelines
+            .iter()
+            .find(|p| p.id == pipeline_id)
+            .and_then(|p| p.dns64_prefix)
+            .or(self.dns64_prefix)
+    }
+
+    /// 默认上游；开启 shard_by_domain 时按 qname 的可注册域名在 upstream_groups 中选择
+    pub fn default_upstream_for(&self, qname: &str) -> &UpstreamGroup {
+        if self.settings.shard_by_domain
+            && let Some(upstream) = crate::shard::shard_group(&self.upstream_shards, qname)
+        {
+            return upstream;
+        }
+        &self.default_upstream
+    }
+}
+
+/// 请求匹配器或 pipeline 选择器中是否用到 client_geo
+fn uses_client_geo(cfg: &PipelineConfig) -> bool {
+    let in_rules = cfg
+        .pipelines
+        .iter()
+        .flat_map(|p| &p.rules)
+        .flat_map(|r| &r.matchers)
+        .any(|m| m.matcher.any(&|m| matches!(m, config::Matcher::ClientGeo { .. })));
+    let in_select = cfg
+        .pipeline_select
+        .iter()
+        .flat_map(|s| &s.matchers)
+        .any(|m| matches!(m.matcher, config::PipelineSelectorMatcher::ClientGeo { .. }));
+    in_rules || in_select
+}
+
+/// 请求匹配器或 pipeline 选择器中是否用到 client_asn
+fn uses_client_asn(cfg: &PipelineConfig) -> bool {
+    let in_rules = cfg
+        .pipelines
+        .iter()
+        .flat_map(|p| &p.rules)
+        .flat_map(|r| &r.matchers)
+        .any(|m| m.matcher.any(&|m| matches!(m, config::Matcher::ClientAsn { .. })));
+    let in_select = cfg
+        .pipeline_select
+        .iter()
+        .flat_map(|s| &s.matchers)
+        .any(|m| matches!(m.matcher, config::PipelineSelectorMatcher::ClientAsn { .. }));
+    in_rules || in_select
+}
+
+/// 编译 client_geo / client_asn 匹配器所需的数据库查询，未配置的为 None
+#[derive(Clone, Copy, Default)]
+struct ClientLookups<'a> {
+    geo: Option<&'a Arc<dyn GeoLookup>>,
+    asn: Option<&'a Arc<dyn AsnLookup>>,
+}
+
+/// client_asn 的列表不能为空，数据库缺失视为配置错误
+fn client_asn_parts(asns: Vec<u32>, asn: Option<&Arc<dyn AsnLookup>>) -> anyhow::Result<(Vec<u32>, Arc<dyn AsnLookup>)> {
+    if asns.is_empty() {
+        anyhow::bail!("client_asn requires at least one asn");
+    }
+    let asn = asn.context("client_asn matcher requires settings.asn_db")?;
+    Ok((asns, Arc::clone(asn)))
+}
+
+/// client_geo 的国家代码统一为大写，数据库缺失视为配置错误
+fn client_geo_parts(country: &str, geo: Option<&Arc<dyn GeoLookup>>) -> anyhow::Result<(String, Arc<dyn GeoLookup>)> {
+    let country = country.trim().to_ascii_uppercase();
+    if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_alphabetic()) {
+        anyhow::bail!("client_geo country must be a two-letter ISO code: {}", country);
+    }
+    let geo = geo.context("client_geo matcher requires settings.geoip_db")?;
+    Ok((country, Arc::clone(geo)))
+}
+
+/// 编译一条匹配链（规则或 group）：各项都用缺省 and 且 matcher_operator 不是 and 时，把 matcher_operator 作用到每一项
+fn compile_matchers(
+    list: Vec<config::MatcherWithOp>,
+    matcher_operator: MatchOperator,
+    lookups: ClientLookups<'_>,
+) -> anyhow::Result<Vec<RuntimeMatcherWithOp>> {
+    let all_default = list.iter().all(|m| m.operator == MatchOperator::And);
+    let mut matchers = list
+        .into_iter()
+        .map(|m| {
+            Ok(RuntimeMatcherWithOp {
+                operator: m.operator,
+                matcher: RuntimeMatcher::from_config(m.matcher, lookups)?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if all_default && matcher_operator != MatchOperator::And {
+        for m in &mut matchers {
+            m.operator = matcher_operator;
+        }
+    }
+    Ok(matchers)
 }
 
 impl RuntimeMatcher {
-    fn from_config(m: config::Matcher) -> anyhow::Result<Self> {
+    /// 自身或 group 内是否有 ecs_subnet
+    fn uses_ecs(&self) -> bool {
+        match self {
+            RuntimeMatcher::EcsSubnet { .. } => true,
+            RuntimeMatcher::Group { children } => children.iter().any(|c| c.matcher.uses_ecs()),
+            _ => false,
+        }
+    }
+
+    /// 收集自身及 group 内的 domain_set 列表文件
+    fn collect_domain_sets(&self, out: &mut Vec<Arc<DomainSetFile>>) {
+        match self {
+            RuntimeMatcher::DomainSet { set } => out.push(Arc::clone(set)),
+            RuntimeMatcher::Group { children } => {
+                for c in children {
+                    c.matcher.collect_domain_sets(out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn from_config(m: config::Matcher, lookups: ClientLookups<'_>) -> anyhow::Result<Self> {
         Ok(match m {
             config::Matcher::Any => RuntimeMatcher::Any,
             config::Matcher::DomainSuffix { value } => RuntimeMatcher::DomainSuffix {
@@ -272,6 +868,49 @@ impl RuntimeMatcher {
                 value: parse_dns_class(&value)?,
             },
             config::Matcher::EdnsPresent { expect } => RuntimeMatcher::EdnsPresent { expect },
+            config::Matcher::EdnsAtLeast { bufsize } => RuntimeMatcher::EdnsAtLeast { bufsize },
+            config::Matcher::EcsSubnet { cidr } => RuntimeMatcher::EcsSubnet { net: cidr.parse()? },
+            config::Matcher::QueryType { value } => RuntimeMatcher::QueryType {
+                qtype: parse_record_type(&value)?,
+            },
+            config::Matcher::DomainSet { file } => RuntimeMatcher::DomainSet {
+                set: Arc::new(DomainSetFile::load(file)?),
+            },
+            config::Matcher::ClientGeo { country } => {
+                let (country, geo) = client_geo_parts(&country, lookups.geo)?;
+                RuntimeMatcher::ClientGeo { country, geo }
+            }
+            config::Matcher::ClientAsn { asns } => {
+                let (asns, asn) = client_asn_parts(asns, lookups.asn)?;
+                RuntimeMatcher::ClientAsn { asns, asn }
+            }
+            config::Matcher::NameLength { min, max } => {
+                if min > max {
+                    anyhow::bail!("name_length min {} exceeds max {}", min, max);
+                }
+                RuntimeMatcher::NameLength { min, max }
+            }
+            config::Matcher::LabelCount { min, max } => {
+                if min > max {
+                    anyhow::bail!("label_count min {} exceeds max {}", min, max);
+                }
+                RuntimeMatcher::LabelCount { min, max }
+            }
+            config::Matcher::Opcode { value } => RuntimeMatcher::Opcode {
+                opcode: parse_opcode(&value)?,
+            },
+            config::Matcher::Group { matcher_operator, matchers } => {
+                if matchers.is_empty() {
+                    anyhow::bail!("group requires at least one matcher");
+                }
+                // opcode 规则按顶层匹配器单独索引，不支持放进 group
+                if matchers.iter().any(|m| m.matcher.any(&|m| matches!(m, config::Matcher::Opcode { .. }))) {
+                    anyhow::bail!("opcode matcher is not allowed inside a group");
+                }
+                RuntimeMatcher::Group {
+                    children: compile_matchers(matchers, matcher_operator, lookups)?,
+                }
+            }
         })
     }
 
@@ -279,9 +918,11 @@ impl RuntimeMatcher {
     pub fn matches(
         &self,
         qname: &str,
+        qtype: RecordType,
         qclass: DNSClass,
         client_ip: IpAddr,
-        edns_present: bool,
+        edns_bufsize: Option<u16>,
+        ecs: Option<IpAddr>,
     ) -> bool {
         match self {
             RuntimeMatcher::Any => true,
@@ -289,13 +930,41 @@ impl RuntimeMatcher {
             RuntimeMatcher::ClientIp { net } => net.contains(&client_ip),
             RuntimeMatcher::DomainRegex { regex } => regex.is_match(qname),
             RuntimeMatcher::Qclass { value } => &qclass == value,
-            RuntimeMatcher::EdnsPresent { expect } => *expect == edns_present,
+            RuntimeMatcher::EdnsPresent { expect } => *expect == edns_bufsize.is_some(),
+            RuntimeMatcher::EdnsAtLeast { bufsize } => edns_bufsize.is_some_and(|b| b >= *bufsize),
+            RuntimeMatcher::EcsSubnet { net } => ecs.is_some_and(|ip| net.contains(&ip)),
+            RuntimeMatcher::QueryType { qtype: value } => *value == qtype,
+            RuntimeMatcher::DomainSet { set } => set.contains(qname),
+            RuntimeMatcher::ClientGeo { country, geo } => client_in_country(geo.as_ref(), client_ip, country),
+            RuntimeMatcher::ClientAsn { asns, asn } => client_in_asns(asn.as_ref(), client_ip, asns),
+            RuntimeMatcher::NameLength { min, max } => (*min..=*max).contains(&name_length(qname)),
+            RuntimeMatcher::LabelCount { min, max } => (*min..=*max).contains(&label_count(qname)),
+            // 常规规则评估只会见到 QUERY；其他 opcode 由 engine 单独按 opcode_rules 处理
+            RuntimeMatcher::Opcode { opcode } => *opcode == OPCODE_QUERY,
+            RuntimeMatcher::Group { children } => eval_match_chain(
+                children,
+                |c| c.operator,
+                |c| c.matcher.matches(qname, qtype, qclass, client_ip, edns_bufsize, ecs),
+            ),
         }
     }
 }
 
+/// 查询名长度，不含末尾的点
+#[inline]
+pub fn name_length(qname: &str) -> usize {
+    qname.strip_suffix('.').unwrap_or(qname).len()
+}
+
+/// 查询名标签数：按 '.' 分隔，忽略末尾的点，根域为 0
+#[inline]
+pub fn label_count(qname: &str) -> usize {
+    let name = qname.strip_suffix('.').unwrap_or(qname);
+    if name.is_empty() { 0 } else { name.split('.').count() }
+}
+
 impl RuntimePipelineSelectorMatcher {
-    fn from_config(m: config::PipelineSelectorMatcher) -> anyhow::Result<Self> {
+    fn from_config(m: config::PipelineSelectorMatcher, lookups: ClientLookups<'_>) -> anyhow::Result<Self> {
         Ok(match m {
             config::PipelineSelectorMatcher::ListenerLabel { value } => {
                 RuntimePipelineSelectorMatcher::ListenerLabel { value }
@@ -322,6 +991,17 @@ impl RuntimePipelineSelectorMatcher {
             config::PipelineSelectorMatcher::EdnsPresent { expect } => {
                 RuntimePipelineSelectorMatcher::EdnsPresent { expect }
             }
+            config::PipelineSelectorMatcher::EdnsAtLeast { bufsize } => {
+                RuntimePipelineSelectorMatcher::EdnsAtLeast { bufsize }
+            }
+            config::PipelineSelectorMatcher::ClientGeo { country } => {
+                let (country, geo) = client_geo_parts(&country, lookups.geo)?;
+                RuntimePipelineSelectorMatcher::ClientGeo { country, geo }
+            }
+            config::PipelineSelectorMatcher::ClientAsn { asns } => {
+                let (asns, asn) = client_asn_parts(asns, lookups.asn)?;
+                RuntimePipelineSelectorMatcher::ClientAsn { asns, asn }
+            }
         })
     }
 
@@ -332,7 +1012,7 @@ impl RuntimePipelineSelectorMatcher {
         client_ip: IpAddr,
         qname: &str,
         qclass: DNSClass,
-        edns_present: bool,
+        edns_bufsize: Option<u16>,
     ) -> bool {
         match self {
             RuntimePipelineSelectorMatcher::ListenerLabel { value } => {
@@ -343,11 +1023,30 @@ impl RuntimePipelineSelectorMatcher {
             RuntimePipelineSelectorMatcher::DomainRegex { regex } => regex.is_match(qname),
             RuntimePipelineSelectorMatcher::Any => true,
             RuntimePipelineSelectorMatcher::Qclass { value } => value == &qclass,
-            RuntimePipelineSelectorMatcher::EdnsPresent { expect } => *expect == edns_present,
+            RuntimePipelineSelectorMatcher::EdnsPresent { expect } => *expect == edns_bufsize.is_some(),
+            RuntimePipelineSelectorMatcher::EdnsAtLeast { bufsize } => {
+                edns_bufsize.is_some_and(|b| b >= *bufsize)
+            }
+            RuntimePipelineSelectorMatcher::ClientGeo { country, geo } => {
+                client_in_country(geo.as_ref(), client_ip, country)
+            }
+            RuntimePipelineSelectorMatcher::ClientAsn { asns, asn } => client_in_asns(asn.as_ref(), client_ip, asns),
         }
     }
 }
 
+/// 库中查不到的 IP（如内网地址）视为不匹配
+#[inline]
+pub(crate) fn client_in_country(geo: &dyn GeoLookup, client_ip: IpAddr, country: &str) -> bool {
+    geo.country(client_ip).is_some_and(|c| c == country)
+}
+
+/// 库中查不到的 IP 视为不匹配
+#[inline]
+pub(crate) fn client_in_asns(asn: &dyn AsnLookup, client_ip: IpAddr, asns: &[u32]) -> bool {
+    asn.asn(client_ip).is_some_and(|n| asns.contains(&n))
+}
+
 #[allow(dead_code)]
 pub fn apply_match_operator(op: &MatchOperator, mut results: impl Iterator<Item = bool>) -> bool {
     match op {
@@ -474,6 +1173,13 @@ impl RuntimeResponseMatcher {
             config::ResponseMatcher::ResponseEdnsPresent { expect } => {
                 RuntimeResponseMatcher::ResponseEdnsPresent { expect }
             }
+            config::ResponseMatcher::ResponseTruncated => RuntimeResponseMatcher::ResponseTruncated,
+            config::ResponseMatcher::ResponseAnswerCount { min, max } => {
+                if min > max {
+                    anyhow::bail!("response_answer_count min {} exceeds max {}", min, max);
+                }
+                RuntimeResponseMatcher::ResponseAnswerCount { min, max }
+            }
         })
     }
 
@@ -542,6 +1248,8 @@ impl RuntimeResponseMatcher {
                 let edns = msg.extensions().is_some();
                 edns == *expect
             }
+            RuntimeResponseMatcher::ResponseTruncated => msg.truncated(),
+            RuntimeResponseMatcher::ResponseAnswerCount { min, max } => (*min..=*max).contains(&msg.answers().len()),
         }
     }
 }
@@ -557,6 +1265,229 @@ mod tests {
     use std::net::{Ipv4Addr, Ipv6Addr};
     use std::str::FromStr;
 
+    #[test]
+    fn domain_set_rules_are_indexed_and_reread_on_reload() {
+        let dir = std::env::temp_dir().join(format!("kixdns-domain-set-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        std::fs::write(
+            &cfg_path,
+            serde_json::json!({
+                "pipelines": [{ "id": "main", "rules": [
+                    { "name": "block", "matchers": [ { "type": "domain_set", "file": "blocklist.txt" } ],
+                      "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] },
+                    { "name": "other", "matchers": [ { "type": "domain_suffix", "value": "example.org" } ],
+                      "actions": [ { "type": "forward" } ] }
+                ] }]
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let load = || {
+            let cfg = config::load_config(&cfg_path).expect("load");
+            let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+            crate::advanced_rule::compile_pipelines(&runtime).remove(0)
+        };
+
+        std::fs::write(dir.join("blocklist.txt"), "ads.example.com\n").unwrap();
+        let compiled = load();
+        assert!(compiled.index.always_check.is_empty());
+        assert_eq!(compiled.index.get_candidates("x.ads.example.com", RecordType::A), vec![0]);
+        assert!(compiled.index.get_candidates("tracker.example.net", RecordType::A).is_empty());
+
+        // 主配置重新加载时读取列表文件的最新内容
+        std::fs::write(dir.join("blocklist.txt"), "ads.example.com\ntracker.example.net\n").unwrap();
+        let compiled = load();
+        assert_eq!(compiled.index.get_candidates("tracker.example.net", RecordType::A), vec![0]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn client_geo_requires_geoip_db() {
+        let raw = serde_json::json!({
+            "pipeline_select": [ { "pipeline": "eu", "matchers": [ { "type": "client_geo", "country": "DE" } ] } ],
+            "pipelines": [ { "id": "eu", "rules": [] } ]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("missing geoip_db");
+        assert!(format!("{err:#}").contains("settings.geoip_db"), "unexpected error: {err:#}");
+    }
+
+    #[test]
+    fn pipeline_select_with_unknown_target_is_rejected() {
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "p1", "rules": [] } ],
+            "pipeline_select": [
+                { "pipeline": "p1", "matchers": [ { "type": "any" } ] },
+                { "pipeline": "missing", "matchers": [ { "type": "any" } ] }
+            ]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("dangling select target");
+        assert!(err.to_string().contains("#2"), "unexpected error: {err}");
+        assert!(err.to_string().contains("missing"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn unknown_jump_targets_are_rejected() {
+        let raw = serde_json::json!({
+            "pipelines": [
+                { "id": "p1", "rules": [
+                    { "name": "ok", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "jump_to_pipeline", "pipeline": "p2" } ] },
+                    { "name": "typo", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "jump_to_pipeline", "pipeline": "p3" } ] },
+                    { "name": "bad", "matchers": [ { "type": "any" } ],
+                      "actions": [ { "type": "forward", "upstream": null } ],
+                      "response_actions_on_miss": [ { "type": "jump_to_pipeline", "pipeline": "nope" } ] }
+                ] },
+                { "id": "p2", "rules": [] }
+            ]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("dangling jumps").to_string();
+        assert!(err.contains("rule typo") && err.contains("p3"), "{err}");
+        assert!(err.contains("rule bad") && err.contains("nope"), "{err}");
+        assert!(!err.contains("rule ok"), "{err}");
+    }
+
+    #[test]
+    fn diff_reports_pipeline_rule_and_setting_changes() {
+        let load = |raw: serde_json::Value| {
+            let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+            RuntimePipelineConfig::from_config(cfg).expect("runtime")
+        };
+        let old = load(serde_json::json!({
+            "settings": { "upstream_timeout_ms": 2000 },
+            "pipelines": [
+                { "id": "main", "rules": [{ "name": "r1", "actions": [{ "type": "allow" }] }] },
+                { "id": "legacy", "rules": [] }
+            ]
+        }));
+        let new = load(serde_json::json!({
+            "settings": { "upstream_timeout_ms": 3000 },
+            "pipelines": [
+                { "id": "main", "rules": [
+                    { "name": "r1", "actions": [{ "type": "allow" }] },
+                    { "name": "r2", "actions": [{ "type": "deny" }] }
+                ] },
+                { "id": "edge", "rules": [] }
+            ]
+        }));
+        let diff = old.diff(&new);
+        assert_eq!(diff.pipelines_added, vec!["edge".to_string()]);
+        assert_eq!(diff.pipelines_removed, vec!["legacy".to_string()]);
+        assert_eq!(diff.rule_counts_changed, vec![("main".to_string(), 1, 2)]);
+        assert_eq!(diff.settings_changed, vec!["upstream_timeout_ms".to_string()]);
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(old.diff(&old).to_string(), "no changes");
+    }
+
+    #[test]
+    fn rewrite_ip_prefix_mismatch_is_rejected() {
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "main", "rules": [{
+                "name": "vpn",
+                "response_actions_on_match": [{ "type": "rewrite_ip", "from": "203.0.113.0/24", "to": "10.8.0.0/16" }]
+            }] }]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("prefix mismatch");
+        let msg = format!("{err:#}");
+        assert!(msg.contains("rule vpn"), "{msg}");
+        assert!(msg.contains("prefix lengths differ"), "{msg}");
+    }
+
+    #[test]
+    fn zero_rate_limit_is_rejected() {
+        for (max_qps, burst) in [(0, 10), (10, 0)] {
+            let raw = serde_json::json!({
+                "pipelines": [{ "id": "main", "rules": [{
+                    "name": "rl",
+                    "actions": [{ "type": "rate_limit", "max_qps": max_qps, "burst": burst }]
+                }] }]
+            });
+            let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+            let err = RuntimePipelineConfig::from_config(cfg).expect_err("zero rate");
+            assert!(format!("{err:#}").contains("rule rl: rate_limit max_qps and burst must be positive"), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn shard_by_domain_picks_a_whole_upstream_group() {
+        let raw = serde_json::json!({
+            "settings": {
+                "shard_by_domain": true,
+                "upstream_groups": [["10.0.0.1:53", "10.0.0.2:53"], [], ["10.0.1.1:53", "10.0.1.2:53"]]
+            }
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        // 空组被忽略；选中的是整组，成员交给上游策略与故障转移
+        assert_eq!(runtime.upstream_shards.len(), 2);
+        let group = runtime.default_upstream_for("www.example.com");
+        assert_eq!(group.members().len(), 2);
+        assert!(runtime.upstream_shards.contains(group));
+        assert_eq!(runtime.default_upstream_for("api.example.com"), group);
+    }
+
+    #[test]
+    fn nxdomain_if_answer_ip_addresses_are_parsed_on_load() {
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "main", "rules": [{
+                "name": "sinkhole",
+                "response_actions_on_match": [{ "type": "nxdomain_if_answer_ip", "ips": [" 198.18.0.9", "2001:db8::53"] }]
+            }] }]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let runtime = RuntimePipelineConfig::from_config(cfg).expect("runtime");
+        let Action::NxdomainIfAnswerIp { addrs, .. } = &runtime.pipelines[0].rules[0].response_actions_on_match[0] else {
+            panic!("expected nxdomain_if_answer_ip");
+        };
+        let expected: Vec<IpAddr> = vec!["198.18.0.9".parse().unwrap(), "2001:db8::53".parse().unwrap()];
+        assert_eq!(addrs, &expected);
+    }
+
+    #[test]
+    fn ttl_actions_parse_and_inverted_clamp_is_rejected() {
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "main", "rules": [{
+                "name": "ttl",
+                "response_actions_on_match": [{ "type": "set_ttl", "seconds": 60 }, { "type": "clamp_ttl", "max": 300 }]
+            }] }]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let rule = &cfg.pipelines[0].rules[0];
+        assert!(matches!(rule.response_actions_on_match[0], Action::SetTtl { seconds: 60 }));
+        assert!(matches!(rule.response_actions_on_match[1], Action::ClampTtl { min: None, max: Some(300) }));
+        RuntimePipelineConfig::from_config(cfg).expect("valid ttl actions");
+
+        let raw = serde_json::json!({
+            "pipelines": [{ "id": "main", "rules": [{
+                "name": "ttl",
+                "response_actions_on_match": [{ "type": "clamp_ttl", "min": 600, "max": 300 }]
+            }] }]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("min above max");
+        assert!(format!("{err:#}").contains("clamp_ttl min 600 exceeds max 300"));
+    }
+
+    #[test]
+    fn bad_regex_error_names_the_rule() {
+        let raw = serde_json::json!({
+            "pipelines": [ { "id": "p1", "rules": [
+                { "name": "re", "matchers": [ { "type": "domain_regex", "value": "(unclosed" } ], "actions": [] }
+            ] } ]
+        });
+        let cfg: PipelineConfig = serde_json::from_value(raw).expect("parse");
+        let err = RuntimePipelineConfig::from_config(cfg).expect_err("bad regex");
+        let msg = format!("{err:#}");
+        assert!(msg.contains("pipeline p1 rule re"), "{msg}");
+        assert!(msg.contains("unclosed"), "{msg}");
+    }
+
     fn build_message(rcode: ResponseCode, edns_present: bool) -> Message {
         let mut msg = Message::new();
         msg.set_response_code(rcode);
@@ -675,7 +1606,7 @@ mod tests {
         ];
         let res_and = m_and_true
             .iter()
-            .map(|m| m.matches(qname, qclass, client_ip, true));
+            .map(|m| m.matches(qname, RecordType::A, qclass, client_ip, Some(1232), None));
         assert!(apply_match_operator(&MatchOperator::And, res_and));
 
         let m_and_false = vec![
@@ -688,7 +1619,7 @@ mod tests {
         ];
         let res_and_false = m_and_false
             .iter()
-            .map(|m| m.matches(qname, qclass, client_ip, true));
+            .map(|m| m.matches(qname, RecordType::A, qclass, client_ip, Some(1232), None));
         assert!(!apply_match_operator(&MatchOperator::And, res_and_false));
 
         let m_or = vec![
@@ -701,7 +1632,7 @@ mod tests {
         ];
         let res_or = m_or
             .iter()
-            .map(|m| m.matches(qname, qclass, client_ip, true));
+            .map(|m| m.matches(qname, RecordType::A, qclass, client_ip, Some(1232), None));
         assert!(apply_match_operator(&MatchOperator::Or, res_or));
 
         let m_not_all_false = vec![
@@ -714,7 +1645,7 @@ mod tests {
         ];
         let res_not = m_not_all_false
             .iter()
-            .map(|m| m.matches(qname, qclass, client_ip, true));
+            .map(|m| m.matches(qname, RecordType::A, qclass, client_ip, Some(1232), None));
         // none match -> NOT should be true
         assert!(apply_match_operator(&MatchOperator::Not, res_not));
 
@@ -728,7 +1659,7 @@ mod tests {
         ];
         let res_not_false = m_not_one_true
             .iter()
-            .map(|m| m.matches(qname, qclass, client_ip, true));
+            .map(|m| m.matches(qname, RecordType::A, qclass, client_ip, Some(1232), None));
         // one matches -> NOT should be false
         assert!(!apply_match_operator(&MatchOperator::Not, res_not_false));
     }
@@ -821,21 +1752,21 @@ mod tests {
             RuntimePipelineSelectorMatcher::ListenerLabel {
                 value: "edge-internal".into()
             }
-            .matches(listener_label, client_ip, qname, DNSClass::IN, false)
+            .matches(listener_label, client_ip, qname, DNSClass::IN, None)
         );
 
         assert!(
             RuntimePipelineSelectorMatcher::ClientIp {
                 net: "10.1.2.0/24".parse().unwrap()
             }
-            .matches(listener_label, client_ip, qname, DNSClass::IN, false)
+            .matches(listener_label, client_ip, qname, DNSClass::IN, None)
         );
 
         assert!(
             RuntimePipelineSelectorMatcher::DomainSuffix {
                 value: "example.com".into()
             }
-            .matches(listener_label, client_ip, qname, DNSClass::IN, false)
+            .matches(listener_label, client_ip, qname, DNSClass::IN, None)
         );
     }
 
@@ -847,14 +1778,14 @@ mod tests {
         let qclass = DNSClass::IN;
 
         // Any always matches
-        assert!(RuntimeMatcher::Any.matches(&qname, qclass, client_ip, false));
+        assert!(RuntimeMatcher::Any.matches(&qname, RecordType::A, qclass, client_ip, None, None));
 
         // DomainSuffix should match when suffix equals
         assert!(
             RuntimeMatcher::DomainSuffix {
                 value: "example.com".into()
             }
-            .matches(&qname, qclass, client_ip, false)
+            .matches(&qname, RecordType::A, qclass, client_ip, None, None)
         );
 
         // ClientIp CIDR
@@ -862,7 +1793,7 @@ mod tests {
             RuntimeMatcher::ClientIp {
                 net: "192.0.2.0/24".parse().unwrap()
             }
-            .matches(&qname, qclass, client_ip, false)
+            .matches(&qname, RecordType::A, qclass, client_ip, None, None)
         );
 
         // Qclass
@@ -870,15 +1801,121 @@ mod tests {
             RuntimeMatcher::Qclass {
                 value: DNSClass::IN
             }
-            .matches(&qname, qclass, client_ip, false)
+            .matches(&qname, RecordType::A, qclass, client_ip, None, None)
         );
 
         // EdnsPresent
         assert!(
-            RuntimeMatcher::EdnsPresent { expect: false }.matches(&qname, qclass, client_ip, false)
+            RuntimeMatcher::EdnsPresent { expect: false }.matches(&qname, RecordType::A, qclass, client_ip, None, None)
         );
     }
 
+    #[test]
+    fn groups_evaluate_with_parenthesized_precedence() {
+        use std::net::IpAddr;
+        let client_ip = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 5));
+        let compile = |raw: serde_json::Value| {
+            let list: Vec<config::MatcherWithOp> = serde_json::from_value(raw).expect("parse");
+            compile_matchers(list, MatchOperator::And, ClientLookups::default()).expect("compile")
+        };
+        let eval = |chain: &[RuntimeMatcherWithOp], qname: &str, qtype: RecordType| {
+            eval_match_chain(chain, |m| m.operator, |m| m.matcher.matches(qname, qtype, DNSClass::IN, client_ip, None, None))
+        };
+
+        // (example.com and A) or (example.net and AAAA)
+        let flat = compile(serde_json::json!([
+            { "type": "domain_suffix", "value": "example.com" },
+            { "type": "query_type", "value": "A" },
+            { "operator": "or", "type": "domain_suffix", "value": "example.net" },
+            { "type": "query_type", "value": "AAAA" }
+        ]));
+        let grouped = compile(serde_json::json!([
+            { "type": "group", "matchers": [
+                { "type": "domain_suffix", "value": "example.com" },
+                { "type": "query_type", "value": "A" }
+            ] },
+            { "operator": "or", "type": "group", "matchers": [
+                { "type": "domain_suffix", "value": "example.net" },
+                { "type": "query_type", "value": "AAAA" }
+            ] }
+        ]));
+        // 扁平链按 ((com and A) or net) and AAAA 求值，第一组成立也会被最后的 and 否决
+        assert!(!eval(&flat, "www.example.com", RecordType::A));
+        assert!(eval(&grouped, "www.example.com", RecordType::A));
+        assert!(eval(&grouped, "www.example.net", RecordType::AAAA));
+        assert!(!eval(&grouped, "www.example.net", RecordType::A));
+        assert!(!eval(&grouped, "www.example.org", RecordType::A));
+
+        // A and (B or C)：组内用 matcher_operator 统一为 or；扁平链会把 C 单独 or 进来
+        let flat = compile(serde_json::json!([
+            { "type": "query_type", "value": "TXT" },
+            { "type": "domain_suffix", "value": "example.com" },
+            { "operator": "or", "type": "domain_suffix", "value": "example.net" }
+        ]));
+        let grouped = compile(serde_json::json!([
+            { "type": "query_type", "value": "TXT" },
+            { "type": "group", "matcher_operator": "or", "matchers": [
+                { "type": "domain_suffix", "value": "example.com" },
+                { "type": "domain_suffix", "value": "example.net" }
+            ] }
+        ]));
+        assert!(eval(&flat, "www.example.net", RecordType::A));
+        assert!(!eval(&grouped, "www.example.net", RecordType::A));
+        assert!(eval(&grouped, "www.example.net", RecordType::TXT));
+
+        // 组可以嵌套；空组与组内 opcode 在加载时拒绝
+        let nested = compile(serde_json::json!([
+            { "type": "group", "matcher_operator": "or", "matchers": [
+                { "type": "domain_suffix", "value": "example.org" },
+                { "type": "group", "matchers": [
+                    { "type": "domain_suffix", "value": "example.com" },
+                    { "operator": "and_not", "type": "query_type", "value": "AAAA" }
+                ] }
+            ] }
+        ]));
+        assert!(eval(&nested, "a.example.com", RecordType::A));
+        assert!(!eval(&nested, "a.example.com", RecordType::AAAA));
+        assert!(eval(&nested, "a.example.org", RecordType::AAAA));
+        for bad in [
+            serde_json::json!([{ "type": "group", "matchers": [] }]),
+            serde_json::json!([{ "type": "group", "matchers": [{ "type": "opcode", "value": "notify" }] }]),
+        ] {
+            let list: Vec<config::MatcherWithOp> = serde_json::from_value(bad).expect("parse");
+            assert!(compile_matchers(list, MatchOperator::And, ClientLookups::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn name_length_and_label_count_boundaries() {
+        use std::net::IpAddr;
+        let client_ip = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 5));
+        let matches = |m: &RuntimeMatcher, qname: &str| m.matches(qname, RecordType::A, DNSClass::IN, client_ip, None, None);
+
+        let raw = serde_json::json!([
+            { "type": "name_length", "min": 20 },
+            { "type": "label_count", "max": 3 }
+        ]);
+        let parsed: Vec<config::Matcher> = serde_json::from_value(raw).expect("parse");
+        let mut parsed = parsed.into_iter().map(|m| RuntimeMatcher::from_config(m, ClientLookups::default()).expect("runtime"));
+        let long = parsed.next().unwrap();
+        let shallow = parsed.next().unwrap();
+        assert!(matches!(long, RuntimeMatcher::NameLength { min: 20, max: 255 }));
+
+        // 19 / 20 字节；末尾的点不计入长度
+        assert!(!matches(&long, "abcdefghijk.example."));
+        assert!(matches(&long, "abcdefghijkl.example."));
+        assert!(matches(&long, "abcdefghijkl.example"));
+
+        assert!(matches(&shallow, "a.b.example."));
+        assert!(!matches(&shallow, "x.a.b.example"));
+        assert!(matches(&shallow, "."));
+        assert_eq!(label_count("."), 0);
+        assert_eq!(label_count("a.b.example."), 3);
+
+        let inverted = config::Matcher::LabelCount { min: 5, max: 2 };
+        assert!(RuntimeMatcher::from_config(inverted, ClientLookups::default()).is_err());
+    }
+
     #[test]
     fn response_upstream_ip_parsing_and_nonparseable() {
         let qname = "sub.example.com";
@@ -918,21 +1955,73 @@ mod tests {
         let re_cs = Regex::new("example\\.com$").unwrap();
         assert!(!RuntimeMatcher::DomainRegex { regex: re_cs }.matches(
             &qname,
+            RecordType::A,
             DNSClass::IN,
             std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-            false
+            None,
+            None
         ));
 
         // With (?i) should match
         let re_ci = Regex::new("(?i)example\\.com$").unwrap();
         assert!(RuntimeMatcher::DomainRegex { regex: re_ci }.matches(
             &qname,
+            RecordType::A,
             DNSClass::IN,
             std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
-            false
+            None,
+            None
         ));
     }
 
+    #[test]
+    fn response_truncated_and_answer_count_read_the_header() {
+        let upstream = "1.2.3.4:53";
+        let (qname, qtype, qclass) = ("example.com", RecordType::A, DNSClass::IN);
+        let mut msg = build_message(ResponseCode::NoError, false);
+        let truncated = RuntimeResponseMatcher::from_config(config::ResponseMatcher::ResponseTruncated).unwrap();
+        assert!(!truncated.matches(upstream, qname, qtype, qclass, &msg));
+        msg.set_truncated(true);
+        assert!(truncated.matches(upstream, qname, qtype, qclass, &msg));
+
+        let count = |raw: serde_json::Value| {
+            let m: config::ResponseMatcher = serde_json::from_value(raw).expect("parse");
+            RuntimeResponseMatcher::from_config(m)
+        };
+        let one_or_more = count(serde_json::json!({ "type": "response_answer_count", "min": 1 })).unwrap();
+        assert!(matches!(one_or_more, RuntimeResponseMatcher::ResponseAnswerCount { min: 1, max: 65535 }));
+        assert!(one_or_more.matches(upstream, qname, qtype, qclass, &msg));
+        assert!(!one_or_more.matches(upstream, qname, qtype, qclass, &Message::new()));
+        let empty = count(serde_json::json!({ "type": "response_answer_count", "max": 0 })).unwrap();
+        assert!(empty.matches(upstream, qname, qtype, qclass, &Message::new()));
+        msg.add_answer(msg.answers()[0].clone());
+        let at_most_one = count(serde_json::json!({ "type": "response_answer_count", "min": 1, "max": 1 })).unwrap();
+        assert!(!at_most_one.matches(upstream, qname, qtype, qclass, &msg));
+        assert!(count(serde_json::json!({ "type": "response_answer_count", "min": 3, "max": 2 })).is_err());
+    }
+
+    #[test]
+    fn response_answer_count_bounds_are_inclusive() {
+        let upstream = "1.2.3.4:53";
+        let (qname, qtype, qclass) = ("example.com", RecordType::A, DNSClass::IN);
+        let m: config::ResponseMatcher =
+            serde_json::from_value(serde_json::json!({ "type": "response_answer_count", "min": 2, "max": 4 })).unwrap();
+        let two_to_four = RuntimeResponseMatcher::from_config(m).unwrap();
+        let only_zero = RuntimeResponseMatcher::ResponseAnswerCount { min: 0, max: 0 };
+        let mut msg = Message::new();
+        let record = build_message(ResponseCode::NoError, false).answers()[0].clone();
+        // NODATA：0 条应答只命中下界为 0 的区间
+        assert!(!two_to_four.matches(upstream, qname, qtype, qclass, &msg));
+        assert!(only_zero.matches(upstream, qname, qtype, qclass, &msg));
+        let expected = [false, false, true, true, true, false];
+        for (n, want) in expected.iter().enumerate().skip(1) {
+            msg.add_answer(record.clone());
+            assert_eq!(msg.answers().len(), n);
+            assert_eq!(two_to_four.matches(upstream, qname, qtype, qclass, &msg), *want, "{n} answers");
+            assert!(!only_zero.matches(upstream, qname, qtype, qclass, &msg));
+        }
+    }
+
     #[test]
     fn response_type_no_answers_uses_qtype_fallback() {
         let mut msg = Message::new();
@@ -955,6 +2044,26 @@ mod tests {
     }
 }
 
+fn parse_record_type(v: &str) -> anyhow::Result<RecordType> {
+    let upper = v.to_ascii_uppercase();
+    RecordType::from_str(&upper).map_err(|_| anyhow::anyhow!("unsupported query type: {upper}"))
+}
+
+pub const OPCODE_QUERY: u8 = 0;
+
+fn parse_opcode(v: &str) -> anyhow::Result<u8> {
+    let lower = v.trim().to_ascii_lowercase();
+    let code = match lower.as_str() {
+        "query" => OPCODE_QUERY,
+        "iquery" => 1,
+        "status" => 2,
+        "notify" => 4,
+        "update" => 5,
+        _ => lower.parse::<u8>().ok().filter(|c| *c <= 15).with_context(|| format!("unsupported opcode: {v}"))?,
+    };
+    Ok(code)
+}
+
 fn parse_dns_class(v: &str) -> anyhow::Result<DNSClass> {
     let upper = v.to_ascii_uppercase();
     let parsed = match upper.as_str() {
@@ -0,0 +1,51 @@
+//! OTLP span 导出（仅在 otel feature 下编译）：将 engine 中的 dns_query / forward span 通过 tracing-opentelemetry 发送到采集端。
+
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing_subscriber::filter::{Filtered, Targets};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// 导出层：只接收本 crate 的 info 及以上 span，与日志过滤器相互独立
+pub type OtelLayer<S> = Filtered<tracing_opentelemetry::OpenTelemetryLayer<S, SdkTracer>, Targets, S>;
+
+pub fn layer<S>(endpoint: &str) -> anyhow::Result<OtelLayer<S>>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|err| anyhow::anyhow!("build otlp exporter for {}: {}", endpoint, err))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(opentelemetry_sdk::Resource::builder().with_service_name("kixdns").build())
+        .build();
+    let layer = layer_with_provider(&provider);
+    let _ = PROVIDER.set(provider);
+    Ok(layer)
+}
+
+pub fn layer_with_provider<S>(provider: &SdkTracerProvider) -> OtelLayer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("kixdns"))
+        .with_filter(Targets::new().with_target("kixdns", tracing::Level::INFO))
+}
+
+/// 退出前刷新尚未发送的 span
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get()
+        && let Err(err) = provider.shutdown()
+    {
+        tracing::warn!(error = %err, "otlp exporter shutdown failed");
+    }
+}
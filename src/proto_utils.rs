@@ -1,11 +1,370 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 use std::str::from_utf8;
 
+use bytes::Bytes;
+
 /// 快速解析结果，尽可能零拷贝
 pub struct QuickQuery<'a> {
     pub tx_id: u16,
     pub qname: &'a str,
     pub qtype: u16,
     pub qclass: u16,
+    /// 附加段中 OPT 伪记录（EDNS）声明的 UDP 负载大小；无 OPT 时为 None
+    pub edns_bufsize: Option<u16>,
+    /// OPT 记录中的 DO 位（客户端需要 DNSSEC 记录）
+    pub dnssec_ok: bool,
+    /// 头部 OPCODE（0 = QUERY, 1 = IQUERY, 2 = STATUS, 4 = NOTIFY, 5 = UPDATE）
+    pub opcode: u8,
+    /// QDCOUNT 大于 1：只解析了第一个问题，其余问题被忽略
+    pub multiple_questions: bool,
+    /// OPT 记录携带的 EDNS 选项个数；无 OPT 时为 0
+    pub edns_options: usize,
+}
+
+const RR_TYPE_OPT: u16 = 41;
+/// EDNS Client Subnet（RFC 7871）
+const EDNS_OPTION_ECS: u16 = 8;
+/// 无 EDNS 时的 UDP 应答上限（RFC 1035）
+pub const MIN_UDP_PAYLOAD: usize = 512;
+
+/// 跳过一个（可能被压缩的）域名，返回其后的偏移
+#[inline]
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if (len & 0xC0) == 0xC0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + (len as usize);
+    }
+}
+
+/// 从第一个问题之后开始，跳过剩余问题、应答段和授权段，在附加段中查找 OPT 记录，返回整条记录的范围。
+/// 报文在问题之后不完整时按无 EDNS 处理，不影响问题本身的解析结果。
+fn find_opt_record(packet: &[u8], mut pos: usize) -> Option<Range<usize>> {
+    let count = |off: usize| u16::from_be_bytes([packet[off], packet[off + 1]]) as usize;
+    let (qd_count, an_count, ns_count, ar_count) = (count(4), count(6), count(8), count(10));
+    if ar_count == 0 {
+        return None;
+    }
+
+    for _ in 1..qd_count {
+        pos = skip_name(packet, pos)? + 4;
+    }
+
+    for idx in 0..an_count + ns_count + ar_count {
+        let p = skip_name(packet, pos)?;
+        if p + 10 > packet.len() {
+            return None;
+        }
+        let rtype = u16::from_be_bytes([packet[p], packet[p + 1]]);
+        let rd_len = u16::from_be_bytes([packet[p + 8], packet[p + 9]]) as usize;
+        if idx >= an_count + ns_count && rtype == RR_TYPE_OPT {
+            return Some(pos..p + 10 + rd_len);
+        }
+        pos = p + 10 + rd_len;
+    }
+    None
+}
+
+/// OPT 记录 CLASS 字段声明的 UDP 负载大小（原值，不做下限修正）、DO 位与 RDATA 中的选项个数
+#[inline]
+fn scan_edns(packet: &[u8], pos: usize) -> Option<(u16, bool, usize)> {
+    let opt = find_opt_record(packet, pos)?;
+    // OPT 的 owner 为根域（1 字节），其后依次是 TYPE、CLASS（即 UDP 负载大小）、
+    // TTL（扩展 RCODE、版本、标志位，DO 为标志位最高位）、RDLENGTH
+    let fields = packet.get(opt.start + 3..opt.start + 9)?;
+    // 选项为 (CODE, LENGTH, DATA) 序列；截断在报文末尾的选项同样计数
+    let rdata_end = opt.end.min(packet.len());
+    let mut option_pos = opt.start + 11;
+    let mut options = 0;
+    while option_pos + 4 <= rdata_end {
+        let len = u16::from_be_bytes([packet[option_pos + 2], packet[option_pos + 3]]) as usize;
+        option_pos += 4 + len;
+        options += 1;
+    }
+    Some((u16::from_be_bytes([fields[0], fields[1]]), fields[4] & 0x80 != 0, options))
+}
+
+/// 客户端可接收的 UDP 应答大小：OPT 记录 CLASS 字段声明的值，不低于 512；无 EDNS 时为 512
+pub fn udp_payload_limit(request: &[u8]) -> usize {
+    if request.len() < 12 {
+        return MIN_UDP_PAYLOAD;
+    }
+    let Some(pos) = skip_name(request, 12).map(|p| p + 4) else {
+        return MIN_UDP_PAYLOAD;
+    };
+    scan_edns(request, pos).map_or(MIN_UDP_PAYLOAD, |(size, _, _)| (size as usize).max(MIN_UDP_PAYLOAD))
+}
+
+/// UDP 应答超过客户端上限时截断：置 TC 位，只保留问题段与 OPT 记录，客户端据此改用 TCP 重试。
+/// 本地构造的静态应答与上游应答共用该逻辑；TCP 应答不经过这里。
+pub fn truncate_for_udp(request: &[u8], response: Bytes) -> Bytes {
+    if response.len() <= MIN_UDP_PAYLOAD || response.len() <= udp_payload_limit(request) {
+        return response;
+    }
+    truncated_response(&response)
+}
+
+/// 第一个问题之后的 OPT 记录及其 RDATA 起始偏移；报文没有问题或 OPT 不完整时为 None
+#[inline]
+fn locate_opt(packet: &[u8]) -> Option<(Range<usize>, usize)> {
+    if packet.len() < 12 || u16::from_be_bytes([packet[4], packet[5]]) == 0 {
+        return None;
+    }
+    let pos = skip_name(packet, 12)? + 4;
+    let opt = find_opt_record(packet, pos).filter(|r| r.end <= packet.len())?;
+    let rdata_start = skip_name(packet, opt.start)? + 10;
+    Some((opt, rdata_start))
+}
+
+/// 去掉查询 OPT 记录中的 ECS 选项（option code 8），其余选项原样保留；无 ECS 时返回 None，调用方继续使用原报文
+pub fn strip_client_ecs(packet: &[u8]) -> Option<Vec<u8>> {
+    strip_edns_options(packet, &[EDNS_OPTION_ECS])
+}
+
+/// 去掉 OPT 记录中 option code 属于 codes 的选项，其余选项原样保留；一个都没去掉时返回 None
+pub fn strip_edns_options(packet: &[u8], codes: &[u16]) -> Option<Vec<u8>> {
+    let (opt, rdata_start) = locate_opt(packet)?;
+
+    let mut kept = Vec::with_capacity(opt.end - rdata_start);
+    let mut removed = false;
+    let mut p = rdata_start;
+    while p + 4 <= opt.end {
+        let code = u16::from_be_bytes([packet[p], packet[p + 1]]);
+        let len = u16::from_be_bytes([packet[p + 2], packet[p + 3]]) as usize;
+        let end = (p + 4 + len).min(opt.end);
+        if codes.contains(&code) {
+            removed = true;
+        } else {
+            kept.extend_from_slice(&packet[p..end]);
+        }
+        p = end;
+    }
+    if !removed {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(packet.len());
+    out.extend_from_slice(&packet[..rdata_start - 2]);
+    out.extend_from_slice(&(kept.len() as u16).to_be_bytes());
+    out.extend_from_slice(&kept);
+    out.extend_from_slice(&packet[opt.end..]);
+    Some(out)
+}
+
+/// 请求 ECS 选项（RFC 7871）声明的客户端地址，按 SOURCE PREFIX-LENGTH 之外的位清零；
+/// 无 OPT、无 ECS 或选项格式不合法时返回 None
+pub fn client_subnet(packet: &[u8]) -> Option<IpAddr> {
+    let data = edns_option(packet, EDNS_OPTION_ECS)?;
+    let (family, prefix, addr) = (u16::from_be_bytes([*data.first()?, *data.get(1)?]), *data.get(2)?, data.get(4..)?);
+    let max_prefix = match family {
+        1 => 32,
+        2 => 128,
+        _ => return None,
+    };
+    // 先按地址族校验前缀，地址只携带前缀覆盖的字节
+    if prefix > max_prefix || addr.len() != (prefix as usize).div_ceil(8) {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    bytes[..addr.len()].copy_from_slice(addr);
+    let ip = if family == 1 {
+        IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(bytes))
+    };
+    let net = ipnet::IpNet::new(ip, prefix).ok()?;
+    Some(net.network())
+}
+
+/// OPT 记录中第一个 option code 为 code 的选项数据
+pub fn edns_option(packet: &[u8], code: u16) -> Option<&[u8]> {
+    let (opt, rdata_start) = locate_opt(packet)?;
+    let mut p = rdata_start;
+    while p + 4 <= opt.end {
+        let len = u16::from_be_bytes([packet[p + 2], packet[p + 3]]) as usize;
+        let end = p + 4 + len;
+        if end > opt.end {
+            return None;
+        }
+        if u16::from_be_bytes([packet[p], packet[p + 1]]) == code {
+            return Some(&packet[p + 4..end]);
+        }
+        p = end;
+    }
+    None
+}
+
+/// 在应答的 OPT 记录中写入选项（替换同 code 的旧选项）；应答没有 OPT 时在附加段末尾追加一条。
+/// 应答没有问题段（无法定位附加段）时返回 None
+pub fn set_edns_option(response: &[u8], code: u16, data: &[u8]) -> Option<Vec<u8>> {
+    if response.len() < 12 || u16::from_be_bytes([response[4], response[5]]) == 0 {
+        return None;
+    }
+    let mut option = Vec::with_capacity(4 + data.len());
+    option.extend_from_slice(&code.to_be_bytes());
+    option.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    option.extend_from_slice(data);
+
+    let base = strip_edns_options(response, &[code]);
+    let base = base.as_deref().unwrap_or(response);
+    if let Some((opt, rdata_start)) = locate_opt(base) {
+        let rd_len = (opt.end - rdata_start + option.len()) as u16;
+        let mut out = Vec::with_capacity(base.len() + option.len());
+        out.extend_from_slice(&base[..rdata_start - 2]);
+        out.extend_from_slice(&rd_len.to_be_bytes());
+        out.extend_from_slice(&base[rdata_start..opt.end]);
+        out.extend_from_slice(&option);
+        out.extend_from_slice(&base[opt.end..]);
+        return Some(out);
+    }
+
+    // 根域 owner、TYPE=OPT、CLASS=UDP 负载大小、TTL=0（扩展 RCODE/版本/标志）
+    let ar_count = u16::from_be_bytes([base[10], base[11]]).checked_add(1)?;
+    let mut out = Vec::with_capacity(base.len() + 11 + option.len());
+    out.extend_from_slice(base);
+    out[10..12].copy_from_slice(&ar_count.to_be_bytes());
+    out.push(0);
+    out.extend_from_slice(&RR_TYPE_OPT.to_be_bytes());
+    out.extend_from_slice(&1232u16.to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(&(option.len() as u16).to_be_bytes());
+    out.extend_from_slice(&option);
+    Some(out)
+}
+
+/// 只保留问题段与 OPT 记录并置 TC 位的应答，客户端据此改用 TCP 重试
+pub fn truncated_response(response: &[u8]) -> Bytes {
+    let count = |off: usize| u16::from_be_bytes([response[off], response[off + 1]]);
+    // 问题段无法解析时退化为只有头部的截断应答
+    let mut question_end = 12;
+    let mut first_question_end = None;
+    for _ in 0..count(4) {
+        match skip_name(response, question_end) {
+            Some(p) if p + 4 <= response.len() => {
+                question_end = p + 4;
+                first_question_end.get_or_insert(question_end);
+            }
+            _ => {
+                question_end = 12;
+                first_question_end = None;
+                break;
+            }
+        }
+    }
+    let qd_count = if first_question_end.is_some() { count(4) } else { 0 };
+    let opt = first_question_end
+        .and_then(|pos| find_opt_record(response, pos))
+        .filter(|r| r.end <= response.len());
+
+    let mut out = Vec::with_capacity(question_end + opt.as_ref().map_or(0, |r| r.len()));
+    out.extend_from_slice(&response[..question_end]);
+    out[2] |= 0x02;
+    out[4..6].copy_from_slice(&qd_count.to_be_bytes());
+    out[6..10].fill(0);
+    out[10..12].copy_from_slice(&u16::from(opt.is_some()).to_be_bytes());
+    if let Some(opt) = opt {
+        out.extend_from_slice(&response[opt]);
+    }
+    Bytes::from(out)
+}
+
+/// 头部中的 OPCODE 位；不足 12 字节的报文返回 None
+#[inline]
+pub fn header_opcode(packet: &[u8]) -> Option<u8> {
+    (packet.len() >= 12).then(|| (packet[2] >> 3) & 0x0F)
+}
+
+/// 缓存应答中的问题名改写为当前请求的原始字节（保留请求方的大小写，含 0x20 随机化）。
+/// 两者的第一个问题名须为未压缩、大小写不敏感相同的名字，否则不做改动
+pub fn echo_question_name(request: &[u8], response: &mut [u8]) {
+    if request.len() < 12 || response.len() < 12 || request[4..6] == [0, 0] || response[4..6] == [0, 0] {
+        return;
+    }
+    let (Some(req_end), Some(resp_end)) = (skip_name(request, 12), skip_name(response, 12)) else {
+        return;
+    };
+    if req_end != resp_end || req_end > request.len() || resp_end > response.len() {
+        return;
+    }
+    let (req_name, resp_name) = (&request[12..req_end], &response[12..resp_end]);
+    // 末尾为 0 表示名字以根标签结束而不是压缩指针
+    if req_name.last() != Some(&0) || resp_name.last() != Some(&0) || !req_name.eq_ignore_ascii_case(resp_name) {
+        return;
+    }
+    response[12..resp_end].copy_from_slice(req_name);
+}
+
+/// 第一个问题名在报文中的范围（含结尾的根标签）；名字被压缩或越界时返回 None
+fn question_name_range(packet: &[u8]) -> Option<Range<usize>> {
+    if packet.len() < 12 || packet[4..6] == [0, 0] {
+        return None;
+    }
+    let mut pos = 12;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some(12..pos + 1);
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        pos += 1 + len;
+    }
+}
+
+/// DNS 0x20：按 bits 的各位把第一个问题名中的字母改为大写（1）或小写（0），bits 不足时其余字母不变；
+/// 问题名被压缩或无法解析时不做改动并返回 false
+pub fn randomize_question_case(packet: &mut [u8], bits: &[u8]) -> bool {
+    let Some(range) = question_name_range(packet) else {
+        return false;
+    };
+    for (i, b) in packet[range].iter_mut().enumerate() {
+        let Some(byte) = bits.get(i / 8) else {
+            break;
+        };
+        // 长度字节都小于 64，不会被当作字母改写
+        if b.is_ascii_alphabetic() {
+            *b = if byte >> (i % 8) & 1 == 1 { b.to_ascii_uppercase() } else { b.to_ascii_lowercase() };
+        }
+    }
+    true
+}
+
+/// 应答的第一个问题名与请求逐字节相同（区分大小写），用于校验 0x20 随机化
+pub fn question_case_matches(request: &[u8], response: &[u8]) -> bool {
+    match (question_name_range(request), question_name_range(response)) {
+        (Some(req), Some(resp)) => request[req] == response[resp],
+        _ => false,
+    }
+}
+
+/// 由请求直接构造的错误应答：回显 ID、OPCODE、RD 位与第一个问题，不含其他记录段。
+/// 问题段无法解析时只返回头部（如 QDCOUNT 为 0 的 IQUERY）
+pub fn error_response(request: &[u8], rcode: u8) -> Option<Bytes> {
+    if request.len() < 12 {
+        return None;
+    }
+    let qd_count = u16::from_be_bytes([request[4], request[5]]);
+    let question_end = (qd_count > 0)
+        .then(|| skip_name(request, 12))
+        .flatten()
+        .map(|p| p + 4)
+        .filter(|&end| end <= request.len())
+        .unwrap_or(12);
+    let mut out = Vec::with_capacity(question_end);
+    out.extend_from_slice(&request[..question_end]);
+    // QR=1，保留 OPCODE 与 RD；AA/TC/RA/Z 清零
+    out[2] = 0x80 | (request[2] & 0x79);
+    out[3] = rcode & 0x0F;
+    out[4..6].copy_from_slice(&u16::from(question_end > 12).to_be_bytes());
+    out[6..12].fill(0);
+    Some(Bytes::from(out))
 }
 
 /// 仅解析 DNS 头部和第一个 Query，用于快速缓存查找
@@ -104,6 +463,9 @@ pub fn parse_quick<'a>(packet: &[u8], buf: &'a mut [u8]) -> Option<QuickQuery<'a
     let qtype = u16::from_be_bytes([packet[pos], packet[pos + 1]]);
     let qclass = u16::from_be_bytes([packet[pos + 2], packet[pos + 3]]);
 
+    // 5. EDNS: walk to the additional section looking for OPT
+    let edns = scan_edns(packet, pos + 4);
+
     // Return slice of buf
     let qname = from_utf8(&buf[..buf_pos]).ok()?;
 
@@ -112,9 +474,46 @@ pub fn parse_quick<'a>(packet: &[u8], buf: &'a mut [u8]) -> Option<QuickQuery<'a
         qname,
         qtype,
         qclass,
+        edns_bufsize: edns.map(|(size, _, _)| size),
+        dnssec_ok: edns.is_some_and(|(_, dnssec_ok, _)| dnssec_ok),
+        opcode: (packet[2] >> 3) & 0x0F,
+        multiple_questions: qd_count > 1,
+        edns_options: edns.map_or(0, |(_, _, options)| options),
     })
 }
 
+/// RFC 2308：NXDOMAIN/NODATA 的负缓存 TTL 取授权段 SOA 的 min(TTL, MINIMUM)
+fn negative_ttl_quick(packet: &[u8], qd_count: u16, ns_count: u16) -> Option<u32> {
+    let mut pos = 12;
+    for _ in 0..qd_count {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    for _ in 0..ns_count {
+        pos = skip_name(packet, pos)?;
+        if pos + 10 > packet.len() {
+            return None;
+        }
+        let rtype = u16::from_be_bytes([packet[pos], packet[pos + 1]]);
+        let ttl = u32::from_be_bytes([packet[pos + 4], packet[pos + 5], packet[pos + 6], packet[pos + 7]]);
+        let rd_len = u16::from_be_bytes([packet[pos + 8], packet[pos + 9]]) as usize;
+        let rd_end = pos + 10 + rd_len;
+        if rd_end > packet.len() {
+            return None;
+        }
+        if rtype == RR_TYPE_SOA {
+            // MINIMUM 固定为 RDATA 最后 4 字节（MNAME/RNAME 可能被压缩，无需解析）
+            if rd_len < 22 {
+                return None;
+            }
+            let m = rd_end - 4;
+            let minimum = u32::from_be_bytes([packet[m], packet[m + 1], packet[m + 2], packet[m + 3]]);
+            return Some(ttl.min(minimum));
+        }
+        pos = rd_end;
+    }
+    None
+}
+
 /// 快速解析响应包，仅提取 RCODE 和最小 TTL
 /// 避免全量解析 Message
 pub struct QuickResponse {
@@ -122,7 +521,10 @@ pub struct QuickResponse {
     pub min_ttl: u32,
 }
 
-pub fn parse_response_quick(packet: &[u8]) -> Option<QuickResponse> {
+const RR_TYPE_SOA: u16 = 6;
+
+/// max_negative_ttl: 无应答记录时由授权段 SOA 推导的负缓存 TTL 上限
+pub fn parse_response_quick(packet: &[u8], max_negative_ttl: u32) -> Option<QuickResponse> {
     if packet.len() < 12 {
         return None;
     }
@@ -136,11 +538,17 @@ pub fn parse_response_quick(packet: &[u8]) -> Option<QuickResponse> {
     // 2. Counts
     let qd_count = u16::from_be_bytes([packet[4], packet[5]]);
     let an_count = u16::from_be_bytes([packet[6], packet[7]]);
-    // We don't strictly need NS and AR counts for TTL, but we iterate through them if we want to be thorough.
-    // For caching, we usually care about Answer section TTLs.
-    
+    let ns_count = u16::from_be_bytes([packet[8], packet[9]]);
+    // We don't strictly need AR count for TTL.
+    // For caching, we usually care about Answer section TTLs; negative answers use the authority SOA.
+
     if an_count == 0 {
-        return Some(QuickResponse { rcode, min_ttl: 0 });
+        let min_ttl = if ns_count == 0 {
+            0
+        } else {
+            negative_ttl_quick(packet, qd_count, ns_count).map_or(0, |ttl| ttl.min(max_negative_ttl))
+        };
+        return Some(QuickResponse { rcode, min_ttl });
     }
 
     let mut pos = 12;
@@ -208,3 +616,254 @@ pub fn parse_response_quick(packet: &[u8]) -> Option<QuickResponse> {
 
     Some(QuickResponse { rcode, min_ttl })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Edns, Message, Query};
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    fn query(edns: bool) -> Message {
+        let mut msg = Message::new();
+        msg.set_id(7);
+        msg.add_query(Query::query(Name::from_str("Example.COM.").unwrap(), RecordType::AAAA));
+        if edns {
+            let mut e = Edns::new();
+            e.set_max_payload(1232);
+            e.set_dnssec_ok(true);
+            msg.set_edns(e);
+        }
+        msg
+    }
+
+    #[test]
+    fn parse_quick_detects_opt_record() {
+        let mut buf = [0u8; 256];
+        let packet = query(true).to_vec().unwrap();
+        let q = parse_quick(&packet, &mut buf).expect("parse");
+        assert_eq!(q.qname, "example.com");
+        assert_eq!(q.qtype, u16::from(RecordType::AAAA));
+        assert_eq!(q.edns_bufsize, Some(1232));
+        assert!(q.dnssec_ok);
+
+        let packet = query(false).to_vec().unwrap();
+        assert_eq!(parse_quick(&packet, &mut buf).expect("parse").edns_bufsize, None);
+    }
+
+    #[test]
+    fn question_case_randomization_round_trips() {
+        let original = query(false).to_vec().unwrap();
+        let mut randomized = original.clone();
+        assert!(randomize_question_case(&mut randomized, &[0xFF; 32]));
+        let mut buf = [0u8; 256];
+        assert_eq!(parse_quick(&randomized, &mut buf).expect("parse").qname, "example.com");
+        assert_eq!(&randomized[12..25], b"\x07EXAMPLE\x03COM\x00");
+        assert!(randomize_question_case(&mut randomized, &[0x00; 32]));
+        assert_eq!(&randomized[12..25], b"\x07example\x03com\x00");
+        // 只有与请求逐字节相同的问题名才算回显
+        let mut upper = randomized.clone();
+        assert!(randomize_question_case(&mut upper, &[0xFF; 32]));
+        assert!(question_case_matches(&randomized, &randomized));
+        assert!(!question_case_matches(&randomized, &upper));
+
+        let mut header_only = original[..12].to_vec();
+        header_only[4..6].fill(0);
+        assert!(!randomize_question_case(&mut header_only, &[0xFF; 32]));
+    }
+
+    #[test]
+    fn error_response_echoes_opcode_and_question() {
+        let mut buf = [0u8; 256];
+        let mut update = query(true).to_vec().unwrap();
+        update[2] = (5 << 3) | 0x01; // UPDATE + RD
+        assert_eq!(parse_quick(&update, &mut buf).expect("parse").opcode, 5);
+        assert_eq!(header_opcode(&update), Some(5));
+
+        let resp = error_response(&update, 4).expect("response");
+        let msg = Message::from_vec(&resp).expect("decode");
+        assert_eq!(msg.id(), 7);
+        assert_eq!(msg.op_code(), hickory_proto::op::OpCode::Update);
+        assert_eq!(msg.response_code(), hickory_proto::op::ResponseCode::NotImp);
+        assert!(msg.recursion_desired());
+        assert_eq!(msg.queries().len(), 1);
+        assert!(msg.additionals().is_empty() && msg.extensions().is_none());
+
+        // 不带问题段的 IQUERY 只回头部
+        let mut iquery = vec![0u8; 12];
+        iquery[0..2].copy_from_slice(&9u16.to_be_bytes());
+        iquery[2] = 1 << 3;
+        assert!(parse_quick(&iquery, &mut buf).is_none());
+        let resp = error_response(&iquery, 4).expect("response");
+        assert_eq!(resp.len(), 12);
+        assert_eq!(&resp[..4], &[0, 9, 0x80 | (1 << 3), 4]);
+        assert_eq!(header_opcode(&resp[..4]), None);
+    }
+
+    fn nxdomain_with_soa(soa_ttl: u32, minimum: u32) -> Message {
+        use hickory_proto::op::{MessageType, ResponseCode};
+        use hickory_proto::rr::rdata::SOA;
+
+        let mut msg = query(false);
+        msg.set_message_type(MessageType::Response);
+        msg.set_response_code(ResponseCode::NXDomain);
+        let zone = Name::from_str("example.com.").unwrap();
+        let soa = SOA::new(
+            Name::from_str("ns1.example.com.").unwrap(),
+            Name::from_str("hostmaster.example.com.").unwrap(),
+            2024010101,
+            7200,
+            3600,
+            1209600,
+            minimum,
+        );
+        msg.add_name_server(Record::from_rdata(zone, soa_ttl, RData::SOA(soa)));
+        msg
+    }
+
+    #[test]
+    fn parse_response_quick_uses_soa_for_negative_ttl() {
+        use hickory_proto::op::ResponseCode;
+
+        let packet = nxdomain_with_soa(600, 120).to_vec().unwrap();
+        let qr = parse_response_quick(&packet, 3600).expect("parse");
+        assert_eq!(qr.rcode, ResponseCode::NXDomain);
+        assert_eq!(qr.min_ttl, 120);
+
+        let packet = nxdomain_with_soa(90, 900).to_vec().unwrap();
+        assert_eq!(parse_response_quick(&packet, 3600).unwrap().min_ttl, 90);
+        assert_eq!(parse_response_quick(&packet, 30).unwrap().min_ttl, 30);
+
+        // No SOA in authority: nothing to derive a negative TTL from.
+        let packet = query(false).to_vec().unwrap();
+        assert_eq!(parse_response_quick(&packet, 3600).unwrap().min_ttl, 0);
+    }
+
+    #[test]
+    fn parse_quick_skips_answer_and_authority_before_additional() {
+        let mut msg = query(true);
+        let name = Name::from_str("example.com.").unwrap();
+        msg.add_answer(Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 1)))));
+        msg.add_name_server(Record::from_rdata(name.clone(), 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 2)))));
+        msg.add_additional(Record::from_rdata(name, 60, RData::A(A(Ipv4Addr::new(192, 0, 2, 3)))));
+        let packet = msg.to_vec().unwrap();
+        let mut buf = [0u8; 256];
+        assert_eq!(parse_quick(&packet, &mut buf).expect("parse").edns_bufsize, Some(1232));
+
+        // Truncated trailing data still yields the question, just without EDNS.
+        let truncated = &packet[..packet.len() - 5];
+        let q = parse_quick(truncated, &mut buf).expect("parse truncated");
+        assert_eq!(q.qname, "example.com");
+        assert_eq!(q.edns_bufsize, None);
+    }
+
+    #[test]
+    fn strip_client_ecs_removes_only_the_subnet_option() {
+        use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+        let mut msg = query(true);
+        let mut edns = msg.extensions().clone().unwrap();
+        edns.options_mut().insert(EdnsOption::Subnet(ClientSubnet::new("198.51.100.7".parse().unwrap(), 24, 0)));
+        edns.options_mut().insert(EdnsOption::Unknown(10, vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        msg.set_edns(edns);
+        let packet = msg.to_vec().unwrap();
+
+        let stripped = strip_client_ecs(&packet).expect("ecs removed");
+        let parsed = Message::from_vec(&stripped).expect("valid message");
+        let edns = parsed.extensions().as_ref().expect("opt kept");
+        assert!(edns.option(EdnsCode::Subnet).is_none());
+        assert!(edns.option(EdnsCode::Cookie).is_some());
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.dnssec_ok());
+        assert_eq!(parsed.queries(), msg.queries());
+
+        assert!(strip_client_ecs(&stripped).is_none());
+        assert!(strip_client_ecs(&query(false).to_vec().unwrap()).is_none());
+    }
+
+    #[test]
+    fn client_subnet_reads_the_claimed_address() {
+        use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsOption};
+        let with_option = |option: EdnsOption| {
+            let mut msg = query(true);
+            let mut edns = msg.extensions().clone().unwrap();
+            edns.options_mut().insert(option);
+            msg.set_edns(edns);
+            msg.to_vec().unwrap()
+        };
+        let v4 = with_option(EdnsOption::Subnet(ClientSubnet::new("198.51.100.7".parse().unwrap(), 24, 0)));
+        assert_eq!(client_subnet(&v4), Some("198.51.100.0".parse().unwrap()));
+        let v6 = with_option(EdnsOption::Subnet(ClientSubnet::new("2001:db8:1:2::5".parse().unwrap(), 48, 0)));
+        assert_eq!(client_subnet(&v6), Some("2001:db8:1::".parse().unwrap()));
+
+        assert!(client_subnet(&query(false).to_vec().unwrap()).is_none());
+        assert!(client_subnet(&query(true).to_vec().unwrap()).is_none());
+        // 地址字节数与前缀长度不符、地址族未知
+        assert!(client_subnet(&with_option(EdnsOption::Unknown(8, vec![0, 1, 24, 0, 198, 51]))).is_none());
+        assert!(client_subnet(&with_option(EdnsOption::Unknown(8, vec![0, 3, 8, 0, 10]))).is_none());
+        // 超出地址族的前缀：200 位前缀带 25 个地址字节，不得越界
+        let mut oversized = vec![0, 2, 200, 0];
+        oversized.extend_from_slice(&[0xab; 25]);
+        assert!(client_subnet(&with_option(EdnsOption::Unknown(8, oversized))).is_none());
+        assert!(client_subnet(&with_option(EdnsOption::Unknown(8, vec![0, 1, 40, 0, 10, 0, 0, 1, 2]))).is_none());
+    }
+
+    #[test]
+    fn set_edns_option_replaces_or_adds_the_option() {
+        use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+        let mut msg = query(true);
+        let mut edns = msg.extensions().clone().unwrap();
+        edns.options_mut().insert(EdnsOption::Unknown(10, vec![1; 8]));
+        msg.set_edns(edns);
+        let packet = msg.to_vec().unwrap();
+        assert_eq!(edns_option(&packet, 10), Some(&[1u8; 8][..]));
+        assert_eq!(edns_option(&packet, 8), None);
+
+        let replaced = set_edns_option(&packet, 10, &[2; 24]).expect("opt present");
+        assert_eq!(edns_option(&replaced, 10), Some(&[2u8; 24][..]));
+        let parsed = Message::from_vec(&replaced).expect("valid message");
+        let edns = parsed.extensions().as_ref().expect("opt kept");
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.option(EdnsCode::Cookie), Some(&EdnsOption::Unknown(10, vec![2; 24])));
+
+        // 没有 OPT 的应答追加一条
+        let plain = query(false).to_vec().unwrap();
+        let added = set_edns_option(&plain, 10, &[3; 16]).expect("opt appended");
+        let parsed = Message::from_vec(&added).expect("valid message");
+        assert_eq!(parsed.additional_count(), 1);
+        assert_eq!(parsed.extensions().as_ref().unwrap().option(EdnsCode::Cookie), Some(&EdnsOption::Unknown(10, vec![3; 16])));
+        assert_eq!(parsed.queries(), query(false).queries());
+    }
+
+    #[test]
+    fn oversized_udp_response_keeps_question_and_opt() {
+        let request = query(true).to_vec().unwrap();
+        assert_eq!(udp_payload_limit(&request), 1232);
+        assert_eq!(udp_payload_limit(&query(false).to_vec().unwrap()), MIN_UDP_PAYLOAD);
+
+        let mut resp = query(true);
+        resp.set_message_type(hickory_proto::op::MessageType::Response);
+        for i in 0..120u8 {
+            resp.add_answer(Record::from_rdata(
+                Name::from_str("example.com.").unwrap(),
+                60,
+                RData::A(A(Ipv4Addr::new(192, 0, 2, i))),
+            ));
+        }
+        let full = Bytes::from(resp.to_vec().unwrap());
+        assert!(full.len() > 1232);
+
+        let cut = truncate_for_udp(&request, full.clone());
+        let msg = Message::from_vec(&cut).expect("truncated response parses");
+        assert!(msg.truncated());
+        assert_eq!(msg.id(), 7);
+        assert_eq!(msg.queries().len(), 1);
+        assert!(msg.answers().is_empty());
+        assert_eq!(msg.extensions().as_ref().map(|e| e.max_payload()), Some(1232));
+
+        // 未超过上限的应答原样返回
+        let small = Bytes::from(query(true).to_vec().unwrap());
+        assert_eq!(truncate_for_udp(&request, small.clone()), small);
+    }
+}
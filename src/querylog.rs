@@ -0,0 +1,201 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::RecordType;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// 写入任务积压的最大条目数，超出后新条目直接丢弃
+const QUERY_LOG_CAPACITY: usize = 8192;
+/// 缓冲区定时刷盘间隔
+const QUERY_LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 一条查询日志，对应 engine 中一次 dns_response 事件
+#[derive(Debug, Clone)]
+pub struct QueryLogEntry {
+    pub client_ip: IpAddr,
+    pub qname: String,
+    pub qtype: RecordType,
+    pub rcode: ResponseCode,
+    pub upstream: String,
+    pub cache_hit: bool,
+    pub latency_ms: u64,
+}
+
+enum Msg {
+    Entry { timestamp_ms: u64, entry: QueryLogEntry },
+    Flush(oneshot::Sender<()>),
+}
+
+/// 追加写入的 JSONL 查询日志：热路径只做一次 try_send，序列化与写文件在后台任务中完成；
+/// 通道满时丢弃并计数，从不阻塞查询处理。
+#[derive(Clone)]
+pub struct QueryLog {
+    tx: mpsc::Sender<Msg>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::open_with(path, QUERY_LOG_CAPACITY, QUERY_LOG_FLUSH_INTERVAL)
+    }
+
+    pub fn open_with(path: impl AsRef<Path>, capacity: usize, flush_interval: Duration) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        // 同步打开，路径错误在启动时即可报出
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open query log: {}", path.display()))?;
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        tokio::spawn(run_writer(tokio::fs::File::from_std(file), rx, flush_interval));
+        Ok(Self {
+            tx,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    #[inline]
+    pub fn record(&self, entry: QueryLogEntry) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(Msg::Entry { timestamp_ms, entry }) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 因积压而丢弃的条目数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 等待此前已入队的条目全部写入文件（用于退出前收尾）
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(Msg::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+async fn run_writer(file: tokio::fs::File, mut rx: mpsc::Receiver<Msg>, flush_interval: Duration) {
+    let mut out = BufWriter::new(file);
+    let mut ticker = tokio::time::interval(flush_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut dirty = false;
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Msg::Entry { timestamp_ms, entry }) => {
+                    let mut line = format_line(timestamp_ms, &entry);
+                    line.push('\n');
+                    if let Err(err) = out.write_all(line.as_bytes()).await {
+                        warn!(error = %err, "query log write failed");
+                    }
+                    dirty = true;
+                }
+                Some(Msg::Flush(done)) => {
+                    if let Err(err) = out.flush().await {
+                        warn!(error = %err, "query log flush failed");
+                    }
+                    dirty = false;
+                    let _ = done.send(());
+                }
+                None => break,
+            },
+            _ = ticker.tick(), if dirty => {
+                if let Err(err) = out.flush().await {
+                    warn!(error = %err, "query log flush failed");
+                }
+                dirty = false;
+            }
+        }
+    }
+    let _ = out.flush().await;
+}
+
+fn format_line(timestamp_ms: u64, entry: &QueryLogEntry) -> String {
+    serde_json::json!({
+        "timestamp": timestamp_ms,
+        "client_ip": entry.client_ip.to_string(),
+        "qname": entry.qname,
+        "qtype": format!("{:?}", entry.qtype),
+        "rcode": format!("{:?}", entry.rcode),
+        "upstream": entry.upstream,
+        "cache_hit": entry.cache_hit,
+        "latency_ms": entry.latency_ms,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(qname: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            client_ip: "192.0.2.7".parse().unwrap(),
+            qname: qname.to_string(),
+            qtype: RecordType::AAAA,
+            rcode: ResponseCode::NXDomain,
+            upstream: "1.1.1.1:53".to_string(),
+            cache_hit: true,
+            latency_ms: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_as_json_lines_and_flushed_on_timer() {
+        let path = std::env::temp_dir().join(format!("kixdns-querylog-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = QueryLog::open_with(&path, 16, Duration::from_millis(20)).expect("open");
+        log.record(entry("a.example.com"));
+        log.record(entry("b.example.com"));
+
+        // 不显式 flush，等待定时刷盘
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        let raw = loop {
+            let raw = std::fs::read_to_string(&path).unwrap_or_default();
+            if raw.lines().count() == 2 || std::time::Instant::now() > deadline {
+                break raw;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let lines: Vec<serde_json::Value> = raw.lines().map(|l| serde_json::from_str(l).expect("json line")).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["qname"], "a.example.com");
+        assert_eq!(lines[1]["qname"], "b.example.com");
+        assert_eq!(lines[0]["client_ip"], "192.0.2.7");
+        assert_eq!(lines[0]["qtype"], "AAAA");
+        assert_eq!(lines[0]["rcode"], "NXDomain");
+        assert_eq!(lines[0]["upstream"], "1.1.1.1:53");
+        assert_eq!(lines[0]["cache_hit"], true);
+        assert_eq!(lines[0]["latency_ms"], 3);
+        assert!(lines[0]["timestamp"].as_u64().unwrap() > 0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn full_channel_drops_and_counts() {
+        let path = std::env::temp_dir().join(format!("kixdns-querylog-drop-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // 单线程运行时下写入任务在本测试让出前不会运行，通道必然写满
+        let log = QueryLog::open_with(&path, 4, QUERY_LOG_FLUSH_INTERVAL).expect("open");
+        for i in 0..10 {
+            log.record(entry(&format!("q{i}.example.com")));
+        }
+        assert_eq!(log.dropped(), 6);
+        log.flush().await;
+        let raw = std::fs::read_to_string(&path).expect("read");
+        assert_eq!(raw.lines().count(), 4);
+        let _ = std::fs::remove_file(&path);
+    }
+}
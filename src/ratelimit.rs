@@ -0,0 +1,459 @@
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use moka::Expiry;
+use moka::ops::compute::Op;
+use moka::sync::Cache;
+use rustc_hash::FxHasher;
+
+/// 单个客户端的令牌桶，按 Instant 惰性补充
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+    // 最近一次使用的参数，清理时据此判断桶是否已补满
+    max_qps: u32,
+    burst: u32,
+}
+
+impl TokenBucket {
+    fn new(burst: u32, now: Instant) -> Self {
+        Self {
+            tokens: burst as f64,
+            last: now,
+            max_qps: 0,
+            burst,
+        }
+    }
+
+    /// 先按经过时间补充令牌（不超过 burst），再尝试消耗一个
+    fn try_take(&mut self, max_qps: u32, burst: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * max_qps as f64).min(burst as f64);
+        self.last = now;
+        self.max_qps = max_qps;
+        self.burst = burst;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 到 now 为止是否已补满：补满的桶与新建的桶等价
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.max_qps as f64 >= self.burst as f64
+    }
+}
+
+/// 按 client_ip 限速；同一客户端共享一个桶，参数取自命中的 rate_limit 规则
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 返回 true 表示放行（已消耗一个令牌）
+    #[inline]
+    pub fn check(&self, ip: IpAddr, max_qps: u32, burst: u32) -> bool {
+        self.check_at(ip, max_qps, burst, Instant::now())
+    }
+
+    pub fn check_at(&self, ip: IpAddr, max_qps: u32, burst: u32, now: Instant) -> bool {
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(burst, now))
+            .try_take(max_qps, burst, now)
+    }
+
+    /// 清理超过 idle 未访问且已补满的桶，删除不影响限速结果；max_qps 很低时闲置的桶可能仍未补满，留待之后清理
+    pub fn prune(&self, idle: Duration) {
+        self.prune_at(idle, Instant::now());
+    }
+
+    pub fn prune_at(&self, idle: Duration, now: Instant) {
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.last) < idle || !b.is_full_at(now));
+    }
+
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+/// RRL 计数窗口长度
+const RRL_WINDOW: Duration = Duration::from_secs(1);
+
+/// 滑动窗口计数：用上一窗口的计数按剩余比例加权近似最近 1 秒的应答数
+#[derive(Debug, Clone, Copy)]
+struct SlidingWindow {
+    start: Instant,
+    current: u32,
+    previous: u32,
+    /// 超限后的应答序号，用于 slip（每 N 个改为截断应答）
+    limited: u32,
+}
+
+impl SlidingWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            current: 0,
+            previous: 0,
+            limited: 0,
+        }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= RRL_WINDOW * 2 {
+            self.previous = 0;
+            self.current = 0;
+            self.start = now;
+        } else if elapsed >= RRL_WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.start += RRL_WINDOW;
+        }
+    }
+
+    fn estimate(&self, now: Instant) -> f64 {
+        let frac = now.saturating_duration_since(self.start).as_secs_f64() / RRL_WINDOW.as_secs_f64();
+        self.previous as f64 * (1.0 - frac.min(1.0)) + self.current as f64
+    }
+}
+
+/// RRL 对一条 UDP 应答的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlVerdict {
+    Allow,
+    /// 不发送应答
+    Drop,
+    /// 以 TC=1 的截断应答代替，合法客户端可改用 TCP 取得结果
+    Truncate,
+}
+
+/// 响应限速（RRL）：按 (客户端前缀, qname, qtype, rcode) 统计相同应答的速率，键为元组的哈希；
+/// DashMap 内部分片，不同元组之间几乎没有锁竞争
+#[derive(Debug, Default)]
+pub struct ResponseRateLimiter {
+    windows: DashMap<u64, SlidingWindow>,
+}
+
+impl ResponseRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 客户端前缀：IPv4 取 /24，IPv6 取 /56
+    pub fn key(client_ip: IpAddr, qname: &str, qtype: u16, rcode: u8) -> u64 {
+        let prefix = match client_ip {
+            IpAddr::V4(ip) => {
+                let o = ip.octets();
+                IpAddr::from([o[0], o[1], o[2], 0])
+            }
+            IpAddr::V6(ip) => {
+                let mut o = ip.octets();
+                o[7..].fill(0);
+                IpAddr::from(o)
+            }
+        };
+        let mut h = FxHasher::default();
+        prefix.hash(&mut h);
+        qname.trim_end_matches('.').to_ascii_lowercase().hash(&mut h);
+        qtype.hash(&mut h);
+        rcode.hash(&mut h);
+        h.finish()
+    }
+
+    /// responses_per_second 为每个元组每秒允许的应答数；slip 为 N 时超限应答每 N 个有一个改为截断，0 表示全部丢弃
+    #[inline]
+    pub fn check(&self, key: u64, responses_per_second: u32, slip: u32) -> RrlVerdict {
+        self.check_at(key, responses_per_second, slip, Instant::now())
+    }
+
+    pub fn check_at(&self, key: u64, responses_per_second: u32, slip: u32, now: Instant) -> RrlVerdict {
+        let mut window = self.windows.entry(key).or_insert_with(|| SlidingWindow::new(now));
+        window.advance(now);
+        if window.estimate(now) < responses_per_second as f64 {
+            window.current += 1;
+            return RrlVerdict::Allow;
+        }
+        window.limited = window.limited.wrapping_add(1);
+        if slip > 0 && window.limited.is_multiple_of(slip) {
+            RrlVerdict::Truncate
+        } else {
+            RrlVerdict::Drop
+        }
+    }
+
+    /// 清理超过 idle 未更新的窗口；两个窗口长度之后计数必然归零，删除不影响结果
+    pub fn prune(&self, idle: Duration) {
+        self.prune_at(idle, Instant::now());
+    }
+
+    pub fn prune_at(&self, idle: Duration, now: Instant) {
+        let idle = idle.max(RRL_WINDOW * 2);
+        self.windows.retain(|_, w| now.saturating_duration_since(w.start) < idle);
+    }
+
+    #[allow(dead_code)]
+    pub fn window_count(&self) -> usize {
+        self.windows.len()
+    }
+}
+
+/// 一个 (客户端, 域名) 在当前窗口内的拦截次数
+#[derive(Debug, Clone, Copy)]
+struct BlockWindow {
+    expires: Instant,
+    count: u32,
+}
+
+/// 计数窗口随自身的 expires 过期
+struct BlockWindowExpiry;
+
+impl Expiry<u64, BlockWindow> for BlockWindowExpiry {
+    fn expire_after_create(&self, _key: &u64, value: &BlockWindow, created_at: Instant) -> Option<Duration> {
+        Some(value.expires.saturating_duration_since(created_at))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &u64,
+        value: &BlockWindow,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.expires.saturating_duration_since(updated_at))
+    }
+}
+
+/// 同时计数的 (客户端, 域名) 窗口上限；伪造来源的 UDP 查询最多占用这么多条目
+const BLOCK_WINDOW_CAPACITY: u64 = 100_000;
+
+/// settings.block_escalation 的状态：按 (客户端, 域名) 计数拦截应答的固定窗口（有容量上限，到期自动移除），
+/// 以及升级后暂时封禁的客户端（由限速清理任务定期删除）
+pub struct BlockEscalator {
+    windows: Cache<u64, BlockWindow>,
+    denied: DashMap<IpAddr, Instant>,
+}
+
+impl Default for BlockEscalator {
+    fn default() -> Self {
+        Self::with_capacity(BLOCK_WINDOW_CAPACITY)
+    }
+}
+
+impl BlockEscalator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(max_windows: u64) -> Self {
+        Self {
+            windows: Cache::builder().max_capacity(max_windows).expire_after(BlockWindowExpiry).build(),
+            denied: DashMap::new(),
+        }
+    }
+
+    pub fn key(client_ip: IpAddr, qname: &str) -> u64 {
+        let mut h = FxHasher::default();
+        client_ip.hash(&mut h);
+        qname.trim_end_matches('.').to_ascii_lowercase().hash(&mut h);
+        h.finish()
+    }
+
+    /// 记录一次拦截，返回当前窗口内的累计次数（含本次）
+    #[inline]
+    pub fn record(&self, key: u64, window: Duration) -> u32 {
+        self.record_at(key, window, Instant::now())
+    }
+
+    pub fn record_at(&self, key: u64, window: Duration, now: Instant) -> u32 {
+        let mut count = 0;
+        self.windows.entry(key).and_compute_with(|entry| {
+            let w = match entry.map(|e| e.into_value()) {
+                Some(w) if now < w.expires => w,
+                _ => BlockWindow { expires: now + window, count: 0 },
+            };
+            count = w.count.saturating_add(1);
+            Op::Put(BlockWindow { count, ..w })
+        });
+        count
+    }
+
+    /// 当前窗口内已记录的次数，不计入本次
+    #[inline]
+    pub fn count(&self, key: u64) -> u32 {
+        self.count_at(key, Instant::now())
+    }
+
+    pub fn count_at(&self, key: u64, now: Instant) -> u32 {
+        self.windows.get(&key).filter(|w| now < w.expires).map_or(0, |w| w.count)
+    }
+
+    pub fn deny(&self, client_ip: IpAddr, duration: Duration) {
+        self.deny_at(client_ip, duration, Instant::now());
+    }
+
+    pub fn deny_at(&self, client_ip: IpAddr, duration: Duration, now: Instant) {
+        self.denied.insert(client_ip, now + duration);
+    }
+
+    /// 客户端是否处于封禁期；没有任何封禁时只检查 map 是否为空
+    #[inline]
+    pub fn is_denied(&self, client_ip: IpAddr) -> bool {
+        self.is_denied_at(client_ip, Instant::now())
+    }
+
+    pub fn is_denied_at(&self, client_ip: IpAddr, now: Instant) -> bool {
+        !self.denied.is_empty() && self.denied.get(&client_ip).is_some_and(|until| now < *until)
+    }
+
+    /// 删除已过期的封禁；计数窗口由缓存自行过期
+    pub fn prune(&self) {
+        self.prune_at(Instant::now());
+    }
+
+    pub fn prune_at(&self, now: Instant) {
+        self.windows.run_pending_tasks();
+        self.denied.retain(|_, until| now < *until);
+    }
+
+    #[allow(dead_code)]
+    pub fn tracked(&self) -> usize {
+        self.windows.run_pending_tasks();
+        self.windows.entry_count() as usize + self.denied.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limiter = RateLimiter::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, 2, 3, t0));
+        }
+        assert!(!limiter.check_at(ip, 2, 3, t0));
+        // 2 qps: 500ms 补充一个令牌
+        assert!(limiter.check_at(ip, 2, 3, t0 + Duration::from_millis(500)));
+        assert!(!limiter.check_at(ip, 2, 3, t0 + Duration::from_millis(500)));
+        // 其他客户端不受影响
+        assert!(limiter.check_at("192.0.2.2".parse().unwrap(), 2, 3, t0));
+    }
+
+    #[test]
+    fn prune_drops_idle_buckets() {
+        let limiter = RateLimiter::new();
+        let t0 = Instant::now();
+        limiter.check_at("192.0.2.1".parse().unwrap(), 1, 1, t0);
+        limiter.check_at("192.0.2.2".parse().unwrap(), 1, 1, t0 + Duration::from_secs(50));
+        limiter.prune_at(Duration::from_secs(30), t0 + Duration::from_secs(60));
+        assert_eq!(limiter.bucket_count(), 1);
+    }
+
+    #[test]
+    fn prune_keeps_idle_buckets_that_have_not_refilled() {
+        let limiter = RateLimiter::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let t0 = Instant::now();
+        // 1 qps、burst 100：耗尽后要 100 秒才补满
+        for _ in 0..100 {
+            assert!(limiter.check_at(ip, 1, 100, t0));
+        }
+        limiter.prune_at(Duration::from_secs(30), t0 + Duration::from_secs(60));
+        assert_eq!(limiter.bucket_count(), 1);
+        // 清理后仍按剩余令牌限速，而不是重新给满 burst
+        for _ in 0..60 {
+            assert!(limiter.check_at(ip, 1, 100, t0 + Duration::from_secs(60)));
+        }
+        assert!(!limiter.check_at(ip, 1, 100, t0 + Duration::from_secs(60)));
+
+        limiter.prune_at(Duration::from_secs(30), t0 + Duration::from_secs(200));
+        assert_eq!(limiter.bucket_count(), 0);
+    }
+
+    #[test]
+    fn rrl_limits_identical_responses_per_prefix() {
+        let rrl = ResponseRateLimiter::new();
+        let key = ResponseRateLimiter::key("192.0.2.10".parse().unwrap(), "Amp.Example.com.", 255, 0);
+        // 同一 /24 内的其他地址共享计数；不同 qtype 或 rcode 互不影响
+        assert_eq!(key, ResponseRateLimiter::key("192.0.2.200".parse().unwrap(), "amp.example.com", 255, 0));
+        assert_ne!(key, ResponseRateLimiter::key("192.0.3.10".parse().unwrap(), "amp.example.com", 255, 0));
+        assert_ne!(key, ResponseRateLimiter::key("192.0.2.10".parse().unwrap(), "amp.example.com", 1, 0));
+
+        let t0 = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(rrl.check_at(key, 5, 2, t0), RrlVerdict::Allow);
+        }
+        // slip=2：超限应答交替丢弃与截断
+        let verdicts: Vec<RrlVerdict> = (0..4).map(|_| rrl.check_at(key, 5, 2, t0)).collect();
+        assert_eq!(verdicts, [RrlVerdict::Drop, RrlVerdict::Truncate, RrlVerdict::Drop, RrlVerdict::Truncate]);
+        assert_eq!(rrl.check_at(key, 5, 0, t0), RrlVerdict::Drop);
+
+        // 1.5 秒后上一窗口只计一半（2.5），可再放行 3 个
+        let later = t0 + Duration::from_millis(1500);
+        assert_eq!(rrl.check_at(key, 5, 0, later), RrlVerdict::Allow);
+        assert_eq!(rrl.check_at(key, 5, 0, later), RrlVerdict::Allow);
+        assert_eq!(rrl.check_at(key, 5, 0, later), RrlVerdict::Allow);
+        assert_eq!(rrl.check_at(key, 5, 0, later), RrlVerdict::Drop);
+        // 两个窗口之后计数清零
+        assert_eq!(rrl.check_at(key, 5, 0, t0 + Duration::from_secs(4)), RrlVerdict::Allow);
+
+        rrl.prune_at(Duration::from_secs(10), t0 + Duration::from_secs(20));
+        assert_eq!(rrl.window_count(), 0);
+    }
+
+    #[test]
+    fn block_escalator_counts_per_window_and_expires_denials() {
+        let blocks = BlockEscalator::new();
+        let client: IpAddr = "192.0.2.10".parse().unwrap();
+        let key = BlockEscalator::key(client, "Beacon.Example.");
+        assert_eq!(key, BlockEscalator::key(client, "beacon.example"));
+        assert_ne!(key, BlockEscalator::key("192.0.2.11".parse().unwrap(), "beacon.example"));
+
+        let window = Duration::from_secs(60);
+        let t0 = Instant::now();
+        assert_eq!(blocks.count_at(key, t0), 0);
+        assert_eq!(blocks.record_at(key, window, t0), 1);
+        assert_eq!(blocks.record_at(key, window, t0 + Duration::from_secs(30)), 2);
+        assert_eq!(blocks.count_at(key, t0 + Duration::from_secs(30)), 2);
+        // 窗口结束后重新计数
+        assert_eq!(blocks.count_at(key, t0 + Duration::from_secs(61)), 0);
+        assert_eq!(blocks.record_at(key, window, t0 + Duration::from_secs(61)), 1);
+
+        assert!(!blocks.is_denied_at(client, t0));
+        blocks.deny_at(client, Duration::from_secs(10), t0);
+        assert!(blocks.is_denied_at(client, t0 + Duration::from_secs(9)));
+        assert!(!blocks.is_denied_at(client, t0 + Duration::from_secs(10)));
+
+        blocks.prune_at(t0 + Duration::from_secs(200));
+        assert_eq!(blocks.tracked(), 1);
+    }
+
+    #[test]
+    fn block_windows_are_bounded() {
+        let blocks = BlockEscalator::with_capacity(16);
+        let t0 = Instant::now();
+        // 伪造来源的查询不断产生新的 (客户端, 域名)
+        for i in 0..1000u32 {
+            let client = IpAddr::from(std::net::Ipv4Addr::from(0xC000_0200 + i));
+            blocks.record_at(BlockEscalator::key(client, "beacon.example"), Duration::from_secs(60), t0);
+        }
+        assert!(blocks.tracked() <= 16, "{}", blocks.tracked());
+    }
+}
@@ -0,0 +1,77 @@
+use std::hash::{Hash, Hasher};
+
+use rustc_hash::FxHasher;
+
+/// 返回 qname 的可注册域名（eTLD+1），无法识别时返回原名。qname 需为小写。
+#[inline]
+pub fn registrable_domain(qname: &str) -> &str {
+    let name = qname.trim_end_matches('.');
+    psl::domain_str(name).unwrap_or(name)
+}
+
+/// 最高随机权重（rendezvous）哈希：同一 key 总选中同一下标，增删候选时仅迁移少量 key
+#[inline]
+fn rendezvous_pick(key: &str, salt: u8, n: usize) -> Option<usize> {
+    (0..n).max_by_key(|idx| {
+        let mut h = FxHasher::default();
+        key.hash(&mut h);
+        salt.hash(&mut h);
+        idx.hash(&mut h);
+        h.finish()
+    })
+}
+
+/// 按可注册域名选择上游组，同一 eTLD+1 下的查询总落到同一组；组内成员交由上游策略、健康检查与故障转移选择
+pub fn shard_group<'a, T>(groups: &'a [T], qname: &str) -> Option<&'a T> {
+    groups.get(rendezvous_pick(registrable_domain(qname), 0, groups.len())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups() -> Vec<Vec<String>> {
+        (0..4)
+            .map(|g| vec![format!("10.0.{}.1:53", g), format!("10.0.{}.2:53", g)])
+            .collect()
+    }
+
+    fn group_of(groups: &[Vec<String>], group: &[String]) -> usize {
+        groups.iter().position(|g| g == group).unwrap()
+    }
+
+    #[test]
+    fn registrable_domain_uses_public_suffix_list() {
+        assert_eq!(registrable_domain("www.example.com"), "example.com");
+        assert_eq!(registrable_domain("a.b.example.co.uk."), "example.co.uk");
+        assert_eq!(registrable_domain("foo.github.io"), "foo.github.io");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn same_registrable_domain_routes_to_same_group() {
+        let groups = groups();
+        let base = shard_group(&groups, "example.co.uk").unwrap();
+        for name in ["www.example.co.uk", "api.v2.example.co.uk", "cdn.example.co.uk."] {
+            assert_eq!(shard_group(&groups, name), Some(base));
+        }
+
+        // Different registrable domains spread across more than one group.
+        let used: std::collections::HashSet<usize> = (0..64)
+            .map(|i| group_of(&groups, shard_group(&groups, &format!("www.site{}.com", i)).unwrap()))
+            .collect();
+        assert!(used.len() > 1);
+
+        // Adding a group keeps most domains where they were.
+        let mut grown = groups.clone();
+        grown.push(vec!["10.0.9.1:53".to_string()]);
+        let moved = (0..200)
+            .filter(|i| {
+                let name = format!("site{}.org", i);
+                shard_group(&groups, &name) != shard_group(&grown, &name)
+            })
+            .count();
+        assert!(moved < 100, "too many domains moved: {moved}");
+        assert_eq!(shard_group::<String>(&[], "example.com"), None);
+    }
+}
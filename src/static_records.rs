@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::config::{StaticRecord, parse_static_rdata};
+
+/// 未写 TTL 的记录使用的 TTL
+const DEFAULT_TTL: u32 = 300;
+
+/// 本地覆盖记录（settings.static_records_file），先于 pipeline 规则与上游转发查询。
+///
+/// 文件格式：每行一条，`#` 之后为注释；支持 hosts 格式 `1.2.3.4 name [name...]`，
+/// 以及 `name [ttl] A|AAAA|CNAME value`。`*.example.com` 匹配其下任意子域，精确名字优先于通配符，
+/// 多个通配符时取最接近的一个。A/AAAA 记录同时用于对应地址的 PTR 反向应答。
+#[derive(Default)]
+pub struct StaticRecords {
+    exact: HashMap<String, Vec<StaticRecord>>,
+    /// 键为通配符去掉 "*." 后的父域
+    wildcard: HashMap<String, Vec<StaticRecord>>,
+    /// 反向映射：地址 -> (名字, TTL)，取文件中该地址第一次出现时的名字（与 hosts 约定一致），通配符名字不参与
+    reverse: HashMap<IpAddr, (String, u32)>,
+}
+
+impl std::fmt::Debug for StaticRecords {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticRecords")
+            .field("exact", &self.exact.len())
+            .field("wildcard", &self.wildcard.len())
+            .field("reverse", &self.reverse.len())
+            .finish()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl StaticRecords {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("read static records file: {}", path.display()))?;
+        Self::parse(&raw).with_context(|| format!("parse static records file: {}", path.display()))
+    }
+
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let mut records = Self::default();
+        for (idx, line) in raw.lines().enumerate() {
+            let fields: Vec<&str> = line.split('#').next().unwrap_or_default().split_whitespace().collect();
+            let Some((first, rest)) = fields.split_first() else {
+                continue;
+            };
+            if let Ok(ip) = first.parse::<IpAddr>() {
+                let rdata = match ip {
+                    IpAddr::V4(v4) => RData::A(A(v4)),
+                    IpAddr::V6(v6) => RData::AAAA(AAAA(v6)),
+                };
+                if rest.is_empty() {
+                    anyhow::bail!("line {}: hosts entry without a name", idx + 1);
+                }
+                for name in rest {
+                    records.insert(name, StaticRecord { rdata: rdata.clone(), ttl: DEFAULT_TTL })?;
+                }
+                continue;
+            }
+            let (ttl, rtype, value) = match rest {
+                [rtype, value] => (DEFAULT_TTL, *rtype, *value),
+                [ttl, rtype, value] => (
+                    ttl.parse().with_context(|| format!("line {}: invalid ttl {ttl}", idx + 1))?,
+                    *rtype,
+                    *value,
+                ),
+                _ => anyhow::bail!("line {}: expected \"<name> [ttl] <type> <value>\" or \"<ip> <name>...\"", idx + 1),
+            };
+            if !["A", "AAAA", "CNAME"].contains(&rtype.to_ascii_uppercase().as_str()) {
+                anyhow::bail!("line {}: unsupported record type {rtype} (A/AAAA/CNAME)", idx + 1);
+            }
+            let rdata = parse_static_rdata(rtype, value).with_context(|| format!("line {}: invalid {rtype} value {value}", idx + 1))?;
+            records.insert(first, StaticRecord { rdata, ttl })?;
+        }
+        Ok(records)
+    }
+
+    fn insert(&mut self, name: &str, record: StaticRecord) -> anyhow::Result<()> {
+        let name = normalize(name);
+        let name_is_wildcard = name.starts_with("*.");
+        let (map, key) = match name.strip_prefix("*.") {
+            Some(parent) => (&mut self.wildcard, parent.to_string()),
+            None => (&mut self.exact, name),
+        };
+        Name::from_str(&key).with_context(|| format!("invalid name {key}"))?;
+        if !name_is_wildcard {
+            let ip = match &record.rdata {
+                RData::A(a) => Some(IpAddr::V4(a.0)),
+                RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+                _ => None,
+            };
+            if let Some(ip) = ip {
+                self.reverse.entry(ip).or_insert_with(|| (key.clone(), record.ttl));
+            }
+        }
+        map.entry(key).or_default().push(record);
+        Ok(())
+    }
+
+    /// 地址对应的名字与 TTL（PTR 应答用）
+    pub fn reverse(&self, ip: IpAddr) -> Option<(String, u32)> {
+        self.reverse.get(&ip).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.values().chain(self.wildcard.values()).map(Vec::len).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+
+    /// qname 需为小写；返回 qtype 对应的记录，名字只有 CNAME 时返回 CNAME；无匹配返回 None
+    pub fn lookup(&self, qname: &str, qtype: RecordType) -> Option<Vec<Record>> {
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+        let entries = match self.exact.get(qname) {
+            Some(entries) => entries,
+            None => {
+                let mut search_name = qname;
+                loop {
+                    let idx = search_name.find('.')?;
+                    search_name = &search_name[idx + 1..];
+                    if let Some(entries) = self.wildcard.get(search_name) {
+                        break entries;
+                    }
+                }
+            }
+        };
+        let pick = |t: RecordType| entries.iter().filter(move |r| r.rdata.record_type() == t);
+        let mut matched: Vec<&StaticRecord> = pick(qtype).collect();
+        if matched.is_empty() {
+            matched = pick(RecordType::CNAME).collect();
+        }
+        if matched.is_empty() {
+            return None;
+        }
+        let name = Name::from_str(qname).ok()?;
+        Some(
+            matched
+                .into_iter()
+                .map(|r| Record::from_rdata(name.clone(), r.ttl, r.rdata.clone()))
+                .collect(),
+        )
+    }
+}
+
+/// settings.static_records_file 对应的文件：内容放在 ArcSwap 中，文件变化时由 watcher 重新读取并原子替换
+pub struct StaticRecordsFile {
+    path: PathBuf,
+    records: ArcSwap<StaticRecords>,
+}
+
+impl std::fmt::Debug for StaticRecordsFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticRecordsFile")
+            .field("path", &self.path)
+            .field("records", &*self.records.load())
+            .finish()
+    }
+}
+
+impl StaticRecordsFile {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let records = StaticRecords::load(&path)?;
+        Ok(Self {
+            path,
+            records: ArcSwap::from_pointee(records),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 重新读取文件并替换记录，返回新的记录数；读取或解析失败时保留旧内容
+    pub fn reload(&self) -> anyhow::Result<usize> {
+        let records = StaticRecords::load(&self.path)?;
+        let len = records.len();
+        self.records.store(std::sync::Arc::new(records));
+        Ok(len)
+    }
+
+    #[inline]
+    pub fn lookup(&self, qname: &str, qtype: RecordType) -> Option<Vec<Record>> {
+        self.records.load().lookup(qname, qtype)
+    }
+
+    #[inline]
+    pub fn reverse(&self, ip: IpAddr) -> Option<(String, u32)> {
+        self.records.load().reverse(ip)
+    }
+}
+
+/// 反向名字（x.x.x.x.in-addr.arpa 或 32 个半字节的 ip6.arpa，需为小写）对应的地址；不是完整地址时返回 None
+pub fn reverse_name_ip(qname: &str) -> Option<IpAddr> {
+    let qname = qname.strip_suffix('.').unwrap_or(qname);
+    if let Some(labels) = qname.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0u8; 4];
+        let mut parts = labels.split('.');
+        for octet in octets.iter_mut().rev() {
+            let part = parts.next()?;
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            *octet = part.parse().ok()?;
+        }
+        return parts.next().is_none().then(|| IpAddr::from(octets));
+    }
+    let labels = qname.strip_suffix(".ip6.arpa")?;
+    let mut addr: u128 = 0;
+    let mut count = 0;
+    for (i, part) in labels.split('.').enumerate() {
+        let [nibble] = part.as_bytes() else {
+            return None;
+        };
+        if i >= 32 {
+            return None;
+        }
+        addr |= ((*nibble as char).to_digit(16)? as u128) << (4 * i);
+        count += 1;
+    }
+    (count == 32).then(|| IpAddr::from(addr.to_be_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn a(records: &[Record]) -> Vec<Ipv4Addr> {
+        records
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::A(a)) => Some(a.0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_hosts_and_zone_lines() {
+        let records = StaticRecords::parse(
+            "# local overrides\n\
+             10.0.0.1 nas.lan NAS.home.\n\
+             fd00::1 nas.lan\n\
+             printer.lan 60 A 10.0.0.9 # office\n\
+             docs.lan CNAME nas.lan\n\n",
+        )
+        .unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(a(&records.lookup("nas.home", RecordType::A).unwrap()), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        let aaaa = records.lookup("nas.lan", RecordType::AAAA).unwrap();
+        assert_eq!(aaaa[0].data(), Some(&RData::AAAA(AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)))));
+        assert_eq!(records.lookup("printer.lan", RecordType::A).unwrap()[0].ttl(), 60);
+        assert!(records.lookup("printer.lan", RecordType::AAAA).is_none());
+        // 只有 CNAME 的名字对任意类型返回 CNAME
+        assert_eq!(records.lookup("docs.lan", RecordType::A).unwrap()[0].record_type(), RecordType::CNAME);
+        assert!(records.lookup("other.lan", RecordType::A).is_none());
+
+        let err = StaticRecords::parse("mail.lan MX 10 mx.lan\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{err}");
+    }
+
+    #[test]
+    fn exact_names_win_over_wildcards() {
+        let records = StaticRecords::parse(
+            "*.example.com A 192.0.2.1\n\
+             *.dev.example.com A 192.0.2.2\n\
+             api.example.com A 192.0.2.3\n",
+        )
+        .unwrap();
+        assert_eq!(a(&records.lookup("api.example.com", RecordType::A).unwrap()), vec![Ipv4Addr::new(192, 0, 2, 3)]);
+        assert_eq!(a(&records.lookup("www.example.com", RecordType::A).unwrap()), vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        // 更接近的通配符优先
+        assert_eq!(a(&records.lookup("x.dev.example.com", RecordType::A).unwrap()), vec![Ipv4Addr::new(192, 0, 2, 2)]);
+        assert_eq!(a(&records.lookup("a.b.example.com", RecordType::A).unwrap()), vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        // 通配符不匹配父域本身
+        assert!(records.lookup("example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn reverse_map_keeps_first_name_and_parses_arpa_names() {
+        let records = StaticRecords::parse(
+            "10.0.0.1 nas.lan nas.home\n\
+             router.lan 60 A 10.0.0.254\n\
+             *.lan A 10.0.0.99\n\
+             fd00::1 nas.lan\n",
+        )
+        .unwrap();
+        assert_eq!(records.reverse("10.0.0.1".parse().unwrap()), Some(("nas.lan".to_string(), DEFAULT_TTL)));
+        assert_eq!(records.reverse("10.0.0.254".parse().unwrap()), Some(("router.lan".to_string(), 60)));
+        assert_eq!(records.reverse("fd00::1".parse().unwrap()).unwrap().0, "nas.lan");
+        assert!(records.reverse("10.0.0.99".parse().unwrap()).is_none());
+
+        assert_eq!(reverse_name_ip("1.0.0.10.in-addr.arpa."), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(
+            reverse_name_ip("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa"),
+            Some("fd00::1".parse().unwrap())
+        );
+        assert!(reverse_name_ip("0.10.in-addr.arpa").is_none());
+        assert!(reverse_name_ip("256.0.0.10.in-addr.arpa").is_none());
+        assert!(reverse_name_ip("1.1.0.0.10.in-addr.arpa").is_none());
+        assert!(reverse_name_ip("d.f.ip6.arpa").is_none());
+        assert!(reverse_name_ip("www.example.com").is_none());
+    }
+}
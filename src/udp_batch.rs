@@ -0,0 +1,201 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+
+use bytes::Bytes;
+use socket2::SockAddr;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+/// 单个接收缓冲区大小，与逐包接收路径一致
+pub const RECV_BUF_SIZE: usize = 4096;
+
+/// 预分配的批量接收缓冲区：一次 recvmmsg 最多取回 batch_size 个数据报（Linux，--udp-batch-size > 1 时启用）
+pub struct RecvBatch {
+    bufs: Vec<u8>,
+    addrs: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+    received: usize,
+}
+
+// mmsghdr/iovec 中的裸指针只指向本结构自有的堆内存（Vec 构造后不再扩容），可随结构跨线程移动
+unsafe impl Send for RecvBatch {}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        let mut bufs = vec![0u8; batch_size * RECV_BUF_SIZE];
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { std::mem::zeroed() }; batch_size];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .chunks_exact_mut(RECV_BUF_SIZE)
+            .map(|chunk| libc::iovec {
+                iov_base: chunk.as_mut_ptr().cast(),
+                iov_len: RECV_BUF_SIZE,
+            })
+            .collect();
+        let msgs = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, addr)| {
+                let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                hdr.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+                hdr.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_hdr.msg_iov = iov;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+        Self {
+            bufs,
+            addrs,
+            iovecs,
+            msgs,
+            received: 0,
+        }
+    }
+
+    /// 非阻塞地取回当前已到达的数据报（最多 batch_size 个），返回数量；无数据时返回 WouldBlock
+    fn recv_now(&mut self, fd: libc::c_int) -> io::Result<usize> {
+        for hdr in &mut self.msgs {
+            // 内核会改写 namelen，每次调用前复位
+            hdr.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_len = 0;
+        }
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.msgs.as_mut_ptr(),
+                self.msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            self.received = 0;
+            return Err(io::Error::last_os_error());
+        }
+        self.received = n as usize;
+        Ok(self.received)
+    }
+
+    /// 等待 socket 可读后批量接收，一次系统调用取回全部已到达的数据报
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let fd = socket.as_raw_fd();
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || self.recv_now(fd)) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.received
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.received == 0
+    }
+
+    /// 第 idx 个数据报的内容与来源地址；来源地址无法识别时返回 None
+    pub fn get(&self, idx: usize) -> Option<(&[u8], SocketAddr)> {
+        if idx >= self.received {
+            return None;
+        }
+        let hdr = &self.msgs[idx];
+        let len = (hdr.msg_len as usize).min(self.iovecs[idx].iov_len);
+        let start = idx * RECV_BUF_SIZE;
+        let addr = unsafe { SockAddr::new(self.addrs[idx], hdr.msg_hdr.msg_namelen) };
+        Some((&self.bufs[start..start + len], addr.as_socket()?))
+    }
+}
+
+/// 用 sendmmsg 一次发出多条应答；发送缓冲区满或部分发送时，剩余应答逐条异步发送
+pub async fn send_batch(socket: &UdpSocket, replies: &[(Bytes, SocketAddr)]) {
+    if replies.is_empty() {
+        return;
+    }
+    let sent = if replies.len() == 1 {
+        0
+    } else {
+        let fd = socket.as_raw_fd();
+        socket
+            .try_io(Interest::WRITABLE, || send_now(fd, replies))
+            .unwrap_or(0)
+    };
+    for (resp, peer) in &replies[sent..] {
+        let _ = socket.send_to(resp, *peer).await;
+    }
+}
+
+fn send_now(fd: libc::c_int, replies: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+    let addrs: Vec<SockAddr> = replies.iter().map(|(_, peer)| SockAddr::from(*peer)).collect();
+    let mut iovecs: Vec<libc::iovec> = replies
+        .iter()
+        .map(|(resp, _)| libc::iovec {
+            iov_base: resp.as_ptr() as *mut libc::c_void,
+            iov_len: resp.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(&addrs)
+        .map(|(iov, addr)| {
+            let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = addr.len();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+    let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, libc::MSG_DONTWAIT) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn one_recvmmsg_drains_queued_datagrams() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        for i in 0..5u8 {
+            client.send_to(&[i; 3], server_addr).await.unwrap();
+        }
+
+        let mut batch = RecvBatch::new(8);
+        let mut got = Vec::new();
+        let mut syscalls = 0;
+        while got.len() < 5 {
+            let n = batch.recv(&server).await.unwrap();
+            syscalls += 1;
+            for idx in 0..n {
+                let (data, from) = batch.get(idx).unwrap();
+                assert_eq!(from, client_addr);
+                got.push(data.to_vec());
+            }
+        }
+        // 回环上数据报已全部排队，单次 recvmmsg 即可取完（逐包接收需要 5 次 recv_from）
+        assert_eq!(syscalls, 1);
+        assert_eq!(got, (0..5u8).map(|i| vec![i; 3]).collect::<Vec<_>>());
+
+        let replies: Vec<(Bytes, SocketAddr)> = (0..5u8).map(|i| (Bytes::from(vec![i + 10; 2]), client_addr)).collect();
+        send_batch(&server, &replies).await;
+        let mut buf = [0u8; 16];
+        for i in 0..5u8 {
+            let (n, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from, server_addr);
+            assert_eq!(&buf[..n], &[i + 10; 2]);
+        }
+    }
+}
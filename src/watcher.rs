@@ -1,60 +1,763 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use arc_swap::ArcSwap;
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use tracing::{error, info, warn};
 
 use crate::config;
-use crate::matcher::RuntimePipelineConfig;
+use crate::matcher::{ConfigDiff, RuntimePipelineConfig};
 
-pub fn spawn(path: PathBuf, pipeline: Arc<ArcSwap<RuntimePipelineConfig>>) {
+/// 主配置热加载的结果计数，由 watcher 写入、引擎与 admin 读取
+#[derive(Debug)]
+pub struct ReloadStats {
+    success: AtomicU64,
+    failure: AtomicU64,
+    /// 最近一次成功加载的 Unix 时间戳（秒）；启动时的初始加载也算
+    last_success_unix: AtomicU64,
+    /// 每次成功热加载后唤醒等待者（如 TCP 连接池预热）
+    reloaded: tokio::sync::Notify,
+    /// 列表文件（domain_set / static_records_file）被换入新内容的次数；engine 据此失效规则决策缓存
+    list_reloads: AtomicU64,
+}
+
+impl Default for ReloadStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReloadStats {
+    pub fn new() -> Self {
+        Self {
+            success: AtomicU64::new(0),
+            failure: AtomicU64::new(0),
+            last_success_unix: AtomicU64::new(unix_now()),
+            reloaded: tokio::sync::Notify::new(),
+            list_reloads: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.success.fetch_add(1, Ordering::Relaxed);
+        self.last_success_unix.store(unix_now(), Ordering::Relaxed);
+        self.reloaded.notify_waiters();
+    }
+
+    /// 下一次成功热加载时完成；只有已 enable 或正在 poll 的等待者会被唤醒
+    pub fn reloaded(&self) -> tokio::sync::futures::Notified<'_> {
+        self.reloaded.notified()
+    }
+
+    pub fn record_list_reload(&self) {
+        self.list_reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn list_reloads(&self) -> u64 {
+        self.list_reloads.load(Ordering::Relaxed)
+    }
+
+    pub fn record_failure(&self) {
+        self.failure.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn successes(&self) -> u64 {
+        self.success.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failure.load(Ordering::Relaxed)
+    }
+
+    pub fn last_success_unix(&self) -> u64 {
+        self.last_success_unix.load(Ordering::Relaxed)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub fn spawn(path: PathBuf, pipeline: Arc<ArcSwap<RuntimePipelineConfig>>, stats: Arc<ReloadStats>) {
     // 使用阻塞线程持有watcher，避免异步生命周期问题。
     thread::spawn(move || {
-        if let Err(err) = run_watcher(path, pipeline) {
+        if let Err(err) = run_watcher(path, pipeline, stats) {
             error!(target = "watcher", error = %err, "config watcher exited with error");
         }
     });
 }
 
-fn run_watcher(path: PathBuf, pipeline: Arc<ArcSwap<RuntimePipelineConfig>>) -> notify::Result<()> {
+fn run_watcher(
+    path: PathBuf,
+    pipeline: Arc<ArcSwap<RuntimePipelineConfig>>,
+    stats: Arc<ReloadStats>,
+) -> notify::Result<()> {
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher: RecommendedWatcher = Watcher::new(tx, Config::default())?;
-    watcher.watch(&path, RecursiveMode::NonRecursive)?;
+    // 监视所在目录而不是文件本身：rename 覆盖或替换符号链接会换掉 inode，直接监视文件会就此失效
+    watcher.watch(parent_dir(&path), RecursiveMode::NonRecursive)?;
+    let name_key = config_name_key(&path);
+    let mut config = WatchedPaths {
+        target: name_key.clone(),
+        name_key,
+    };
+    sync_config_target(&mut watcher, &path, &mut config);
+    let mut lists = ListWatches {
+        config_dir: watch_key(parent_dir(&path)),
+        ..Default::default()
+    };
+    sync_list_watches(&mut watcher, &mut lists, &pipeline.load());
 
     info!(target = "watcher", path = %path.display(), "config watcher started");
 
-    for res in rx {
-        match res {
-            Ok(_event) => {
-                // Simple retry mechanism to handle file write races (e.g. truncate+write)
-                let mut retries = 3;
-                while retries > 0 {
-                    match config::load_config(&path)
-                        .and_then(|cfg| RuntimePipelineConfig::from_config(cfg).map_err(Into::into))
-                    {
-                        Ok(new_cfg) => {
-                            pipeline.store(Arc::new(new_cfg));
-                            info!(target = "watcher", path = %path.display(), "config reloaded");
-                            break;
-                        }
-                        Err(err) => {
-                            retries -= 1;
-                            if retries == 0 {
-                                warn!(target = "watcher", path = %path.display(), error = %err, "config reload failed, keeping old config");
-                            } else {
-                                // Wait a bit and retry
-                                std::thread::sleep(std::time::Duration::from_millis(50));
-                            }
-                        }
+    while let Some((changed, reload_config)) = next_batch(&rx, &config, &lists, &pipeline) {
+        if !reload_config {
+            // 只有列表文件变化：重建受影响的集合，不重新编译整个配置
+            let cfg = pipeline.load();
+            let mut swapped = false;
+            for key in lists.changed(&changed) {
+                swapped |= reload_domain_sets(&cfg, &key) > 0;
+                swapped |= reload_static_records(&cfg, &key);
+            }
+            if swapped {
+                stats.record_list_reload();
+            }
+            // 列表文件是符号链接时可能已改指到其他目录
+            sync_list_watches(&mut watcher, &mut lists, &cfg);
+            continue;
+        }
+        // 符号链接可能已指向新文件：先切换 watch 再读取，加载之后对新目标的修改不会漏掉
+        sync_config_target(&mut watcher, &path, &mut config);
+        // Simple retry mechanism to handle file write races (e.g. truncate+write)
+        let mut retries = 3;
+        while retries > 0 {
+            match config::load_config(&path)
+                .and_then(|cfg| RuntimePipelineConfig::from_config(cfg).map_err(Into::into))
+            {
+                Ok(new_cfg) => {
+                    let diff = pipeline.load().diff(&new_cfg);
+                    pipeline.store(Arc::new(new_cfg));
+                    stats.record_success();
+                    log_reload(&path, &diff);
+                    sync_list_watches(&mut watcher, &mut lists, &pipeline.load());
+                    break;
+                }
+                Err(err) => {
+                    retries -= 1;
+                    if retries == 0 {
+                        stats.record_failure();
+                        warn!(target = "watcher", path = %path.display(), error = %err, "config reload failed, keeping old config");
+                    } else {
+                        // Wait a bit and retry
+                        std::thread::sleep(std::time::Duration::from_millis(50));
                     }
                 }
             }
+        }
+    }
+    Ok(())
+}
+
+/// 被监视文件的两种写法：目录中的名字（规范化父目录 + 文件名，rename 覆盖或替换符号链接后不变），
+/// 以及解析符号链接后的实际文件
+struct WatchedPaths {
+    name_key: PathBuf,
+    target: PathBuf,
+}
+
+impl WatchedPaths {
+    fn new(path: &Path) -> Self {
+        Self {
+            name_key: config_name_key(path),
+            target: watch_key(path),
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        // 先比较文件名：目录中无关文件的事件不必规范化路径
+        let name = path.file_name();
+        if name != self.name_key.file_name() && name != self.target.file_name() {
+            return false;
+        }
+        config_name_key(path) == self.name_key || watch_key(path) == self.target
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.target != self.name_key
+    }
+}
+
+/// 路径所在目录；相对路径只有文件名时为当前目录
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// 只规范化父目录、保留文件名本身，符号链接不被解析
+fn config_name_key(path: &Path) -> PathBuf {
+    match path.file_name() {
+        Some(name) => watch_key(parent_dir(path)).join(name),
+        None => path.to_path_buf(),
+    }
+}
+
+/// 配置是符号链接时另外监视其指向的文件（原地编辑目标文件不会在链接所在目录产生事件），链接改指后切换到新目标
+fn sync_config_target(watcher: &mut impl Watcher, path: &Path, config: &mut WatchedPaths) {
+    let target = watch_key(path);
+    if target == config.target {
+        return;
+    }
+    if config.is_symlink() {
+        // 旧目标被删除时 watch 已随之失效，unwatch 失败可忽略
+        let _ = watcher.unwatch(&config.target);
+    }
+    config.target = target;
+    if config.is_symlink() {
+        match watcher.watch(&config.target, RecursiveMode::NonRecursive) {
+            Ok(()) => info!(target = "watcher", path = %config.target.display(), "watching config symlink target"),
+            Err(err) => warn!(target = "watcher", path = %config.target.display(), error = %err, "watch config symlink target failed"),
+        }
+    }
+}
+
+/// 一批事件最多收集多少个 debounce：相关文件被持续写入时也按时重新加载
+const MAX_BATCH_DEBOUNCES: u32 = 10;
+
+/// 阻塞等待下一批文件事件：收到第一个相关事件后持续收集，直到 settings.reload_debounce_ms 内没有新的相关事件，
+/// 最长 MAX_BATCH_DEBOUNCES 个 debounce。返回变化的文件（去重）以及是否需要重新加载主配置；channel 关闭时返回 None
+fn next_batch(
+    rx: &Receiver<notify::Result<notify::Event>>,
+    config: &WatchedPaths,
+    lists: &ListWatches,
+    pipeline: &ArcSwap<RuntimePipelineConfig>,
+) -> Option<(Vec<PathBuf>, bool)> {
+    let debounce = Duration::from_millis(pipeline.load().settings.reload_debounce_ms);
+    let mut changed: Vec<PathBuf> = Vec::new();
+    let mut reload_config = false;
+    // 目录中其他文件（如部署工具的临时文件）的事件既不归入本批，也不推迟安静期；返回事件是否相关
+    let mut collect = |res: notify::Result<notify::Event>| match res {
+        Ok(event) => {
+            // 不带路径的事件无法归属，按主配置变化处理
+            let mut relevant = event.paths.is_empty();
+            reload_config |= relevant;
+            for p in event.paths {
+                let is_config = config.matches(&p);
+                if !is_config && !lists.matches(&p) {
+                    continue;
+                }
+                relevant = true;
+                reload_config |= is_config;
+                if !changed.contains(&p) {
+                    changed.push(p);
+                }
+            }
+            relevant
+        }
+        Err(err) => {
+            warn!(target = "watcher", error = %err, "watcher event error");
+            false
+        }
+    };
+    while !collect(rx.recv().ok()?) {}
+    if !debounce.is_zero() {
+        let deadline = Instant::now() + debounce * MAX_BATCH_DEBOUNCES;
+        let mut quiet_until = Instant::now() + debounce;
+        loop {
+            let wait = quiet_until.min(deadline).saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                break;
+            }
+            // 超时（安静期结束）或 channel 关闭都结束本批
+            match rx.recv_timeout(wait) {
+                Ok(res) => {
+                    if collect(res) {
+                        quiet_until = Instant::now() + debounce;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Some((changed, reload_config))
+}
+
+/// 事件路径与 watch 路径的写法可能不同（相对/绝对），统一按规范化路径比较
+fn watch_key(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// 列表文件（domain_set 与 static_records_file）的 watch：与主配置一样监视所在目录并按名字过滤，
+/// mv 原子替换换掉 inode 后 watch 仍然有效
+#[derive(Default)]
+struct ListWatches {
+    /// 当前配置引用的列表文件（配置中的写法）
+    files: Vec<(PathBuf, WatchedPaths)>,
+    /// 为列表文件注册 watch 的目录（规范化路径）
+    dirs: HashSet<PathBuf>,
+    /// 主配置所在目录已由主 watch 覆盖；重复注册同一 inode 后 unwatch 会连主 watch 一起移除
+    config_dir: PathBuf,
+}
+
+impl ListWatches {
+    fn matches(&self, path: &Path) -> bool {
+        self.files.iter().any(|(_, watched)| watched.matches(path))
+    }
+
+    /// 本批事件涉及的列表文件，返回其规范化路径（按当前符号链接指向重新解析），供 reload_* 比较
+    fn changed(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut keys: Vec<PathBuf> = Vec::new();
+        for (path, watched) in &self.files {
+            if paths.iter().any(|p| watched.matches(p)) {
+                let key = watch_key(path);
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys
+    }
+}
+
+/// 按当前配置引用的 domain_set 文件与 static_records_file 增删目录 watch
+fn sync_list_watches(watcher: &mut impl Watcher, lists: &mut ListWatches, cfg: &RuntimePipelineConfig) {
+    lists.files = cfg
+        .domain_sets()
+        .iter()
+        .map(|set| set.path().to_path_buf())
+        .chain(cfg.static_records.iter().map(|f| f.path().to_path_buf()))
+        .map(|path| {
+            let watched = WatchedPaths::new(&path);
+            (path, watched)
+        })
+        .collect();
+    // 链接所在目录与其指向文件所在目录都要监视：原地编辑目标文件不会在链接所在目录产生事件
+    let wanted: HashSet<PathBuf> = lists
+        .files
+        .iter()
+        .flat_map(|(_, w)| [parent_dir(&w.name_key), parent_dir(&w.target)])
+        .filter(|dir| *dir != lists.config_dir)
+        .map(Path::to_path_buf)
+        .collect();
+    lists.dirs.retain(|dir| {
+        if wanted.contains(dir) {
+            return true;
+        }
+        if let Err(err) = watcher.unwatch(dir) {
+            warn!(target = "watcher", path = %dir.display(), error = %err, "unwatch list directory failed");
+        }
+        false
+    });
+    for dir in wanted {
+        if lists.dirs.contains(&dir) {
+            continue;
+        }
+        match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                info!(target = "watcher", path = %dir.display(), "watching list directory");
+                lists.dirs.insert(dir);
+            }
+            Err(err) => warn!(target = "watcher", path = %dir.display(), error = %err, "watch list directory failed"),
+        }
+    }
+}
+
+/// 重建引用该文件的所有 domain_set 并原子替换；返回成功替换的数量
+fn reload_domain_sets(cfg: &RuntimePipelineConfig, key: &Path) -> usize {
+    let mut reloaded = 0;
+    for set in cfg.domain_sets().iter().filter(|s| watch_key(s.path()) == key) {
+        // 与主配置相同的重试，容忍 truncate+write 的中间状态
+        let mut retries = 3;
+        loop {
+            match set.reload() {
+                Ok(entries) => {
+                    info!(target = "watcher", path = %set.path().display(), entries = entries, "domain set reloaded");
+                    reloaded += 1;
+                    break;
+                }
+                Err(err) => {
+                    retries -= 1;
+                    if retries == 0 {
+                        warn!(target = "watcher", path = %set.path().display(), error = %err, "domain set reload failed, keeping old set");
+                        break;
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+            }
+        }
+    }
+    reloaded
+}
+
+/// 重新读取 static_records_file 并原子替换；文件不是该配置的 static_records_file 或读取失败时返回 false
+fn reload_static_records(cfg: &RuntimePipelineConfig, key: &Path) -> bool {
+    let Some(file) = cfg.static_records.as_ref().filter(|f| watch_key(f.path()) == key) else {
+        return false;
+    };
+    let mut retries = 3;
+    loop {
+        match file.reload() {
+            Ok(records) => {
+                info!(target = "watcher", path = %file.path().display(), records = records, "static records reloaded");
+                return true;
+            }
             Err(err) => {
-                warn!(target = "watcher", error = %err, "watcher event error");
+                retries -= 1;
+                if retries == 0 {
+                    warn!(target = "watcher", path = %file.path().display(), error = %err, "static records reload failed, keeping old records");
+                    return false;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
             }
         }
     }
-    Ok(())
+}
+
+/// 热加载成功后输出一条结构化日志，列出与旧配置的差异
+fn log_reload(path: &Path, diff: &ConfigDiff) {
+    info!(
+        target = "watcher",
+        path = %path.display(),
+        pipelines_added = ?diff.pipelines_added,
+        pipelines_removed = ?diff.pipelines_removed,
+        rule_counts_changed = ?diff.rule_counts_changed,
+        settings_changed = ?diff.settings_changed,
+        changes = %diff,
+        "config reloaded"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::CaptureWriter;
+
+    fn runtime(raw: serde_json::Value) -> RuntimePipelineConfig {
+        let cfg: config::PipelineConfig = serde_json::from_value(raw).expect("parse");
+        RuntimePipelineConfig::from_config(cfg).expect("runtime")
+    }
+
+    #[test]
+    fn reload_log_lists_added_pipeline_and_changed_setting() {
+        let old = runtime(serde_json::json!({
+            "settings": { "min_ttl": 0 },
+            "pipelines": [{ "id": "main", "rules": [] }]
+        }));
+        let new = runtime(serde_json::json!({
+            "settings": { "min_ttl": 30 },
+            "pipelines": [{ "id": "main", "rules": [] }, { "id": "edge", "rules": [] }]
+        }));
+
+        let capture = CaptureWriter::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(capture.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            log_reload(Path::new("config.json"), &old.diff(&new));
+        });
+
+        let out = capture.contents();
+        assert!(out.contains("config reloaded"), "{out}");
+        assert!(out.contains("pipelines_added=[\"edge\"]"), "{out}");
+        assert!(out.contains("settings_changed=[\"min_ttl\"]"), "{out}");
+    }
+
+    fn wait_until(mut cond: impl FnMut() -> bool) -> bool {
+        for _ in 0..100 {
+            if cond() {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn domain_set_edits_swap_the_set_without_recompiling_config() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-sets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let write_cfg = |file: &str| {
+            let raw = serde_json::json!({
+                "pipelines": [{ "id": "main", "rules": [
+                    { "name": "block", "matchers": [ { "type": "domain_set", "file": file } ],
+                      "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+                ] }]
+            });
+            std::fs::write(&cfg_path, raw.to_string()).unwrap();
+        };
+        std::fs::write(dir.join("a.txt"), "ads.example.com\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "other.example.net\n").unwrap();
+        write_cfg("a.txt");
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        let before = pipeline.load_full();
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 列表文件变化：同一份配置中的集合被替换
+        std::fs::write(dir.join("a.txt"), "ads.example.com\ntracker.example.org\n").unwrap();
+        assert!(wait_until(|| before.domain_sets()[0].contains("tracker.example.org")));
+        assert!(Arc::ptr_eq(&before, &pipeline.load_full()));
+
+        // 主配置改为引用 b.txt：新文件被 watch，旧文件不再触发重建
+        write_cfg("b.txt");
+        assert!(wait_until(|| pipeline.load().domain_sets()[0].contains("other.example.net")));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        std::fs::write(dir.join("b.txt"), "other.example.net\nnew.example.net\n").unwrap();
+        assert!(wait_until(|| pipeline.load().domain_sets()[0].contains("new.example.net")));
+        std::fs::write(dir.join("a.txt"), "late.example.com\n").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert!(!before.domain_sets()[0].contains("late.example.com"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn renaming_over_a_list_file_keeps_reloading() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-list-rename-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lists")).unwrap();
+        let cfg_path = dir.join("config.json");
+        let list = dir.join("lists/block.txt");
+        std::fs::write(&list, "ads.example.com\n").unwrap();
+        let raw = serde_json::json!({
+            "settings": { "reload_debounce_ms": 50 },
+            "pipelines": [{ "id": "main", "rules": [
+                { "name": "block", "matchers": [ { "type": "domain_set", "file": "lists/block.txt" } ],
+                  "actions": [ { "type": "static_response", "rcode": "NXDOMAIN" } ] }
+            ] }]
+        });
+        std::fs::write(&cfg_path, raw.to_string()).unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        let before = pipeline.load_full();
+        let stats = Arc::new(ReloadStats::new());
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::clone(&stats));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 先写临时文件再 mv 覆盖：每次都换了 inode，列表仍会重建，engine 据计数失效规则缓存
+        for (i, name) in ["first.example.org", "second.example.org"].into_iter().enumerate() {
+            let tmp = dir.join("lists/block.txt.tmp");
+            std::fs::write(&tmp, format!("{name}\n")).unwrap();
+            std::fs::rename(&tmp, &list).unwrap();
+            assert!(wait_until(|| before.domain_sets()[0].contains(name)), "{name}");
+            assert!(wait_until(|| stats.list_reloads() > i as u64));
+        }
+        assert!(Arc::ptr_eq(&before, &pipeline.load_full()));
+        assert_eq!(stats.successes(), 0);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn static_records_file_edits_take_effect_without_recompiling_config() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        std::fs::write(&cfg_path, serde_json::json!({ "settings": { "static_records_file": "hosts" } }).to_string()).unwrap();
+        std::fs::write(dir.join("hosts"), "10.0.0.1 nas.lan\n").unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        let before = pipeline.load_full();
+        let lookup = |qname: &str| before.static_records.as_ref().unwrap().lookup(qname, hickory_proto::rr::RecordType::A);
+        assert!(lookup("nas.lan").is_some());
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        std::fs::write(dir.join("hosts"), "10.0.0.1 nas.lan\n*.lab.lan A 10.0.1.1\n").unwrap();
+        assert!(wait_until(|| lookup("x.lab.lan").is_some()));
+        assert!(Arc::ptr_eq(&before, &pipeline.load_full()));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn reload_stats_count_successes_and_failures() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let write_cfg = |min_ttl: u32| {
+            let raw = serde_json::json!({ "settings": { "min_ttl": min_ttl }, "pipelines": [] });
+            std::fs::write(&cfg_path, raw.to_string()).unwrap();
+        };
+        write_cfg(0);
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        let stats = Arc::new(ReloadStats::new());
+        assert!(stats.last_success_unix() > 0);
+        stats.last_success_unix.store(0, Ordering::Relaxed);
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::clone(&stats));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 校验失败：旧配置继续生效，只记失败
+        std::fs::write(&cfg_path, "{ \"settings\": { \"min_ttl\": ").unwrap();
+        assert!(wait_until(|| stats.failures() > 0));
+        assert_eq!((stats.successes(), stats.last_success_unix()), (0, 0));
+        assert_eq!(pipeline.load().settings.min_ttl, 0);
+
+        write_cfg(30);
+        assert!(wait_until(|| stats.successes() > 0));
+        assert!(stats.last_success_unix() > 0);
+        assert!(wait_until(|| pipeline.load().settings.min_ttl == 30));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn bursts_of_config_writes_reload_once() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-debounce-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let write_cfg = |min_ttl: u32| {
+            let raw = serde_json::json!({ "settings": { "min_ttl": min_ttl, "reload_debounce_ms": 300 }, "pipelines": [] });
+            std::fs::write(&cfg_path, raw.to_string()).unwrap();
+        };
+        write_cfg(0);
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        let stats = Arc::new(ReloadStats::new());
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::clone(&stats));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 窗口内连续三次写入（每次写入本身还会产生多个事件）只触发一次加载，且加载的是最后一版
+        for min_ttl in [10, 20, 30] {
+            write_cfg(min_ttl);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        assert!(wait_until(|| stats.successes() > 0));
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        assert_eq!((stats.successes(), stats.failures()), (1, 0));
+        assert_eq!(pipeline.load().settings.min_ttl, 30);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rename_over_the_config_keeps_reloading() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-rename-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let config_json = |min_ttl: u32| serde_json::json!({ "settings": { "min_ttl": min_ttl, "reload_debounce_ms": 50 } }).to_string();
+        std::fs::write(&cfg_path, config_json(0)).unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 先写临时文件再 rename 覆盖：每次都换了 inode，watch 仍然有效
+        for min_ttl in [10, 20] {
+            let tmp = dir.join("config.json.tmp");
+            std::fs::write(&tmp, config_json(min_ttl)).unwrap();
+            std::fs::rename(&tmp, &cfg_path).unwrap();
+            assert!(wait_until(|| pipeline.load().settings.min_ttl == min_ttl), "min_ttl {min_ttl}");
+        }
+        // 之后原地编辑同样生效
+        std::fs::write(&cfg_path, config_json(30)).unwrap();
+        assert!(wait_until(|| pipeline.load().settings.min_ttl == 30));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn unrelated_files_in_the_config_dir_do_not_delay_reloads() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-noise-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let config_json = |min_ttl: u32| serde_json::json!({ "settings": { "min_ttl": min_ttl, "reload_debounce_ms": 200 } }).to_string();
+        std::fs::write(&cfg_path, config_json(0)).unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 同目录中的其他文件每 50ms 写一次，持续远超 debounce
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let noise = {
+            let (dir, stop) = (dir.clone(), Arc::clone(&stop));
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::fs::write(dir.join("noise.log"), "x").unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+            })
+        };
+        std::fs::write(&cfg_path, config_json(10)).unwrap();
+        let reloaded = wait_until(|| pipeline.load().settings.min_ttl == 10);
+        stop.store(true, Ordering::Relaxed);
+        noise.join().unwrap();
+        assert!(reloaded);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn continuous_config_writes_still_reload_within_the_batch_cap() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-cap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_path = dir.join("config.json");
+        let config_json = |min_ttl: u32| serde_json::json!({ "settings": { "min_ttl": min_ttl, "reload_debounce_ms": 100 } }).to_string();
+        std::fs::write(&cfg_path, config_json(0)).unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&cfg_path).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        spawn(cfg_path.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 每 30ms 重写一次主配置，安静期永远不会结束；批次上限（10 个 debounce）到了仍然加载
+        let started = std::time::Instant::now();
+        let mut reloaded = false;
+        while started.elapsed() < std::time::Duration::from_secs(3) {
+            std::fs::write(&cfg_path, config_json(10)).unwrap();
+            if pipeline.load().settings.min_ttl == 10 {
+                reloaded = true;
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(30));
+        }
+        assert!(reloaded);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repointing_a_config_symlink_reloads_the_new_target() {
+        let dir = std::env::temp_dir().join(format!("kixdns-watch-symlink-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("releases")).unwrap();
+        let config_json = |min_ttl: u32| serde_json::json!({ "settings": { "min_ttl": min_ttl, "reload_debounce_ms": 50 } }).to_string();
+        let (v1, v2) = (dir.join("releases/v1.json"), dir.join("releases/v2.json"));
+        std::fs::write(&v1, config_json(0)).unwrap();
+        std::fs::write(&v2, config_json(40)).unwrap();
+        let link = dir.join("config.json");
+        std::os::unix::fs::symlink(&v1, &link).unwrap();
+        let initial = RuntimePipelineConfig::from_config(config::load_config(&link).unwrap()).unwrap();
+        let pipeline = Arc::new(ArcSwap::from_pointee(initial));
+        spawn(link.clone(), Arc::clone(&pipeline), Arc::default());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // 原地编辑链接指向的文件
+        std::fs::write(&v1, config_json(5)).unwrap();
+        assert!(wait_until(|| pipeline.load().settings.min_ttl == 5));
+
+        // 与 ln -sfn 相同：新建临时链接再 rename 覆盖
+        let tmp = dir.join("config.json.new");
+        std::os::unix::fs::symlink(&v2, &tmp).unwrap();
+        std::fs::rename(&tmp, &link).unwrap();
+        assert!(wait_until(|| pipeline.load().settings.min_ttl == 40));
+
+        // 之后只跟随新目标，旧目标的修改不再触发加载
+        std::fs::write(&v2, config_json(50)).unwrap();
+        assert!(wait_until(|| pipeline.load().settings.min_ttl == 50));
+        std::fs::write(&v1, config_json(60)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert_eq!(pipeline.load().settings.min_ttl, 50);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }